@@ -5,15 +5,21 @@
 use axum::extract::DefaultBodyLimit;
 use axum::{body::Body, extract::Request, routing::get, Json};
 use clap::{Parser, ValueHint};
-use cloudstate_runtime::backup::BackupProgress;
-use cloudstate_runtime::ServerInfo;
+use cloudstate_runtime::backup::{
+    BackupProgress, BlobBackup, list_snapshots, prune_snapshots, restore_backup,
+};
+use cloudstate_runtime::check::check_database;
+use cloudstate_runtime::export::{export_ndjson, import_ndjson};
+use cloudstate_runtime::migrations::migrate;
+use cloudstate_runtime::refcount;
+use cloudstate_runtime::{RequestArgs, ServerInfo};
 use cloudstate_runtime::{
     blob_storage::{
         fs_store::FsBlobStore, in_memory_store::InMemoryBlobStore, CloudstateBlobStorage,
         CloudstateBlobStorageEngine,
     },
     extensions::cloudstate::ReDBCloudstate,
-    gc::mark_and_sweep,
+    gc::{collect, collect_database, GcOptions},
 };
 use indicatif::ProgressBar;
 use notify::Watcher;
@@ -21,14 +27,15 @@ use redb::{
     backends::{self},
     Database,
 };
-use server::cloudstate_runner::simple::SimpleCloudstateRunner;
+use server::cloudstate_runner::{limits::ScriptLimits, simple::SimpleCloudstateRunner};
 use server::{cloudstate_runner::execute::execute_script, CloudstateServer};
 use std::{
     collections::HashMap,
     fs::{self},
     future::poll_fn,
+    io::{BufReader, BufWriter, Write},
     os::unix::fs::MetadataExt,
-    path::Path,
+    path::{Path, PathBuf},
     sync::{Arc, Mutex},
     time::Duration,
 };
@@ -36,7 +43,7 @@ use tokio::net::TcpListener;
 use tokio::runtime::Runtime; // 0.3.5
 use tokio::sync::RwLock;
 use tower::Service;
-use tracing::{debug, info};
+use tracing::{debug, error, info};
 
 #[derive(clap::Parser)]
 struct CliArguments {
@@ -59,6 +66,56 @@ struct CliArguments {
         help = "Only store data in memory"
     )]
     memory_only: bool,
+
+    #[arg(
+        long = "snapshot-interval",
+        required = false,
+        help = "Snapshot the database every this many seconds while serving"
+    )]
+    snapshot_interval: Option<u64>,
+
+    #[arg(
+        long = "snapshot-dir",
+        help = "The directory to write snapshots to",
+        default_value = "cloudstate-snapshots"
+    )]
+    snapshot_dir: String,
+
+    #[arg(
+        long = "snapshot-retention",
+        help = "How many snapshots to keep, deleting the oldest first",
+        default_value_t = 24
+    )]
+    snapshot_retention: usize,
+
+    #[arg(
+        long = "gc-interval",
+        required = false,
+        help = "Collect garbage every this many seconds while serving"
+    )]
+    gc_interval: Option<u64>,
+
+    #[arg(
+        long = "reference-counting",
+        num_args = 0,
+        required = false,
+        help = "Delete objects as soon as nothing references them, instead of waiting for the garbage collector"
+    )]
+    reference_counting: bool,
+
+    #[arg(
+        long = "script-timeout",
+        help = "Terminate a request's script after this many seconds while serving",
+        default_value_t = 30
+    )]
+    script_timeout: u64,
+
+    #[arg(
+        long = "script-heap-limit",
+        help = "Terminate a request's script once the heap grows past this many megabytes while serving",
+        default_value_t = 512
+    )]
+    script_heap_limit: usize,
 }
 
 #[derive(clap::Parser)]
@@ -69,6 +126,19 @@ struct GcArguments {
         help = "The database file to run the garbage collector on"
     )]
     filename: String,
+    #[arg(
+        long = "blobs-dir",
+        help = "The directory the database's blobs are stored in",
+        default_value = "cloudstate-blobs"
+    )]
+    blobs_dir: String,
+    #[arg(
+        long = "dry-run",
+        num_args = 0,
+        required = false,
+        help = "Report what would be deleted, and how many bytes of blobs, without deleting anything"
+    )]
+    dry_run: bool,
 }
 
 #[derive(clap::Parser)]
@@ -87,6 +157,172 @@ struct BackupArguments {
         default_value = "backup"
     )]
     backup_filename: String,
+    #[arg(
+        long = "include-blobs",
+        num_args = 0,
+        required = false,
+        help = "Also copy blob payloads into a directory named after the backup file with -blobs appended, which can be used as a blob directory directly"
+    )]
+    include_blobs: bool,
+    #[arg(
+        long = "blobs-dir",
+        help = "The directory the database's blobs are stored in",
+        default_value = "cloudstate-blobs"
+    )]
+    blobs_dir: String,
+    #[arg(
+        long,
+        num_args = 0,
+        required = false,
+        requires = "since",
+        help = "Only copy what changed after the sequence given by --since"
+    )]
+    incremental: bool,
+    #[arg(
+        long,
+        help = "The change sequence printed by the backup this one builds on"
+    )]
+    since: Option<u64>,
+    #[arg(
+        long,
+        num_args = 0,
+        required = false,
+        conflicts_with = "incremental",
+        help = "After a full backup, drop the rows removed before it from the change log, so incremental backups can only start from this backup or a later one"
+    )]
+    compact: bool,
+}
+
+#[derive(clap::Parser)]
+struct RestoreArguments {
+    #[arg(
+        required = true,
+        long,
+        help = "The backup or snapshot file to restore from"
+    )]
+    from: String,
+    #[arg(
+        long = "delta",
+        help = "An incremental backup to apply on top, can be given more than once in the order the backups were taken"
+    )]
+    deltas: Vec<String>,
+    #[arg(
+        long,
+        help = "The database file to replace",
+        default_value = "cloudstate"
+    )]
+    filename: String,
+    #[arg(
+        long = "blobs-dir",
+        help = "The directory the backup's blobs are stored in, whose payloads move if an older backup gives them new ids",
+        default_value = "cloudstate-blobs"
+    )]
+    blobs_dir: String,
+}
+
+#[derive(clap::Parser)]
+struct SnapshotsArguments {
+    #[arg(
+        long,
+        help = "The directory snapshots were written to",
+        default_value = "cloudstate-snapshots"
+    )]
+    dir: String,
+}
+
+#[derive(clap::Parser)]
+struct ExportArguments {
+    #[arg(
+        long,
+        help = "The database file to export",
+        default_value = "cloudstate"
+    )]
+    filename: String,
+    #[arg(
+        long,
+        help = "The NDJSON file to write to",
+        default_value = "cloudstate.ndjson"
+    )]
+    to: String,
+    #[arg(
+        long = "include-blobs",
+        num_args = 0,
+        required = false,
+        help = "Also copy blob payloads into a directory named after the export file with -blobs appended, which import reads them from"
+    )]
+    include_blobs: bool,
+    #[arg(
+        long = "blobs-dir",
+        help = "The directory the database's blobs are stored in",
+        default_value = "cloudstate-blobs"
+    )]
+    blobs_dir: String,
+}
+
+#[derive(clap::Parser)]
+struct ImportArguments {
+    #[arg(required = true, long, help = "The NDJSON export to read")]
+    from: String,
+    #[arg(
+        long,
+        help = "The database file to create",
+        default_value = "cloudstate"
+    )]
+    filename: String,
+    #[arg(
+        long = "include-blobs",
+        num_args = 0,
+        required = false,
+        help = "Also copy blob payloads from the directory named after the export file with -blobs appended"
+    )]
+    include_blobs: bool,
+    #[arg(
+        long = "blobs-dir",
+        help = "The directory to store the database's blobs in",
+        default_value = "cloudstate-blobs"
+    )]
+    blobs_dir: String,
+}
+
+#[derive(clap::Parser)]
+struct MigrateArguments {
+    #[clap(value_hint = ValueHint::FilePath)]
+    classes: String,
+    #[arg(
+        long,
+        help = "The database file to migrate",
+        default_value = "cloudstate"
+    )]
+    filename: String,
+    #[arg(
+        long = "blobs-dir",
+        help = "The directory the database's blobs are stored in",
+        default_value = "cloudstate-blobs"
+    )]
+    blobs_dir: String,
+}
+
+#[derive(clap::Parser)]
+struct CheckArguments {
+    #[arg(
+        long,
+        help = "The database file to check",
+        default_value = "cloudstate"
+    )]
+    filename: String,
+    #[arg(
+        long = "blobs-dir",
+        help = "The directory the database's blobs are stored in",
+        default_value = "cloudstate-blobs"
+    )]
+    blobs_dir: String,
+    #[arg(
+        long,
+        num_args = 0,
+        required = false,
+        help = "Move rows that don't decode into the quarantine table, so the rest of the database can be used"
+    )]
+    quarantine: bool,
 }
 
 #[derive(clap::Parser)]
@@ -107,8 +343,43 @@ enum Cli {
     Serve(CliArguments),
     #[command(name = "gc", about = "Runs the garbage collector on a database file")]
     Gc(GcArguments),
-    #[command(name = "backup", about = "Backs up a database file")]
+    #[command(
+        name = "backup",
+        about = "Backs up a database file",
+        long_about = "Backs up a database file. The change log incremental backups are built from only grows, pass --compact with a full backup to drop what it covers once earlier backups and snapshots are no longer needed as a base. Snapshots taken by serve never compact it."
+    )]
     Backup(BackupArguments),
+    #[command(
+        name = "restore",
+        about = "Replaces a database file with a backup or snapshot",
+        long_about = "Replaces a database file with a backup or snapshot. The backup is checked against the tables this version of cloudstate knows about before it's swapped in."
+    )]
+    Restore(RestoreArguments),
+    #[command(name = "snapshots", about = "Lists the snapshots taken by serve")]
+    Snapshots(SnapshotsArguments),
+    #[command(
+        name = "export",
+        about = "Exports a database file as NDJSON",
+        long_about = "Exports a database file as NDJSON, in a format that doesn't depend on the version of cloudstate. The file lists blobs without their payloads, pass --include-blobs to copy them from --blobs-dir into a directory named after the export file with -blobs appended, which import --include-blobs reads them from."
+    )]
+    Export(ExportArguments),
+    #[command(
+        name = "import",
+        about = "Creates a database file from an NDJSON export"
+    )]
+    Import(ImportArguments),
+    #[command(
+        name = "migrate",
+        about = "Migrates stored objects to the current version of their classes",
+        long_about = "Migrates stored objects to the current version of their classes. Objects are otherwise migrated when they're first read, this upgrades all of them at once."
+    )]
+    Migrate(MigrateArguments),
+    #[command(
+        name = "check",
+        about = "Checks every row of a database file decodes",
+        long_about = "Checks every row of a database file decodes, and lists the rows that don't. With --quarantine, those rows are moved into a quarantine table keyed by their table and key, so ops stop failing on them."
+    )]
+    Check(CheckArguments),
 }
 
 #[tokio::main]
@@ -149,6 +420,11 @@ async fn main() {
 
             let blob_storage = CloudstateBlobStorage::new(engine);
 
+            if let Err(e) = migrate(&db, &blob_storage) {
+                error!("Failed to open the database: {:?}", e);
+                return;
+            }
+
             // todo get output
             let result = execute_script(
                 &format!(
@@ -164,12 +440,14 @@ async fn main() {
                 }}"
                 ),
                 "",
-                ReDBCloudstate::new(Arc::new(Mutex::new(db))),
+                ReDBCloudstate::new(Arc::new(Mutex::new(db))).unwrap(),
                 blob_storage,
                 ServerInfo {
                     deployment_id: None,
                     domain: None,
                 },
+                RequestArgs::default(),
+                ScriptLimits::unlimited(),
             )
             .await;
 
@@ -179,6 +457,13 @@ async fn main() {
             filename,
             watch,
             memory_only,
+            snapshot_interval,
+            snapshot_dir,
+            snapshot_retention,
+            gc_interval,
+            reference_counting,
+            script_timeout,
+            script_heap_limit,
         }) => {
             let env: HashMap<String, String> = std::env::vars().collect();
 
@@ -201,16 +486,38 @@ async fn main() {
 
             let blob_storage = CloudstateBlobStorage::new(blob_storage_engine.clone());
 
+            if let Err(e) = migrate(&db, &blob_storage) {
+                error!("Failed to open the database: {:?}", e);
+                return;
+            }
+
+            // whether counts are kept is stored in the database, so it has to
+            // be turned off again when the flag is left out
+            let counting = if reference_counting {
+                refcount::enable(&db)
+            } else {
+                refcount::disable(&db)
+            };
+            if let Err(e) = counting {
+                error!("Failed to set up reference counts: {:?}", e);
+                return;
+            }
+
+            let limits = ScriptLimits {
+                timeout: Some(Duration::from_secs(script_timeout)),
+                max_heap_bytes: Some(script_heap_limit * 1024 * 1024),
+            };
+
             let classes = fs::read_to_string(&filename).unwrap_or("".to_string());
             let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await.unwrap();
-            let cloudstate = ReDBCloudstate::new(Arc::new(Mutex::new(db)));
+            let cloudstate = ReDBCloudstate::new(Arc::new(Mutex::new(db))).unwrap();
             let server = CloudstateServer::new(
                 cloudstate.clone(),
                 blob_storage.clone(),
                 &classes,
                 env.clone(),
                 "http://localhost:8910/__invalidate__".to_string(),
-                SimpleCloudstateRunner::new(),
+                SimpleCloudstateRunner::with_limits(limits.clone()),
                 ServerInfo {
                     deployment_id: None,
                     domain: None,
@@ -218,6 +525,63 @@ async fn main() {
             )
             .await;
 
+            if let Some(interval) = snapshot_interval {
+                let cloudstate = cloudstate.clone();
+                tokio::spawn(async move {
+                    let mut ticker = tokio::time::interval(Duration::from_secs(interval));
+                    // the first tick completes immediately
+                    ticker.tick().await;
+                    loop {
+                        ticker.tick().await;
+
+                        let cloudstate = cloudstate.clone();
+                        let snapshot_dir = snapshot_dir.clone();
+                        let result = tokio::task::spawn_blocking(move || {
+                            cloudstate.snapshot(&snapshot_dir).and_then(|path| {
+                                prune_snapshots(&snapshot_dir, snapshot_retention)?;
+                                Ok(path)
+                            })
+                        })
+                        .await
+                        .unwrap();
+
+                        match result {
+                            Ok(path) => info!("Wrote snapshot {:?}", path),
+                            Err(e) => error!("Failed to write snapshot: {:?}", e),
+                        }
+                    }
+                });
+            }
+
+            if let Some(interval) = gc_interval {
+                let cloudstate = cloudstate.clone();
+                let blob_storage = blob_storage.clone();
+                tokio::spawn(async move {
+                    let mut ticker = tokio::time::interval(Duration::from_secs(interval));
+                    // the first tick completes immediately
+                    ticker.tick().await;
+                    loop {
+                        ticker.tick().await;
+
+                        let cloudstate = cloudstate.clone();
+                        let blob_storage = blob_storage.clone();
+                        let result = tokio::task::spawn_blocking(move || {
+                            collect(&cloudstate, &blob_storage, &GcOptions::default(), &mut None)
+                        })
+                        .await
+                        .unwrap();
+
+                        match result {
+                            Ok(progress) => info!(
+                                "Collected garbage, deleted {} rows and {} blobs",
+                                progress.deleted, progress.blobs
+                            ),
+                            Err(e) => error!("Failed to collect garbage: {:?}", e),
+                        }
+                    }
+                });
+            }
+
             let app_state = Arc::new(RwLock::new(server));
 
             let cloned = Arc::clone(&app_state);
@@ -253,7 +617,7 @@ async fn main() {
                                         &new_classes,
                                         env.clone(),
                                         "http://localhost:8910/__invalidate__".to_string(),
-                                        SimpleCloudstateRunner::new(),
+                                        SimpleCloudstateRunner::with_limits(limits.clone()),
                                         ServerInfo {
                                             deployment_id: None,
                                             domain: None,
@@ -286,12 +650,35 @@ async fn main() {
                 other_thread.await.unwrap()
             }
         }
-        Cli::Gc(GcArguments { filename }) => {
+        Cli::Gc(GcArguments {
+            filename,
+            blobs_dir,
+            dry_run,
+        }) => {
             let metadata_before = fs::metadata(filename.clone()).unwrap();
 
             if let Ok(mut cloudstate) = Database::open(filename.clone()) {
+                let blob_storage =
+                    CloudstateBlobStorage::new(Arc::new(FsBlobStore::new(blobs_dir.into())));
+
+                if let Err(e) = migrate(&cloudstate, &blob_storage) {
+                    error!("Failed to open the database: {:?}", e);
+                    return;
+                }
+                let options = GcOptions {
+                    dry_run,
+                    ..GcOptions::default()
+                };
+
                 info!("Running garbage collection");
-                match mark_and_sweep(&cloudstate) {
+                match collect_database(&cloudstate, &blob_storage, &options, &mut None) {
+                    Ok(progress) if dry_run => {
+                        info!(
+                            "Would delete {} of {} rows, including {} blobs taking up {} bytes",
+                            progress.deleted, progress.scanned, progress.blobs, progress.blob_bytes
+                        );
+                        return;
+                    }
                     Ok(_) => {
                         info!("Garbage collection complete");
                     }
@@ -330,6 +717,11 @@ async fn main() {
         Cli::Backup(BackupArguments {
             filename,
             backup_filename,
+            include_blobs,
+            blobs_dir,
+            incremental,
+            since,
+            compact,
         }) => {
             let db = match Database::open(filename.clone()) {
                 Ok(db) => db,
@@ -344,50 +736,305 @@ async fn main() {
                 }
             };
 
-            let cloudstate = ReDBCloudstate::new(Arc::new(Mutex::new(db)));
+            let blob_storage =
+                CloudstateBlobStorage::new(Arc::new(FsBlobStore::new(blobs_dir.into())));
+
+            if let Err(e) = migrate(&db, &blob_storage) {
+                error!("Failed to open the database: {:?}", e);
+                return;
+            }
+
+            let cloudstate = ReDBCloudstate::new(Arc::new(Mutex::new(db))).unwrap();
+            let blobs_backup_dir = PathBuf::from(format!("{}-blobs", backup_filename));
+            let blobs_backup = FsBlobStore::new(blobs_backup_dir.clone());
+            if include_blobs {
+                if let Err(e) = fs::create_dir_all(&blobs_backup_dir) {
+                    info!("Failed to create {:?}: {:?}", blobs_backup_dir, e);
+                    return;
+                }
+            }
+            let blob_backup = include_blobs.then(|| BlobBackup {
+                source: &blob_storage,
+                destination: &blobs_backup,
+            });
+
             let style = indicatif::ProgressStyle::default_bar()
                 .template("{msg:<18} {bar:40.green/white} ({pos}/{len})")
                 .unwrap();
             // .progress_chars("=>|");
             let mut current_progress_bar = ProgressBarState::None;
-            cloudstate
-                .backup(
-                    backup_filename.clone(),
-                    &mut Some(Box::new(|progress: BackupProgress| {
-                        if let Some(current_table) = progress.tables.last() {
-                            let (table_name, table_progress) = current_table;
-                            match &mut current_progress_bar {
-                                ProgressBarState::None => {
-                                    let bar = ProgressBar::new(table_progress.total)
-                                        .with_style(style.clone())
-                                        .with_message(format!("Backing up {}", table_name));
-
-                                    current_progress_bar =
-                                        ProgressBarState::Named(table_name.clone(), bar);
-                                }
-                                ProgressBarState::Named(name, pb) => {
-                                    if name != table_name {
+            let mut progress_callback: Option<Box<dyn FnMut(BackupProgress) + '_>> =
+                Some(Box::new(|progress: BackupProgress| {
+                    if let Some(current_table) = progress.tables.last() {
+                        let (table_name, table_progress) = current_table;
+                        match &mut current_progress_bar {
+                            ProgressBarState::None => {
+                                let bar = ProgressBar::new(table_progress.total)
+                                    .with_style(style.clone())
+                                    .with_message(format!("Backing up {}", table_name));
+
+                                current_progress_bar =
+                                    ProgressBarState::Named(table_name.clone(), bar);
+                            }
+                            ProgressBarState::Named(name, pb) => {
+                                if name != table_name {
+                                    pb.finish();
+                                    current_progress_bar = ProgressBarState::Named(
+                                        table_name.clone(),
+                                        ProgressBar::new(table_progress.total)
+                                            .with_style(style.clone())
+                                            .with_message(format!("Backing up {}", table_name)),
+                                    );
+                                } else {
+                                    pb.set_position(table_progress.current);
+                                    if table_progress.current == table_progress.total {
                                         pb.finish();
-                                        current_progress_bar = ProgressBarState::Named(
-                                            table_name.clone(),
-                                            ProgressBar::new(table_progress.total)
-                                                .with_style(style.clone())
-                                                .with_message(format!("Backing up {}", table_name)),
-                                        );
-                                    } else {
-                                        pb.set_position(table_progress.current);
-                                        if table_progress.current == table_progress.total {
-                                            pb.finish();
-                                        }
                                     }
                                 }
                             }
                         }
+                    }
 
-                        // progress_bar_height = progress.tables.len();
-                    })),
-                )
-                .unwrap();
+                    // progress_bar_height = progress.tables.len();
+                }));
+
+            let result = match (incremental, since) {
+                (true, Some(since)) => cloudstate.backup_incremental(
+                    backup_filename.clone(),
+                    since,
+                    blob_backup.as_ref(),
+                    &mut progress_callback,
+                ),
+                _ => cloudstate.backup(
+                    backup_filename.clone(),
+                    blob_backup.as_ref(),
+                    &mut progress_callback,
+                ),
+            };
+            match result {
+                Ok(sequence) => {
+                    info!(
+                        "Backed up changes up to sequence {}, pass --incremental --since {} to back up from here",
+                        sequence, sequence
+                    );
+                    if compact {
+                        match cloudstate.compact_change_log(sequence) {
+                            Ok(compacted) => info!(
+                                "Dropped {} removed rows from the change log, earlier backups can no longer be built on",
+                                compacted
+                            ),
+                            Err(e) => error!("Failed to compact the change log: {:?}", e),
+                        }
+                    }
+                }
+                Err(e) => error!("Failed to back up: {:?}", e),
+            }
+        }
+        Cli::Restore(RestoreArguments {
+            from,
+            deltas,
+            filename,
+            blobs_dir,
+        }) => match restore_backup(
+            &from,
+            &deltas,
+            &filename,
+            &CloudstateBlobStorage::new(Arc::new(FsBlobStore::new(blobs_dir.into()))),
+        ) {
+            Ok(()) => info!("Restored {:?} from {:?}", filename, from),
+            Err(e) => error!("Failed to restore: {:?}", e),
+        },
+        Cli::Snapshots(SnapshotsArguments { dir }) => match list_snapshots(&dir) {
+            Ok(snapshots) if snapshots.is_empty() => info!("No snapshots in {:?}", dir),
+            Ok(snapshots) => {
+                for snapshot in snapshots {
+                    println!("{}  {}", snapshot.created_at, snapshot.path.display());
+                }
+            }
+            Err(e) => error!("Failed to list snapshots: {:?}", e),
+        },
+        Cli::Export(ExportArguments {
+            filename,
+            to,
+            include_blobs,
+            blobs_dir,
+        }) => {
+            let blob_storage =
+                CloudstateBlobStorage::new(Arc::new(FsBlobStore::new(blobs_dir.into())));
+            let exported_blobs_dir = PathBuf::from(format!("{}-blobs", to));
+            let exported_blobs = FsBlobStore::new(exported_blobs_dir.clone());
+            let blob_export = include_blobs.then(|| BlobBackup {
+                source: &blob_storage,
+                destination: &exported_blobs,
+            });
+
+            let result = Database::open(&filename)
+                .map_err(|e| e.to_string())
+                .and_then(|db| {
+                    migrate(&db, &blob_storage).map_err(|e| e.to_string())?;
+                    if include_blobs {
+                        fs::create_dir_all(&exported_blobs_dir).map_err(|e| e.to_string())?;
+                    }
+                    let read = db.begin_read().map_err(|e| e.to_string())?;
+                    let mut out = BufWriter::new(fs::File::create(&to).map_err(|e| e.to_string())?);
+                    let count = export_ndjson(&read, &mut out, blob_export.as_ref())
+                        .map_err(|e| e.to_string())?;
+                    out.flush().map_err(|e| e.to_string())?;
+                    Ok(count)
+                });
+
+            match result {
+                Ok(count) => info!("Exported {} records from {:?} to {:?}", count, filename, to),
+                Err(e) => error!("Failed to export: {}", e),
+            }
+        }
+        Cli::Import(ImportArguments {
+            from,
+            filename,
+            include_blobs,
+            blobs_dir,
+        }) => {
+            if Path::new(&filename).exists() {
+                error!(
+                    "{:?} already exists, import into a new database file",
+                    filename
+                );
+                return;
+            }
+
+            let exported_blobs = CloudstateBlobStorage::new(Arc::new(FsBlobStore::new(
+                PathBuf::from(format!("{}-blobs", from)),
+            )));
+            let blobs_dir = PathBuf::from(blobs_dir);
+            let blobs = FsBlobStore::new(blobs_dir.clone());
+            let blob_import = include_blobs.then(|| BlobBackup {
+                source: &exported_blobs,
+                destination: &blobs,
+            });
+
+            let result = fs::File::open(&from)
+                .map_err(|e| e.to_string())
+                .and_then(|input| {
+                    let db = Database::create(&filename).map_err(|e| e.to_string())?;
+                    // records the format version while the database is empty, so
+                    // there are no blobs to move
+                    migrate(&db, &CloudstateBlobStorage::default()).map_err(|e| e.to_string())?;
+                    if include_blobs {
+                        fs::create_dir_all(&blobs_dir).map_err(|e| e.to_string())?;
+                    }
+                    let write = db.begin_write().map_err(|e| e.to_string())?;
+                    let count = import_ndjson(BufReader::new(input), &write, blob_import.as_ref())
+                        .map_err(|e| e.to_string())?;
+                    write.commit().map_err(|e| e.to_string())?;
+                    Ok(count)
+                });
+
+            match result {
+                Ok(count) => info!(
+                    "Imported {} records from {:?} into {:?}",
+                    count, from, filename
+                ),
+                Err(e) => {
+                    let _ = fs::remove_file(&filename);
+                    error!("Failed to import: {}", e);
+                }
+            }
+        }
+        Cli::Migrate(MigrateArguments {
+            classes,
+            filename,
+            blobs_dir,
+        }) => {
+            let classes = match fs::read_to_string(&classes) {
+                Ok(classes) => classes,
+                Err(e) => {
+                    error!("Failed to read {:?}: {:?}", classes, e);
+                    return;
+                }
+            };
+
+            let db = match Database::open(&filename) {
+                Ok(db) => db,
+                Err(e) => {
+                    error!("Failed to open {:?}: {:?}", filename, e);
+                    return;
+                }
+            };
+
+            let blob_storage =
+                CloudstateBlobStorage::new(Arc::new(FsBlobStore::new(blobs_dir.into())));
+
+            if let Err(e) = migrate(&db, &blob_storage) {
+                error!(
+                    "Failed to migrate {:?} to the current format version: {:?}",
+                    filename, e
+                );
+                return;
+            }
+
+            let result = execute_script(
+                include_str!("./migrate.js"),
+                &classes,
+                ReDBCloudstate::new(Arc::new(Mutex::new(db))).unwrap(),
+                blob_storage,
+                ServerInfo {
+                    deployment_id: None,
+                    domain: None,
+                },
+                RequestArgs::default(),
+                ScriptLimits::unlimited(),
+            )
+            .await;
+
+            println!("{result}");
+        }
+        Cli::Check(CheckArguments {
+            filename,
+            quarantine,
+            blobs_dir,
+        }) => {
+            let blob_storage =
+                CloudstateBlobStorage::new(Arc::new(FsBlobStore::new(blobs_dir.into())));
+            let result = Database::open(&filename)
+                .map_err(|e| e.to_string())
+                .and_then(|db| {
+                    migrate(&db, &blob_storage).map_err(|e| e.to_string())?;
+                    check_database(&db, quarantine).map_err(|e| e.to_string())
+                });
+
+            let report = match result {
+                Ok(report) => report,
+                Err(e) => {
+                    error!("Failed to check: {}", e);
+                    return;
+                }
+            };
+
+            for row in &report.corrupt {
+                let key: String = row.key.iter().map(|byte| format!("{byte:02x}")).collect();
+                println!("{} {}: {}", row.table, key, row.error);
+            }
+
+            if report.corrupt.is_empty() {
+                info!(
+                    "Checked {} rows in {} tables, all of them decode",
+                    report.rows, report.tables
+                );
+            } else if quarantine {
+                info!(
+                    "Checked {} rows in {} tables, moved {} corrupt rows into the quarantine table",
+                    report.rows,
+                    report.tables,
+                    report.corrupt.len()
+                );
+            } else {
+                error!(
+                    "Checked {} rows in {} tables, {} are corrupt. Pass --quarantine to move them out of the way",
+                    report.rows,
+                    report.tables,
+                    report.corrupt.len()
+                );
+            }
         }
     };
 }
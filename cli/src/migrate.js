@@ -0,0 +1,17 @@
+const classes = await import("./lib.js");
+
+for (const klass of Object.values(classes)) {
+  registerCustomClass(klass);
+}
+
+try {
+  globalThis.result = { migrated: migrateObjects() };
+  commit();
+} catch (e) {
+  globalThis.result = {
+    error: {
+      message: e.message,
+      stack: e.stack,
+    },
+  };
+}
@@ -0,0 +1,145 @@
+//! Compares the throughput of large maps and arrays keyed by bincode, which
+//! redb decodes on every comparison, with the order-preserving encoding the
+//! tables use now.
+//!
+//! Run with `cargo bench -p cloudstate --bench keys`.
+
+use std::fmt::Debug;
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use cloudstate_runtime::bincode::Bincode;
+use cloudstate_runtime::extensions::cloudstate::{
+    CloudstateArrayItemKey, CloudstateArrayItemValue, CloudstateId, CloudstateMapFieldKey,
+    CloudstateMapFieldValue, CloudstateMapKey, CloudstatePrimitiveData,
+};
+use cloudstate_runtime::ordered::Ordered;
+use redb::{Database, Key, ReadableTable, TableDefinition, backends::InMemoryBackend};
+
+const ROWS: usize = 200_000;
+
+fn main() {
+    let (map_id, array_id) = (CloudstateId::random(), CloudstateId::random());
+    let map_keys: Vec<CloudstateMapFieldKey> = (0..ROWS)
+        .map(|i| CloudstateMapFieldKey {
+            id: map_id,
+            field: CloudstateMapKey::String(format!("field-{}", i * 7919 % ROWS)),
+        })
+        .collect();
+    let map_value = || CloudstateMapFieldValue {
+        data: CloudstatePrimitiveData::Number(1.0),
+    };
+
+    let array_keys: Vec<CloudstateArrayItemKey> = (0..ROWS as i32)
+        .map(|index| CloudstateArrayItemKey {
+            id: array_id,
+            index,
+        })
+        .collect();
+    let array_value = || CloudstateArrayItemValue {
+        data: CloudstatePrimitiveData::Number(1.0),
+    };
+
+    println!("{} rows", ROWS);
+    compare(
+        "map",
+        TableDefinition::<Bincode<CloudstateMapFieldKey>, Bincode<CloudstateMapFieldValue>>::new(
+            "maps",
+        ),
+        TableDefinition::<Ordered<CloudstateMapFieldKey>, Bincode<CloudstateMapFieldValue>>::new(
+            "maps",
+        ),
+        &map_keys,
+        map_value,
+    );
+    compare(
+        "array",
+        TableDefinition::<Bincode<CloudstateArrayItemKey>, Bincode<CloudstateArrayItemValue>>::new(
+            "arrays",
+        ),
+        TableDefinition::<Ordered<CloudstateArrayItemKey>, Bincode<CloudstateArrayItemValue>>::new(
+            "arrays",
+        ),
+        &array_keys,
+        array_value,
+    );
+}
+
+fn compare<T, V, BK, OK>(
+    name: &str,
+    bincode: TableDefinition<BK, Bincode<V>>,
+    ordered: TableDefinition<OK, Bincode<V>>,
+    keys: &[T],
+    value: impl Fn() -> V,
+) where
+    BK: for<'a> Key<SelfType<'a> = T> + 'static,
+    OK: for<'a> Key<SelfType<'a> = T> + 'static,
+    V: Debug + serde::Serialize + serde::de::DeserializeOwned + 'static,
+{
+    let bincode = run(bincode, keys, &value);
+    let ordered = run(ordered, keys, &value);
+
+    for (operation, bincode, ordered) in [
+        ("insert", bincode.insert, ordered.insert),
+        ("get", bincode.get, ordered.get),
+        ("scan", bincode.scan, ordered.scan),
+    ] {
+        println!(
+            "{:<6} {:<7} bincode {:>10.0} rows/s  ordered {:>10.0} rows/s  {:.1}x",
+            name,
+            operation,
+            keys.len() as f64 / bincode.as_secs_f64(),
+            keys.len() as f64 / ordered.as_secs_f64(),
+            bincode.as_secs_f64() / ordered.as_secs_f64(),
+        );
+    }
+}
+
+struct Timings {
+    insert: Duration,
+    get: Duration,
+    scan: Duration,
+}
+
+fn run<T, K, V>(
+    definition: TableDefinition<K, Bincode<V>>,
+    keys: &[T],
+    value: impl Fn() -> V,
+) -> Timings
+where
+    K: for<'a> Key<SelfType<'a> = T> + 'static,
+    V: Debug + serde::Serialize + serde::de::DeserializeOwned + 'static,
+{
+    let db = Database::builder()
+        .create_with_backend(InMemoryBackend::new())
+        .unwrap();
+
+    let start = Instant::now();
+    let write = db.begin_write().unwrap();
+    {
+        let mut table = write.open_table(definition).unwrap();
+        for key in keys {
+            table.insert(key, value()).unwrap();
+        }
+    }
+    write.commit().unwrap();
+    let insert = start.elapsed();
+
+    let read = db.begin_read().unwrap();
+    let table = read.open_table(definition).unwrap();
+
+    let start = Instant::now();
+    for key in keys {
+        black_box(table.get(key).unwrap().unwrap().value());
+    }
+    let get = start.elapsed();
+
+    let start = Instant::now();
+    for item in table.iter().unwrap() {
+        let (key, value) = item.unwrap();
+        black_box((key.value(), value.value()));
+    }
+    let scan = start.elapsed();
+
+    Timings { insert, get, scan }
+}
@@ -1,9 +1,40 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail};
+use chrono::{DateTime, Utc};
+use tracing::warn;
+
+use crate::bincode::{Bincode, raw};
+use crate::blob_storage::{CloudstateBlobStorage, CloudstateBlobStorageEngine};
+use crate::changes::{CloudstateChange, last_sequence};
+use crate::extensions::cloudstate::{CloudstateBlobKey, CloudstateId};
+use crate::migrations::migrate;
 use redb::{
-    ReadTransaction, ReadableTable, ReadableTableMetadata, TableDefinition, TableHandle,
-    WriteTransaction,
+    Database, DatabaseError, ReadTransaction, ReadableTable, ReadableTableMetadata,
+    TableDefinition, TableError, TableHandle, Value, WriteTransaction,
+};
+
+use crate::tables::{
+    ARRAY_METADATA_TABLE, ARRAYS_TABLE, BLOBS_TABLE, CHANGE_SEQUENCES_TABLE, CHANGES_TABLE,
+    CLASS_VERSIONS_INDEX, FIELD_INDEX_TABLE, INDEXED_FIELDS_TABLE, MAPS_TABLE, METADATA_TABLE,
+    OBJECT_IDS_INDEX, OBJECTS_TABLE, QUARANTINE_TABLE, REFERENCE_COUNTS_TABLE, ROOTS_TABLE,
+    SETS_TABLE,
 };
 
-use crate::tables::{ARRAYS_TABLE, BLOBS_TABLE, MAPS_TABLE, OBJECTS_TABLE, ROOTS_TABLE};
+/// Only found in backup files. Holds the change sequence a backup covers up
+/// to under "sequence", and for incremental backups the sequence they start
+/// after under "since".
+const BACKUP_INFO_TABLE: TableDefinition<&str, u64> = TableDefinition::new("backup_info");
+
+/// Only found in incremental backups. Rows removed since the previous backup.
+const DELETED_ROWS_TABLE: TableDefinition<Bincode<CloudstateChange>, ()> =
+    TableDefinition::new("deleted_rows");
+
+/// Kept in the metadata table. The change log no longer has the removals from
+/// before this sequence, so incremental backups can't start before it.
+const CHANGES_COMPACTED_KEY: &str = "changes_compacted_until";
 
 impl<K: redb::Key, V: redb::Value> Backup for TableDefinition<'_, K, V> {
     fn backup(
@@ -14,12 +45,101 @@ impl<K: redb::Key, V: redb::Value> Backup for TableDefinition<'_, K, V> {
     ) -> anyhow::Result<()> {
         backup_table(*self, read, write, handle)
     }
+
+    fn table_name(&self) -> &str {
+        TableHandle::name(self)
+    }
+
+    fn validate(&self, read: &ReadTransaction) -> anyhow::Result<()> {
+        match read.open_table(*self) {
+            Ok(_) | Err(TableError::TableDoesNotExist(_)) => Ok(()),
+            Err(e) => Err(anyhow!(
+                "Table {:?} doesn't match this version of cloudstate: {}",
+                self.table_name(),
+                e
+            )),
+        }
+    }
+
+    fn backup_keys(
+        &self,
+        read: &ReadTransaction,
+        write: &WriteTransaction,
+        keys: &BTreeSet<Vec<u8>>,
+        handle: &mut ProgressHandle,
+    ) -> anyhow::Result<Vec<Vec<u8>>> {
+        // read as bytes, since removed keys can be ones that were quarantined
+        // because they don't decode
+        let table = match read.open_table(raw(self)) {
+            Ok(table) => table,
+            Err(TableError::TableDoesNotExist(_)) => return Ok(keys.iter().cloned().collect()),
+            Err(e) => return Err(e.into()),
+        };
+        let mut write_table = write.open_table(raw(self))?;
+
+        let mut deleted = Vec::new();
+        for (index, bytes) in keys.iter().enumerate() {
+            match table.get(bytes.as_slice())? {
+                Some(value) => {
+                    write_table.insert(bytes.as_slice(), value.value())?;
+                }
+                None => deleted.push(bytes.clone()),
+            }
+            handle.report(self.table_name(), keys.len() as u64, index as u64 + 1);
+        }
+
+        Ok(deleted)
+    }
+
+    fn contains_key(&self, write: &WriteTransaction, key: &[u8]) -> anyhow::Result<bool> {
+        let table = write.open_table(raw(self))?;
+        Ok(table.get(key)?.is_some())
+    }
+
+    fn apply_delta(
+        &self,
+        delta: &ReadTransaction,
+        write: &WriteTransaction,
+        deleted: &[Vec<u8>],
+    ) -> anyhow::Result<()> {
+        // as bytes, since removed keys can be ones that were quarantined
+        // because they don't decode
+        let mut write_table = write.open_table(raw(self))?;
+
+        match delta.open_table(raw(self)) {
+            Ok(table) => {
+                for item in table.iter()? {
+                    let (key, value) = item?;
+                    write_table.insert(key.value(), value.value())?;
+                }
+            }
+            Err(TableError::TableDoesNotExist(_)) => {}
+            Err(e) => return Err(e.into()),
+        }
+
+        for key in deleted {
+            write_table.remove(key.as_slice())?;
+        }
+
+        Ok(())
+    }
 }
 
 pub struct ProgressHandle<'a> {
     progress_callback: Option<Box<dyn FnMut(String, TableBackupProgress) + 'a>>,
 }
 
+impl ProgressHandle<'_> {
+    fn report(&mut self, table_name: &str, total: u64, current: u64) {
+        if let Some(ref mut callback) = self.progress_callback {
+            callback(
+                table_name.to_string(),
+                TableBackupProgress { total, current },
+            );
+        }
+    }
+}
+
 pub trait Backup {
     fn backup(
         &self,
@@ -28,15 +148,56 @@ pub trait Backup {
         handle: &mut ProgressHandle,
         // progress_callback: &mut Option<Box<dyn FnMut(String, TableBackupProgress) + '_>>,
     ) -> anyhow::Result<()>;
+
+    fn table_name(&self) -> &str;
+
+    /// Checks the table, if it exists, has the key and value types we expect
+    fn validate(&self, read: &ReadTransaction) -> anyhow::Result<()>;
+
+    /// Copies the rows with the given encoded keys, returning the keys that no
+    /// longer exist
+    fn backup_keys(
+        &self,
+        read: &ReadTransaction,
+        write: &WriteTransaction,
+        keys: &BTreeSet<Vec<u8>>,
+        handle: &mut ProgressHandle,
+    ) -> anyhow::Result<Vec<Vec<u8>>>;
+
+    /// Whether the table has a row with the given encoded key
+    fn contains_key(&self, write: &WriteTransaction, key: &[u8]) -> anyhow::Result<bool>;
+
+    /// Copies every row of an incremental backup's table, then removes the
+    /// given encoded keys
+    fn apply_delta(
+        &self,
+        delta: &ReadTransaction,
+        write: &WriteTransaction,
+        deleted: &[Vec<u8>],
+    ) -> anyhow::Result<()>;
 }
 // backup utilities here, so when we add/remove tables we can easily update the backup code
 
-const BACKUP_TABLE_LIST: [&dyn Backup; 5] = [
+const BACKUP_TABLE_LIST: [&dyn Backup; 16] = [
     &ROOTS_TABLE,
     &OBJECTS_TABLE,
+    &OBJECT_IDS_INDEX,
+    &FIELD_INDEX_TABLE,
+    &INDEXED_FIELDS_TABLE,
+    &CLASS_VERSIONS_INDEX,
     &MAPS_TABLE,
     &ARRAYS_TABLE,
+    &ARRAY_METADATA_TABLE,
+    &SETS_TABLE,
     &BLOBS_TABLE,
+    &REFERENCE_COUNTS_TABLE,
+    // the only copy of rows `check_database` moved out of their tables
+    &QUARANTINE_TABLE,
+    // so sequence numbers carry on from where they were after a restore
+    &CHANGES_TABLE,
+    &CHANGE_SEQUENCES_TABLE,
+    // so a restored database is migrated from the right format version
+    &METADATA_TABLE,
 ];
 
 #[derive(Debug, Clone)]
@@ -50,6 +211,16 @@ pub struct BackupProgress {
     pub tables: Vec<(String, TableBackupProgress)>,
 }
 
+/// The name blob payloads are reported under in `BackupProgress`
+pub const BLOBS_PROGRESS_NAME: &str = "blob payloads";
+
+/// Where a backup copies blob payloads from and to. The blobs table only holds
+/// metadata, so without this a restored database can point at missing blobs.
+pub struct BlobBackup<'a> {
+    pub source: &'a CloudstateBlobStorage,
+    pub destination: &'a dyn CloudstateBlobStorageEngine,
+}
+
 fn backup_table<K: redb::Key + 'static, V: redb::Value + 'static>(
     table_definition: redb::TableDefinition<K, V>,
     read: &ReadTransaction,
@@ -64,7 +235,7 @@ fn backup_table<K: redb::Key + 'static, V: redb::Value + 'static>(
                 return Err(anyhow::anyhow!(
                     "Failed to get table length for table: {:?}",
                     table_definition.name()
-                ))
+                ));
             }
         };
         let mut write_table = match write.open_table(table_definition) {
@@ -73,7 +244,7 @@ fn backup_table<K: redb::Key + 'static, V: redb::Value + 'static>(
                 return Err(anyhow::anyhow!(
                     "Failed to open table for writing: {:?}",
                     table_definition.name()
-                ))
+                ));
             }
         };
 
@@ -83,7 +254,7 @@ fn backup_table<K: redb::Key + 'static, V: redb::Value + 'static>(
                 return Err(anyhow::anyhow!(
                     "Failed to get table iterator for table: {:?}",
                     table_definition.name()
-                ))
+                ));
             }
         };
 
@@ -105,14 +276,60 @@ fn backup_table<K: redb::Key + 'static, V: redb::Value + 'static>(
     Ok(())
 }
 
-pub fn backup_all_tables<'a>(
+/// Copies the payload of every blob in the read transaction's blobs table, or
+/// only of the blobs in `only`
+fn backup_blobs(
     read: &ReadTransaction,
-    write: &WriteTransaction,
-    progress_callback: &mut Option<Box<dyn FnMut(BackupProgress) -> () + 'a>>,
+    blobs: &BlobBackup,
+    only: Option<&BTreeSet<CloudstateId>>,
+    handle: &mut ProgressHandle,
 ) -> anyhow::Result<()> {
+    let Ok(table) = read.open_table(BLOBS_TABLE) else {
+        return Ok(());
+    };
+    let total = match only {
+        Some(only) => only.len() as u64,
+        None => table.len()?,
+    };
+
+    let ids = table
+        .iter()?
+        .map(|item| item.map(|(key, _)| key.value().id));
+    let ids = ids.filter(|id| match (only, id) {
+        (Some(only), Ok(id)) => only.contains(id),
+        _ => true,
+    });
+
+    for (index, blob_id) in ids.enumerate() {
+        let blob_id = blob_id?;
+
+        match blobs.source.get_blob_data(&blob_id) {
+            Ok(data) => blobs.destination.put_blob(&blob_id.to_string(), data)?,
+            // already lost, the rest of the backup is still worth having
+            Err(e) => warn!("Blob {:?} has no payload to back up: {}", blob_id, e),
+        }
+
+        if let Some(ref mut callback) = handle.progress_callback {
+            callback(
+                BLOBS_PROGRESS_NAME.to_string(),
+                TableBackupProgress {
+                    total,
+                    current: index as u64 + 1,
+                },
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Collects per-table progress into a `BackupProgress` for the callback
+fn progress_handle<'a, 'b: 'a>(
+    progress_callback: &'a mut Option<Box<dyn FnMut(BackupProgress) -> () + 'b>>,
+) -> ProgressHandle<'a> {
     let mut backup_progress = BackupProgress { tables: Vec::new() };
-    let mut handle = ProgressHandle {
-        progress_callback: Some(Box::new(|table_name: String, prog| {
+    ProgressHandle {
+        progress_callback: Some(Box::new(move |table_name: String, prog| {
             // println!("Backing up table: {:?} {:?}", table_name, prog);
             if let Some(ref mut table) = backup_progress
                 .tables
@@ -129,10 +346,432 @@ pub fn backup_all_tables<'a>(
                 callback(backup_progress.clone());
             }
         })),
-    };
+    }
+}
+
+/// The sequence number of the last change in the read transaction
+fn current_sequence(read: &ReadTransaction) -> anyhow::Result<u64> {
+    match read.open_table(CHANGES_TABLE) {
+        Ok(log) => last_sequence(&log),
+        Err(TableError::TableDoesNotExist(_)) => Ok(0),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// The sequence the change log was last compacted up to, or 0 if it never was
+fn compacted_until(read: &ReadTransaction) -> anyhow::Result<u64> {
+    match read.open_table(METADATA_TABLE) {
+        Ok(metadata) => Ok(metadata
+            .get(CHANGES_COMPACTED_KEY)?
+            .map_or(0, |value| value.value())),
+        Err(TableError::TableDoesNotExist(_)) => Ok(0),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Drops the log entries of removed rows from before sequence `until`, which
+/// a full backup covers, returning how many were dropped. The log keeps one
+/// entry for every row ever written, so without this it only ever grows.
+///
+/// Entries of rows that still exist are kept, since garbage collection reads
+/// them to find what was written while it ran. The entry at `until` is kept
+/// too, so sequence numbers carry on from the same place.
+pub fn compact_changes(write: &WriteTransaction, until: u64) -> anyhow::Result<u64> {
+    let mut logged: BTreeMap<String, Vec<(u64, Vec<u8>)>> = BTreeMap::new();
+    {
+        let log = write.open_table(CHANGES_TABLE)?;
+        for item in log.range(..until)? {
+            let (sequence, change) = item?;
+            let change = change.value();
+            logged
+                .entry(change.table)
+                .or_default()
+                .push((sequence.value(), change.key));
+        }
+    }
+
+    let mut removed = Vec::new();
+    for table in BACKUP_TABLE_LIST {
+        let Some(keys) = logged.remove(table.table_name()) else {
+            continue;
+        };
+
+        for (sequence, key) in keys {
+            if !table.contains_key(write, &key)? {
+                let change = CloudstateChange {
+                    table: table.table_name().to_string(),
+                    key,
+                };
+                removed.push((sequence, change));
+            }
+        }
+    }
+
+    let mut log = write.open_table(CHANGES_TABLE)?;
+    let mut sequences = write.open_table(CHANGE_SEQUENCES_TABLE)?;
+    for (sequence, change) in &removed {
+        log.remove(sequence)?;
+        sequences.remove(change)?;
+    }
+
+    let mut metadata = write.open_table(METADATA_TABLE)?;
+    let compacted = metadata
+        .get(CHANGES_COMPACTED_KEY)?
+        .map_or(0, |value| value.value());
+    metadata.insert(CHANGES_COMPACTED_KEY, compacted.max(until))?;
+
+    Ok(removed.len() as u64)
+}
+
+/// Copies every table, returning the change sequence the backup covers up to
+pub fn backup_all_tables<'a>(
+    read: &ReadTransaction,
+    write: &WriteTransaction,
+    blobs: Option<&BlobBackup>,
+    progress_callback: &mut Option<Box<dyn FnMut(BackupProgress) -> () + 'a>>,
+) -> anyhow::Result<u64> {
+    let mut handle = progress_handle(progress_callback);
 
     for table in BACKUP_TABLE_LIST {
         table.backup(read, write, &mut handle)?;
     }
+
+    if let Some(blobs) = blobs {
+        backup_blobs(read, blobs, None, &mut handle)?;
+    }
+
+    let sequence = current_sequence(read)?;
+    write
+        .open_table(BACKUP_INFO_TABLE)?
+        .insert("sequence", sequence)?;
+    Ok(sequence)
+}
+
+/// Copies the rows changed after sequence `since`, and records the ones
+/// removed since then, so the result can be replayed onto a backup that covers
+/// up to `since`. Returns the sequence the delta covers up to.
+///
+/// Rows deleted by garbage collection or when their reference count drops to
+/// zero are logged like any other removal, so they're removed on restore too.
+pub fn backup_changed_rows<'a>(
+    read: &ReadTransaction,
+    write: &WriteTransaction,
+    since: u64,
+    blobs: Option<&BlobBackup>,
+    progress_callback: &mut Option<Box<dyn FnMut(BackupProgress) -> () + 'a>>,
+) -> anyhow::Result<u64> {
+    let mut handle = progress_handle(progress_callback);
+
+    let sequence = current_sequence(read)?;
+    if since > sequence {
+        bail!(
+            "Sequence {} is ahead of the database, which is at {}",
+            since,
+            sequence
+        );
+    }
+    let compacted = compacted_until(read)?;
+    if since < compacted {
+        bail!(
+            "The change log no longer has the rows removed before sequence {}, take a full backup and back up incrementally from there",
+            compacted
+        );
+    }
+
+    let mut changed: BTreeMap<String, BTreeSet<Vec<u8>>> = BTreeMap::new();
+    if sequence > 0 {
+        let log = read.open_table(CHANGES_TABLE)?;
+        let mut log_copy = write.open_table(CHANGES_TABLE)?;
+        let mut sequences_copy = write.open_table(CHANGE_SEQUENCES_TABLE)?;
+
+        for item in log.range(since + 1..)? {
+            let (sequence, change) = item?;
+            let (sequence, change) = (sequence.value(), change.value());
+
+            log_copy.insert(sequence, &change)?;
+            sequences_copy.insert(&change, sequence)?;
+            changed.entry(change.table).or_default().insert(change.key);
+        }
+    }
+
+    {
+        let mut deleted_rows = write.open_table(DELETED_ROWS_TABLE)?;
+        for table in BACKUP_TABLE_LIST {
+            let Some(keys) = changed.get(table.table_name()) else {
+                continue;
+            };
+
+            for key in table.backup_keys(read, write, keys, &mut handle)? {
+                let change = CloudstateChange {
+                    table: table.table_name().to_string(),
+                    key,
+                };
+                deleted_rows.insert(change, ())?;
+            }
+        }
+    }
+
+    if let Some(blobs) = blobs {
+        let changed_blobs = changed
+            .get(BLOBS_TABLE.name())
+            .into_iter()
+            .flatten()
+            .map(|key| Bincode::<CloudstateBlobKey>::from_bytes(key).id)
+            .collect();
+        backup_blobs(read, blobs, Some(&changed_blobs), &mut handle)?;
+    }
+
+    let mut info = write.open_table(BACKUP_INFO_TABLE)?;
+    info.insert("since", since)?;
+    info.insert("sequence", sequence)?;
+    Ok(sequence)
+}
+
+/// Replays an incremental backup onto the tables of `write`
+fn apply_delta(delta: &ReadTransaction, write: &WriteTransaction) -> anyhow::Result<()> {
+    let mut deleted: BTreeMap<String, Vec<Vec<u8>>> = BTreeMap::new();
+    match delta.open_table(DELETED_ROWS_TABLE) {
+        Ok(table) => {
+            for item in table.iter()? {
+                let change = item?.0.value();
+                deleted.entry(change.table).or_default().push(change.key);
+            }
+        }
+        Err(TableError::TableDoesNotExist(_)) => {}
+        Err(e) => return Err(e.into()),
+    }
+
+    for table in BACKUP_TABLE_LIST {
+        let deleted = deleted
+            .get(table.table_name())
+            .map_or(&[][..], |keys| keys.as_slice());
+        table.apply_delta(delta, write, deleted)?;
+    }
+
+    Ok(())
+}
+
+fn backup_info(
+    info: &impl ReadableTable<&'static str, u64>,
+    key: &str,
+) -> anyhow::Result<Option<u64>> {
+    Ok(info.get(key)?.map(|value| value.value()))
+}
+
+/// Checks a backup only has tables we know about, with the types we expect.
+/// Tables are created on first write, so missing ones are fine.
+pub fn validate_backup(read: &ReadTransaction) -> anyhow::Result<()> {
+    for table in read.list_tables()? {
+        let is_backup_metadata =
+            table.name() == BACKUP_INFO_TABLE.name() || table.name() == DELETED_ROWS_TABLE.name();
+
+        if !is_backup_metadata
+            && !BACKUP_TABLE_LIST
+                .iter()
+                .any(|known| known.table_name() == table.name())
+        {
+            bail!("Backup contains unknown table {:?}", table.name());
+        }
+    }
+
+    for table in BACKUP_TABLE_LIST {
+        table.validate(read)?;
+    }
+
+    Ok(())
+}
+
+/// Replaces the database at `target` with a full backup, after replaying any
+/// incremental backups onto it in the order they were taken. The copy is
+/// staged next to the target and renamed over it, so the target is never left
+/// half written. `blob_storage` holds the backup's blob payloads, which move
+/// if migrating an older backup changes their ids.
+///
+/// The target and the backup are kept open until the copy is in place, so a
+/// server can't start writing to the target while it's being replaced, and
+/// pruning can't delete the backup while it's copied.
+pub fn restore_backup(
+    backup: impl AsRef<Path>,
+    deltas: &[impl AsRef<Path>],
+    target: impl AsRef<Path>,
+    blob_storage: &CloudstateBlobStorage,
+) -> anyhow::Result<()> {
+    let backup = backup.as_ref();
+    let target = target.as_ref();
+
+    let _target = match Database::create(target) {
+        Ok(db) => Some(db),
+        Err(DatabaseError::DatabaseAlreadyOpen) => bail!(
+            "Database {:?} is in use, stop the server before restoring",
+            target
+        ),
+        // nothing else can open it either, and the restore replaces it
+        Err(e) => {
+            warn!("Restoring over {:?}, which failed to open: {}", target, e);
+            None
+        }
+    };
+    let _backup =
+        Database::open(backup).map_err(|e| anyhow!("Failed to open backup {:?}: {}", backup, e))?;
+
+    let file_name = target
+        .file_name()
+        .ok_or_else(|| anyhow!("Invalid database path {:?}", target))?;
+    let staging = target.with_file_name(format!(".{}.restore", file_name.to_string_lossy()));
+
+    fs::copy(backup, &staging)?;
+    if let Err(e) = stage_backup(backup, &staging, deltas, blob_storage) {
+        let _ = fs::remove_file(&staging);
+        return Err(e);
+    }
+    fs::File::open(&staging)?.sync_all()?;
+    fs::rename(&staging, target)?;
+
+    Ok(())
+}
+
+/// Brings the staged copy of a full backup up to the current format, since it
+/// may have been taken by an older version, then checks it
+fn stage_backup(
+    backup: &Path,
+    staging: &Path,
+    deltas: &[impl AsRef<Path>],
+    blob_storage: &CloudstateBlobStorage,
+) -> anyhow::Result<()> {
+    let staged = Database::open(staging)
+        .map_err(|e| anyhow!("Failed to open backup {:?}: {}", backup, e))?;
+    migrate(&staged, blob_storage)?;
+
+    let read = staged.begin_read()?;
+    validate_backup(&read)?;
+
+    if let Ok(info) = read.open_table(BACKUP_INFO_TABLE) {
+        if backup_info(&info, "since")?.is_some() {
+            bail!(
+                "Backup {:?} is incremental, restore it with --delta on top of a full backup",
+                backup
+            );
+        }
+    }
+    read.close()?;
+
+    stage_deltas(&staged, deltas)
+}
+
+/// Applies incremental backups to the staged copy of a full backup, checking
+/// each one starts where the one before it ended, then drops the backup
+/// metadata tables. Incremental backups aren't migrated, so they have to be
+/// in the current format.
+fn stage_deltas(staged: &Database, deltas: &[impl AsRef<Path>]) -> anyhow::Result<()> {
+    let write = staged.begin_write()?;
+
+    let mut sequence = backup_info(&write.open_table(BACKUP_INFO_TABLE)?, "sequence")?;
+    for delta in deltas {
+        let delta = delta.as_ref();
+        let delta_db = Database::open(delta)
+            .map_err(|e| anyhow!("Failed to open backup {:?}: {}", delta, e))?;
+        let read = delta_db.begin_read()?;
+        validate_backup(&read)?;
+
+        let info = read
+            .open_table(BACKUP_INFO_TABLE)
+            .map_err(|_| anyhow!("Backup {:?} isn't incremental", delta))?;
+        let (Some(since), Some(until)) = (
+            backup_info(&info, "since")?,
+            backup_info(&info, "sequence")?,
+        ) else {
+            bail!("Backup {:?} isn't incremental", delta);
+        };
+
+        match sequence {
+            Some(sequence) if sequence == since => {}
+            Some(sequence) => bail!(
+                "Backup {:?} starts after sequence {}, but the backups before it end at {}",
+                delta,
+                since,
+                sequence
+            ),
+            None => bail!("The full backup has no change sequence, take a new one to use deltas"),
+        }
+
+        apply_delta(&read, &write)?;
+        sequence = Some(until);
+    }
+
+    write.delete_table(BACKUP_INFO_TABLE)?;
+    write.delete_table(DELETED_ROWS_TABLE)?;
+    write.commit()?;
+
     Ok(())
 }
+
+const SNAPSHOT_PREFIX: &str = "snapshot-";
+const SNAPSHOT_EXTENSION: &str = ".redb";
+
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    pub path: PathBuf,
+    pub created_at: DateTime<Utc>,
+}
+
+pub fn snapshot_path(dir: impl AsRef<Path>, created_at: DateTime<Utc>) -> PathBuf {
+    dir.as_ref().join(format!(
+        "{}{}{}",
+        SNAPSHOT_PREFIX,
+        created_at.timestamp_millis(),
+        SNAPSHOT_EXTENSION
+    ))
+}
+
+/// Lists the snapshots in `dir`, oldest first
+pub fn list_snapshots(dir: impl AsRef<Path>) -> anyhow::Result<Vec<Snapshot>> {
+    let dir = dir.as_ref();
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut snapshots = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+            continue;
+        };
+
+        let created_at = name
+            .strip_prefix(SNAPSHOT_PREFIX)
+            .and_then(|name| name.strip_suffix(SNAPSHOT_EXTENSION))
+            .and_then(|millis| millis.parse().ok())
+            .and_then(DateTime::from_timestamp_millis);
+
+        if let Some(created_at) = created_at {
+            snapshots.push(Snapshot { path, created_at });
+        }
+    }
+
+    snapshots.sort_by_key(|snapshot| snapshot.created_at);
+    Ok(snapshots)
+}
+
+/// Deletes all but the `retain` newest snapshots in `dir`, returning the
+/// deleted paths. Snapshots that are open, like one being restored, are left
+/// for the next prune.
+pub fn prune_snapshots(dir: impl AsRef<Path>, retain: usize) -> anyhow::Result<Vec<PathBuf>> {
+    let snapshots = list_snapshots(dir)?;
+    let excess = snapshots.len().saturating_sub(retain);
+
+    let mut pruned = Vec::with_capacity(excess);
+    for snapshot in snapshots.into_iter().take(excess) {
+        // kept open until it's deleted, so nothing can start reading it first
+        let _snapshot = match Database::open(&snapshot.path) {
+            Err(DatabaseError::DatabaseAlreadyOpen) => {
+                warn!("Snapshot {:?} is in use, not pruning it", snapshot.path);
+                continue;
+            }
+            open => open.ok(),
+        };
+        fs::remove_file(&snapshot.path)?;
+        pruned.push(snapshot.path);
+    }
+
+    Ok(pruned)
+}
@@ -1,22 +1,171 @@
-use std::{any::type_name, cmp::Ordering, fmt::Debug};
+use std::{
+    any::type_name,
+    cmp::Ordering,
+    fmt::{self, Debug, Display},
+    marker::PhantomData,
+};
 
 use bincode::{deserialize, serialize};
-use redb::{Key, TypeName, Value};
-use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use redb::{Key, TableDefinition, TableHandle, TypeName, Value};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
 
 /// Wrapper type to handle keys and values using bincode serialization
 #[derive(Debug)]
 pub struct Bincode<T>(pub T);
 
+/// A stored row whose bytes don't decode as the type its table holds
+#[derive(Debug, Clone)]
+pub struct CorruptRow {
+    pub type_name: &'static str,
+    pub error: String,
+}
+
+impl Display for CorruptRow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Corrupt row: could not decode {}: {}",
+            self.type_name, self.error
+        )
+    }
+}
+
+impl std::error::Error for CorruptRow {}
+
+impl<T: DeserializeOwned> Bincode<T> {
+    /// Decodes a stored row, returning an error instead of panicking when the
+    /// bytes are corrupt
+    pub fn decode(data: &[u8]) -> Result<T, CorruptRow> {
+        deserialize(data).map_err(|e| CorruptRow {
+            type_name: type_name::<T>(),
+            error: e.to_string(),
+        })
+    }
+}
+
+/// A table's key or value type, decoded from its stored bytes without
+/// trusting them.
+///
+/// redb decodes rows through `Value::from_bytes`, which can't fail, so code
+/// that may meet a corrupt row opens its table through `Raw` and decodes each
+/// row with this instead.
+pub trait Decode: Value {
+    type Decoded;
+
+    fn decode(data: &[u8]) -> Result<Self::Decoded, CorruptRow>;
+}
+
+impl<T> Decode for Bincode<T>
+where
+    T: Debug + Serialize + DeserializeOwned,
+{
+    type Decoded = T;
+
+    fn decode(data: &[u8]) -> Result<T, CorruptRow> {
+        Bincode::<T>::decode(data)
+    }
+}
+
+impl Decode for u64 {
+    type Decoded = u64;
+
+    fn decode(data: &[u8]) -> Result<u64, CorruptRow> {
+        let bytes = data.try_into().map_err(|_| CorruptRow {
+            type_name: "u64",
+            error: format!("expected 8 bytes, found {}", data.len()),
+        })?;
+        Ok(u64::from_le_bytes(bytes))
+    }
+}
+
+impl Decode for () {
+    type Decoded = ();
+
+    fn decode(_data: &[u8]) -> Result<(), CorruptRow> {
+        Ok(())
+    }
+}
+
+impl Decode for &str {
+    type Decoded = String;
+
+    fn decode(data: &[u8]) -> Result<String, CorruptRow> {
+        String::from_utf8(data.to_vec()).map_err(|e| CorruptRow {
+            type_name: "&str",
+            error: e.to_string(),
+        })
+    }
+}
+
+/// The bytes of a stored key or value
+pub fn encode<T: Value>(value: &T::SelfType<'_>) -> Vec<u8> {
+    T::as_bytes(value).as_ref().to_vec()
+}
+
+/// Reads and writes a table's rows as bytes, in the order `T` sorts them
+#[derive(Debug)]
+pub struct Raw<T>(PhantomData<T>);
+
+/// The same table as `definition`, with its rows read and written as bytes
+pub fn raw<'a, K: Key + 'static, V: Value + 'static>(
+    definition: &'a TableDefinition<'a, K, V>,
+) -> TableDefinition<'a, Raw<K>, Raw<V>> {
+    TableDefinition::new(definition.name())
+}
+
+impl<T: Value + 'static> Value for Raw<T> {
+    type SelfType<'a>
+        = &'a [u8]
+    where
+        Self: 'a;
+
+    type AsBytes<'a>
+        = &'a [u8]
+    where
+        Self: 'a;
+
+    fn fixed_width() -> Option<usize> {
+        T::fixed_width()
+    }
+
+    fn from_bytes<'a>(data: &'a [u8]) -> Self::SelfType<'a>
+    where
+        Self: 'a,
+    {
+        data
+    }
+
+    fn as_bytes<'a, 'b: 'a>(value: &'a Self::SelfType<'b>) -> Self::AsBytes<'a>
+    where
+        Self: 'a,
+        Self: 'b,
+    {
+        value
+    }
+
+    fn type_name() -> TypeName {
+        // matches `T` so redb opens the table
+        T::type_name()
+    }
+}
+
+impl<T: Key + 'static> Key for Raw<T> {
+    fn compare(data1: &[u8], data2: &[u8]) -> Ordering {
+        T::compare(data1, data2)
+    }
+}
+
 impl<T> Value for Bincode<T>
 where
     T: Debug + Serialize + for<'a> Deserialize<'a>,
 {
-    type SelfType<'a> = T
+    type SelfType<'a>
+        = T
     where
         Self: 'a;
 
-    type AsBytes<'a> = Vec<u8>
+    type AsBytes<'a>
+        = Vec<u8>
     where
         Self: 'a;
 
@@ -28,7 +177,8 @@ where
     where
         Self: 'a,
     {
-        deserialize(data).unwrap()
+        // rows that may be corrupt are read through `Raw` and `Decode`
+        Self::decode(data).unwrap_or_else(|corrupt| panic!("{corrupt}"))
     }
 
     fn as_bytes<'a, 'b: 'a>(value: &'a Self::SelfType<'b>) -> Self::AsBytes<'a>
@@ -36,7 +186,9 @@ where
         Self: 'a,
         Self: 'b,
     {
-        serialize(value).unwrap()
+        // the stored types are plain structs and enums, which bincode can
+        // always serialize
+        serialize(value).expect("stored values serialize with bincode")
     }
 
     fn type_name() -> TypeName {
@@ -49,6 +201,13 @@ where
     T: Debug + Serialize + DeserializeOwned + Ord,
 {
     fn compare(data1: &[u8], data2: &[u8]) -> Ordering {
-        Self::from_bytes(data1).cmp(&Self::from_bytes(data2))
+        // redb compares keys while searching its pages, where a panic can't be
+        // caught safely, so corrupt keys sort after every key that decodes
+        match (Self::decode(data1), Self::decode(data2)) {
+            (Ok(key1), Ok(key2)) => key1.cmp(&key2),
+            (Ok(_), Err(_)) => Ordering::Less,
+            (Err(_), Ok(_)) => Ordering::Greater,
+            (Err(_), Err(_)) => data1.cmp(data2),
+        }
     }
 }
@@ -3,7 +3,10 @@ use std::sync::Arc;
 use anyhow::Error;
 use serde::{Deserialize, Serialize};
 
-use crate::{extensions::cloudstate::Transaction, tables::BLOBS_TABLE};
+use crate::{
+    extensions::cloudstate::{CloudstateId, Transaction},
+    tables::BLOBS_TABLE,
+};
 
 #[derive(Debug, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Clone)]
 pub struct CloudstateBlobValue {
@@ -33,53 +36,84 @@ impl CloudstateBlobStorage {
         Self { inner_storage }
     }
 
-    pub fn get_blob_data(&self, blob_id: &str) -> Result<CloudstateBlobValue, Error> {
-        self.inner_storage.get_blob_data(blob_id)
+    pub fn get_blob_data(&self, blob_id: &CloudstateId) -> Result<CloudstateBlobValue, Error> {
+        self.inner_storage.get_blob_data(&blob_id.to_string())
     }
 
-    pub fn get_blob_size(&self, blob_id: &str) -> Result<usize, Error> {
-        self.inner_storage.get_blob_size(blob_id)
+    pub fn get_blob_size(&self, blob_id: &CloudstateId) -> Result<usize, Error> {
+        self.inner_storage.get_blob_size(&blob_id.to_string())
     }
 
     pub fn put_blob(
         &self,
-        blob_id: &str,
+        blob_id: &CloudstateId,
         transaction: &Transaction,
         blob_data: CloudstateBlobValue,
         blob_metadata: CloudstateBlobMetadata,
     ) -> Result<(), Error> {
         let mut blob_table = transaction.open_table(BLOBS_TABLE)?;
-        blob_table.insert(&blob_id.into(), blob_metadata)?;
-        self.inner_storage.put_blob(blob_id, blob_data)
+        blob_table.insert(&(*blob_id).into(), blob_metadata)?;
+        self.inner_storage.put_blob(&blob_id.to_string(), blob_data)
     }
 
-    pub fn delete_blob(&self, blob_id: &str, transaction: &Transaction) -> Result<(), Error> {
+    pub fn delete_blob(
+        &self,
+        blob_id: &CloudstateId,
+        transaction: &Transaction,
+    ) -> Result<(), Error> {
         let mut blob_table = transaction.open_table(BLOBS_TABLE)?;
-        blob_table.remove(&blob_id.into())?;
-        self.inner_storage.delete_blob(blob_id)
+        blob_table.remove(&(*blob_id).into())?;
+        self.inner_storage.delete_blob(&blob_id.to_string())
     }
 
-    pub fn has_blob(&self, blob_id: &str) -> Result<bool, Error> {
-        self.inner_storage.has_blob(blob_id)
+    /// Deletes a blob's payload, for when its row is already gone
+    pub fn delete_blob_data(&self, blob_id: &CloudstateId) -> Result<(), Error> {
+        self.inner_storage.delete_blob(&blob_id.to_string())
+    }
+
+    /// Copies the payload stored under a blob's id from before ids were 16
+    /// byte values to its current id. Returns false if there was no payload.
+    pub fn copy_legacy_blob_data(
+        &self,
+        legacy_id: &str,
+        blob_id: &CloudstateId,
+    ) -> Result<bool, Error> {
+        if !self.inner_storage.has_blob(legacy_id)? {
+            return Ok(false);
+        }
+        let data = self.inner_storage.get_blob_data(legacy_id)?;
+        self.inner_storage.put_blob(&blob_id.to_string(), data)?;
+        Ok(true)
+    }
+
+    /// Deletes the payload stored under a blob's id from before ids were 16
+    /// byte values, once it has been copied to its current id
+    pub fn delete_legacy_blob_data(&self, legacy_id: &str) -> Result<(), Error> {
+        self.inner_storage.delete_blob(legacy_id)
+    }
+
+    pub fn has_blob(&self, blob_id: &CloudstateId) -> Result<bool, Error> {
+        self.inner_storage.has_blob(&blob_id.to_string())
     }
 
     pub fn get_blob_slice(
         &self,
-        blob_id: &str,
+        blob_id: &CloudstateId,
         start: Option<i32>,
         end: Option<i32>,
     ) -> Result<Vec<u8>, Error> {
-        self.inner_storage.get_blob_slice(blob_id, start, end)
+        self.inner_storage
+            .get_blob_slice(&blob_id.to_string(), start, end)
     }
 
     pub fn get_blob_metadata(
         &self,
-        blob_id: &str,
+        blob_id: &CloudstateId,
         transaction: &Transaction,
     ) -> Result<CloudstateBlobMetadata, Error> {
         let blob_table = transaction.open_table(BLOBS_TABLE)?;
-        let out = match blob_table.get(&blob_id.into()) {
-            Ok(Some(metadata)) => Ok(metadata.value()),
+        let out = match blob_table.get(&(*blob_id).into()) {
+            Ok(Some(metadata)) => Ok(metadata),
             Ok(None) => Err(Error::msg("Blob not found")),
             Err(e) => Err(e),
         };
@@ -0,0 +1,122 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+use redb::{ReadableTable, WriteTransaction};
+use serde::{Deserialize, Serialize};
+
+use crate::bincode::CorruptRow;
+use crate::gc::Pointer;
+use crate::refcount;
+use crate::tables::{CHANGE_SEQUENCES_TABLE, CHANGES_TABLE};
+
+/// A row that was written or removed, identified by its table and the bytes
+/// of its key
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Clone)]
+pub struct CloudstateChange {
+    pub table: String,
+    pub key: Vec<u8>,
+}
+
+/// Rows written by a transaction so far, stamped with sequence numbers when it
+/// commits
+#[derive(Debug)]
+pub struct PendingChanges {
+    /// Each row's encoded value from before the transaction, when reference
+    /// counts need it, or `None` if the row didn't exist
+    rows: RefCell<BTreeMap<CloudstateChange, Option<Vec<u8>>>>,
+    reference_counts: bool,
+    corrupt: RefCell<Option<CorruptRow>>,
+    /// Values to delete at commit if nothing references them anymore
+    reclaim: RefCell<Vec<Pointer>>,
+}
+
+impl PendingChanges {
+    /// Starts recording the changes of a write transaction, keeping what rows
+    /// held before if the database keeps reference counts
+    pub fn new(transaction: &WriteTransaction) -> anyhow::Result<Self> {
+        Ok(Self {
+            rows: RefCell::default(),
+            reference_counts: refcount::enabled(transaction)?,
+            corrupt: RefCell::default(),
+            reclaim: RefCell::default(),
+        })
+    }
+
+    /// Records a write to a row, given the bytes of its key and what it held
+    /// before
+    pub fn record(&self, table: &str, key: Vec<u8>, previous: Option<&[u8]>) {
+        let change = CloudstateChange {
+            table: table.to_string(),
+            key,
+        };
+
+        // only the first write of a row knows what it held before the
+        // transaction
+        self.rows.borrow_mut().entry(change).or_insert_with(|| {
+            previous
+                .filter(|_| self.reference_counts)
+                .map(<[u8]>::to_vec)
+        });
+    }
+
+    /// Keeps the transaction from committing because it read a corrupt row,
+    /// which may have cut an op short partway through its writes
+    pub fn poison(&self, corrupt: &CorruptRow) {
+        self.corrupt
+            .borrow_mut()
+            .get_or_insert_with(|| corrupt.clone());
+    }
+
+    /// The corrupt row the transaction read, if it read one
+    pub fn corrupt(&self) -> Option<CorruptRow> {
+        self.corrupt.borrow().clone()
+    }
+
+    /// Deletes values that lost their last reference earlier in the request
+    /// when the transaction commits, unless something references them again
+    pub fn reclaim(&self, unreferenced: Vec<Pointer>) {
+        self.reclaim.borrow_mut().extend(unreferenced);
+    }
+
+    /// Gives every recorded row the next sequence number. Each row only keeps
+    /// its latest sequence number, so the log grows with the number of rows
+    /// rather than the number of writes. Returns the values that lost their
+    /// last reference, which are left to `reclaim` once the request is over.
+    pub fn commit(self, transaction: &WriteTransaction) -> anyhow::Result<Vec<Pointer>> {
+        let mut changes = self.rows.into_inner();
+        let mut unreferenced = Vec::new();
+        if self.reference_counts {
+            let counted = refcount::update(transaction, &changes)?;
+            let reclaimed = refcount::reclaim(transaction, self.reclaim.into_inner())?;
+            for change in counted.changed.into_iter().chain(reclaimed) {
+                changes.entry(change).or_default();
+            }
+            unreferenced = counted.unreferenced;
+        }
+        if changes.is_empty() {
+            return Ok(unreferenced);
+        }
+
+        let mut log = transaction.open_table(CHANGES_TABLE)?;
+        let mut sequences = transaction.open_table(CHANGE_SEQUENCES_TABLE)?;
+
+        let mut sequence = last_sequence(&log)?;
+        for change in changes.into_keys() {
+            sequence += 1;
+            if let Some(previous) = sequences.insert(&change, sequence)? {
+                let previous = previous.value();
+                log.remove(previous)?;
+            }
+            log.insert(sequence, change)?;
+        }
+
+        Ok(unreferenced)
+    }
+}
+
+/// The sequence number of the most recent change, or 0 if nothing has changed
+pub fn last_sequence(
+    log: &impl ReadableTable<u64, crate::bincode::Bincode<CloudstateChange>>,
+) -> anyhow::Result<u64> {
+    Ok(log.last()?.map_or(0, |(sequence, _)| sequence.value()))
+}
@@ -0,0 +1,209 @@
+//! Finds rows that no longer decode as the type their table stores, and can
+//! move them into `QUARANTINE_TABLE` so the rest of the database stays usable.
+//! The move is logged like any other write, so incremental backups remove the
+//! rows too, and reference counts stop counting what they pointed to.
+
+use redb::{
+    Database, Key, ReadTransaction, ReadableTable, ReadableTableMetadata, TableDefinition,
+    TableError, TableHandle, WriteTransaction,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::bincode::{Bincode, CorruptRow, Decode, encode, raw};
+use crate::changes::PendingChanges;
+use crate::tables::{
+    ARRAY_METADATA_TABLE, ARRAYS_TABLE, BLOBS_TABLE, CHANGE_SEQUENCES_TABLE, CHANGES_TABLE,
+    CLASS_VERSIONS_INDEX, FIELD_INDEX_TABLE, INDEXED_FIELDS_TABLE, MAPS_TABLE, METADATA_TABLE,
+    OBJECT_IDS_INDEX, OBJECTS_TABLE, QUARANTINE_TABLE, REFERENCE_COUNTS_TABLE, ROOTS_TABLE,
+    SETS_TABLE,
+};
+
+/// A row moved out of its table because it didn't decode
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct QuarantinedRowKey {
+    pub table: String,
+    pub key: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QuarantinedRowValue {
+    pub value: Vec<u8>,
+    pub error: String,
+}
+
+#[derive(Debug)]
+pub struct CorruptEntry {
+    pub table: String,
+    /// The row's key as stored, since it may be the part that doesn't decode
+    pub key: Vec<u8>,
+    pub error: CorruptRow,
+}
+
+#[derive(Debug)]
+pub struct CheckReport {
+    pub tables: u64,
+    pub rows: u64,
+    pub corrupt: Vec<CorruptEntry>,
+}
+
+trait Check {
+    fn table_name(&self) -> &str;
+
+    /// Decodes every row of the table, if it exists, returning how many rows
+    /// it has
+    fn check(&self, read: &ReadTransaction, corrupt: &mut Vec<CorruptEntry>)
+    -> anyhow::Result<u64>;
+
+    /// Moves the rows with the given encoded keys into the quarantine table,
+    /// recording the move in `changes`
+    fn quarantine(
+        &self,
+        write: &WriteTransaction,
+        changes: &PendingChanges,
+        corrupt: &[&CorruptEntry],
+    ) -> anyhow::Result<()>;
+}
+
+// every table a database can hold but the quarantine, like `BACKUP_TABLE_LIST`
+const CHECK_TABLE_LIST: [&dyn Check; 15] = [
+    &ROOTS_TABLE,
+    &OBJECTS_TABLE,
+    &OBJECT_IDS_INDEX,
+    &FIELD_INDEX_TABLE,
+    &INDEXED_FIELDS_TABLE,
+    &CLASS_VERSIONS_INDEX,
+    &MAPS_TABLE,
+    &ARRAYS_TABLE,
+    &ARRAY_METADATA_TABLE,
+    &SETS_TABLE,
+    &BLOBS_TABLE,
+    &REFERENCE_COUNTS_TABLE,
+    &CHANGES_TABLE,
+    &CHANGE_SEQUENCES_TABLE,
+    &METADATA_TABLE,
+];
+
+impl<K: Key + Decode + 'static, V: Decode + 'static> Check for TableDefinition<'_, K, V> {
+    fn table_name(&self) -> &str {
+        TableHandle::name(self)
+    }
+
+    fn check(
+        &self,
+        read: &ReadTransaction,
+        corrupt: &mut Vec<CorruptEntry>,
+    ) -> anyhow::Result<u64> {
+        let table = match read.open_table(raw(self)) {
+            Ok(table) => table,
+            Err(TableError::TableDoesNotExist(_)) => return Ok(0),
+            Err(e) => return Err(e.into()),
+        };
+
+        for item in table.iter()? {
+            let (key, value) = item?;
+            let decoded = K::decode(key.value()).and_then(|_| V::decode(value.value()));
+            if let Err(error) = decoded {
+                corrupt.push(CorruptEntry {
+                    table: self.name().to_string(),
+                    key: key.value().to_vec(),
+                    error,
+                });
+            }
+        }
+
+        Ok(table.len()?)
+    }
+
+    fn quarantine(
+        &self,
+        write: &WriteTransaction,
+        changes: &PendingChanges,
+        corrupt: &[&CorruptEntry],
+    ) -> anyhow::Result<()> {
+        let mut quarantine = write.open_table(QUARANTINE_TABLE)?;
+
+        let mut rows = Vec::new();
+        {
+            let table = write.open_table(raw(self))?;
+            for item in table.iter()? {
+                let (key, value) = item?;
+                let (key, value) = (key.value().to_vec(), value.value().to_vec());
+                match corrupt.iter().find(|row| row.key == key) {
+                    Some(row) => {
+                        let quarantined = QuarantinedRowKey {
+                            table: self.name().to_string(),
+                            key: key.clone(),
+                        };
+                        changes.record(
+                            QUARANTINE_TABLE.name(),
+                            encode::<Bincode<QuarantinedRowKey>>(&quarantined),
+                            None,
+                        );
+                        quarantine.insert(
+                            &quarantined,
+                            QuarantinedRowValue {
+                                value: value.clone(),
+                                error: row.error.to_string(),
+                            },
+                        )?;
+
+                        // only a value that decodes can say what it pointed
+                        // to, so the counts of what a corrupt one pointed to
+                        // are left for the garbage collector
+                        let previous = V::decode(&value).is_ok().then_some(value.as_slice());
+                        changes.record(self.name(), key, previous);
+                    }
+                    None => rows.push((key, value)),
+                }
+            }
+        }
+
+        // a corrupt key may not sort where it was inserted, so rather than
+        // removing rows the table is rebuilt from the ones that decode
+        write.delete_table(raw(self))?;
+        let mut table = write.open_table(raw(self))?;
+        for (key, value) in rows {
+            table.insert(key.as_slice(), value.as_slice())?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Decodes every row of every table. With `quarantine`, the rows that don't
+/// decode are moved into `QUARANTINE_TABLE`, keyed by their table and key.
+pub fn check_database(db: &Database, quarantine: bool) -> anyhow::Result<CheckReport> {
+    let read = db.begin_read()?;
+
+    let mut report = CheckReport {
+        tables: 0,
+        rows: 0,
+        corrupt: Vec::new(),
+    };
+    for table in CHECK_TABLE_LIST {
+        report.tables += 1;
+        report.rows += table.check(&read, &mut report.corrupt)?;
+    }
+    read.close()?;
+
+    if quarantine && !report.corrupt.is_empty() {
+        let write = db.begin_write()?;
+        let changes = PendingChanges::new(&write)?;
+        for table in CHECK_TABLE_LIST {
+            let corrupt: Vec<&CorruptEntry> = report
+                .corrupt
+                .iter()
+                .filter(|row| row.table == table.table_name())
+                .collect();
+            if !corrupt.is_empty() {
+                table.quarantine(&write, &changes, &corrupt)?;
+            }
+        }
+        // values that lost their last reference with the quarantined rows are
+        // left for the garbage collector, in case the rows are put back
+        changes.commit(&write)?;
+        write.commit()?;
+    }
+
+    Ok(report)
+}
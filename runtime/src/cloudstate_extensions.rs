@@ -21,3 +21,22 @@ pub fn cloudstate_extensions() -> Vec<deno_core::Extension> {
         cloudstate::init_ops_and_esm(),
     ]
 }
+
+/// The same extensions without their JavaScript, for runtimes started from
+/// the snapshot `snapshot::create_snapshot` writes
+pub fn cloudstate_extensions_from_snapshot() -> Vec<deno_core::Extension> {
+    let deno_blob_storage = Arc::new(deno_web::BlobStore::default());
+
+    vec![
+        deno_webidl::deno_webidl::init_ops(),
+        deno_telemetry::deno_telemetry::init_ops(),
+        deno_url::deno_url::init_ops(),
+        deno_console::deno_console::init_ops(),
+        deno_web::deno_web::init_ops::<CloudstatePermissions>(deno_blob_storage, None),
+        deno_crypto::deno_crypto::init_ops(None),
+        bootstrap::init_ops(),
+        deno_fetch::deno_fetch::init_ops::<CloudstatePermissions>(Default::default()),
+        deno_net::deno_net::init_ops::<CloudstatePermissions>(None, None),
+        cloudstate::init_ops(),
+    ]
+}
@@ -79,6 +79,8 @@ pub fn run_script_source(
             .unwrap();
         let evaluation = js_runtime.mod_evaluate(mod_id);
 
+        // a transaction that read a corrupt row is rolled back instead
+        let mut commit_error = None;
         let result = poll_fn(|cx| {
             event!(tracing::Level::DEBUG, "polling event loop");
             let poll_result = js_runtime.poll_event_loop(
@@ -89,34 +91,41 @@ pub fn run_script_source(
                 },
             );
 
-            js_runtime
-                .op_state()
-                .borrow_mut()
-                .borrow_mut::<TransactionContext>()
-                .commit_transaction();
+            // like the server, through the script's commit, which leaves an
+            // open transaction() to commit or roll back when it finishes
+            let committed = js_runtime.execute_script("<handle>", "globalThis.commit();");
+            if let Err(e) = committed {
+                commit_error.get_or_insert(anyhow::anyhow!(e));
+            }
 
             poll_result
         })
         .await;
 
-        js_runtime
+        let committed = js_runtime
             .op_state()
             .borrow_mut()
             .borrow_mut::<TransactionContext>()
             .commit_transaction();
+        if let Err(e) = committed {
+            commit_error.get_or_insert(e);
+        }
 
         let _ = evaluation.await;
 
-        (js_runtime, result)
+        (js_runtime, result, commit_error)
     };
 
-    let (_, result) = tokio::runtime::Builder::new_current_thread()
+    let (_, result, commit_error) = tokio::runtime::Builder::new_current_thread()
         .enable_all()
         .build()
         .unwrap()
         .block_on(future);
 
-    let result = result.map_err(|err| anyhow::anyhow!(err));
+    let result = match commit_error {
+        Some(e) => Err(e),
+        None => result.map_err(|err| anyhow::anyhow!(err)),
+    };
 
     Ok((cloudstate, result))
 }
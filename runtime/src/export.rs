@@ -0,0 +1,676 @@
+//! A portable export of the object graph as newline delimited JSON, which
+//! doesn't depend on how this version of cloudstate lays out its tables.
+//!
+//! The first line is a header, `{"type":"header","format":"cloudstate","version":1}`.
+//! Every other line is one of these records, tagged by `type`:
+//!
+//! - `root`: `alias`, `id` of the object stored with `setRoot`
+//! - `object`: `id`, `constructor` (the class name or null), `version` (the
+//!   class's `static version` when it was written, 0 if missing), `fields`
+//! - `array`: `id`, `length`
+//! - `array_item`: `id`, `index`, `value`
+//! - `map_entry`: `id`, `key`, `value`
+//! - `set_item`: `id`, `value`
+//! - `blob`: `id`, `content_type`. Payloads aren't in the file, an export can
+//!   copy them to a blob store of their own for the import to read them from.
+//! - `index`: `class`, `field`, `object` for an object in a class's static
+//!   `indexes`
+//!
+//! Values, including map keys, are objects tagged by `type` too:
+//! `number`, `string`, `boolean`, `date` (RFC 3339), `url` and `bigint` (a
+//! decimal string) have a `value`; `undefined` and `null` have nothing else;
+//! `blob`, `object`, `map`, `array` and `set` have the `id` they refer to.
+//! `NaN`, `Infinity` and `-Infinity` are written as strings.
+
+use std::collections::BTreeMap;
+use std::io::{BufRead, Write};
+
+use anyhow::{anyhow, bail};
+use chrono::{DateTime, Utc};
+use redb::{ReadTransaction, ReadableTable, Table, TableError, WriteTransaction};
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+use url::Url;
+
+use crate::backup::BlobBackup;
+use crate::bincode::Bincode;
+use crate::blob_storage::CloudstateBlobMetadata;
+use crate::extensions::cloudstate::{
+    Blob, CloudstateArrayItemKey, CloudstateArrayItemValue, CloudstateArrayMetadataKey,
+    CloudstateArrayMetadataValue, CloudstateBlobKey, CloudstateClassVersionKey,
+    CloudstateFieldIndexKey, CloudstateId, CloudstateMapFieldKey, CloudstateMapFieldValue,
+    CloudstateMapKey, CloudstateObjectData, CloudstateObjectIdIndexKey, CloudstateObjectKey,
+    CloudstateObjectValue, CloudstatePrimitiveData, CloudstateRootKey, CloudstateRootValue,
+    CloudstateSetItemKey, CloudstateSetItemValue, ObjectReference,
+};
+use crate::ordered::Ordered;
+use crate::tables::{
+    ARRAY_METADATA_TABLE, ARRAYS_TABLE, BLOBS_TABLE, CLASS_VERSIONS_INDEX, FIELD_INDEX_TABLE,
+    MAPS_TABLE, OBJECT_IDS_INDEX, OBJECTS_TABLE, ROOTS_TABLE, SETS_TABLE,
+};
+
+pub const EXPORT_FORMAT: &str = "cloudstate";
+pub const EXPORT_VERSION: u32 = 1;
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ExportRecord {
+    Header {
+        format: String,
+        version: u32,
+    },
+    Root {
+        alias: String,
+        id: CloudstateId,
+    },
+    Object {
+        id: CloudstateId,
+        constructor: Option<String>,
+        #[serde(default)]
+        version: u32,
+        fields: BTreeMap<String, ExportValue>,
+    },
+    Array {
+        id: CloudstateId,
+        length: i32,
+    },
+    ArrayItem {
+        id: CloudstateId,
+        index: i32,
+        value: ExportValue,
+    },
+    MapEntry {
+        id: CloudstateId,
+        key: ExportValue,
+        value: ExportValue,
+    },
+    SetItem {
+        id: CloudstateId,
+        value: ExportValue,
+    },
+    Blob {
+        id: CloudstateId,
+        content_type: String,
+    },
+    Index {
+        class: String,
+        field: String,
+        object: CloudstateId,
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ExportValue {
+    Number {
+        value: ExportNumber,
+    },
+    String {
+        value: String,
+    },
+    Boolean {
+        value: bool,
+    },
+    #[serde(rename = "bigint")]
+    BigInt {
+        value: String,
+    },
+    Undefined,
+    Null,
+    Date {
+        value: DateTime<Utc>,
+    },
+    Url {
+        value: Url,
+    },
+    Blob {
+        id: CloudstateId,
+    },
+    Object {
+        id: CloudstateId,
+    },
+    Map {
+        id: CloudstateId,
+    },
+    Array {
+        id: CloudstateId,
+    },
+    Set {
+        id: CloudstateId,
+    },
+}
+
+/// JSON has no NaN or infinities, so those are written as strings
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+enum ExportNumber {
+    Finite(f64),
+    NonFinite(String),
+}
+
+impl From<f64> for ExportNumber {
+    fn from(value: f64) -> Self {
+        if value.is_nan() {
+            ExportNumber::NonFinite("NaN".to_string())
+        } else if value == f64::INFINITY {
+            ExportNumber::NonFinite("Infinity".to_string())
+        } else if value == f64::NEG_INFINITY {
+            ExportNumber::NonFinite("-Infinity".to_string())
+        } else {
+            ExportNumber::Finite(value)
+        }
+    }
+}
+
+impl TryFrom<ExportNumber> for f64 {
+    type Error = anyhow::Error;
+
+    fn try_from(value: ExportNumber) -> Result<Self, Self::Error> {
+        match value {
+            ExportNumber::Finite(value) => Ok(value),
+            ExportNumber::NonFinite(value) => match value.as_str() {
+                "NaN" => Ok(f64::NAN),
+                "Infinity" => Ok(f64::INFINITY),
+                "-Infinity" => Ok(f64::NEG_INFINITY),
+                _ => Err(anyhow!("Invalid number {:?}", value)),
+            },
+        }
+    }
+}
+
+/// 10^19, the largest power of ten that fits in a word
+const DECIMAL_CHUNK: u128 = 10_000_000_000_000_000_000;
+
+/// Formats little-endian words as a decimal number
+fn words_to_decimal(words: &[u64]) -> String {
+    let mut words = words.to_vec();
+    let mut chunks = Vec::new();
+    while words.iter().any(|word| *word != 0) {
+        let mut remainder = 0u128;
+        for word in words.iter_mut().rev() {
+            let current = (remainder << 64) | *word as u128;
+            *word = (current / DECIMAL_CHUNK) as u64;
+            remainder = current % DECIMAL_CHUNK;
+        }
+        chunks.push(remainder as u64);
+    }
+
+    match chunks.split_last() {
+        None => "0".to_string(),
+        Some((most_significant, rest)) => {
+            let mut decimal = most_significant.to_string();
+            for chunk in rest.iter().rev() {
+                decimal.push_str(&format!("{:019}", chunk));
+            }
+            decimal
+        }
+    }
+}
+
+/// Parses a decimal number into little-endian words, without trailing zero
+/// words
+fn decimal_to_words(decimal: &str) -> anyhow::Result<Vec<u64>> {
+    if decimal.is_empty() {
+        bail!("Invalid bigint {:?}", decimal);
+    }
+
+    let mut words: Vec<u64> = Vec::new();
+    for c in decimal.chars() {
+        let digit = c
+            .to_digit(10)
+            .ok_or_else(|| anyhow!("Invalid bigint {:?}", decimal))?;
+
+        let mut carry = digit as u128;
+        for word in words.iter_mut() {
+            let current = *word as u128 * 10 + carry;
+            *word = current as u64;
+            carry = current >> 64;
+        }
+        if carry != 0 {
+            words.push(carry as u64);
+        }
+    }
+
+    Ok(words)
+}
+
+/// Splits off the sign of a decimal bigint
+fn parse_bigint(value: &str) -> anyhow::Result<(bool, Vec<u64>)> {
+    match value.strip_prefix('-') {
+        Some(magnitude) => {
+            let words = decimal_to_words(magnitude)?;
+            Ok((!words.is_empty(), words))
+        }
+        None => Ok((false, decimal_to_words(value)?)),
+    }
+}
+
+impl From<CloudstatePrimitiveData> for ExportValue {
+    fn from(data: CloudstatePrimitiveData) -> Self {
+        match data {
+            CloudstatePrimitiveData::Number(value) => ExportValue::Number {
+                value: value.into(),
+            },
+            CloudstatePrimitiveData::String(value) => ExportValue::String { value },
+            CloudstatePrimitiveData::Boolean(value) => ExportValue::Boolean { value },
+            CloudstatePrimitiveData::BigInt(words) => ExportValue::BigInt {
+                value: words_to_decimal(&words),
+            },
+            CloudstatePrimitiveData::Undefined => ExportValue::Undefined,
+            CloudstatePrimitiveData::Null => ExportValue::Null,
+            CloudstatePrimitiveData::Date(value) => ExportValue::Date { value },
+            CloudstatePrimitiveData::Blob(blob) => ExportValue::Blob { id: blob.id },
+            CloudstatePrimitiveData::Url(value) => ExportValue::Url { value },
+            CloudstatePrimitiveData::ObjectReference(reference) => {
+                ExportValue::Object { id: reference.id }
+            }
+            CloudstatePrimitiveData::MapReference(id) => ExportValue::Map { id },
+            CloudstatePrimitiveData::ArrayReference(id) => ExportValue::Array { id },
+            CloudstatePrimitiveData::SetReference(id) => ExportValue::Set { id },
+        }
+    }
+}
+
+impl TryFrom<ExportValue> for CloudstatePrimitiveData {
+    type Error = anyhow::Error;
+
+    fn try_from(value: ExportValue) -> Result<Self, Self::Error> {
+        Ok(match value {
+            ExportValue::Number { value } => CloudstatePrimitiveData::Number(value.try_into()?),
+            ExportValue::String { value } => CloudstatePrimitiveData::String(value),
+            ExportValue::Boolean { value } => CloudstatePrimitiveData::Boolean(value),
+            ExportValue::BigInt { value } => match parse_bigint(&value)? {
+                (false, words) => CloudstatePrimitiveData::BigInt(words.into()),
+                (true, _) => bail!("Negative bigint {} can only be a map key", value),
+            },
+            ExportValue::Undefined => CloudstatePrimitiveData::Undefined,
+            ExportValue::Null => CloudstatePrimitiveData::Null,
+            ExportValue::Date { value } => CloudstatePrimitiveData::Date(value),
+            ExportValue::Url { value } => CloudstatePrimitiveData::Url(value),
+            ExportValue::Blob { id } => CloudstatePrimitiveData::Blob(Blob { id }),
+            ExportValue::Object { id } => {
+                CloudstatePrimitiveData::ObjectReference(ObjectReference { id })
+            }
+            ExportValue::Map { id } => CloudstatePrimitiveData::MapReference(id),
+            ExportValue::Array { id } => CloudstatePrimitiveData::ArrayReference(id),
+            ExportValue::Set { id } => CloudstatePrimitiveData::SetReference(id),
+        })
+    }
+}
+
+impl From<CloudstateMapKey> for ExportValue {
+    fn from(key: CloudstateMapKey) -> Self {
+        match key {
+            CloudstateMapKey::Boolean(value) => ExportValue::Boolean { value },
+            CloudstateMapKey::Number(value) => ExportValue::Number {
+                value: value.into(),
+            },
+            CloudstateMapKey::BigInt { negative, words } => {
+                let magnitude = words_to_decimal(&words);
+                ExportValue::BigInt {
+                    value: if negative {
+                        format!("-{}", magnitude)
+                    } else {
+                        magnitude
+                    },
+                }
+            }
+            CloudstateMapKey::String(value) => ExportValue::String { value },
+            CloudstateMapKey::Date(value) => ExportValue::Date { value },
+        }
+    }
+}
+
+impl TryFrom<ExportValue> for CloudstateMapKey {
+    type Error = anyhow::Error;
+
+    fn try_from(value: ExportValue) -> Result<Self, Self::Error> {
+        Ok(match value {
+            ExportValue::Boolean { value } => CloudstateMapKey::Boolean(value),
+            ExportValue::Number { value } => CloudstateMapKey::Number(value.try_into()?),
+            ExportValue::BigInt { value } => {
+                let (negative, words) = parse_bigint(&value)?;
+                CloudstateMapKey::BigInt {
+                    negative,
+                    words: words.into(),
+                }
+            }
+            ExportValue::String { value } => CloudstateMapKey::String(value),
+            ExportValue::Date { value } => CloudstateMapKey::Date(value),
+            value => bail!("{:?} can't be a map key", value),
+        })
+    }
+}
+
+fn write_record(out: &mut impl Write, record: &ExportRecord) -> anyhow::Result<()> {
+    serde_json::to_writer(&mut *out, record)?;
+    out.write_all(b"\n")?;
+    Ok(())
+}
+
+/// Opens a table for reading, treating one that was never created as empty
+macro_rules! open_if_exists {
+    ($read:expr, $table:expr) => {
+        match $read.open_table($table) {
+            Ok(table) => Some(table),
+            Err(TableError::TableDoesNotExist(_)) => None,
+            Err(e) => return Err(e.into()),
+        }
+    };
+}
+
+/// Copies a blob's payload between the stores of `blobs`. A payload that's
+/// already lost only gets a warning, the rest of the data is still worth having.
+fn copy_blob_payload(blobs: &BlobBackup, id: &CloudstateId) -> anyhow::Result<()> {
+    match blobs.source.get_blob_data(id) {
+        Ok(data) => blobs.destination.put_blob(&id.to_string(), data),
+        Err(e) => {
+            warn!("Blob {:?} has no payload to copy: {}", id, e);
+            Ok(())
+        }
+    }
+}
+
+/// Writes every root, object, array, map, set, blob and index entry in the
+/// read transaction as NDJSON, returning how many records were written. Blob
+/// payloads are copied too when `blobs` is given.
+pub fn export_ndjson(
+    read: &ReadTransaction,
+    out: &mut impl Write,
+    blobs: Option<&BlobBackup>,
+) -> anyhow::Result<u64> {
+    let mut count = 0;
+    let mut write = |record: ExportRecord| {
+        count += 1;
+        write_record(out, &record)
+    };
+
+    write(ExportRecord::Header {
+        format: EXPORT_FORMAT.to_string(),
+        version: EXPORT_VERSION,
+    })?;
+
+    if let Some(table) = open_if_exists!(read, ROOTS_TABLE) {
+        for item in table.iter()? {
+            let (key, value) = item?;
+            write(ExportRecord::Root {
+                alias: key.value().alias,
+                id: value.value().id,
+            })?;
+        }
+    }
+
+    if let Some(table) = open_if_exists!(read, OBJECTS_TABLE) {
+        for item in table.iter()? {
+            let (key, value) = item?;
+            let data = value.value().data;
+            write(ExportRecord::Object {
+                id: key.value().id,
+                constructor: data.constructor_name,
+                version: data.version,
+                fields: data
+                    .fields
+                    .into_iter()
+                    .map(|(name, value)| (name, value.into()))
+                    .collect(),
+            })?;
+        }
+    }
+
+    if let Some(metadata) = open_if_exists!(read, ARRAY_METADATA_TABLE) {
+        let items = open_if_exists!(read, ARRAYS_TABLE);
+        for item in metadata.iter()? {
+            let (key, value) = item?;
+            let (id, metadata) = (key.value().id, value.value());
+            write(ExportRecord::Array {
+                id,
+                length: metadata.length,
+            })?;
+
+            let Some(ref items) = items else {
+                continue;
+            };
+            // items are written relative to the offset, which isn't exported
+            for item in items.range(metadata.item_range(&id))? {
+                let (key, value) = item?;
+                write(ExportRecord::ArrayItem {
+                    id,
+                    index: key.value().index - metadata.offset,
+                    value: value.value().data.into(),
+                })?;
+            }
+        }
+    }
+
+    if let Some(table) = open_if_exists!(read, MAPS_TABLE) {
+        for item in table.iter()? {
+            let (key, value) = item?;
+            let key = key.value();
+            write(ExportRecord::MapEntry {
+                id: key.id,
+                key: key.field.into(),
+                value: value.value().data.into(),
+            })?;
+        }
+    }
+
+    if let Some(table) = open_if_exists!(read, SETS_TABLE) {
+        for item in table.iter()? {
+            let (key, value) = item?;
+            write(ExportRecord::SetItem {
+                id: key.value().id,
+                value: value.value().data.into(),
+            })?;
+        }
+    }
+
+    if let Some(table) = open_if_exists!(read, BLOBS_TABLE) {
+        for item in table.iter()? {
+            let (key, value) = item?;
+            let id = key.value().id;
+            if let Some(blobs) = blobs {
+                copy_blob_payload(blobs, &id)?;
+            }
+            write(ExportRecord::Blob {
+                id,
+                content_type: value.value().type_,
+            })?;
+        }
+    }
+
+    if let Some(table) = open_if_exists!(read, FIELD_INDEX_TABLE) {
+        for item in table.iter()? {
+            let key = item?.0.value();
+            write(ExportRecord::Index {
+                class: key.class_name,
+                field: key.field,
+                object: key.object_id,
+            })?;
+        }
+    }
+
+    Ok(count)
+}
+
+/// The tables an import writes to, opened once for the whole file
+struct ImportTables<'txn> {
+    roots: Table<'txn, Ordered<CloudstateRootKey>, Bincode<CloudstateRootValue>>,
+    objects: Table<'txn, Ordered<CloudstateObjectKey>, Bincode<CloudstateObjectValue>>,
+    object_ids: Table<'txn, Bincode<CloudstateObjectIdIndexKey>, Bincode<CloudstateObjectKey>>,
+    class_versions: Table<'txn, Bincode<CloudstateClassVersionKey>, ()>,
+    array_metadata:
+        Table<'txn, Bincode<CloudstateArrayMetadataKey>, Bincode<CloudstateArrayMetadataValue>>,
+    arrays: Table<'txn, Ordered<CloudstateArrayItemKey>, Bincode<CloudstateArrayItemValue>>,
+    maps: Table<'txn, Ordered<CloudstateMapFieldKey>, Bincode<CloudstateMapFieldValue>>,
+    sets: Table<'txn, Bincode<CloudstateSetItemKey>, Bincode<CloudstateSetItemValue>>,
+    blobs: Table<'txn, Bincode<CloudstateBlobKey>, Bincode<CloudstateBlobMetadata>>,
+    /// Where to copy blob payloads from and to, if the export has them
+    blob_payloads: Option<&'txn BlobBackup<'txn>>,
+    /// Index values come from the objects, which may come later in the file
+    indexes: Vec<(String, String, CloudstateId)>,
+}
+
+impl ImportTables<'_> {
+    fn import(&mut self, record: ExportRecord) -> anyhow::Result<()> {
+        match record {
+            ExportRecord::Header { .. } => bail!("Unexpected header"),
+            ExportRecord::Root { alias, id } => {
+                self.roots
+                    .insert(CloudstateRootKey { alias }, CloudstateRootValue { id })?;
+            }
+            ExportRecord::Object {
+                id,
+                constructor,
+                version,
+                fields,
+            } => {
+                let fields = fields
+                    .into_iter()
+                    .map(|(name, value)| Ok((name, value.try_into()?)))
+                    .collect::<anyhow::Result<_>>()?;
+                let data = CloudstateObjectData {
+                    fields,
+                    constructor_name: constructor,
+                    version,
+                };
+
+                let key = CloudstateObjectKey { id };
+                if let Some(CloudstatePrimitiveData::String(id)) = data.fields.get("id") {
+                    self.object_ids
+                        .insert(CloudstateObjectIdIndexKey { id: id.clone() }, &key)?;
+                }
+                if let Some(class_version) = CloudstateClassVersionKey::new(&data, &id) {
+                    self.class_versions.insert(class_version, ())?;
+                }
+                self.objects.insert(&key, CloudstateObjectValue { data })?;
+            }
+            ExportRecord::Array { id, length } => {
+                self.array_metadata.insert(
+                    CloudstateArrayMetadataKey { id },
+                    CloudstateArrayMetadataValue { length, offset: 0 },
+                )?;
+            }
+            ExportRecord::ArrayItem { id, index, value } => {
+                self.arrays.insert(
+                    CloudstateArrayItemKey { id, index },
+                    CloudstateArrayItemValue {
+                        data: value.try_into()?,
+                    },
+                )?;
+            }
+            ExportRecord::MapEntry { id, key, value } => {
+                self.maps.insert(
+                    CloudstateMapFieldKey {
+                        id,
+                        field: key.try_into()?,
+                    },
+                    CloudstateMapFieldValue {
+                        data: value.try_into()?,
+                    },
+                )?;
+            }
+            ExportRecord::SetItem { id, value } => {
+                let data = value.try_into()?;
+                self.sets.insert(
+                    CloudstateSetItemKey::new(id, &data),
+                    CloudstateSetItemValue { data },
+                )?;
+            }
+            ExportRecord::Blob { id, content_type } => {
+                if let Some(blobs) = self.blob_payloads {
+                    copy_blob_payload(blobs, &id)?;
+                }
+                self.blobs.insert(
+                    CloudstateBlobKey { id },
+                    CloudstateBlobMetadata {
+                        type_: content_type,
+                    },
+                )?;
+            }
+            ExportRecord::Index {
+                class,
+                field,
+                object,
+            } => self.indexes.push((class, field, object)),
+        }
+
+        Ok(())
+    }
+}
+
+/// Reads an NDJSON export into the tables of `write`, returning how many
+/// records were read. Meant for an empty database, rows with the same keys
+/// are overwritten. Blob payloads are copied too when `blobs` is given, from
+/// where the export put them to the database's blob storage.
+pub fn import_ndjson<'a>(
+    input: impl BufRead,
+    write: &'a WriteTransaction,
+    blobs: Option<&'a BlobBackup<'a>>,
+) -> anyhow::Result<u64> {
+    let mut tables = ImportTables {
+        roots: write.open_table(ROOTS_TABLE)?,
+        objects: write.open_table(OBJECTS_TABLE)?,
+        object_ids: write.open_table(OBJECT_IDS_INDEX)?,
+        class_versions: write.open_table(CLASS_VERSIONS_INDEX)?,
+        array_metadata: write.open_table(ARRAY_METADATA_TABLE)?,
+        arrays: write.open_table(ARRAYS_TABLE)?,
+        maps: write.open_table(MAPS_TABLE)?,
+        sets: write.open_table(SETS_TABLE)?,
+        blobs: write.open_table(BLOBS_TABLE)?,
+        blob_payloads: blobs,
+        indexes: Vec::new(),
+    };
+    let mut count = 0;
+
+    for (line_number, line) in input.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let record: ExportRecord = serde_json::from_str(&line)
+            .map_err(|e| anyhow!("Invalid record on line {}: {}", line_number + 1, e))?;
+
+        if count == 0 {
+            match record {
+                ExportRecord::Header { format, version }
+                    if format == EXPORT_FORMAT && version <= EXPORT_VERSION => {}
+                ExportRecord::Header { format, version } if format == EXPORT_FORMAT => bail!(
+                    "The export is version {}, this version of cloudstate reads up to {}",
+                    version,
+                    EXPORT_VERSION
+                ),
+                _ => bail!("The export doesn't start with a cloudstate header"),
+            }
+        } else {
+            tables
+                .import(record)
+                .map_err(|e| anyhow!("Failed to import line {}: {}", line_number + 1, e))?;
+        }
+        count += 1;
+    }
+
+    if count == 0 {
+        bail!("The export is empty");
+    }
+
+    let mut field_index = write.open_table(FIELD_INDEX_TABLE)?;
+    for (class, field, object) in tables.indexes {
+        let data = tables
+            .objects
+            .get(CloudstateObjectKey { id: object })?
+            .ok_or_else(|| anyhow!("Index entry for missing object {}", object))?
+            .value()
+            .data;
+
+        let key = data
+            .fields
+            .get(&field)
+            .and_then(|value| CloudstateFieldIndexKey::new(&class, &field, value, &object));
+        if let Some(key) = key {
+            field_index.insert(key, ())?;
+        }
+    }
+
+    Ok(count)
+}
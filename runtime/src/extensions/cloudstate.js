@@ -16,12 +16,8 @@ function span(name, callback) {
   return result;
 }
 
-function uuidv4() {
-  return "xxxxxxxx-xxxx-4xxx-yxxx-xxxxxxxxxxxx".replace(/[xy]/g, function (c) {
-    var r = (Math.random() * 16) | 0;
-    var v = c == "x" ? r : (r & 0x3) | 0x8;
-    return v.toString(16);
-  });
+function newId() {
+  return Deno.core.ops.op_cloudstate_new_id();
 }
 
 class CloudstateObjectReference {
@@ -49,10 +45,29 @@ class CloudstateArrayReference {
   }
 }
 
+class CloudstateSetReference {
+  constructor(objectId) {
+    this.objectId = objectId;
+  }
+}
+
 globalThis.CloudstateMapReference = CloudstateMapReference;
 globalThis.CloudstateObjectReference = CloudstateObjectReference;
 globalThis.CloudstateArrayReference = CloudstateArrayReference;
 globalThis.CloudstateBlobReference = CloudstateBlobReference;
+globalThis.CloudstateSetReference = CloudstateSetReference;
+
+// thrown by ops that read a stored row which no longer decodes. The
+// transaction that read it can't be committed.
+class CorruptRowError extends Error {
+  constructor(message) {
+    super(message);
+    this.name = "CorruptRowError";
+  }
+}
+
+Deno.core.registerErrorClass("CorruptRowError", CorruptRowError);
+globalThis.CorruptRowError = CorruptRowError;
 
 function isPrimitive(value) {
   return (
@@ -175,18 +190,85 @@ function hydrate(object, key, value) {
         value: getMap(value.objectId),
       });
     }
+
+    if (value instanceof CloudstateSetReference) {
+      Object.defineProperty(object, key, {
+        value: getSet(value.objectId),
+      });
+    }
   });
 }
 
+// read-only contexts can't have pending writes, so there's nothing to flush
+let readOnly = false;
+
+// how many transaction() calls are running; commits wait until they finish
+let transactionDepth = 0;
+
+// set when a transaction() call sharing the open transaction throws, so the
+// last call to finish rolls it back rather than committing what it left
+let transactionFailed = false;
+
 function commit() {
+  if (transactionDepth > 0) return;
+
   return span("commit", () => {
-    for (const value of objects.values()) {
-      setObject(value);
+    if (!readOnly) {
+      for (const value of objects.values()) {
+        setObject(value);
+      }
     }
     Deno.core.ops.op_cloudstate_commit_transaction();
   });
 }
 
+function rollback() {
+  return span("rollback", () => {
+    Deno.core.ops.op_cloudstate_abort_transaction();
+
+    // objects created in this runtime hold changes that were just discarded,
+    // so stop writing them back. stored objects are read again when accessed.
+    for (const [id, object] of objects) {
+      if (!trackedObjects.has(object)) {
+        objects.delete(id);
+        objectIds.delete(object);
+      }
+    }
+  });
+}
+
+// runs the callback in a single transaction, committing it if the callback
+// succeeds and rolling it back if it throws. nested and concurrent calls share
+// one transaction, which the last of them to finish commits, or rolls back if
+// any of them threw. calls that finish after one threw throw too, since their
+// writes are discarded with it.
+async function transaction(callback) {
+  transactionDepth++;
+  try {
+    const result = await callback();
+    if (transactionFailed) {
+      throw new Error(
+        "The transaction was rolled back because another call sharing it threw",
+      );
+    }
+    return result;
+  } catch (e) {
+    transactionFailed = true;
+    throw e;
+  } finally {
+    transactionDepth--;
+    if (transactionDepth === 0) {
+      const failed = transactionFailed;
+      transactionFailed = false;
+      if (failed) {
+        rollback();
+      } else {
+        commit();
+      }
+    }
+  }
+}
+
 function getMap(objectId) {
   return span("get_map", () => {
     const map = new Map();
@@ -219,6 +301,27 @@ function getMap(objectId) {
       return Deno.core.ops.op_cloudstate_map_clear(objectId);
     };
 
+    const mapRange = (start, end, query) => {
+      const entries = Deno.core.ops.op_cloudstate_map_range(
+        objectId,
+        start,
+        end,
+        query,
+      );
+      return entries.map(([key, value]) => [key, unpackFromReference(value)]);
+    };
+
+    // entries with keys from start (inclusive) to end (exclusive), in key order
+    map["range"] = (start, end, options = {}) => {
+      return mapRange(start, end, options);
+    };
+
+    map["prefix"] = (prefix, options = {}) => {
+      if (typeof prefix !== "string") throw new Error("prefix must be a string");
+      const { start, ...query } = options;
+      return mapRange(start, undefined, { ...query, prefix });
+    };
+
     map["forEach"] = (fn) => {
       const entries = map.entries();
       for (const entry of entries) {
@@ -261,6 +364,78 @@ function getMap(objectId) {
   });
 }
 
+function getSet(objectId) {
+  return span("get_set", () => {
+    const set = new Set();
+
+    // values that aren't stored yet can't be members of the set
+    const lookup = (value) => {
+      if (isPrimitive(value)) return { found: true, value };
+      if (!objectIds.has(value)) return { found: false };
+      return { found: true, value: packToReferenceOrPrimitive(value) };
+    };
+
+    objectIds.set(set, objectId);
+
+    set["values"] = () => {
+      const values = Deno.core.ops.op_cloudstate_set_values(objectId);
+      return values.map((value) => unpackFromReference(value)).values();
+    };
+    set["keys"] = set["values"];
+    set[Symbol.iterator] = set["values"];
+
+    set["entries"] = () => {
+      const values = Deno.core.ops.op_cloudstate_set_values(objectId);
+      return values
+        .map((value) => {
+          const unpacked = unpackFromReference(value);
+          return [unpacked, unpacked];
+        })
+        .values();
+    };
+
+    set["forEach"] = (fn) => {
+      for (const value of set.values()) {
+        fn(value, value, set);
+      }
+    };
+
+    set.add = (value) => {
+      Deno.core.ops.op_cloudstate_set_add(
+        objectId,
+        packToReferenceOrPrimitive(value),
+      );
+      return set;
+    };
+
+    set.has = (value) => {
+      const result = lookup(value);
+      if (!result.found) return false;
+      return Deno.core.ops.op_cloudstate_set_has(objectId, result.value);
+    };
+
+    set["delete"] = (value) => {
+      const result = lookup(value);
+      if (!result.found) return false;
+      return Deno.core.ops.op_cloudstate_set_delete(objectId, result.value);
+    };
+
+    set["clear"] = () => {
+      return Deno.core.ops.op_cloudstate_set_clear(objectId);
+    };
+
+    Object.defineProperty(set, "size", {
+      get: () => {
+        return Deno.core.ops.op_cloudstate_set_size(objectId);
+      },
+    });
+
+    trackedObjects.add(set);
+
+    return set;
+  });
+}
+
 function getObject(id) {
   return span("get_object", () => {
     if (typeof id !== "string") throw new Error("id must be a string");
@@ -284,8 +459,19 @@ function getObject(id) {
       delete object.__cloudstate__constructorName;
     }
 
+    const version = object.__cloudstate__version ?? 0;
+    delete object.__cloudstate__version;
+
     if (!object) return undefined;
 
+    if (klass && typeof klass.version === "number" && version < klass.version) {
+      if (readOnly) {
+        migrateOutsideSnapshot(id, klass);
+      } else {
+        migrateObject(id, klass, object, version);
+      }
+    }
+
     // for (const [key, value] of Object.entries(object)) {
     //   hydrate(object, key, value);
     // }
@@ -306,6 +492,7 @@ function getObject(id) {
         ownKeys(target) {
           let object = Deno.core.ops.op_cloudstate_object_get(id);
           delete object["__cloudstate__constructorName"];
+          delete object["__cloudstate__version"];
           return Object.keys(object);
         },
         getOwnPropertyDescriptor(target, key) {
@@ -321,6 +508,7 @@ function getObject(id) {
           const object = Deno.core.ops.op_cloudstate_object_get(id);
 
           delete object["__cloudstate__constructorName"];
+          delete object["__cloudstate__version"];
 
           for (const [key, value] of Object.entries(object)) {
             hydrate(object, key, value);
@@ -354,16 +542,91 @@ function getObject(id) {
 
           return true;
         },
+        deleteProperty(target, key) {
+          if (typeof key === "symbol") return Reflect.deleteProperty(target, key);
+          return Deno.core.ops.op_cloudstate_object_delete_property(id, key);
+        },
       },
     );
 
     objects.set(id, proxyObject);
     objectIds.set(proxyObject, id);
+    trackedObjects.add(proxyObject);
 
     return proxyObject;
   });
 }
 
+// Brings an object written by an older version of its class up to date with
+// the class's static migrate method, and saves it under the same id
+function migrateObject(id, klass, object, fromVersion) {
+  return span("migrate_object", () => {
+    if (typeof klass.migrate !== "function") {
+      throw new Error(
+        `${klass.name} is at version ${klass.version} but has no static migrate method for objects from version ${fromVersion}`,
+      );
+    }
+
+    for (const [key, value] of Object.entries(object)) {
+      hydrate(object, key, value);
+    }
+
+    const migrated = klass.migrate(object, fromVersion) ?? object;
+    Object.setPrototypeOf(migrated, klass.prototype);
+
+    objectIds.set(migrated, id);
+    setObject(migrated);
+    objectIds.delete(migrated);
+  });
+}
+
+// A snapshot can't be written to, so an object found to be outdated on one is
+// migrated in a write transaction of its own, after which reads go on from a
+// new snapshot. Reads either side of it can see different versions of other
+// objects, as they could across two requests.
+function migrateOutsideSnapshot(id, klass) {
+  return span("migrate_outside_snapshot", () => {
+    __setReadWrite();
+    try {
+      // another writer may have migrated it since the snapshot was taken
+      const object = Deno.core.ops.op_cloudstate_object_get(id);
+      const version = object?.__cloudstate__version ?? 0;
+      if (object && version < klass.version) {
+        delete object.__cloudstate__constructorName;
+        delete object.__cloudstate__version;
+        migrateObject(id, klass, object, version);
+      }
+      Deno.core.ops.op_cloudstate_commit_transaction();
+    } catch (e) {
+      Deno.core.ops.op_cloudstate_abort_transaction();
+      throw e;
+    } finally {
+      __setReadOnly();
+    }
+  });
+}
+
+// Migrates every stored object whose class has a newer version, instead of
+// waiting for them to be read. Returns how many were migrated.
+function migrateObjects() {
+  return span("migrate_objects", () => {
+    let count = 0;
+    for (const klass of customClasses) {
+      if (typeof klass.version !== "number") continue;
+
+      const ids = Deno.core.ops.op_cloudstate_object_outdated(
+        klass.name,
+        klass.version,
+      );
+      for (const id of ids) {
+        getObject(id);
+        count++;
+      }
+    }
+    return count;
+  });
+}
+
 function packToReferenceOrPrimitive(value) {
   return span("pack_to_reference_or_primitive", () => {
     if (isPrimitive(value)) {
@@ -375,6 +638,9 @@ function packToReferenceOrPrimitive(value) {
     if (value instanceof Map) {
       return new CloudstateMapReference(setObject(value));
     }
+    if (value instanceof Set) {
+      return new CloudstateSetReference(setObject(value));
+    }
     if (value instanceof Blob) {
       return new CloudstateBlobReference(setObject(value));
     }
@@ -382,6 +648,7 @@ function packToReferenceOrPrimitive(value) {
       value instanceof CloudstateObjectReference ||
       value instanceof CloudstateArrayReference ||
       value instanceof CloudstateMapReference ||
+      value instanceof CloudstateSetReference ||
       value instanceof CloudstateBlobReference
     ) {
       console.error(
@@ -407,6 +674,9 @@ function unpackFromReference(reference, notValidCb) {
     if (reference instanceof CloudstateMapReference) {
       return getMap(reference.objectId);
     }
+    if (reference instanceof CloudstateSetReference) {
+      return getSet(reference.objectId);
+    }
     if (notValidCb) {
       notValidCb.call();
     }
@@ -443,7 +713,7 @@ function setObject(object, visited = new Set()) {
       if (object instanceof Blob) {
         let id = objectIds.get(object);
         if (!id) {
-          id = uuidv4();
+          id = newId();
           objectIds.set(object, id);
           objects.set(id, object);
 
@@ -497,6 +767,33 @@ function setObject(object, visited = new Set()) {
         continue;
       }
 
+      if (object instanceof Set) {
+        let id = objectIds.get(object);
+        if (!id) {
+          id = newId();
+          objectIds.set(object, id);
+        }
+
+        if (!rootObject) {
+          rootObject = id;
+        }
+
+        visited.add(object);
+
+        if (trackedObjects.has(object)) {
+          continue;
+        }
+
+        for (const value of object.values()) {
+          Deno.core.ops.op_cloudstate_set_add(
+            id,
+            packToReferenceOrPrimitive(value),
+          );
+        }
+
+        continue;
+      }
+
       const isArray = object instanceof Array;
       const flatObject = isArray ? [] : {};
 
@@ -514,7 +811,7 @@ function setObject(object, visited = new Set()) {
           let id = objectIds.get(value);
 
           if (!id) {
-            id = uuidv4();
+            id = newId();
             objectIds.set(value, id);
             objects.set(id, value);
 
@@ -531,6 +828,8 @@ function setObject(object, visited = new Set()) {
 
           if (value instanceof Map) {
             flatObject[key] = new CloudstateMapReference(id);
+          } else if (value instanceof Set) {
+            flatObject[key] = new CloudstateSetReference(id);
           } else if (value instanceof Array) {
             flatObject[key] = new CloudstateArrayReference(id);
           } else if (value instanceof Blob) {
@@ -560,7 +859,7 @@ function setObject(object, visited = new Set()) {
       if (flatObject instanceof Array) {
         let id = objectIds.get(object);
         if (!id) {
-          id = uuidv4();
+          id = newId();
           objectIds.set(object, id);
         }
 
@@ -638,7 +937,7 @@ function exportObject(object, data) {
   return span("export_object", () => {
     const existingId = objectIds.get(object);
     if (!existingId) {
-      const id = uuidv4();
+      const id = newId();
       Deno.core.ops.op_cloudstate_object_set(id, data);
       objectIds.set(object, id);
       objects.set(id, object);
@@ -714,7 +1013,8 @@ function handleArrayMethods(key, array, id) {
     }
     case "shift": {
       return () => {
-        return Deno.core.ops.op_cloudstate_array_shift(id);
+        const raw = Deno.core.ops.op_cloudstate_array_shift(id);
+        return unpackFromReference(raw);
       };
     }
     case "some": {
@@ -896,23 +1196,14 @@ function handleArrayMethods(key, array, id) {
     case "unshift": {
       return (...args) => {
         let length = Deno.core.ops.op_cloudstate_array_length(id);
-        for (let i = length - 1; i >= 0; i--) {
-          Deno.core.ops.op_cloudstate_array_set(
-            id,
-            i + args.length,
-            array[i],
-          );
-        }
-
-        for (let i = 0; i < args.length; i++) {
-          Deno.core.ops.op_cloudstate_array_set(
+        for (let i = args.length - 1; i >= 0; i--) {
+          length = Deno.core.ops.op_cloudstate_array_unshift(
             id,
-            i,
             packToReferenceOrPrimitive(args[i]),
           );
         }
 
-        return length + args.length;
+        return length;
       };
     }
     case "slice": {
@@ -968,15 +1259,118 @@ function handleArrayMethods(key, array, id) {
 
 function registerCustomClass(klass) {
   customClasses.push(klass);
+
+  if (klass.indexes) {
+    if (!Array.isArray(klass.indexes)) {
+      throw new Error(`${klass.name}.indexes must be an array of field names`);
+    }
+    Deno.core.ops.op_cloudstate_index_register(klass.name, klass.indexes);
+  }
+}
+
+function queryIndex(klass, field, value) {
+  return span("query_index", () => {
+    const className = typeof klass === "string" ? klass : klass.name;
+    if (typeof field !== "string") throw new Error("field must be a string");
+
+    const ids = Deno.core.ops.op_cloudstate_index_query(
+      className,
+      field,
+      value,
+    );
+    return ids.map((id) => getObject(id));
+  });
 }
 
 function __setReadOnly() {
+  readOnly = true;
   Deno.core.ops.op_cloudstate_set_read_only();
 }
 
+function __setReadWrite() {
+  readOnly = false;
+  Deno.core.ops.op_cloudstate_set_read_write();
+}
+
+// whether a write was refused since the last call because we were read-only
+function __takeWriteAttempted() {
+  return Deno.core.ops.op_cloudstate_take_write_attempted();
+}
+
+// the id, method, params and so on of the request being served
+function __requestArgs() {
+  return Deno.core.ops.op_cloudstate_request_args();
+}
+
+// the request's body as a stream, read from the server as it's consumed
+function __requestBody() {
+  return new ReadableStream({
+    async pull(controller) {
+      const chunk = await Deno.core.ops.op_cloudstate_request_body_read();
+      if (chunk == null) {
+        controller.close();
+      } else {
+        controller.enqueue(chunk);
+      }
+    },
+  });
+}
+
+// sends a response's status and headers, then its body as it's read
+async function __sendResponse(response) {
+  Deno.core.ops.op_cloudstate_response_start({
+    status: response.status,
+    headers: [...response.headers.entries()],
+  });
+
+  if (!response.body) return;
+  const reader = response.body.getReader();
+  while (true) {
+    let read;
+    try {
+      read = await reader.read();
+    } catch (e) {
+      // the head has gone out, so the client can only find out the body
+      // failed by the connection being cut
+      await Deno.core.ops.op_cloudstate_response_error(String(e?.message ?? e));
+      throw e;
+    }
+    const { done, value } = read;
+    if (done) return;
+    // stop producing the body once nobody is reading it
+    if (!(await Deno.core.ops.op_cloudstate_response_write(value))) {
+      await reader.cancel();
+      return;
+    }
+  }
+}
+
+// forgets everything loaded or registered, for a runtime that serves more
+// than one request
+function __resetState() {
+  objects.clear();
+  arrays.clear();
+  objectIds.clear();
+  cloudstateObjects.clear();
+  trackedObjects.clear();
+  customClasses.length = 0;
+  readOnly = false;
+  transactionDepth = 0;
+  transactionFailed = false;
+}
+
 globalThis.getRoot = getRoot;
 globalThis.setRoot = setRoot;
 globalThis.commit = commit;
+globalThis.transaction = transaction;
 globalThis.getCloudstate = getCloudstate;
 globalThis.registerCustomClass = registerCustomClass;
+globalThis.queryIndex = queryIndex;
+globalThis.migrateObjects = migrateObjects;
 globalThis.__setReadOnly = __setReadOnly;
+globalThis.__setReadWrite = __setReadWrite;
+globalThis.__takeWriteAttempted = __takeWriteAttempted;
+globalThis.__requestArgs = __requestArgs;
+globalThis.__requestBody = __requestBody;
+globalThis.__sendResponse = __sendResponse;
+globalThis.__resetState = __resetState;
@@ -1,6 +1,17 @@
-use crate::backup::{BackupProgress, backup_all_tables};
+use crate::backup::{
+    BackupProgress, BlobBackup, backup_all_tables, backup_changed_rows, compact_changes,
+    snapshot_path,
+};
+use crate::bincode::{Bincode, CorruptRow, Decode, Raw, encode, raw};
 use crate::blob_storage::{CloudstateBlobMetadata, CloudstateBlobStorage, CloudstateBlobValue};
-use crate::tables::{ARRAYS_TABLE, MAPS_TABLE, OBJECTS_TABLE, ROOTS_TABLE};
+use crate::changes::PendingChanges;
+use crate::gc::Pointer;
+use crate::migrations::check_format_version;
+use crate::ordered::Ordered;
+use crate::tables::{
+    ARRAY_METADATA_TABLE, ARRAYS_TABLE, CLASS_VERSIONS_INDEX, FIELD_INDEX_TABLE,
+    INDEXED_FIELDS_TABLE, MAPS_TABLE, OBJECT_IDS_INDEX, OBJECTS_TABLE, ROOTS_TABLE, SETS_TABLE,
+};
 use crate::v8_string_key;
 use anyhow::Result;
 use anyhow::anyhow;
@@ -8,12 +19,12 @@ use chrono::{DateTime, TimeZone, Utc};
 use deno_core::anyhow::Error;
 // use deno_core::error::JsError;
 
-use crate::ServerInfo;
+use crate::{RequestArgs, ServerInfo};
 use deno_core::*;
 use deno_error::JsErrorBox;
 use redb::{
     AccessGuard, Database, Key, Range, ReadOnlyTable, ReadTransaction, ReadableTable,
-    TableDefinition, Value, WriteTransaction,
+    TableDefinition, TableError, TableHandle, Value, WriteTransaction,
 };
 use serde::{Deserialize, Serialize};
 use std::borrow::Borrow;
@@ -21,25 +32,39 @@ use std::cell::RefCell;
 use std::cmp::Ordering;
 use std::collections::HashMap;
 use std::i32;
-use std::ops::RangeBounds;
-use std::path::Path;
+use std::ops::{Bound, RangeBounds};
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
 use std::result::Result::Ok;
 use std::sync::Arc;
-use std::sync::{Mutex, MutexGuard};
-use tracing::{debug, event, info_span, instrument};
+use std::sync::{Condvar, Mutex, MutexGuard};
+use tracing::{debug, event, info_span, instrument, warn};
 use url::Url;
 use v8::GetPropertyNamesArgs;
 
+pub use http::{RequestBody, ResponseHead, ResponseSink, ResponseStream, ScriptClock};
+pub use id::CloudstateId;
 pub use js_spans::JavaScriptSpans;
 
+mod http;
+mod id;
 mod js_spans;
 
 pub struct TransactionContext {
     database: ReDBCloudstate,
     blob_storage: CloudstateBlobStorage,
     current_transaction: Option<Transaction>,
+    /// Held while `current_transaction` is a write transaction, declared after
+    /// it so the transaction is dropped before the next writer is admitted
+    write_permit: Option<WritePermit>,
     read_only: bool,
+    /// Set when a write was refused because the context is read-only
+    write_attempted: bool,
+    /// Values whose last reference a commit dropped, deleted when the
+    /// request is over in case the script stores them again before then
+    unreferenced: Vec<Pointer>,
+    /// Fields each custom class listed in its static `indexes`, by class name
+    indexed_fields: Rc<HashMap<String, Vec<String>>>,
 }
 
 impl TransactionContext {
@@ -50,18 +75,22 @@ impl TransactionContext {
 
 pub enum Transaction {
     Read(ReadTransaction),
-    Write(WriteTransaction),
+    Write(WriteTransaction, PendingChanges),
 }
 
+/// A table opened through a `Transaction`. Rows are read as bytes and decoded
+/// here, so a corrupt row is an error rather than a panic, and a write
+/// transaction that reads one can't commit.
 pub enum CloudstateTable<'a, K: Key + 'static, V: Value + 'static> {
-    Read(ReadOnlyTable<K, V>),
-    Write(redb::Table<'a, K, V>),
+    Read(ReadOnlyTable<Raw<K>, Raw<V>>),
+    /// Keeps the table's name so writes can be recorded in the change log
+    Write(redb::Table<'a, Raw<K>, Raw<V>>, &'a PendingChanges, String),
 }
 
 impl<'a, K, V> CloudstateTable<'a, K, V>
 where
-    K: Key + 'static,
-    V: Value + 'static,
+    K: Key + Decode + 'static,
+    V: Decode + 'static,
 {
     pub fn insert(
         &mut self,
@@ -70,24 +99,51 @@ where
     ) -> Result<(), Error> {
         match self {
             CloudstateTable::Read(_table) => Ok(()), //panic!("Cannot insert into read-only table"),
-            CloudstateTable::Write(table) => match table.insert(key, value) {
-                Ok(_) => Ok(()),
-                Err(e) => Err(e.into()),
-            },
+            CloudstateTable::Write(table, changes, name) => {
+                let key = encode::<K>(key.borrow());
+                let value = encode::<V>(value.borrow());
+                let previous = table.insert(key.as_slice(), value.as_slice())?;
+                changes.record(
+                    name,
+                    key,
+                    previous.as_ref().map(|previous| previous.value()),
+                );
+                Ok(())
+            }
         }
     }
 
-    pub fn iter(&self) -> anyhow::Result<redb::Range<K, V>> {
+    pub fn iter(&self) -> anyhow::Result<Rows<'_, K, V>> {
+        let range = match self {
+            CloudstateTable::Read(table) => table.iter()?,
+            CloudstateTable::Write(table, ..) => table.iter()?,
+        };
+        Ok(self.rows(range))
+    }
+
+    /// Removes a row by its encoded key, returning whether it was there
+    pub(crate) fn remove_encoded(&mut self, key: &[u8]) -> redb::Result<bool> {
         match self {
-            CloudstateTable::Read(table) => table.iter().map_err(|e| e.into()),
-            CloudstateTable::Write(table) => table.iter().map_err(|e| e.into()),
+            CloudstateTable::Read(_table) => {
+                panic!("Cannot remove during read-only transaction")
+            }
+            CloudstateTable::Write(table, changes, name) => {
+                let previous = table.remove(key)?;
+                changes.record(
+                    name,
+                    key.to_vec(),
+                    previous.as_ref().map(|previous| previous.value()),
+                );
+                Ok(previous.is_some())
+            }
         }
     }
 
+    /// Removes a row, returning what it held
     pub fn remove<'b>(
         &mut self,
         key: impl std::borrow::Borrow<K::SelfType<'b>>,
-    ) -> redb::Result<Option<AccessGuard<V>>>
+    ) -> anyhow::Result<Option<V::Decoded>>
     where
         K: 'b,
     {
@@ -95,52 +151,233 @@ where
             CloudstateTable::Read(_table) => {
                 panic!("Cannot remove during read-only transaction")
             }
-            CloudstateTable::Write(table) => table.remove(key),
+            CloudstateTable::Write(table, changes, name) => {
+                let key = encode::<K>(key.borrow());
+                let previous = table.remove(key.as_slice())?;
+                let previous = previous.as_ref().map(|previous| previous.value());
+                changes.record(name, key, previous);
+                previous
+                    .map(|previous| decode_row::<V>(Some(*changes), previous))
+                    .transpose()
+            }
         }
     }
 
     pub fn get(
         &self,
         key: impl std::borrow::Borrow<K::SelfType<'a>>,
-    ) -> anyhow::Result<Option<AccessGuard<V>>> {
-        match self {
-            CloudstateTable::Read(table) => table.get(key).map_err(|e| e.into()),
-            CloudstateTable::Write(table) => table.get(key).map_err(|e| e.into()),
-        }
+    ) -> anyhow::Result<Option<V::Decoded>> {
+        let key = encode::<K>(key.borrow());
+        let value = match self {
+            CloudstateTable::Read(table) => table.get(key.as_slice())?,
+            CloudstateTable::Write(table, ..) => table.get(key.as_slice())?,
+        };
+        value
+            .map(|value| decode_row::<V>(self.changes(), value.value()))
+            .transpose()
     }
-}
 
-impl<'txn, K: Key + 'static, V: Value + 'static> CloudstateTable<'txn, K, V> {
-    fn range<'a, KR>(&self, range: impl RangeBounds<KR> + 'a) -> redb::Result<Range<K, V>>
+    pub(crate) fn range<'k, KR>(&self, range: impl RangeBounds<KR>) -> redb::Result<Rows<'_, K, V>>
     where
-        KR: Borrow<K::SelfType<'a>> + 'a,
+        KR: Borrow<K::SelfType<'k>>,
     {
+        let encode_bound = |bound: Bound<&KR>| bound.map(|key| encode::<K>(key.borrow()));
+        let start = encode_bound(range.start_bound());
+        let end = encode_bound(range.end_bound());
+        self.encoded_range((
+            start.as_ref().map(Vec::as_slice),
+            end.as_ref().map(Vec::as_slice),
+        ))
+    }
+
+    /// The rows between two encoded keys
+    pub(crate) fn encoded_range(
+        &self,
+        bounds: (Bound<&[u8]>, Bound<&[u8]>),
+    ) -> redb::Result<Rows<'_, K, V>> {
+        let range = match self {
+            CloudstateTable::Read(table) => table.range::<&[u8]>(bounds)?,
+            CloudstateTable::Write(table, ..) => table.range::<&[u8]>(bounds)?,
+        };
+        Ok(self.rows(range))
+    }
+
+    fn rows<'r>(&'r self, range: Range<'r, Raw<K>, Raw<V>>) -> Rows<'r, K, V> {
+        Rows {
+            range,
+            changes: self.changes(),
+        }
+    }
+
+    fn changes(&self) -> Option<&'a PendingChanges> {
         match self {
-            CloudstateTable::Read(table) => table.range(range),
-            CloudstateTable::Write(table) => table.range(range),
+            CloudstateTable::Read(_) => None,
+            CloudstateTable::Write(_, changes, _) => Some(*changes),
         }
     }
+}
 
-    // fn first(&self) -> redb::Result<Option<(AccessGuard<K>, AccessGuard<V>)>> {
-    //     match self {
-    //         CloudstateTable::Read(table) => table.first(),
-    //         CloudstateTable::Write(table) => table.first(),
-    //     }
-    // }
+/// Decodes a row's key or value, keeping a write transaction that read a
+/// corrupt row from committing
+fn decode_row<T: Decode>(
+    changes: Option<&PendingChanges>,
+    data: &[u8],
+) -> anyhow::Result<T::Decoded> {
+    T::decode(data).map_err(|corrupt| {
+        if let Some(changes) = changes {
+            changes.poison(&corrupt);
+        }
+        corrupt.into()
+    })
+}
 
-    // fn last(&self) -> redb::Result<Option<(AccessGuard<K>, AccessGuard<V>)>> {
-    //     match self {
-    //         CloudstateTable::Read(table) => table.last(),
-    //         CloudstateTable::Write(table) => table.last(),
-    //     }
-    // }
+/// The rows of a `CloudstateTable` in a range, decoded as they're read
+pub struct Rows<'r, K: Key + 'static, V: Value + 'static> {
+    range: Range<'r, Raw<K>, Raw<V>>,
+    changes: Option<&'r PendingChanges>,
+}
+
+impl<K: Key + Decode + 'static, V: Decode + 'static> Rows<'_, K, V> {
+    /// The next row along with its encoded key
+    pub(crate) fn next_encoded(
+        &mut self,
+    ) -> Option<anyhow::Result<(Vec<u8>, K::Decoded, V::Decoded)>> {
+        let row = self.range.next()?;
+        Some(row.map_err(Error::from).and_then(|(key, value)| {
+            let (decoded_key, value) = self.decode(&key, &value)?;
+            Ok((key.value().to_vec(), decoded_key, value))
+        }))
+    }
+
+    fn decode(
+        &self,
+        key: &AccessGuard<Raw<K>>,
+        value: &AccessGuard<Raw<V>>,
+    ) -> anyhow::Result<(K::Decoded, V::Decoded)> {
+        Ok((
+            decode_row::<K>(self.changes, key.value())?,
+            decode_row::<V>(self.changes, value.value())?,
+        ))
+    }
+}
+
+impl<K: Key + Decode + 'static, V: Decode + 'static> Iterator for Rows<'_, K, V> {
+    type Item = anyhow::Result<(K::Decoded, V::Decoded)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let row = self.range.next()?;
+        Some(
+            row.map_err(Error::from)
+                .and_then(|(key, value)| self.decode(&key, &value)),
+        )
+    }
+}
+
+impl<K: Key + Decode + 'static, V: Decode + 'static> DoubleEndedIterator for Rows<'_, K, V> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        let row = self.range.next_back()?;
+        Some(
+            row.map_err(Error::from)
+                .and_then(|(key, value)| self.decode(&key, &value)),
+        )
+    }
+}
+
+/// The item and header tables for arrays, opened together so ops can keep the
+/// header in sync with the items they touch.
+pub struct ArrayTables<'txn> {
+    pub items:
+        CloudstateTable<'txn, Ordered<CloudstateArrayItemKey>, Bincode<CloudstateArrayItemValue>>,
+    /// `None` when a read-only transaction runs against a database written
+    /// before array headers existed
+    pub metadata: Option<
+        CloudstateTable<
+            'txn,
+            Bincode<CloudstateArrayMetadataKey>,
+            Bincode<CloudstateArrayMetadataValue>,
+        >,
+    >,
+}
+
+impl<'txn> ArrayTables<'txn> {
+    pub fn open(transaction: &'txn Transaction) -> Result<Self, Error> {
+        let metadata = match transaction {
+            Transaction::Read(_) => transaction.open_table(ARRAY_METADATA_TABLE).ok(),
+            Transaction::Write(..) => Some(transaction.open_table(ARRAY_METADATA_TABLE)?),
+        };
+
+        Ok(Self {
+            items: transaction.open_table(ARRAYS_TABLE)?,
+            metadata,
+        })
+    }
+
+    /// Returns the header for an array. Arrays written before headers existed
+    /// get one rebuilt from their items, which is then stored for next time.
+    pub fn metadata(&mut self, id: &CloudstateId) -> Result<CloudstateArrayMetadataValue, Error> {
+        let key = CloudstateArrayMetadataKey { id: *id };
+        if let Some(table) = &self.metadata {
+            if let Some(metadata) = table.get(&key)? {
+                return Ok(metadata);
+            }
+        }
+
+        let mut metadata = CloudstateArrayMetadataValue {
+            length: 0,
+            offset: 0,
+        };
+        for entry in self.items.range(CloudstateArrayItemKey::range(id))? {
+            let (key, _value) = entry?;
+            metadata.length = metadata.length.max(key.index + 1);
+        }
+
+        if metadata.length > 0 {
+            self.set_metadata(id, metadata)?;
+        }
+
+        Ok(metadata)
+    }
+
+    pub fn set_metadata(
+        &mut self,
+        id: &CloudstateId,
+        metadata: CloudstateArrayMetadataValue,
+    ) -> Result<(), Error> {
+        match &mut self.metadata {
+            Some(table) => table.insert(CloudstateArrayMetadataKey { id: *id }, metadata),
+            None => Ok(()),
+        }
+    }
 }
 
 impl Transaction {
-    pub fn commit(self) -> Result<(), Error> {
+    /// Commits the transaction, or aborts it if it read a corrupt row, since
+    /// the op that read it may have stopped partway through its writes.
+    /// Returns the values that lost their last reference, in databases that
+    /// count them.
+    pub fn commit(self) -> Result<Vec<Pointer>, Error> {
+        match self {
+            Transaction::Read(transaction) => {
+                transaction.close()?;
+                Ok(Vec::new())
+            }
+            Transaction::Write(transaction, changes) => {
+                if let Some(corrupt) = changes.corrupt() {
+                    transaction.abort()?;
+                    return Err(corrupt.into());
+                }
+                let unreferenced = changes.commit(&transaction)?;
+                transaction.commit()?;
+                Ok(unreferenced)
+            }
+        }
+    }
+
+    /// Discards the transaction, dropping any writes it made
+    pub fn abort(self) -> Result<(), Error> {
         match self {
             Transaction::Read(transaction) => transaction.close().map_err(|e| e.into()),
-            Transaction::Write(transaction) => transaction.commit().map_err(|e| e.into()),
+            Transaction::Write(transaction, _) => transaction.abort().map_err(|e| e.into()),
         }
     }
 
@@ -150,24 +387,52 @@ impl Transaction {
     ) -> Result<CloudstateTable<K, V>, Error> {
         match self {
             Transaction::Read(transaction) => {
-                let table = transaction.open_table(def)?;
+                let table = transaction.open_table(raw(&def))?;
                 Ok(CloudstateTable::Read(table))
             }
-            Transaction::Write(transaction) => {
-                let table = transaction.open_table(def)?;
-                Ok(CloudstateTable::Write(table))
+            Transaction::Write(transaction, changes) => {
+                let table = transaction.open_table(raw(&def))?;
+                Ok(CloudstateTable::Write(
+                    table,
+                    changes,
+                    def.name().to_string(),
+                ))
             }
         }
     }
+
+    /// Opens a table, or returns `None` when a read transaction runs against
+    /// a database that has never written to it
+    pub fn try_open_table<K: Key + 'static, V: Value + 'static>(
+        &self,
+        def: TableDefinition<K, V>,
+    ) -> Result<Option<CloudstateTable<K, V>>, Error> {
+        match self.open_table(def) {
+            Ok(table) => Ok(Some(table)),
+            Err(e)
+                if matches!(
+                    e.downcast_ref::<TableError>(),
+                    Some(TableError::TableDoesNotExist(_))
+                ) =>
+            {
+                Ok(None)
+            }
+            Err(e) => Err(e),
+        }
+    }
 }
 
 impl TransactionContext {
     pub fn new(database: ReDBCloudstate, storage: CloudstateBlobStorage) -> Self {
         Self {
             current_transaction: None,
+            write_permit: None,
             blob_storage: storage,
             database: database.clone(),
             read_only: false,
+            write_attempted: false,
+            unreferenced: Vec::new(),
+            indexed_fields: Rc::new(HashMap::new()),
         }
     }
 
@@ -177,39 +442,147 @@ impl TransactionContext {
         }
     }
 
+    /// Goes back to write transactions, closing any open read snapshot first
+    pub fn set_read_write(&mut self) {
+        if let Some(Transaction::Read(_)) = self.current_transaction {
+            // closing a read snapshot can't fail on corrupt rows
+            self.commit_transaction().unwrap();
+        }
+        self.read_only = false;
+    }
+
+    /// Whether the field index was last built for exactly these fields of the
+    /// class. Most requests declare what the last one did, which a snapshot can
+    /// tell without waiting for other writers.
+    fn field_index_built(&self, class_name: &str, fields: &[String]) -> Result<bool, JsErrorBox> {
+        let snapshot;
+        let transaction = match &self.current_transaction {
+            Some(transaction) => transaction,
+            None => {
+                let read = self.database.get_database_mut().begin_read();
+                snapshot = Transaction::Read(read.map_err(js_error)?);
+                &snapshot
+            }
+        };
+
+        let built = match try_open_table(transaction, INDEXED_FIELDS_TABLE)? {
+            Some(table) => table.get(class_name).map_err(js_error)?,
+            None => None,
+        };
+        Ok(built.as_deref() == Some(fields))
+    }
+
+    /// Returns whether a write was refused since the last call, and resets it
+    pub fn take_write_attempted(&mut self) -> bool {
+        std::mem::take(&mut self.write_attempted)
+    }
+
+    /// Returns the transaction for an op that modifies data, refusing the
+    /// write in read-only mode rather than dropping it silently
+    pub fn write_transaction(&mut self) -> Result<&Transaction, JsErrorBox> {
+        if self.read_only {
+            self.write_attempted = true;
+            return Err(JsErrorBox::generic(
+                "Cannot modify cloudstate objects from a read-only method",
+            ));
+        }
+        self.get_or_create_transaction_mut()
+    }
+
     #[instrument(skip(self))]
-    pub fn get_or_create_transaction_mut(&mut self) -> &Transaction {
+    pub fn get_or_create_transaction_mut(&mut self) -> Result<&Transaction, JsErrorBox> {
         // debug!("Checking for existing transaction");
         if self.current_transaction.is_none() {
             debug!("Creating new transaction");
-            let db = self.database.get_database_mut();
-
             if self.read_only {
-                let read_txn = db.begin_read().unwrap();
+                let db = self.database.get_database_mut();
+                let read_txn = db.begin_read().map_err(js_error)?;
                 self.current_transaction = Some(Transaction::Read(read_txn));
             } else {
-                let write_txn = db.begin_write().unwrap();
-                self.current_transaction = Some(Transaction::Write(write_txn));
+                // wait for other writers before taking the database lock, so
+                // readers can keep starting snapshots in the meantime
+                let permit = self.database.acquire_writer();
+                let db = self.database.get_database_mut();
+                let write_txn = db.begin_write().map_err(js_error)?;
+                let changes = PendingChanges::new(&write_txn).map_err(js_error)?;
+                self.current_transaction = Some(Transaction::Write(write_txn, changes));
+                self.write_permit = Some(permit);
             }
-            self.current_transaction.as_mut().unwrap()
+            Ok(self.current_transaction.as_mut().unwrap())
         } else {
             // debug!("Using existing transaction");
-            self.current_transaction.as_mut().unwrap()
+            Ok(self.current_transaction.as_mut().unwrap())
         }
     }
 
     #[instrument(skip(self))]
-    pub fn commit_transaction(&mut self) {
+    pub fn commit_transaction(&mut self) -> Result<(), Error> {
         // debug!("Checking for transaction to commit");
         if let Some(transaction) = self.current_transaction.take() {
             debug!("Committing transaction");
-            transaction.commit().unwrap();
+            let committed = transaction.commit();
+            self.write_permit = None;
+            self.unreferenced.extend(committed?);
+            Ok(())
         } else {
             debug!("No transaction to commit");
+            Ok(())
+        }
+    }
+
+    #[instrument(skip(self))]
+    pub fn abort_transaction(&mut self) {
+        if let Some(transaction) = self.current_transaction.take() {
+            debug!("Aborting transaction");
+            transaction.abort().unwrap();
+            self.write_permit = None;
+        } else {
+            debug!("No transaction to abort");
+        }
+    }
+}
+
+impl TransactionContext {
+    /// Deletes the values that lost their last reference during the request,
+    /// unless it referenced them again, in a transaction of its own
+    fn reclaim_unreferenced(&mut self) -> Result<(), Error> {
+        let _permit = self.database.acquire_writer();
+        let db = self.database.get_database_mut();
+        let write = db.begin_write()?;
+        let changes = PendingChanges::new(&write)?;
+        changes.reclaim(std::mem::take(&mut self.unreferenced));
+        Transaction::Write(write, changes).commit()?;
+        Ok(())
+    }
+}
+
+/// A context lives for one request, so dropping it is where the request's
+/// unreferenced values are deleted
+impl Drop for TransactionContext {
+    fn drop(&mut self) {
+        // whatever is still open is rolled back, and has to be before another
+        // write transaction can start
+        self.abort_transaction();
+
+        if !self.unreferenced.is_empty() {
+            if let Err(e) = self.reclaim_unreferenced() {
+                // the garbage collector will find them instead
+                warn!("Failed to delete unreferenced values: {}", e);
+            }
         }
     }
 }
 
+/// Fails an op with an error, raising a `CorruptRowError` when it was a row
+/// that didn't decode
+fn js_error(e: impl Into<Error>) -> JsErrorBox {
+    let e = e.into();
+    match e.downcast_ref::<CorruptRow>() {
+        Some(corrupt) => JsErrorBox::new("CorruptRowError", corrupt.to_string()),
+        None => JsErrorBox::generic(e.to_string()),
+    }
+}
+
 #[instrument(skip(state))]
 #[op2(fast)]
 fn op_cloudstate_set_read_only(state: &mut OpState) {
@@ -217,22 +590,275 @@ fn op_cloudstate_set_read_only(state: &mut OpState) {
     cs.set_read_only();
 }
 
+#[instrument(skip(state))]
+#[op2(fast)]
+fn op_cloudstate_set_read_write(state: &mut OpState) {
+    let cs = state.borrow_mut::<TransactionContext>();
+    cs.set_read_write();
+}
+
+#[instrument(skip(state))]
+#[op2(fast)]
+fn op_cloudstate_take_write_attempted(state: &mut OpState) -> bool {
+    let cs = state.borrow_mut::<TransactionContext>();
+    cs.take_write_attempted()
+}
+
+#[instrument(skip(state))]
+#[op2]
+#[serde]
+fn op_cloudstate_request_args(state: &mut OpState) -> Result<serde_json::Value, JsErrorBox> {
+    let args = state
+        .try_borrow::<RequestArgs>()
+        .ok_or_else(|| JsErrorBox::generic("The script wasn't run for a request"))?;
+    serde_json::to_value(args).map_err(js_error)
+}
+
+/// Points the `id` field index at the object, dropping the entry for its
+/// previous `id` if the index still points at this object
+fn update_object_id_index(
+    transaction: &Transaction,
+    key: &CloudstateObjectKey,
+    previous: Option<&CloudstatePrimitiveData>,
+    current: Option<&CloudstatePrimitiveData>,
+) -> Result<(), Error> {
+    // read-only transactions don't persist writes, so there is nothing to index
+    if previous == current || matches!(transaction, Transaction::Read(_)) {
+        return Ok(());
+    }
+
+    let mut index = transaction.open_table(OBJECT_IDS_INDEX)?;
+
+    if let Some(CloudstatePrimitiveData::String(previous)) = previous {
+        let index_key = CloudstateObjectIdIndexKey {
+            id: previous.clone(),
+        };
+        let owner = index.get(&index_key)?;
+        if owner.as_ref() == Some(key) {
+            index.remove(&index_key)?;
+        }
+    }
+
+    if let Some(CloudstatePrimitiveData::String(current)) = current {
+        index.insert(
+            CloudstateObjectIdIndexKey {
+                id: current.clone(),
+            },
+            key,
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Moves the object's entry in the class version index, for when it's written
+/// at a new version of its class
+fn update_class_version_index(
+    transaction: &Transaction,
+    key: &CloudstateObjectKey,
+    previous: Option<&CloudstateObjectData>,
+    current: &CloudstateObjectData,
+) -> Result<(), Error> {
+    let previous = previous.and_then(|object| CloudstateClassVersionKey::new(object, &key.id));
+    let current = CloudstateClassVersionKey::new(current, &key.id);
+    if previous == current || matches!(transaction, Transaction::Read(_)) {
+        return Ok(());
+    }
+
+    let mut index = transaction.open_table(CLASS_VERSIONS_INDEX)?;
+    if let Some(previous) = previous {
+        index.remove(previous)?;
+    }
+    if let Some(current) = current {
+        index.insert(current, ())?;
+    }
+
+    Ok(())
+}
+
+/// `Transaction::try_open_table`, for ops
+fn try_open_table<K: Key + 'static, V: Value + 'static>(
+    transaction: &Transaction,
+    definition: TableDefinition<K, V>,
+) -> Result<Option<CloudstateTable<'_, K, V>>, JsErrorBox> {
+    transaction.try_open_table(definition).map_err(js_error)
+}
+
+/// Moves the object's entries in the field index from the values its indexed
+/// fields had in `previous` to the values they have in `current`
+fn update_field_indexes(
+    transaction: &Transaction,
+    key: &CloudstateObjectKey,
+    class_name: &str,
+    fields: &[String],
+    previous: Option<&CloudstateObjectData>,
+    current: &CloudstateObjectData,
+) -> Result<(), Error> {
+    if matches!(transaction, Transaction::Read(_)) {
+        return Ok(());
+    }
+
+    let mut index = transaction.open_table(FIELD_INDEX_TABLE)?;
+
+    for field in fields {
+        let previous = previous.and_then(|object| object.fields.get(field));
+        let current = current.fields.get(field);
+        if previous == current {
+            continue;
+        }
+
+        if let Some(previous) = previous
+            .and_then(|value| CloudstateFieldIndexKey::new(class_name, field, value, &key.id))
+        {
+            index.remove(&previous)?;
+        }
+
+        if let Some(current) = current
+            .and_then(|value| CloudstateFieldIndexKey::new(class_name, field, value, &key.id))
+        {
+            index.insert(&current, ())?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Rebuilds the class's entries in the field index for the fields it declares
+/// now, so objects written before it declared them can be found too
+fn rebuild_field_index(
+    transaction: &Transaction,
+    class_name: &str,
+    fields: &[String],
+) -> Result<(), Error> {
+    if matches!(transaction, Transaction::Read(_)) {
+        return Ok(());
+    }
+
+    let mut index = transaction.open_table(FIELD_INDEX_TABLE)?;
+    let stale = index
+        .range(CloudstateFieldIndexKey::class(class_name))?
+        .map(|entry| entry.map(|(key, _value)| key))
+        .collect::<Result<Vec<_>, _>>()?;
+    for key in stale {
+        index.remove(&key)?;
+    }
+
+    let objects = transaction.open_table(OBJECTS_TABLE)?;
+    for entry in objects.iter()? {
+        let (key, object) = entry?;
+        if object.data.constructor_name.as_deref() != Some(class_name) {
+            continue;
+        }
+
+        for field in fields {
+            if let Some(index_key) =
+                object.data.fields.get(field).and_then(|value| {
+                    CloudstateFieldIndexKey::new(class_name, field, value, &key.id)
+                })
+            {
+                index.insert(&index_key, ())?;
+            }
+        }
+    }
+
+    transaction
+        .open_table(INDEXED_FIELDS_TABLE)?
+        .insert(class_name, fields.to_vec())?;
+
+    Ok(())
+}
+
+#[instrument(skip(state))]
+#[op2]
+fn op_cloudstate_index_register(
+    state: &mut OpState,
+    #[string] class_name: String,
+    #[serde] fields: Vec<String>,
+) -> Result<(), JsErrorBox> {
+    let cs = state.borrow_mut::<TransactionContext>();
+    Rc::make_mut(&mut cs.indexed_fields).insert(class_name.clone(), fields.clone());
+
+    // read-only requests leave the rebuild to the next request that writes
+    if cs.read_only || cs.field_index_built(&class_name, &fields)? {
+        return Ok(());
+    }
+
+    let transaction = cs.get_or_create_transaction_mut()?;
+    rebuild_field_index(transaction, &class_name, &fields).map_err(js_error)
+}
+
+#[instrument(skip(state))]
+#[op2]
+#[serde]
+fn op_cloudstate_index_query(
+    state: &mut OpState,
+    #[string] class_name: String,
+    #[string] field: String,
+    #[from_v8] value: CloudstatePrimitiveData,
+) -> Result<Vec<String>, JsErrorBox> {
+    let cs = state.borrow_mut::<TransactionContext>();
+    let transaction = cs.get_or_create_transaction_mut()?;
+
+    let Some(range) = CloudstateFieldIndexKey::range(&class_name, &field, &value) else {
+        return Ok(Vec::new());
+    };
+
+    // read-only transactions on databases without indexed objects won't have the table
+    let Some(index) = try_open_table(transaction, FIELD_INDEX_TABLE)? else {
+        return Ok(Vec::new());
+    };
+
+    let mut ids = Vec::new();
+    for entry in index.range(range).map_err(js_error)? {
+        let (key, _value) = entry.map_err(js_error)?;
+        ids.push(key.object_id.to_string());
+    }
+
+    Ok(ids)
+}
+
 #[instrument(skip(state))]
 #[op2]
 fn op_cloudstate_object_set(
     state: &mut OpState,
-    #[string] id: String,
+    #[from_v8] id: CloudstateId,
     #[from_v8] value: CloudstateObjectData,
 ) -> Result<(), JsErrorBox> {
     let cs = state.borrow_mut::<TransactionContext>();
-    let transaction = cs.get_or_create_transaction_mut();
+    let indexed_fields = cs.indexed_fields.clone();
+    let transaction = cs.write_transaction()?;
 
     let mut table = transaction.open_table(OBJECTS_TABLE).unwrap();
     let key = CloudstateObjectKey { id };
 
+    let previous = table.get(&key).map_err(js_error)?.map(|object| object.data);
+    update_object_id_index(
+        transaction,
+        &key,
+        previous.as_ref().and_then(|object| object.fields.get("id")),
+        value.fields.get("id"),
+    )
+    .map_err(js_error)?;
+    update_class_version_index(transaction, &key, previous.as_ref(), &value).map_err(js_error)?;
+    if let Some((class_name, fields)) = value
+        .constructor_name
+        .as_ref()
+        .and_then(|class_name| Some((class_name, indexed_fields.get(class_name)?)))
+    {
+        update_field_indexes(
+            transaction,
+            &key,
+            class_name,
+            fields,
+            previous.as_ref(),
+            &value,
+        )
+        .map_err(js_error)?;
+    }
+
     table
         .insert(&key, CloudstateObjectValue { data: value })
-        .unwrap();
+        .map_err(js_error)?;
 
     Ok(())
 }
@@ -242,18 +868,20 @@ fn op_cloudstate_object_set(
 #[to_v8]
 fn op_cloudstate_object_get(
     state: &mut OpState,
-    #[string] id: String,
+    #[from_v8] id: CloudstateId,
 ) -> Result<CloudstateObjectData, JsErrorBox> {
     let cs = state.borrow_mut::<TransactionContext>();
-    let transaction = cs.get_or_create_transaction_mut();
+    let transaction = cs.get_or_create_transaction_mut()?;
 
     let table =
         info_span!("open_table").in_scope(|| transaction.open_table(OBJECTS_TABLE).unwrap());
 
     let key = CloudstateObjectKey { id };
 
-    let result = info_span!("get").in_scope(|| table.get(key).unwrap());
-    let result = info_span!("map").in_scope(|| result.map(|s| s.value().data));
+    let result = info_span!("get")
+        .in_scope(|| table.get(key))
+        .map_err(js_error)?;
+    let result = info_span!("map").in_scope(|| result.map(|s| s.data));
 
     match result {
         Some(result) => Ok(result),
@@ -264,68 +892,193 @@ fn op_cloudstate_object_get(
     }
 }
 
+/// Ids of the objects of a class written before its current `static version`
+#[instrument(skip(state))]
+#[op2]
+#[serde]
+fn op_cloudstate_object_outdated(
+    state: &mut OpState,
+    #[string] class_name: String,
+    version: u32,
+) -> Result<Vec<String>, JsErrorBox> {
+    let cs = state.borrow_mut::<TransactionContext>();
+    let transaction = cs.get_or_create_transaction_mut()?;
+
+    // read-only transactions on databases without objects of custom classes
+    // won't have the table
+    let Some(index) = try_open_table(transaction, CLASS_VERSIONS_INDEX)? else {
+        return Ok(Vec::new());
+    };
+
+    let mut ids = Vec::new();
+    let range = CloudstateClassVersionKey::outdated(&class_name, version);
+    for entry in index.range(range).map_err(js_error)? {
+        let (key, _value) = entry.map_err(js_error)?;
+        ids.push(key.object_id.to_string());
+    }
+
+    Ok(ids)
+}
+
 #[instrument(skip(state))]
 #[op2]
 fn op_cloudstate_object_set_property(
     state: &mut OpState,
-    #[string] id: String,
+    #[from_v8] id: CloudstateId,
     #[string] property: String,
     #[from_v8] value: CloudstatePrimitiveData,
 ) -> Result<(), JsErrorBox> {
     let cs = state.borrow_mut::<TransactionContext>();
-    let transaction = cs.get_or_create_transaction_mut();
+    let indexed_fields = cs.indexed_fields.clone();
+    let transaction = cs.write_transaction()?;
 
     let mut table = transaction.open_table(OBJECTS_TABLE).unwrap();
     let key = CloudstateObjectKey { id };
 
     let mut object = table
         .get(key.clone())
-        .map_err(|e| JsErrorBox::generic(e.to_string()))?
+        .map_err(js_error)?
         .ok_or(anyhow!("Object not found"))
-        .map_err(|e| JsErrorBox::generic(e.to_string()))?
-        .value();
+        .map_err(js_error)?;
+
+    if property == "id" {
+        update_object_id_index(
+            transaction,
+            &key,
+            object.data.fields.get("id"),
+            Some(&value),
+        )
+        .map_err(js_error)?;
+    }
+
+    let indexed = object
+        .data
+        .constructor_name
+        .as_ref()
+        .and_then(|class_name| {
+            indexed_fields
+                .get(class_name)
+                .filter(|fields| fields.contains(&property))
+                .map(|fields| (class_name.clone(), fields))
+        });
+    let previous = indexed.as_ref().map(|_| object.data.clone());
 
     object.data.fields.insert(property, value);
 
-    table
-        .insert(key, object.clone())
-        .map_err(|e| JsErrorBox::generic(e.to_string()))?;
+    if let Some((class_name, fields)) = indexed {
+        update_field_indexes(
+            transaction,
+            &key,
+            &class_name,
+            fields,
+            previous.as_ref(),
+            &object.data,
+        )
+        .map_err(js_error)?;
+    }
+
+    table.insert(key, object.clone()).map_err(js_error)?;
 
     Ok(())
 }
 
 #[instrument(skip(state))]
-#[op2(fast)]
-fn op_cloudstate_array_reverse(state: &mut OpState, #[string] array_id: String) {
+#[op2]
+fn op_cloudstate_object_delete_property(
+    state: &mut OpState,
+    #[from_v8] id: CloudstateId,
+    #[string] property: String,
+) -> Result<bool, JsErrorBox> {
     let cs = state.borrow_mut::<TransactionContext>();
-    let transaction = cs.get_or_create_transaction_mut();
+    let indexed_fields = cs.indexed_fields.clone();
+    let transaction = cs.write_transaction()?;
 
-    let mut table = transaction.open_table(ARRAYS_TABLE).unwrap();
-    let keys: Vec<CloudstateArrayItemKey> = table
-        .iter()
-        .unwrap()
-        .map(|entry| entry.unwrap().0.value())
-        .filter(|key| key.id == array_id)
-        .collect();
+    let mut table = transaction.open_table(OBJECTS_TABLE).map_err(js_error)?;
+    let key = CloudstateObjectKey { id };
 
-    let mut values = vec![];
-    for key in &keys {
-        let value = table.get(key).unwrap().unwrap().value().data;
-        values.push(value);
-    }
-
-    for (_i, key) in keys.iter().enumerate() {
-        let value = values.pop().unwrap();
-        table
-            .insert(
-                &CloudstateArrayItemKey {
-                    id: key.id.clone(),
-                    index: key.index,
-                },
-                CloudstateArrayItemValue { data: value },
-            )
-            .unwrap();
+    let mut object = table
+        .get(key.clone())
+        .map_err(js_error)?
+        .ok_or(anyhow!("Object not found"))
+        .map_err(js_error)?;
+    let previous = object.data.clone();
+    if object.data.fields.remove(&property).is_none() {
+        return Ok(true);
+    }
+
+    if property == "id" {
+        update_object_id_index(transaction, &key, previous.fields.get("id"), None)
+            .map_err(js_error)?;
     }
+    if let Some((class_name, fields)) = object
+        .data
+        .constructor_name
+        .as_ref()
+        .and_then(|class_name| Some((class_name, indexed_fields.get(class_name)?)))
+        .filter(|(_, fields)| fields.contains(&property))
+    {
+        update_field_indexes(
+            transaction,
+            &key,
+            class_name,
+            fields,
+            Some(&previous),
+            &object.data,
+        )
+        .map_err(js_error)?;
+    }
+
+    table.insert(key, object).map_err(js_error)?;
+
+    Ok(true)
+}
+
+#[instrument(skip(state))]
+#[op2]
+fn op_cloudstate_array_reverse(
+    state: &mut OpState,
+    #[from_v8] array_id: CloudstateId,
+) -> Result<(), JsErrorBox> {
+    let cs = state.borrow_mut::<TransactionContext>();
+    let transaction = cs.write_transaction()?;
+
+    let mut tables = ArrayTables::open(transaction).map_err(js_error)?;
+    let metadata = tables.metadata(&array_id).map_err(js_error)?;
+
+    let mut values: Vec<Option<CloudstatePrimitiveData>> = vec![None; metadata.length as usize];
+    for entry in tables
+        .items
+        .range(metadata.item_range(&array_id))
+        .map_err(js_error)?
+    {
+        let (key, value) = entry.map_err(js_error)?;
+        values[(key.index - metadata.offset) as usize] = Some(value.data);
+    }
+
+    for (i, value) in values.into_iter().rev().enumerate() {
+        let key = CloudstateArrayItemKey {
+            id: array_id,
+            index: metadata.offset + i as i32,
+        };
+        match value {
+            Some(data) => tables
+                .items
+                .insert(&key, CloudstateArrayItemValue { data })
+                .map_err(js_error)?,
+            None => {
+                tables.items.remove(&key).map_err(js_error)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// A new id for an object, map, array, set or blob
+#[op2]
+#[string]
+fn op_cloudstate_new_id() -> String {
+    CloudstateId::random().to_string()
 }
 
 #[instrument(skip(state))]
@@ -333,14 +1086,14 @@ fn op_cloudstate_array_reverse(state: &mut OpState, #[string] array_id: String)
 #[serde]
 fn op_cloudstate_list_roots(state: &mut OpState) -> Result<Vec<String>, JsErrorBox> {
     let cs = state.borrow_mut::<TransactionContext>();
-    let transaction = cs.get_or_create_transaction_mut();
+    let transaction = cs.get_or_create_transaction_mut()?;
 
     let table = transaction.open_table(ROOTS_TABLE).unwrap();
     let mut roots: Vec<String> = Vec::new();
 
-    for entry in table.iter().unwrap() {
-        let (key, _value) = entry.unwrap();
-        roots.push(key.value().alias);
+    for entry in table.iter().map_err(js_error)? {
+        let (key, _value) = entry.map_err(js_error)?;
+        roots.push(key.alias);
     }
 
     Ok(roots)
@@ -351,30 +1104,33 @@ fn op_cloudstate_list_roots(state: &mut OpState) -> Result<Vec<String>, JsErrorB
 #[to_v8]
 fn op_cloudstate_array_pop(
     state: &mut OpState,
-    #[string] array_id: String,
+    #[from_v8] array_id: CloudstateId,
 ) -> Result<CloudstatePrimitiveData, JsErrorBox> {
     let cs = state.borrow_mut::<TransactionContext>();
-    let transaction = cs.get_or_create_transaction_mut();
-
-    let mut table = transaction.open_table(ARRAYS_TABLE).unwrap();
-    let keys: Vec<CloudstateArrayItemKey> = table
-        .iter()
-        .unwrap()
-        .map(|entry| entry.unwrap().0.value())
-        .filter(|key| key.id == array_id)
-        .collect();
-
-    let length = keys.len() as i32;
-    if length == 0 {
+    let transaction = cs.write_transaction()?;
+
+    let mut tables = ArrayTables::open(transaction).map_err(js_error)?;
+    let mut metadata = tables.metadata(&array_id).map_err(js_error)?;
+
+    if metadata.length == 0 {
         return Ok(CloudstatePrimitiveData::Undefined);
     }
 
-    if let Some(key) = keys.iter().find(|key| key.index == length - 1) {
-        let value = table.remove(key).unwrap().unwrap().value().data;
-        Ok(value)
-    } else {
-        Ok(CloudstatePrimitiveData::Undefined)
-    }
+    let key = CloudstateArrayItemKey {
+        id: array_id,
+        index: metadata.offset + metadata.length - 1,
+    };
+    let value = tables
+        .items
+        .remove(&key)
+        .map_err(js_error)?
+        .map(|value| value.data)
+        .unwrap_or(CloudstatePrimitiveData::Undefined);
+
+    metadata.length -= 1;
+    tables.set_metadata(&array_id, metadata).map_err(js_error)?;
+
+    Ok(value)
 }
 
 #[instrument(skip(state))]
@@ -382,39 +1138,63 @@ fn op_cloudstate_array_pop(
 #[to_v8]
 fn op_cloudstate_array_shift(
     state: &mut OpState,
-    #[string] array_id: String,
-) -> CloudstatePrimitiveData {
+    #[from_v8] array_id: CloudstateId,
+) -> Result<CloudstatePrimitiveData, JsErrorBox> {
     let cs = state.borrow_mut::<TransactionContext>();
-    let transaction = cs.get_or_create_transaction_mut();
+    let transaction = cs.write_transaction()?;
 
-    let mut table = transaction.open_table(ARRAYS_TABLE).unwrap();
-    let keys: Vec<CloudstateArrayItemKey> = table
-        .iter()
-        .unwrap()
-        .map(|entry| entry.unwrap().0.value())
-        .filter(|key| key.id == array_id)
-        .collect();
+    let mut tables = ArrayTables::open(transaction).map_err(js_error)?;
+    let mut metadata = tables.metadata(&array_id).map_err(js_error)?;
 
-    let mut return_value = None;
-    for key in keys {
-        let value = table.remove(&key).unwrap();
-        let value = value.unwrap().value().data;
-        if (key.index - 1) >= 0 {
-            table
-                .insert(
-                    &CloudstateArrayItemKey {
-                        id: key.id.clone(),
-                        index: key.index - 1,
-                    },
-                    CloudstateArrayItemValue { data: value },
-                )
-                .unwrap();
-        } else {
-            return_value = Some(value);
-        }
+    if metadata.length == 0 {
+        return Ok(CloudstatePrimitiveData::Undefined);
     }
 
-    return_value.unwrap_or(CloudstatePrimitiveData::Undefined)
+    let key = CloudstateArrayItemKey {
+        id: array_id,
+        index: metadata.offset,
+    };
+    let value = tables
+        .items
+        .remove(&key)
+        .map_err(js_error)?
+        .map(|value| value.data)
+        .unwrap_or(CloudstatePrimitiveData::Undefined);
+
+    metadata.offset += 1;
+    metadata.length -= 1;
+    tables.set_metadata(&array_id, metadata).map_err(js_error)?;
+
+    Ok(value)
+}
+
+#[instrument(skip(state))]
+#[op2]
+fn op_cloudstate_array_unshift(
+    state: &mut OpState,
+    #[from_v8] array_id: CloudstateId,
+    #[from_v8] value: CloudstatePrimitiveData,
+) -> Result<i32, JsErrorBox> {
+    let cs = state.borrow_mut::<TransactionContext>();
+    let transaction = cs.write_transaction()?;
+
+    let mut tables = ArrayTables::open(transaction).map_err(js_error)?;
+    let mut metadata = tables.metadata(&array_id).map_err(js_error)?;
+
+    metadata.offset -= 1;
+    metadata.length += 1;
+
+    let key = CloudstateArrayItemKey {
+        id: array_id,
+        index: metadata.offset,
+    };
+    tables
+        .items
+        .insert(&key, CloudstateArrayItemValue { data: value })
+        .map_err(js_error)?;
+    tables.set_metadata(&array_id, metadata).map_err(js_error)?;
+
+    Ok(metadata.length)
 }
 
 #[instrument(skip(state))]
@@ -423,31 +1203,24 @@ fn op_cloudstate_array_shift(
 fn op_cloudstate_cloudstate_get(
     state: &mut OpState,
     #[string] id: String,
-) -> CloudstatePrimitiveData {
+) -> Result<CloudstatePrimitiveData, JsErrorBox> {
     let cs = state.borrow_mut::<TransactionContext>();
-    let transaction = cs.get_or_create_transaction_mut();
-
-    let table = transaction.open_table(OBJECTS_TABLE).unwrap();
+    let transaction = cs.get_or_create_transaction_mut()?;
 
-    let result = table.iter().unwrap().find(|value| {
-        value
-            .as_ref()
-            .unwrap()
-            .1
-            .value()
-            .data
-            .fields
-            .get("id")
-            .map_or(false, |id_value| {
-                id_value == &CloudstatePrimitiveData::String(id.clone())
-            })
-    });
+    // read-only transactions on databases without objects with ids won't
+    // have the table
+    let Some(index) = try_open_table(transaction, OBJECT_IDS_INDEX)? else {
+        return Ok(CloudstatePrimitiveData::Undefined);
+    };
 
-    match result {
-        Some(result) => CloudstatePrimitiveData::ObjectReference(ObjectReference {
-            id: result.unwrap().0.value().id.clone(),
-        }),
-        None => CloudstatePrimitiveData::Undefined,
+    match index
+        .get(&CloudstateObjectIdIndexKey { id })
+        .map_err(js_error)?
+    {
+        Some(key) => Ok(CloudstatePrimitiveData::ObjectReference(ObjectReference {
+            id: key.id,
+        })),
+        None => Ok(CloudstatePrimitiveData::Undefined),
     }
 }
 
@@ -455,31 +1228,31 @@ fn op_cloudstate_cloudstate_get(
 #[op2]
 fn op_cloudstate_map_set(
     state: &mut OpState,
-    #[string] id: String,
-    #[string] field: String,
+    #[from_v8] id: CloudstateId,
+    #[from_v8] field: CloudstateMapKey,
     #[from_v8] value: CloudstatePrimitiveData,
 ) -> Result<(), JsErrorBox> {
     let cs = state.borrow_mut::<TransactionContext>();
-    let transaction = cs.get_or_create_transaction_mut();
+    let transaction = cs.write_transaction()?;
 
     let mut table = transaction.open_table(MAPS_TABLE).unwrap();
     let key = CloudstateMapFieldKey { id, field };
 
     table
         .insert(&key, CloudstateMapFieldValue { data: value })
-        .unwrap();
+        .map_err(js_error)?;
     Ok(())
 }
 
 #[instrument(skip(state))]
-#[op2(fast)]
+#[op2]
 fn op_cloudstate_map_delete(
     state: &mut OpState,
-    #[string] map_id: String,
-    #[string] key: String,
-) -> bool {
+    #[from_v8] map_id: CloudstateId,
+    #[from_v8] key: CloudstateMapKey,
+) -> Result<bool, JsErrorBox> {
     let cs = state.borrow_mut::<TransactionContext>();
-    let transaction = cs.get_or_create_transaction_mut();
+    let transaction = cs.write_transaction()?;
 
     let mut table = transaction.open_table(MAPS_TABLE).unwrap();
     let key = CloudstateMapFieldKey {
@@ -487,28 +1260,34 @@ fn op_cloudstate_map_delete(
         field: key,
     };
 
-    let was_removed = table.remove(&key).unwrap_or(None).is_some();
+    let was_removed = table.remove(&key).map_err(js_error)?.is_some();
     println!("{:?} was_removed: {}", key.field, was_removed);
-    was_removed
+    Ok(was_removed)
 }
 
 #[instrument(skip(state))]
-#[op2(fast)]
-fn op_cloudstate_map_clear(state: &mut OpState, #[string] map_id: String) {
+#[op2]
+fn op_cloudstate_map_clear(
+    state: &mut OpState,
+    #[from_v8] map_id: CloudstateId,
+) -> Result<(), JsErrorBox> {
     let cs = state.borrow_mut::<TransactionContext>();
-    let transaction = cs.get_or_create_transaction_mut();
+    let transaction = cs.write_transaction()?;
 
     let mut table = transaction.open_table(MAPS_TABLE).unwrap();
-    let keys: Vec<CloudstateMapFieldKey> = table
-        .iter()
-        .unwrap()
-        .map(|entry| entry.unwrap().0.value())
-        .filter(|key| key.id == map_id)
-        .collect();
+    let mut keys: Vec<CloudstateMapFieldKey> = Vec::new();
+    for entry in table.iter().map_err(js_error)? {
+        let (key, _value) = entry.map_err(js_error)?;
+        if key.id == map_id {
+            keys.push(key);
+        }
+    }
 
     for key in keys {
-        table.remove(&key).unwrap();
+        table.remove(&key).map_err(js_error)?;
     }
+
+    Ok(())
 }
 
 #[instrument(skip(state))]
@@ -516,21 +1295,21 @@ fn op_cloudstate_map_clear(state: &mut OpState, #[string] map_id: String) {
 #[to_v8]
 fn op_cloudstate_map_get(
     state: &mut OpState,
-    #[string] id: String,
-    #[string] field: String,
-) -> CloudstatePrimitiveData {
+    #[from_v8] id: CloudstateId,
+    #[from_v8] field: CloudstateMapKey,
+) -> Result<CloudstatePrimitiveData, JsErrorBox> {
     let cs = state.borrow_mut::<TransactionContext>();
-    let transaction = cs.get_or_create_transaction_mut();
+    let transaction = cs.get_or_create_transaction_mut()?;
 
     let table = transaction.open_table(MAPS_TABLE).unwrap();
     let key = CloudstateMapFieldKey { id, field };
 
-    let primitive = match table.get(key).unwrap_or(None) {
-        Some(value) => value.value().data,
+    let primitive = match table.get(key).map_err(js_error)? {
+        Some(value) => value.data,
         None => CloudstatePrimitiveData::Undefined,
     };
 
-    primitive
+    Ok(primitive)
 }
 
 #[instrument(skip(state))]
@@ -538,65 +1317,195 @@ fn op_cloudstate_map_get(
 #[to_v8]
 fn op_cloudstate_map_has(
     state: &mut OpState,
-    #[string] id: String,
-    #[string] field: String,
-) -> CloudstatePrimitiveData {
+    #[from_v8] id: CloudstateId,
+    #[from_v8] field: CloudstateMapKey,
+) -> Result<CloudstatePrimitiveData, JsErrorBox> {
     let cs = state.borrow_mut::<TransactionContext>();
-    let transaction = cs.get_or_create_transaction_mut();
+    let transaction = cs.get_or_create_transaction_mut()?;
 
     let table = transaction.open_table(MAPS_TABLE).unwrap();
     let key = CloudstateMapFieldKey { id, field };
 
-    let primitive = match table.get(key).unwrap_or(None) {
+    let primitive = match table.get(key).map_err(js_error)? {
         Some(_) => CloudstatePrimitiveData::Boolean(true),
         None => CloudstatePrimitiveData::Boolean(false),
     };
 
-    primitive
+    Ok(primitive)
+}
+
+#[instrument(skip(state))]
+#[op2]
+fn op_cloudstate_set_add(
+    state: &mut OpState,
+    #[from_v8] id: CloudstateId,
+    #[from_v8] value: CloudstatePrimitiveData,
+) -> Result<(), JsErrorBox> {
+    let cs = state.borrow_mut::<TransactionContext>();
+    let transaction = cs.write_transaction()?;
+
+    let mut table = transaction.open_table(SETS_TABLE).map_err(js_error)?;
+    let key = CloudstateSetItemKey::new(id, &value);
+
+    table
+        .insert(&key, CloudstateSetItemValue { data: value })
+        .map_err(js_error)?;
+    Ok(())
+}
+
+#[instrument(skip(state))]
+#[op2]
+fn op_cloudstate_set_has(
+    state: &mut OpState,
+    #[from_v8] id: CloudstateId,
+    #[from_v8] value: CloudstatePrimitiveData,
+) -> Result<bool, JsErrorBox> {
+    let cs = state.borrow_mut::<TransactionContext>();
+    let transaction = cs.get_or_create_transaction_mut()?;
+
+    let Some(table) = try_open_table(transaction, SETS_TABLE)? else {
+        return Ok(false);
+    };
+    let key = CloudstateSetItemKey::new(id, &value);
+
+    Ok(table.get(key).map_err(js_error)?.is_some())
+}
+
+#[instrument(skip(state))]
+#[op2]
+fn op_cloudstate_set_delete(
+    state: &mut OpState,
+    #[from_v8] id: CloudstateId,
+    #[from_v8] value: CloudstatePrimitiveData,
+) -> Result<bool, JsErrorBox> {
+    let cs = state.borrow_mut::<TransactionContext>();
+    let transaction = cs.write_transaction()?;
+
+    let mut table = transaction.open_table(SETS_TABLE).map_err(js_error)?;
+    let key = CloudstateSetItemKey::new(id, &value);
+
+    Ok(table.remove(&key).map_err(js_error)?.is_some())
+}
+
+#[instrument(skip(state))]
+#[op2]
+fn op_cloudstate_set_clear(
+    state: &mut OpState,
+    #[from_v8] set_id: CloudstateId,
+) -> Result<(), JsErrorBox> {
+    let cs = state.borrow_mut::<TransactionContext>();
+    let transaction = cs.write_transaction()?;
+
+    let mut table = transaction.open_table(SETS_TABLE).map_err(js_error)?;
+    let keys = table
+        .range(CloudstateSetItemKey::range(&set_id))
+        .map_err(js_error)?
+        .map(|entry| entry.map(|(key, _value)| key))
+        .collect::<Result<Vec<CloudstateSetItemKey>, _>>()
+        .map_err(js_error)?;
+
+    for key in keys {
+        table.remove(&key).map_err(js_error)?;
+    }
+
+    Ok(())
+}
+
+#[instrument(skip(state))]
+#[op2]
+fn op_cloudstate_set_size(
+    state: &mut OpState,
+    #[from_v8] set_id: CloudstateId,
+) -> Result<u32, JsErrorBox> {
+    let cs = state.borrow_mut::<TransactionContext>();
+    let transaction = cs.get_or_create_transaction_mut()?;
+
+    let Some(table) = try_open_table(transaction, SETS_TABLE)? else {
+        return Ok(0);
+    };
+
+    let mut size = 0;
+    for entry in table
+        .range(CloudstateSetItemKey::range(&set_id))
+        .map_err(js_error)?
+    {
+        entry.map_err(js_error)?;
+        size += 1;
+    }
+
+    Ok(size)
+}
+
+#[instrument(skip(state))]
+#[op2]
+#[to_v8]
+fn op_cloudstate_set_values(
+    state: &mut OpState,
+    #[from_v8] set_id: CloudstateId,
+) -> Result<CloudstatePrimitiveDataVec, JsErrorBox> {
+    let cs = state.borrow_mut::<TransactionContext>();
+    let transaction = cs.get_or_create_transaction_mut()?;
+
+    let Some(table) = try_open_table(transaction, SETS_TABLE)? else {
+        return Ok(CloudstatePrimitiveDataVec { data: Vec::new() });
+    };
+    let mut values = vec![];
+
+    for entry in table
+        .range(CloudstateSetItemKey::range(&set_id))
+        .map_err(js_error)?
+    {
+        let (_key, value) = entry.map_err(js_error)?;
+        values.push(value.data);
+    }
+
+    Ok(values.into())
 }
 
 #[instrument(skip(state))]
 #[op2]
 fn op_cloudstate_array_set(
     state: &mut OpState,
-    #[string] id: String,
+    #[from_v8] id: CloudstateId,
     index: i32,
     #[from_v8] value: CloudstatePrimitiveData,
 ) -> Result<(), JsErrorBox> {
     let cs = state.borrow_mut::<TransactionContext>();
-    let transaction = cs.get_or_create_transaction_mut();
+    let transaction = cs.write_transaction()?;
 
-    let mut table = transaction.open_table(ARRAYS_TABLE).unwrap();
-    let key = CloudstateArrayItemKey { id, index };
+    let mut tables = ArrayTables::open(transaction).map_err(js_error)?;
+    let mut metadata = tables.metadata(&id).map_err(js_error)?;
 
-    table
+    let key = CloudstateArrayItemKey {
+        id,
+        index: metadata.offset + index,
+    };
+
+    tables
+        .items
         .insert(&key, CloudstateArrayItemValue { data: value })
-        .unwrap();
+        .map_err(js_error)?;
+
+    if index >= metadata.length {
+        metadata.length = index + 1;
+        tables.set_metadata(&id, metadata).map_err(js_error)?;
+    }
     Ok(())
 }
 
 #[instrument(skip(state))]
-#[op2(fast)]
-fn op_cloudstate_array_length(state: &mut OpState, #[string] id: String) -> i32 {
+#[op2]
+fn op_cloudstate_array_length(
+    state: &mut OpState,
+    #[from_v8] id: CloudstateId,
+) -> Result<i32, JsErrorBox> {
     let cs = state.borrow_mut::<TransactionContext>();
-    let transaction = cs.get_or_create_transaction_mut();
-
-    let table = transaction.open_table(ARRAYS_TABLE).unwrap();
-
-    let count = table
-        .range(
-            CloudstateArrayItemKey {
-                id: id.clone(),
-                index: 0,
-            }..CloudstateArrayItemKey {
-                id: id.clone() + "\u{0}",
-                index: 0,
-            },
-        )
-        .unwrap()
-        .count();
+    let transaction = cs.get_or_create_transaction_mut()?;
+
+    let mut tables = ArrayTables::open(transaction).map_err(js_error)?;
+    let metadata = tables.metadata(&id).map_err(js_error)?;
 
-    count as i32
+    Ok(metadata.length)
 }
 
 #[instrument(skip(state))]
@@ -604,40 +1513,50 @@ fn op_cloudstate_array_length(state: &mut OpState, #[string] id: String) -> i32
 #[to_v8]
 fn op_cloudstate_array_get(
     state: &mut OpState,
-    #[string] id: String,
+    #[from_v8] id: CloudstateId,
     index: i32,
-) -> CloudstatePrimitiveData {
+) -> Result<CloudstatePrimitiveData, JsErrorBox> {
     let cs = state.borrow_mut::<TransactionContext>();
-    let transaction = cs.get_or_create_transaction_mut();
+    let transaction = cs.get_or_create_transaction_mut()?;
 
-    let table = transaction.open_table(ARRAYS_TABLE).unwrap();
-    let key = CloudstateArrayItemKey { id, index };
+    let mut tables = ArrayTables::open(transaction).map_err(js_error)?;
+    let metadata = tables.metadata(&id).map_err(js_error)?;
+
+    if index < 0 || index >= metadata.length {
+        return Ok(CloudstatePrimitiveData::Undefined);
+    }
+
+    let key = CloudstateArrayItemKey {
+        id,
+        index: metadata.offset + index,
+    };
 
-    let result = table.get(key).unwrap();
-    let result = result.map(|s| s.value().data);
+    let result = tables.items.get(key).map_err(js_error)?;
+    let result = result.map(|s| s.data);
 
     match result {
-        Some(result) => result,
-        None => CloudstatePrimitiveData::Undefined,
+        Some(result) => Ok(result),
+        None => Ok(CloudstatePrimitiveData::Undefined),
     }
 }
 
 #[instrument(skip(state))]
-#[op2(fast)]
+#[op2]
 fn op_cloudstate_map_size(
     state: &mut OpState,
-    #[string] map_id: String,
+    #[from_v8] map_id: CloudstateId,
 ) -> Result<i32, JsErrorBox> {
     let cs = state.borrow_mut::<TransactionContext>();
-    let transaction = cs.get_or_create_transaction_mut();
+    let transaction = cs.get_or_create_transaction_mut()?;
 
     let table = transaction.open_table(MAPS_TABLE).unwrap();
-    let count = table
-        .iter()
-        .unwrap()
-        .map(|entry| entry.unwrap())
-        .filter(|(key, _value)| key.value().id == map_id)
-        .count();
+    let mut count = 0;
+    for entry in table.iter().map_err(js_error)? {
+        let (key, _value) = entry.map_err(js_error)?;
+        if key.id == map_id {
+            count += 1;
+        }
+    }
 
     Ok(count as i32)
 }
@@ -650,30 +1569,32 @@ fn op_cloudstate_object_root_get(
     #[string] alias: String,
 ) -> Result<Option<String>, JsErrorBox> {
     let cs = state.borrow_mut::<TransactionContext>();
-    let transaction = cs.get_or_create_transaction_mut();
+    let transaction = cs.get_or_create_transaction_mut()?;
 
     let table = transaction.open_table(ROOTS_TABLE).unwrap();
     let key = CloudstateRootKey { alias };
 
-    let result = table.get(key).unwrap();
-    let result = result.map(|s| s.value().id);
+    let result = table.get(key).map_err(js_error)?;
+    let result = result.map(|s| s.id.to_string());
     Ok(result)
 }
 
 #[instrument(skip(state))]
-#[op2(fast)]
+#[op2]
 fn op_cloudstate_object_root_set(
     state: &mut OpState,
     #[string] alias: String,
-    #[string] id: String,
+    #[from_v8] id: CloudstateId,
 ) -> Result<(), JsErrorBox> {
     let cs = state.borrow_mut::<TransactionContext>();
-    let transaction = cs.get_or_create_transaction_mut();
+    let transaction = cs.write_transaction()?;
 
     let mut table = transaction.open_table(ROOTS_TABLE).unwrap();
     let key = CloudstateRootKey { alias };
 
-    table.insert(&key, CloudstateRootValue { id }).unwrap();
+    table
+        .insert(&key, CloudstateRootValue { id })
+        .map_err(js_error)?;
     Ok(())
 }
 
@@ -682,31 +1603,41 @@ fn op_cloudstate_object_root_set(
 fn op_cloudstate_commit_transaction(state: &mut OpState) -> Result<(), JsErrorBox> {
     event!(tracing::Level::DEBUG, "Committing transaction");
     let cs = state.borrow_mut::<TransactionContext>();
-    cs.commit_transaction();
+    cs.commit_transaction().map_err(js_error)?;
     debug!("Transaction committed");
     Ok(())
 }
 
+#[instrument(skip(state))]
+#[op2(fast)]
+fn op_cloudstate_abort_transaction(state: &mut OpState) -> Result<(), JsErrorBox> {
+    event!(tracing::Level::DEBUG, "Aborting transaction");
+    let cs = state.borrow_mut::<TransactionContext>();
+    cs.abort_transaction();
+    debug!("Transaction aborted");
+    Ok(())
+}
+
 #[instrument(skip(state))]
 #[op2]
 #[to_v8]
 fn op_cloudstate_map_values(
     state: &mut OpState,
-    #[string] map_id: String,
+    #[from_v8] map_id: CloudstateId,
 ) -> Result<CloudstatePrimitiveDataVec, JsErrorBox> {
     let cs = state.borrow_mut::<TransactionContext>();
-    let transaction = cs.get_or_create_transaction_mut();
+    let transaction = cs.get_or_create_transaction_mut()?;
 
     let table = transaction.open_table(MAPS_TABLE).unwrap();
     let mut values = vec![];
 
-    for entry in table.iter().unwrap() {
-        let (key, value) = entry.unwrap();
-        event!(tracing::Level::DEBUG, "Key: {:?}", key.value());
-        event!(tracing::Level::DEBUG, "Value: {:?}", value.value());
+    for entry in table.iter().map_err(js_error)? {
+        let (key, value) = entry.map_err(js_error)?;
+        event!(tracing::Level::DEBUG, "Key: {:?}", key);
+        event!(tracing::Level::DEBUG, "Value: {:?}", value);
 
-        if key.value().id == map_id {
-            values.push(value.value().data);
+        if key.id == map_id {
+            values.push(value.data);
         }
     }
 
@@ -718,57 +1649,91 @@ fn op_cloudstate_map_values(
 #[to_v8]
 fn op_cloudstate_map_keys(
     state: &mut OpState,
-    #[string] map_id: String,
-) -> Result<CloudstatePrimitiveDataVec, JsErrorBox> {
+    #[from_v8] map_id: CloudstateId,
+) -> Result<CloudstateMapKeyVec, JsErrorBox> {
+    let cs = state.borrow_mut::<TransactionContext>();
+    let transaction = cs.get_or_create_transaction_mut()?;
+
+    let table = transaction.open_table(MAPS_TABLE).unwrap();
+    let mut keys = vec![];
+
+    for entry in table.iter().map_err(js_error)? {
+        let (key, _value) = entry.map_err(js_error)?;
+        if key.id == map_id {
+            keys.push(key.field);
+        }
+    }
+
+    Ok(keys.into())
+}
+
+#[instrument(skip(state))]
+#[op2]
+#[to_v8]
+fn op_cloudstate_map_entries(
+    state: &mut OpState,
+    #[from_v8] map_id: CloudstateId,
+) -> Result<CloudstateEntriesVec, JsErrorBox> {
+    event!(tracing::Level::DEBUG, "Getting map entries");
     let cs = state.borrow_mut::<TransactionContext>();
-    let transaction = cs.get_or_create_transaction_mut();
+    let transaction = cs.get_or_create_transaction_mut()?;
 
     let table = transaction.open_table(MAPS_TABLE).unwrap();
-    let mut keys = vec![];
+    let mut entries: Vec<(CloudstateMapKey, CloudstatePrimitiveData)> = vec![];
 
-    for entry in table.iter().unwrap() {
-        let (key, _value) = entry.unwrap();
-        if key.value().id == map_id {
-            //TODO: UPDATE WHEN MAP KEYS ARE MOVED TO ANY PRIMITIVE
-            keys.push(CloudstatePrimitiveData::String(key.value().field));
+    for entry in table.iter().map_err(js_error)? {
+        let (key, value) = entry.map_err(js_error)?;
+        if key.id == map_id {
+            entries.push((key.field, value.data));
         }
     }
 
-    Ok(keys.into())
+    Ok(CloudstateEntriesVec::from(entries))
 }
 
+/// Reads the entries of a map within a range of fields, so large maps can be
+/// paged through without loading every entry
 #[instrument(skip(state))]
 #[op2]
 #[to_v8]
-fn op_cloudstate_map_entries(
+fn op_cloudstate_map_range(
     state: &mut OpState,
-    #[string] map_id: String,
+    #[from_v8] map_id: CloudstateId,
+    #[from_v8] start: CloudstateMapKeyBound,
+    #[from_v8] end: CloudstateMapKeyBound,
+    #[serde] query: CloudstateMapRangeQuery,
 ) -> Result<CloudstateEntriesVec, JsErrorBox> {
-    event!(tracing::Level::DEBUG, "Getting map entries");
     let cs = state.borrow_mut::<TransactionContext>();
-    let transaction = cs.get_or_create_transaction_mut();
+    let transaction = cs.get_or_create_transaction_mut()?;
 
     let table = transaction.open_table(MAPS_TABLE).unwrap();
-    let mut entries: Vec<Vec<CloudstatePrimitiveData>> = vec![];
-
-    for entry in table.iter().unwrap() {
-        let (key, value) = entry.unwrap();
-        if key.value().id == map_id {
-            entries.push(vec![
-                CloudstatePrimitiveData::String(key.value().field),
-                value.value().data,
-            ]);
-        }
+    let mut entries: Vec<(CloudstateMapKey, CloudstatePrimitiveData)> = vec![];
+
+    let range = query.key_range(&map_id, start.0, end.0);
+    if range.is_empty() {
+        return Ok(CloudstateEntriesVec::from(entries));
+    }
+
+    let range = table.range(range).map_err(js_error)?;
+    let range: Box<dyn Iterator<Item = _>> = if query.reverse {
+        Box::new(range.rev())
+    } else {
+        Box::new(range)
+    };
+
+    for entry in range.take(query.limit.unwrap_or(usize::MAX)) {
+        let (key, value) = entry.map_err(js_error)?;
+        entries.push((key.field, value.data));
     }
 
     Ok(CloudstateEntriesVec::from(entries))
 }
 
 #[instrument(skip(state))]
-#[op2(fast)]
+#[op2]
 fn op_cloudstate_blob_set(
     state: Rc<RefCell<OpState>>,
-    #[string] blob_id: String,
+    #[from_v8] blob_id: CloudstateId,
     #[string] blob_type: String,
     #[arraybuffer] blob_data: &[u8], // #[buffer] blob_data: JsBuffer,
 ) -> Result<(), deno_error::JsErrorBox> {
@@ -776,7 +1741,7 @@ fn op_cloudstate_blob_set(
 
     let transaction_context = state.borrow_mut::<TransactionContext>();
     let storage = transaction_context.blob_storage().clone();
-    let transaction = transaction_context.get_or_create_transaction_mut();
+    let transaction = transaction_context.write_transaction()?;
 
     let data = blob_data.to_vec();
 
@@ -787,7 +1752,7 @@ fn op_cloudstate_blob_set(
             CloudstateBlobValue { data },
             CloudstateBlobMetadata { type_: blob_type },
         )
-        .map_err(|e| JsErrorBox::generic(e.to_string()))?;
+        .map_err(js_error)?;
 
     Ok(())
 }
@@ -797,7 +1762,7 @@ fn op_cloudstate_blob_set(
 #[arraybuffer]
 fn op_cloudstate_blob_slice(
     state: Rc<RefCell<OpState>>,
-    #[string] blob_id: String,
+    #[from_v8] blob_id: CloudstateId,
     start: Option<i32>,
     end: Option<i32>,
 ) -> Result<Vec<u8>, JsErrorBox> {
@@ -817,7 +1782,7 @@ fn op_cloudstate_blob_slice(
 #[arraybuffer]
 fn op_cloudstate_blob_get_array_buffer(
     state: &mut OpState,
-    #[string] blob_id: String,
+    #[from_v8] blob_id: CloudstateId,
 ) -> Result<Vec<u8>, JsErrorBox> {
     let blob_store = state.borrow_mut::<TransactionContext>().blob_storage();
     let result = blob_store
@@ -833,7 +1798,7 @@ fn op_cloudstate_blob_get_array_buffer(
 #[buffer]
 fn op_cloudstate_blob_get_uint8array(
     state: &mut OpState,
-    #[string] blob_id: String,
+    #[from_v8] blob_id: CloudstateId,
 ) -> Result<Vec<u8>, JsErrorBox> {
     let blob_store = state.borrow_mut::<TransactionContext>().blob_storage();
     let result = blob_store
@@ -849,7 +1814,7 @@ fn op_cloudstate_blob_get_uint8array(
 #[string]
 fn op_cloudstate_blob_get_text(
     state: &mut OpState,
-    #[string] blob_id: String,
+    #[from_v8] blob_id: CloudstateId,
 ) -> Result<String, JsErrorBox> {
     let blob_store = state.borrow_mut::<TransactionContext>().blob_storage();
     let result = blob_store
@@ -860,10 +1825,10 @@ fn op_cloudstate_blob_get_text(
 }
 
 #[instrument(skip(state))]
-#[op2(fast)]
+#[op2]
 fn op_cloudstate_blob_get_size(
     state: &mut OpState,
-    #[string] blob_id: String,
+    #[from_v8] blob_id: CloudstateId,
 ) -> Result<i32, JsErrorBox> {
     let blob_store = state.borrow_mut::<TransactionContext>().blob_storage();
     let result = blob_store
@@ -877,13 +1842,13 @@ fn op_cloudstate_blob_get_size(
 #[string]
 fn op_cloudstate_blob_get_type(
     state: Rc<RefCell<OpState>>,
-    #[string] blob_id: String,
+    #[from_v8] blob_id: CloudstateId,
 ) -> Result<String, JsErrorBox> {
     let mut state = RefCell::borrow_mut(&state);
 
     let transaction_context = state.borrow_mut::<TransactionContext>();
     let storage = transaction_context.blob_storage().clone();
-    let transaction = transaction_context.get_or_create_transaction_mut();
+    let transaction = transaction_context.get_or_create_transaction_mut()?;
 
     match storage.get_blob_metadata(&blob_id, transaction) {
         Ok(metadata) => Ok(metadata.type_),
@@ -924,53 +1889,148 @@ pub fn op_tracing_span_finish(state: &mut OpState) {
 
 #[derive(Debug, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Clone)]
 pub struct CloudstateBlobKey {
-    pub id: String,
+    pub id: CloudstateId,
 }
 
-impl From<&str> for CloudstateBlobKey {
-    fn from(id: &str) -> Self {
-        Self { id: id.to_string() }
+impl From<CloudstateId> for CloudstateBlobKey {
+    fn from(id: CloudstateId) -> Self {
+        Self { id }
     }
 }
 
-impl From<String> for CloudstateBlobKey {
-    fn from(id: String) -> Self {
-        Self { id }
+/// Admits one writer at a time. Writers queue here instead of inside
+/// `begin_write`, where they would hold the database lock and stall every
+/// reader behind them.
+#[derive(Debug, Default)]
+struct WriterGate {
+    busy: Mutex<bool>,
+    released: Condvar,
+}
+
+/// Lets its holder open a write transaction, until it is dropped
+#[derive(Debug)]
+pub struct WritePermit {
+    gate: Arc<WriterGate>,
+}
+
+impl Drop for WritePermit {
+    fn drop(&mut self) {
+        *self.gate.busy.lock().unwrap() = false;
+        self.gate.released.notify_one();
     }
 }
 
 #[derive(Clone, Debug)]
 pub struct ReDBCloudstate {
     db: Arc<Mutex<Database>>,
+    writer: Arc<WriterGate>,
 }
 
 impl ReDBCloudstate {
-    pub fn new(db: Arc<Mutex<Database>>) -> Self {
-        Self { db }
+    /// Wraps a database in the current format version, which new databases
+    /// are stamped with. Databases in any other version are refused, older
+    /// ones have to be migrated first.
+    pub fn new(db: Arc<Mutex<Database>>) -> Result<Self, Error> {
+        check_format_version(&db.lock().unwrap())?;
+        Ok(Self {
+            db,
+            writer: Arc::new(WriterGate::default()),
+        })
     }
 
     pub fn get_database_mut(&self) -> MutexGuard<Database> {
         self.db.lock().unwrap()
     }
 
+    /// Waits until no other write transaction is open. Take this before
+    /// `get_database_mut` when calling `begin_write`.
+    pub fn acquire_writer(&self) -> WritePermit {
+        let mut busy = self.writer.busy.lock().unwrap();
+        while *busy {
+            busy = self.writer.released.wait(busy).unwrap();
+        }
+        *busy = true;
+
+        WritePermit {
+            gate: self.writer.clone(),
+        }
+    }
+
+    /// Copies every table to a new database at `path`, and blob payloads too
+    /// when `blobs` is given. Returns the change sequence the backup covers up
+    /// to, which later incremental backups can start from. The change log is
+    /// left as it is, see `compact_change_log`.
     pub fn backup<'a>(
         &self,
         path: impl AsRef<Path>,
+        blobs: Option<&BlobBackup>,
         progress_callback: &mut Option<Box<dyn FnMut(BackupProgress) + 'a>>,
-    ) -> Result<(), Error> {
-        let db = self.get_database_mut();
+    ) -> Result<u64, Error> {
+        // the snapshot stays consistent after the lock is released, so other
+        // transactions can run while it's copied
+        let read = self.get_database_mut().begin_read()?;
         let backup_db = Database::create(path)?;
-        let read = db.begin_read().unwrap();
-        let write = backup_db.begin_write().unwrap();
+        let write = backup_db.begin_write()?;
 
-        backup_all_tables(&read, &write, progress_callback)?;
+        let sequence = backup_all_tables(&read, &write, blobs, progress_callback)?;
 
-        read.close().unwrap();
-        write.commit().unwrap();
+        read.close()?;
+        write.commit()?;
 
-        Ok(())
+        Ok(sequence)
+    }
 
-        //db.begin_read();
+    /// Drops the removals logged before `until`, the sequence a full backup
+    /// returned, from the change log, returning how many were dropped.
+    /// Incremental backups can then only start from that backup or a later
+    /// one, so it's never done as part of a backup or snapshot.
+    pub fn compact_change_log(&self, until: u64) -> Result<u64, Error> {
+        let _permit = self.acquire_writer();
+        let write = self.get_database_mut().begin_write()?;
+        let compacted = compact_changes(&write, until)?;
+        write.commit()?;
+        debug!("Compacted {} removed rows out of the change log", compacted);
+
+        Ok(compacted)
+    }
+
+    /// Copies only the rows changed after sequence `since` to a new database
+    /// at `path`, which `restore_backup` can replay onto the backup that
+    /// covers up to `since`. Returns the sequence this backup covers up to.
+    pub fn backup_incremental<'a>(
+        &self,
+        path: impl AsRef<Path>,
+        since: u64,
+        blobs: Option<&BlobBackup>,
+        progress_callback: &mut Option<Box<dyn FnMut(BackupProgress) + 'a>>,
+    ) -> Result<u64, Error> {
+        let read = self.get_database_mut().begin_read()?;
+        let backup_db = Database::create(path)?;
+        let write = backup_db.begin_write()?;
+
+        let sequence = backup_changed_rows(&read, &write, since, blobs, progress_callback)?;
+
+        read.close()?;
+        write.commit()?;
+
+        Ok(sequence)
+    }
+
+    /// Backs the database up to a new timestamped file in `dir`, which can be
+    /// found with `list_snapshots` and restored with `restore_backup`. Like
+    /// any full backup it leaves the change log alone, so incremental backups
+    /// can keep starting from a snapshot after newer ones were taken.
+    pub fn snapshot(&self, dir: impl AsRef<Path>) -> Result<PathBuf, Error> {
+        let dir = dir.as_ref();
+        std::fs::create_dir_all(dir)?;
+
+        let path = snapshot_path(dir, Utc::now());
+        // written under a temporary name so a partial snapshot is never listed
+        let partial = path.with_extension("partial");
+        self.backup(&partial, None, &mut None)?;
+        std::fs::rename(&partial, &path)?;
+
+        Ok(path)
     }
 }
 
@@ -981,11 +2041,17 @@ pub struct CloudstateRootKey {
 
 #[derive(Debug, Serialize, Deserialize, PartialEq)]
 pub struct CloudstateRootValue {
-    pub id: String,
+    pub id: CloudstateId,
 }
 
 #[derive(Debug, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Clone)]
 pub struct CloudstateObjectKey {
+    pub id: CloudstateId,
+}
+
+/// Key of the index from an object's `id` field to the object holding it
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Clone)]
+pub struct CloudstateObjectIdIndexKey {
     pub id: String,
 }
 
@@ -998,6 +2064,9 @@ pub struct CloudstateObjectValue {
 pub struct CloudstateObjectData {
     pub fields: HashMap<String, CloudstatePrimitiveData>,
     pub constructor_name: Option<String>,
+    /// The `static version` of the class when the object was written, 0 for
+    /// classes without one
+    pub version: u32,
 }
 
 impl ToV8<'_> for CloudstateObjectData {
@@ -1018,6 +2087,12 @@ impl ToV8<'_> for CloudstateObjectData {
                 v8::String::new(scope, "__cloudstate__constructorName").unwrap();
             let constructor_name = v8::String::new(scope, constructor_name).unwrap();
             object.set(scope, constructor_name_key.into(), constructor_name.into());
+
+            if self.version != 0 {
+                let version_key = v8::String::new(scope, "__cloudstate__version").unwrap();
+                let version = v8::Integer::new_from_unsigned(scope, self.version);
+                object.set(scope, version_key.into(), version.into());
+            }
         }
 
         Ok(v8::Local::<v8::Value>::from(object))
@@ -1053,23 +2128,33 @@ impl FromV8<'_> for CloudstateObjectData {
 
         let constructor_key = v8_string_key!(scope, "constructor");
         let name_key = v8_string_key!(scope, "name");
+        let version_key = v8_string_key!(scope, "version");
+        let constructor = v8::Local::<v8::Object>::try_from(
+            object
+                // .get_constructor_name()
+                .get(scope, constructor_key)
+                .unwrap(),
+        )
+        .unwrap();
+
+        let version = constructor
+            .get(scope, version_key)
+            .filter(|version| version.is_number())
+            .and_then(|version| version.uint32_value(scope))
+            .unwrap_or(0);
+
         Ok(CloudstateObjectData {
             fields,
-            constructor_name: match v8::Local::<v8::Object>::try_from(
-                object
-                    // .get_constructor_name()
-                    .get(scope, constructor_key)
-                    .unwrap(),
-            )
-            .unwrap()
-            .get(scope, name_key)
-            .unwrap()
-            .to_rust_string_lossy(scope)
-            .as_str()
+            constructor_name: match constructor
+                .get(scope, name_key)
+                .unwrap()
+                .to_rust_string_lossy(scope)
+                .as_str()
             {
                 "Object" => None,
                 constructor_name => Some(constructor_name.to_string()),
             },
+            version,
         })
     }
 
@@ -1089,13 +2174,14 @@ pub enum CloudstatePrimitiveData {
     Url(Url),
     // Error(JsError),
     ObjectReference(ObjectReference),
-    MapReference(String),
-    ArrayReference(String),
+    MapReference(CloudstateId),
+    ArrayReference(CloudstateId),
+    SetReference(CloudstateId),
 }
 
 #[derive(Debug, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Clone)]
 pub struct ObjectReference {
-    pub id: String,
+    pub id: CloudstateId,
 }
 
 impl ObjectReference {
@@ -1104,7 +2190,7 @@ impl ObjectReference {
 
 #[derive(Debug, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Clone)]
 pub struct Blob {
-    pub id: String,
+    pub id: CloudstateId,
 }
 
 struct CloudstatePrimitiveDataVec {
@@ -1117,12 +2203,38 @@ impl From<Vec<CloudstatePrimitiveData>> for CloudstatePrimitiveDataVec {
     }
 }
 
+struct CloudstateMapKeyVec {
+    data: Vec<CloudstateMapKey>,
+}
+
+impl From<Vec<CloudstateMapKey>> for CloudstateMapKeyVec {
+    fn from(data: Vec<CloudstateMapKey>) -> Self {
+        CloudstateMapKeyVec { data }
+    }
+}
+
+impl ToV8<'_> for CloudstateMapKeyVec {
+    type Error = JsErrorBox;
+
+    fn to_v8<'a>(
+        self,
+        scope: &mut v8::HandleScope<'a>,
+    ) -> Result<v8::Local<'a, v8::Value>, Self::Error> {
+        let array = v8::Array::new(scope, self.data.len() as i32);
+        for (i, key) in self.data.into_iter().enumerate() {
+            let key = key.to_v8(scope)?;
+            array.set_index(scope, i as u32, key);
+        }
+        Ok(array.into())
+    }
+}
+
 struct CloudstateEntriesVec {
-    data: Vec<Vec<CloudstatePrimitiveData>>,
+    data: Vec<(CloudstateMapKey, CloudstatePrimitiveData)>,
 }
 
-impl From<Vec<Vec<CloudstatePrimitiveData>>> for CloudstateEntriesVec {
-    fn from(data: Vec<Vec<CloudstatePrimitiveData>>) -> Self {
+impl From<Vec<(CloudstateMapKey, CloudstatePrimitiveData)>> for CloudstateEntriesVec {
+    fn from(data: Vec<(CloudstateMapKey, CloudstatePrimitiveData)>) -> Self {
         CloudstateEntriesVec { data }
     }
 }
@@ -1133,12 +2245,9 @@ impl ToV8<'_> for CloudstateEntriesVec {
         scope: &mut v8::HandleScope<'a>,
     ) -> Result<v8::Local<'a, v8::Value>, JsErrorBox> {
         let array = v8::Array::new(scope, self.data.len() as i32);
-        for (i, entry) in self.data.iter().enumerate() {
-            let key = entry[0].clone();
-            let value = entry[1].clone();
-
-            let key = key.clone().to_v8(scope).unwrap();
-            let value = value.clone().to_v8(scope).unwrap();
+        for (i, (key, value)) in self.data.into_iter().enumerate() {
+            let key = key.to_v8(scope).unwrap();
+            let value = value.to_v8(scope).unwrap();
 
             let entry = v8::Array::new(scope, 2);
             entry.set_index(scope, 0, key);
@@ -1214,7 +2323,7 @@ impl ToV8<'_> for CloudstatePrimitiveData {
                 let object = v8::Object::new(scope);
                 object.set_prototype(scope, prototype).unwrap();
                 let key = v8::String::new(scope, "blobId").unwrap().into();
-                let value = v8::String::new(scope, &value.id).unwrap().into();
+                let value = value.id.to_v8(scope)?;
                 object.set(scope, key, value);
                 object.into()
             }
@@ -1241,7 +2350,7 @@ impl ToV8<'_> for CloudstatePrimitiveData {
                 let object = v8::Object::new(scope);
                 object.set_prototype(scope, prototype).unwrap();
                 let key = v8::String::new(scope, "objectId").unwrap().into();
-                let object_value = v8::String::new(scope, &value.id).unwrap().into();
+                let object_value = value.id.to_v8(scope)?;
 
                 object.set(scope, key, object_value);
 
@@ -1269,7 +2378,7 @@ impl ToV8<'_> for CloudstatePrimitiveData {
                 let object = v8::Object::new(scope);
                 object.set_prototype(scope, prototype).unwrap();
                 let key = v8::String::new(scope, "objectId").unwrap().into();
-                let value = v8::String::new(scope, &value).unwrap().into();
+                let value = value.to_v8(scope)?;
                 object.set(scope, key, value);
                 object.into()
             }
@@ -1295,7 +2404,33 @@ impl ToV8<'_> for CloudstatePrimitiveData {
                 let object = v8::Object::new(scope);
                 object.set_prototype(scope, prototype).unwrap();
                 let key = v8::String::new(scope, "objectId").unwrap().into();
-                let value = v8::String::new(scope, &value).unwrap().into();
+                let value = value.to_v8(scope)?;
+                object.set(scope, key, value);
+                object.into()
+            }
+            CloudstatePrimitiveData::SetReference(value) => {
+                let context = scope.get_current_context();
+                let export_name = "CloudstateSetReference";
+
+                let class = {
+                    let global = context.global(scope);
+
+                    let export_name = v8::String::new(scope, export_name).unwrap().into();
+                    global
+                        .get(scope, export_name)
+                        .expect("CloudstateSetReference class should be globally defined")
+                };
+
+                let prototype_key = v8::String::new(scope, "prototype").unwrap().into();
+                let prototype = v8::Local::<v8::Function>::try_from(class)
+                    .unwrap()
+                    .get(scope, prototype_key)
+                    .unwrap();
+
+                let object = v8::Object::new(scope);
+                object.set_prototype(scope, prototype).unwrap();
+                let key = v8::String::new(scope, "objectId").unwrap().into();
+                let value = value.to_v8(scope)?;
                 object.set(scope, key, value);
                 object.into()
             }
@@ -1347,18 +2482,16 @@ impl FromV8<'_> for CloudstatePrimitiveData {
             match constructor.as_str() {
                 "CloudstateMapReference" => {
                     let key = v8::String::new(scope, "objectId").unwrap().into();
+                    let id = object.get(scope, key).unwrap();
                     return Ok(CloudstatePrimitiveData::MapReference(
-                        v8::Local::<v8::String>::try_from(object.get(scope, key).unwrap())
-                            .unwrap()
-                            .to_rust_string_lossy(scope),
+                        CloudstateId::from_v8(scope, id)?,
                     ));
                 }
                 "CloudstateBlobReference" => {
                     let key = v8::String::new(scope, "blobId").unwrap().into();
+                    let id = object.get(scope, key).unwrap();
                     return Ok(CloudstatePrimitiveData::Blob(Blob {
-                        id: v8::Local::<v8::String>::try_from(object.get(scope, key).unwrap())
-                            .unwrap()
-                            .to_rust_string_lossy(scope),
+                        id: CloudstateId::from_v8(scope, id)?,
                     }));
                 }
                 "CloudstateObjectReference" => {
@@ -1376,23 +2509,26 @@ impl FromV8<'_> for CloudstatePrimitiveData {
                         )
                     };
 
+                    let id = object.get(scope, object_key).unwrap();
                     let object_reference =
                         CloudstatePrimitiveData::ObjectReference(ObjectReference {
-                            id: v8::Local::<v8::String>::try_from(
-                                object.get(scope, object_key).unwrap(),
-                            )
-                            .unwrap()
-                            .to_rust_string_lossy(scope),
+                            id: CloudstateId::from_v8(scope, id)?,
                         });
 
                     return Ok(object_reference);
                 }
                 "CloudstateArrayReference" => {
                     let key = v8::String::new(scope, "objectId").unwrap().into();
+                    let id = object.get(scope, key).unwrap();
                     return Ok(CloudstatePrimitiveData::ArrayReference(
-                        v8::Local::<v8::String>::try_from(object.get(scope, key).unwrap())
-                            .unwrap()
-                            .to_rust_string_lossy(scope),
+                        CloudstateId::from_v8(scope, id)?,
+                    ));
+                }
+                "CloudstateSetReference" => {
+                    let key = v8::String::new(scope, "objectId").unwrap().into();
+                    let id = object.get(scope, key).unwrap();
+                    return Ok(CloudstatePrimitiveData::SetReference(
+                        CloudstateId::from_v8(scope, id)?,
                     ));
                 }
                 _ => {
@@ -1418,8 +2554,196 @@ impl FromV8<'_> for CloudstatePrimitiveData {
 
 #[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
 pub struct CloudstateMapFieldKey {
-    pub id: String,
-    pub field: String,
+    pub id: CloudstateId,
+    pub field: CloudstateMapKey,
+}
+
+impl CloudstateMapFieldKey {
+    /// Covers every field of the map with the given id
+    pub fn range(id: &CloudstateId) -> std::ops::Range<CloudstateMapFieldKey> {
+        CloudstateMapFieldKey {
+            id: *id,
+            field: CloudstateMapKey::MIN,
+        }..CloudstateMapFieldKey {
+            id: id.next(),
+            field: CloudstateMapKey::MIN,
+        }
+    }
+}
+
+/// A key of a persisted Map. Keys of different types are ordered by type, in
+/// the order the variants are declared, and keys of one type by value.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub enum CloudstateMapKey {
+    Boolean(bool),
+    Number(f64),
+    /// Little-endian words of the magnitude, without trailing zero words
+    BigInt {
+        negative: bool,
+        words: Box<[u64]>,
+    },
+    String(String),
+    Date(DateTime<Utc>),
+}
+
+impl CloudstateMapKey {
+    pub const MIN: CloudstateMapKey = CloudstateMapKey::Boolean(false);
+
+    /// Maps compare keys with SameValueZero, so -0 and +0 are the same key,
+    /// as are all NaNs
+    pub fn number(value: f64) -> Self {
+        if value == 0.0 {
+            CloudstateMapKey::Number(0.0)
+        } else if value.is_nan() {
+            CloudstateMapKey::Number(f64::NAN)
+        } else {
+            CloudstateMapKey::Number(value)
+        }
+    }
+
+    pub fn bigint(negative: bool, mut words: Vec<u64>) -> Self {
+        while words.last() == Some(&0) {
+            words.pop();
+        }
+
+        CloudstateMapKey::BigInt {
+            // there is no negative zero
+            negative: negative && !words.is_empty(),
+            words: words.into_boxed_slice(),
+        }
+    }
+
+    pub(crate) fn type_order(&self) -> u8 {
+        match self {
+            CloudstateMapKey::Boolean(_) => 0,
+            CloudstateMapKey::Number(_) => 1,
+            CloudstateMapKey::BigInt { .. } => 2,
+            CloudstateMapKey::String(_) => 3,
+            CloudstateMapKey::Date(_) => 4,
+        }
+    }
+}
+
+impl PartialEq for CloudstateMapKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for CloudstateMapKey {}
+
+impl PartialOrd for CloudstateMapKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for CloudstateMapKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // magnitudes are compared by word count first, since they have no trailing zeros
+        fn cmp_magnitude(a: &[u64], b: &[u64]) -> Ordering {
+            a.len()
+                .cmp(&b.len())
+                .then_with(|| a.iter().rev().cmp(b.iter().rev()))
+        }
+
+        match (self, other) {
+            (CloudstateMapKey::Boolean(a), CloudstateMapKey::Boolean(b)) => a.cmp(b),
+            (CloudstateMapKey::Number(a), CloudstateMapKey::Number(b)) => a.total_cmp(b),
+            (
+                CloudstateMapKey::BigInt {
+                    negative: a_negative,
+                    words: a,
+                },
+                CloudstateMapKey::BigInt {
+                    negative: b_negative,
+                    words: b,
+                },
+            ) => match (a_negative, b_negative) {
+                (false, false) => cmp_magnitude(a, b),
+                (true, true) => cmp_magnitude(b, a),
+                (true, false) => Ordering::Less,
+                (false, true) => Ordering::Greater,
+            },
+            (CloudstateMapKey::String(a), CloudstateMapKey::String(b)) => a.cmp(b),
+            (CloudstateMapKey::Date(a), CloudstateMapKey::Date(b)) => a.cmp(b),
+            _ => self.type_order().cmp(&other.type_order()),
+        }
+    }
+}
+
+impl ToV8<'_> for CloudstateMapKey {
+    fn to_v8<'a>(
+        self,
+        scope: &mut v8::HandleScope<'a>,
+    ) -> Result<v8::Local<'a, v8::Value>, JsErrorBox> {
+        Ok(match self {
+            CloudstateMapKey::Boolean(value) => v8::Boolean::new(scope, value).into(),
+            CloudstateMapKey::Number(value) => v8::Number::new(scope, value).into(),
+            CloudstateMapKey::BigInt { negative, words } => {
+                v8::BigInt::new_from_words(scope, negative, &words)
+                    .unwrap()
+                    .into()
+            }
+            CloudstateMapKey::String(value) => v8::String::new(scope, &value).unwrap().into(),
+            CloudstateMapKey::Date(value) => v8::Date::new(scope, value.timestamp_millis() as f64)
+                .unwrap()
+                .into(),
+        })
+    }
+
+    type Error = JsErrorBox;
+}
+
+impl FromV8<'_> for CloudstateMapKey {
+    fn from_v8<'a>(
+        scope: &mut v8::HandleScope<'a>,
+        value: v8::Local<'a, v8::Value>,
+    ) -> Result<Self, Self::Error> {
+        if value.is_boolean() {
+            Ok(CloudstateMapKey::Boolean(value.boolean_value(scope)))
+        } else if value.is_number() {
+            let number = v8::Local::<v8::Number>::try_from(value).unwrap();
+            Ok(CloudstateMapKey::number(number.value()))
+        } else if value.is_big_int() {
+            let bigint = v8::Local::<v8::BigInt>::try_from(value).unwrap();
+            let mut words = vec![0; bigint.word_count()];
+            let (negative, _) = bigint.to_words_array(&mut words);
+            Ok(CloudstateMapKey::bigint(negative, words))
+        } else if value.is_string() {
+            Ok(CloudstateMapKey::String(value.to_rust_string_lossy(scope)))
+        } else if value.is_date() {
+            let date = v8::Local::<v8::Date>::try_from(value).unwrap();
+            Utc.timestamp_millis_opt(date.value_of() as i64)
+                .single()
+                .map(CloudstateMapKey::Date)
+                .ok_or_else(|| JsErrorBox::range_error("Invalid Date can't be a Map key"))
+        } else {
+            Err(JsErrorBox::type_error(
+                "Map keys must be strings, numbers, bigints, booleans or Dates",
+            ))
+        }
+    }
+
+    type Error = JsErrorBox;
+}
+
+/// A Map key that can be left out with `undefined` or `null`
+pub struct CloudstateMapKeyBound(pub Option<CloudstateMapKey>);
+
+impl FromV8<'_> for CloudstateMapKeyBound {
+    fn from_v8<'a>(
+        scope: &mut v8::HandleScope<'a>,
+        value: v8::Local<'a, v8::Value>,
+    ) -> Result<Self, Self::Error> {
+        if value.is_null_or_undefined() {
+            Ok(CloudstateMapKeyBound(None))
+        } else {
+            CloudstateMapKey::from_v8(scope, value).map(|key| CloudstateMapKeyBound(Some(key)))
+        }
+    }
+
+    type Error = JsErrorBox;
 }
 
 impl PartialOrd for CloudstateMapFieldKey {
@@ -1441,9 +2765,74 @@ pub struct CloudstateMapFieldValue {
     pub data: CloudstatePrimitiveData,
 }
 
+/// Which fields of a map `op_cloudstate_map_range` reads, besides its bounds
+#[derive(Debug, Deserialize, Default)]
+#[serde(default)]
+pub struct CloudstateMapRangeQuery {
+    /// Only include string fields starting with this
+    pub prefix: Option<String>,
+    pub limit: Option<usize>,
+    pub reverse: bool,
+}
+
+impl CloudstateMapRangeQuery {
+    /// Keys from `start` (inclusive) to `end` (exclusive), narrowed to the prefix
+    pub fn key_range(
+        &self,
+        id: &CloudstateId,
+        start: Option<CloudstateMapKey>,
+        end: Option<CloudstateMapKey>,
+    ) -> std::ops::Range<CloudstateMapFieldKey> {
+        let prefix = self.prefix.clone().map(CloudstateMapKey::String);
+        let prefix_end = self
+            .prefix
+            .as_deref()
+            .and_then(Self::prefix_end)
+            .map(CloudstateMapKey::String);
+
+        let start = [start, prefix].into_iter().flatten().max();
+        let end = [end, prefix_end].into_iter().flatten().min();
+        let whole_map = CloudstateMapFieldKey::range(id);
+
+        let start = match start {
+            Some(field) => CloudstateMapFieldKey { id: *id, field },
+            None => whole_map.start,
+        };
+        let end = match end {
+            Some(field) => CloudstateMapFieldKey { id: *id, field },
+            // Dates are the only keys ordered after every string
+            None if self.prefix.is_some() => CloudstateMapFieldKey {
+                id: *id,
+                field: CloudstateMapKey::Date(DateTime::<Utc>::MIN_UTC),
+            },
+            None => whole_map.end,
+        };
+
+        start..end
+    }
+
+    /// The first string past every string starting with `prefix`, or `None`
+    /// if there isn't one
+    fn prefix_end(prefix: &str) -> Option<String> {
+        let mut chars: Vec<char> = prefix.chars().collect();
+        while let Some(last) = chars.pop() {
+            // strings order by code point, so bump the last one that can be bumped
+            let next = match last as u32 + 1 {
+                0xD800 => Some('\u{E000}'),
+                next => char::from_u32(next),
+            };
+            if let Some(next) = next {
+                chars.push(next);
+                return Some(chars.into_iter().collect());
+            }
+        }
+        None
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
 pub struct CloudstateArrayItemKey {
-    pub id: String,
+    pub id: CloudstateId,
     pub index: i32,
 }
 
@@ -1461,11 +2850,214 @@ impl Ord for CloudstateArrayItemKey {
     }
 }
 
+impl CloudstateArrayItemKey {
+    /// Covers every stored item of the array with the given id, including
+    /// items stored below zero by `unshift`
+    pub fn range(id: &CloudstateId) -> std::ops::RangeInclusive<CloudstateArrayItemKey> {
+        CloudstateArrayItemKey {
+            id: *id,
+            index: i32::MIN,
+        }..=CloudstateArrayItemKey {
+            id: *id,
+            index: i32::MAX,
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, PartialEq)]
 pub struct CloudstateArrayItemValue {
     pub data: CloudstatePrimitiveData,
 }
 
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Clone)]
+pub struct CloudstateArrayMetadataKey {
+    pub id: CloudstateId,
+}
+
+/// Items of an array are stored at `offset + index`, so `shift` and `unshift`
+/// only move the offset instead of renumbering every item.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone, Copy)]
+pub struct CloudstateArrayMetadataValue {
+    pub length: i32,
+    pub offset: i32,
+}
+
+impl CloudstateArrayMetadataValue {
+    /// Covers the stored items in `0..length`
+    pub fn item_range(&self, id: &CloudstateId) -> std::ops::Range<CloudstateArrayItemKey> {
+        CloudstateArrayItemKey {
+            id: *id,
+            index: self.offset,
+        }..CloudstateArrayItemKey {
+            id: *id,
+            index: self.offset + self.length,
+        }
+    }
+}
+
+/// Set members are keyed by the serialized form of their value, so equal
+/// primitives (and references to the same object) collapse to one entry.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Clone)]
+pub struct CloudstateSetItemKey {
+    pub id: CloudstateId,
+    pub item: Vec<u8>,
+}
+
+/// Serializes a value for use inside a key, so equal values get equal bytes
+fn encode_key_value(value: &CloudstatePrimitiveData) -> Vec<u8> {
+    // SameValueZero: -0 and +0 are the same value
+    match value {
+        CloudstatePrimitiveData::Number(n) if *n == 0.0 => {
+            bincode::serialize(&CloudstatePrimitiveData::Number(0.0))
+        }
+        value => bincode::serialize(value),
+    }
+    .unwrap()
+}
+
+impl CloudstateSetItemKey {
+    pub fn new(id: CloudstateId, value: &CloudstatePrimitiveData) -> Self {
+        Self {
+            id,
+            item: encode_key_value(value),
+        }
+    }
+
+    /// Covers every member of the set with the given id
+    pub fn range(id: &CloudstateId) -> std::ops::Range<CloudstateSetItemKey> {
+        CloudstateSetItemKey {
+            id: *id,
+            item: vec![],
+        }..CloudstateSetItemKey {
+            id: id.next(),
+            item: vec![],
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct CloudstateSetItemValue {
+    pub data: CloudstatePrimitiveData,
+}
+
+/// Key of the index of objects by their class and the version of it they were
+/// written at
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Clone)]
+pub struct CloudstateClassVersionKey {
+    pub class_name: String,
+    pub version: u32,
+    pub object_id: CloudstateId,
+}
+
+impl CloudstateClassVersionKey {
+    /// Returns `None` for objects that aren't instances of a custom class
+    pub fn new(object: &CloudstateObjectData, object_id: &CloudstateId) -> Option<Self> {
+        let class_name = object.constructor_name.as_ref()?;
+        Some(Self {
+            // matches how getObject looks up the class of an object
+            class_name: class_name.replacen('_', "", 1),
+            version: object.version,
+            object_id: *object_id,
+        })
+    }
+
+    /// Covers the objects of a class written before `version`
+    pub fn outdated(class_name: &str, version: u32) -> std::ops::Range<Self> {
+        Self {
+            class_name: class_name.to_string(),
+            version: 0,
+            object_id: CloudstateId::MIN,
+        }..Self {
+            class_name: class_name.to_string(),
+            version,
+            object_id: CloudstateId::MIN,
+        }
+    }
+}
+
+/// Key of the index over the fields custom classes list in their static
+/// `indexes`. Entries for one value are ordered by the id of the object.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Clone)]
+pub struct CloudstateFieldIndexKey {
+    pub class_name: String,
+    pub field: String,
+    pub value: Vec<u8>,
+    pub object_id: CloudstateId,
+}
+
+impl CloudstateFieldIndexKey {
+    /// Returns `None` for values that can't be indexed, like references
+    pub fn new(
+        class_name: &str,
+        field: &str,
+        value: &CloudstatePrimitiveData,
+        object_id: &CloudstateId,
+    ) -> Option<Self> {
+        Self::encode_value(value).map(|value| Self {
+            class_name: class_name.to_string(),
+            field: field.to_string(),
+            value,
+            object_id: *object_id,
+        })
+    }
+
+    /// Covers every entry of the class, whatever the field and value
+    pub fn class(class_name: &str) -> std::ops::Range<CloudstateFieldIndexKey> {
+        // the next class name in order, so every field of this class is included
+        let end = format!("{class_name}\0");
+
+        CloudstateFieldIndexKey {
+            class_name: class_name.to_string(),
+            field: String::new(),
+            value: Vec::new(),
+            object_id: CloudstateId::MIN,
+        }..CloudstateFieldIndexKey {
+            class_name: end,
+            field: String::new(),
+            value: Vec::new(),
+            object_id: CloudstateId::MIN,
+        }
+    }
+
+    /// Covers every object whose field holds the given value
+    pub fn range(
+        class_name: &str,
+        field: &str,
+        value: &CloudstatePrimitiveData,
+    ) -> Option<std::ops::Range<CloudstateFieldIndexKey>> {
+        let value = Self::encode_value(value)?;
+        // the next value in byte order, so every object id for this value is included
+        let mut end = value.clone();
+        end.push(0);
+
+        Some(
+            CloudstateFieldIndexKey {
+                class_name: class_name.to_string(),
+                field: field.to_string(),
+                value,
+                object_id: CloudstateId::MIN,
+            }..CloudstateFieldIndexKey {
+                class_name: class_name.to_string(),
+                field: field.to_string(),
+                value: end,
+                object_id: CloudstateId::MIN,
+            },
+        )
+    }
+
+    fn encode_value(value: &CloudstatePrimitiveData) -> Option<Vec<u8>> {
+        match value {
+            CloudstatePrimitiveData::Number(_)
+            | CloudstatePrimitiveData::String(_)
+            | CloudstatePrimitiveData::Boolean(_)
+            | CloudstatePrimitiveData::BigInt(_)
+            | CloudstatePrimitiveData::Date(_)
+            | CloudstatePrimitiveData::Null => Some(encode_key_value(value)),
+            _ => None,
+        }
+    }
+}
+
 deno_core::extension!(
   cloudstate,
   ops = [
@@ -1475,22 +3067,35 @@ deno_core::extension!(
     op_cloudstate_array_reverse,
     op_cloudstate_array_set,
     op_cloudstate_array_shift,
+    op_cloudstate_array_unshift,
     op_cloudstate_cloudstate_get,
     op_cloudstate_commit_transaction,
+    op_cloudstate_abort_transaction,
+    op_cloudstate_index_query,
+    op_cloudstate_index_register,
     op_cloudstate_map_clear,
     op_cloudstate_map_delete,
     op_cloudstate_map_entries,
     op_cloudstate_map_get,
     op_cloudstate_map_has,
     op_cloudstate_map_keys,
+    op_cloudstate_map_range,
     op_cloudstate_map_set,
     op_cloudstate_map_size,
     op_cloudstate_map_values,
+    op_cloudstate_object_delete_property,
     op_cloudstate_object_get,
+    op_cloudstate_object_outdated,
     op_cloudstate_object_root_get,
     op_cloudstate_object_root_set,
     op_cloudstate_object_set,
     op_cloudstate_object_set_property,
+    op_cloudstate_set_add,
+    op_cloudstate_set_clear,
+    op_cloudstate_set_delete,
+    op_cloudstate_set_has,
+    op_cloudstate_set_size,
+    op_cloudstate_set_values,
     op_cloudstate_blob_get_array_buffer,
     op_cloudstate_blob_get_uint8array,
     op_cloudstate_blob_get_text,
@@ -1499,16 +3104,27 @@ deno_core::extension!(
     op_cloudstate_blob_get_size,
     op_cloudstate_blob_get_type,
     op_cloudstate_list_roots,
+    op_cloudstate_new_id,
     op_cloudstate_set_read_only,
+    op_cloudstate_set_read_write,
+    op_cloudstate_take_write_attempted,
+    op_cloudstate_request_args,
+
+    http::op_cloudstate_request_body_read,
+    http::op_cloudstate_response_start,
+    http::op_cloudstate_response_write,
+    http::op_cloudstate_response_error,
 
     op_tracing_span_finish,
 
     js_spans::op_tracing_span_hydrate,
     js_spans::op_tracing_span_get_map,
+    js_spans::op_tracing_span_get_set,
     js_spans::op_tracing_span_get_object,
     js_spans::op_tracing_span_pack_to_reference_or_primitive,
     js_spans::op_tracing_span_unpack_from_reference,
     js_spans::op_tracing_span_get_cloudstate,
+    js_spans::op_tracing_span_query_index,
     js_spans::op_tracing_span_set_object,
     js_spans::op_tracing_span_get_array,
     js_spans::op_tracing_span_export_object,
@@ -1516,7 +3132,10 @@ deno_core::extension!(
     js_spans::op_tracing_span_get_root,
     js_spans::op_tracing_span_array_filter,
     js_spans::op_tracing_span_array_splice,
-    js_spans::op_tracing_span_commit
+    js_spans::op_tracing_span_commit,
+    js_spans::op_tracing_span_rollback,
+    js_spans::op_tracing_span_migrate_object,
+    js_spans::op_tracing_span_migrate_objects
   ],
   esm_entry_point = "ext:cloudstate/cloudstate.js",
   esm = [ dir "src/extensions", "cloudstate.js" ],
@@ -0,0 +1,189 @@
+//! Streams the body of the request a script is serving in, and the response
+//! it returns back out, a chunk at a time, so neither has to fit in memory.
+
+use std::{cell::RefCell, rc::Rc};
+
+use deno_core::{JsBuffer, OpState, ToJsBuffer, op2};
+use deno_error::JsErrorBox;
+use serde::Deserialize;
+use tokio::sync::{
+    mpsc::{self, error::TrySendError},
+    oneshot,
+};
+
+use crate::RequestArgs;
+
+/// How many chunks can be waiting in either direction
+const CHUNKS_IN_FLIGHT: usize = 16;
+
+/// A request's body, for its script to read
+#[derive(Debug)]
+pub struct RequestBody {
+    chunks: mpsc::Receiver<Result<Vec<u8>, String>>,
+}
+
+impl RequestBody {
+    /// A body, and where to send its chunks, or the error that cut it short
+    pub fn channel() -> (mpsc::Sender<Result<Vec<u8>, String>>, Self) {
+        let (sender, chunks) = mpsc::channel(CHUNKS_IN_FLIGHT);
+        (sender, Self { chunks })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ResponseHead {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+}
+
+/// Where a script sends its response
+#[derive(Debug)]
+pub struct ResponseSink {
+    head: Option<oneshot::Sender<ResponseHead>>,
+    chunks: mpsc::Sender<Result<Vec<u8>, String>>,
+}
+
+/// A script's response, as it sends it
+#[derive(Debug)]
+pub struct ResponseStream {
+    /// Sent once the script starts responding, and dropped unsent if it
+    /// finishes without doing so
+    pub head: oneshot::Receiver<ResponseHead>,
+    /// The body, which ends when the script does, or with an error if the
+    /// script failed to produce all of it
+    pub chunks: mpsc::Receiver<Result<Vec<u8>, String>>,
+}
+
+/// The clock on a script's timeout, which runners that time scripts out keep
+/// in the op state as an `Rc<dyn ScriptClock>`. It's paused while the script
+/// waits for the client to make room for more of its response, which the
+/// script can't hurry along.
+pub trait ScriptClock {
+    fn pause(&self);
+    fn resume(&self);
+}
+
+/// Resumes a paused clock when dropped, even if the wait is cut short
+struct Paused(Option<Rc<dyn ScriptClock>>);
+
+impl Paused {
+    fn new(clock: Option<Rc<dyn ScriptClock>>) -> Self {
+        if let Some(clock) = &clock {
+            clock.pause();
+        }
+        Self(clock)
+    }
+}
+
+impl Drop for Paused {
+    fn drop(&mut self) {
+        if let Some(clock) = &self.0 {
+            clock.resume();
+        }
+    }
+}
+
+impl ResponseSink {
+    pub fn channel() -> (Self, ResponseStream) {
+        let (head, head_receiver) = oneshot::channel();
+        let (chunks, chunks_receiver) = mpsc::channel(CHUNKS_IN_FLIGHT);
+        (
+            Self {
+                head: Some(head),
+                chunks,
+            },
+            ResponseStream {
+                head: head_receiver,
+                chunks: chunks_receiver,
+            },
+        )
+    }
+}
+
+/// The next chunk of the request's body, or null once it's all been read
+#[op2(async)]
+#[serde]
+pub async fn op_cloudstate_request_body_read(
+    state: Rc<RefCell<OpState>>,
+) -> Result<Option<ToJsBuffer>, JsErrorBox> {
+    // the body is taken while waiting, so the op state isn't borrowed across
+    // the await
+    let body = RefCell::borrow_mut(&state)
+        .try_borrow_mut::<RequestArgs>()
+        .and_then(|args| args.body.take());
+    let Some(mut body) = body else {
+        return Ok(None);
+    };
+
+    let chunk = body.chunks.recv().await;
+    if let Some(args) = RefCell::borrow_mut(&state).try_borrow_mut::<RequestArgs>() {
+        args.body = Some(body);
+    }
+
+    match chunk {
+        Some(Ok(chunk)) => Ok(Some(chunk.into())),
+        Some(Err(e)) => Err(JsErrorBox::generic(e)),
+        None => Ok(None),
+    }
+}
+
+#[op2]
+pub fn op_cloudstate_response_start(
+    state: &mut OpState,
+    #[serde] head: ResponseHead,
+) -> Result<(), JsErrorBox> {
+    let sender = state
+        .try_borrow_mut::<RequestArgs>()
+        .and_then(|args| args.response.as_mut())
+        .and_then(|response| response.head.take())
+        .ok_or_else(|| JsErrorBox::generic("The response has already been started"))?;
+
+    // nobody is waiting if the request was dropped
+    let _ = sender.send(head);
+    Ok(())
+}
+
+/// Sends a chunk of the response's body, waiting until there's room for it.
+/// Returns false once nobody is reading the body anymore.
+#[op2(async)]
+pub async fn op_cloudstate_response_write(
+    state: Rc<RefCell<OpState>>,
+    #[buffer] chunk: JsBuffer,
+) -> bool {
+    send_chunk(state, Ok(chunk.to_vec())).await
+}
+
+/// Ends the response's body with an error, so the client sees it fail rather
+/// than end early
+#[op2(async)]
+pub async fn op_cloudstate_response_error(
+    state: Rc<RefCell<OpState>>,
+    #[string] message: String,
+) -> bool {
+    send_chunk(state, Err(message)).await
+}
+
+async fn send_chunk(state: Rc<RefCell<OpState>>, chunk: Result<Vec<u8>, String>) -> bool {
+    let (chunks, clock) = {
+        let state = RefCell::borrow(&state);
+        let chunks = state
+            .try_borrow::<RequestArgs>()
+            .and_then(|args| args.response.as_ref())
+            .map(|response| response.chunks.clone());
+        let clock = state.try_borrow::<Rc<dyn ScriptClock>>().cloned();
+        (chunks, clock)
+    };
+    let Some(chunks) = chunks else {
+        return false;
+    };
+
+    let chunk = match chunks.try_send(chunk) {
+        Ok(()) => return true,
+        Err(TrySendError::Closed(_)) => return false,
+        Err(TrySendError::Full(chunk)) => chunk,
+    };
+
+    // the client is reading slower than the script writes
+    let _paused = Paused::new(clock);
+    chunks.send(chunk).await.is_ok()
+}
@@ -0,0 +1,102 @@
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::anyhow;
+use deno_core::{FromV8, ToV8, v8};
+use deno_error::JsErrorBox;
+use serde::{Deserialize, Deserializer, Serialize, Serializer, de};
+use uuid::Uuid;
+
+/// The id of a stored object, map, array, set or blob: a random version 4
+/// UUID, kept as its 16 bytes. JavaScript and exports see it as the
+/// hyphenated string.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct CloudstateId(pub [u8; 16]);
+
+impl CloudstateId {
+    pub const MIN: CloudstateId = CloudstateId([0; 16]);
+
+    /// A new id from the operating system's secure random number generator
+    pub fn random() -> Self {
+        CloudstateId(Uuid::new_v4().into_bytes())
+    }
+
+    /// The id right after this one, for ranges covering every key of an id.
+    /// Version 4 UUIDs never have every bit set, so there always is one.
+    pub fn next(&self) -> Self {
+        CloudstateId(u128::from_be_bytes(self.0).saturating_add(1).to_be_bytes())
+    }
+}
+
+impl fmt::Display for CloudstateId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        Uuid::from_bytes(self.0).fmt(f)
+    }
+}
+
+impl fmt::Debug for CloudstateId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl FromStr for CloudstateId {
+    type Err = anyhow::Error;
+
+    fn from_str(id: &str) -> Result<Self, Self::Err> {
+        Uuid::try_parse(id)
+            .map(|uuid| CloudstateId(uuid.into_bytes()))
+            .map_err(|_| anyhow!("Invalid id {:?}", id))
+    }
+}
+
+// bincode stores the 16 bytes, formats like JSON the hyphenated string
+impl Serialize for CloudstateId {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.collect_str(self)
+        } else {
+            self.0.serialize(serializer)
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for CloudstateId {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        if deserializer.is_human_readable() {
+            String::deserialize(deserializer)?
+                .parse()
+                .map_err(de::Error::custom)
+        } else {
+            <[u8; 16]>::deserialize(deserializer).map(CloudstateId)
+        }
+    }
+}
+
+impl ToV8<'_> for CloudstateId {
+    type Error = JsErrorBox;
+
+    fn to_v8<'a>(
+        self,
+        scope: &mut v8::HandleScope<'a>,
+    ) -> Result<v8::Local<'a, v8::Value>, Self::Error> {
+        Ok(v8::String::new(scope, &self.to_string()).unwrap().into())
+    }
+}
+
+impl FromV8<'_> for CloudstateId {
+    type Error = JsErrorBox;
+
+    fn from_v8<'a>(
+        scope: &mut v8::HandleScope<'a>,
+        value: v8::Local<'a, v8::Value>,
+    ) -> Result<Self, Self::Error> {
+        if !value.is_string() {
+            return Err(JsErrorBox::type_error("Expected an id string"));
+        }
+        value
+            .to_rust_string_lossy(scope)
+            .parse()
+            .map_err(|e: anyhow::Error| JsErrorBox::type_error(e.to_string()))
+    }
+}
@@ -34,6 +34,7 @@ macro_rules! op_js_span {
 
 op_js_span!(op_tracing_span_hydrate, hydrate);
 op_js_span!(op_tracing_span_get_map, get_map);
+op_js_span!(op_tracing_span_get_set, get_set);
 
 op_js_span!(op_tracing_span_get_object, get_object);
 op_js_span!(
@@ -42,6 +43,7 @@ op_js_span!(
 );
 op_js_span!(op_tracing_span_unpack_from_reference, unpack_from_reference);
 op_js_span!(op_tracing_span_get_cloudstate, get_cloudstate);
+op_js_span!(op_tracing_span_query_index, query_index);
 op_js_span!(op_tracing_span_set_object, set_object);
 op_js_span!(op_tracing_span_get_array, get_array);
 op_js_span!(op_tracing_span_export_object, export_object);
@@ -50,3 +52,6 @@ op_js_span!(op_tracing_span_get_root, get_root);
 op_js_span!(op_tracing_span_array_filter, array_filter);
 op_js_span!(op_tracing_span_array_splice, array_splice);
 op_js_span!(op_tracing_span_commit, commit);
+op_js_span!(op_tracing_span_rollback, rollback);
+op_js_span!(op_tracing_span_migrate_object, migrate_object);
+op_js_span!(op_tracing_span_migrate_objects, migrate_objects);
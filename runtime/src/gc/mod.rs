@@ -1,301 +1,760 @@
+//! The garbage collector, which deletes objects, maps, arrays, sets and blobs
+//! no root can reach.
+//!
+//! Marking reads a snapshot, so it doesn't hold up requests. Sweeping runs in
+//! batches of `GcOptions::batch_size` rows, each in its own write transaction,
+//! so requests get to write in between. Anything written since the snapshot
+//! could have been handed a reference to an object the snapshot saw as
+//! garbage, so before each batch deletes anything the collector marks what is
+//! reachable from the rows the change log says were written since.
+//!
+//! The mark set and the stack of pointers still to visit are kept in a
+//! scratch database rather than in memory, which caches at most
+//! `GcOptions::memory_limit` bytes of them.
+//!
+//! A blob's payload is deleted from the blob storage once the batch that
+//! deleted its row has committed, so a batch that fails leaves it in place.
+
+use crate::bincode::{Bincode, CorruptRow, Decode, raw};
+use crate::blob_storage::CloudstateBlobStorage;
+use crate::changes::{CloudstateChange, PendingChanges, last_sequence};
 use crate::extensions::cloudstate::{
-    CloudstateArrayItemKey, CloudstateMapFieldKey, CloudstatePrimitiveData,
+    CloudstateArrayItemKey, CloudstateBlobKey, CloudstateId, CloudstateMapFieldKey,
+    CloudstateObjectKey, CloudstatePrimitiveData, CloudstateRootKey, CloudstateSetItemKey,
+    ReDBCloudstate, Transaction, WritePermit,
 };
-use crate::tables::{ARRAYS_TABLE, MAPS_TABLE};
-use crate::{
-    extensions::cloudstate::CloudstateObjectKey,
-    tables::{OBJECTS_TABLE, ROOTS_TABLE},
+use crate::ordered::Ordered;
+use crate::tables::{
+    ARRAY_METADATA_TABLE, ARRAYS_TABLE, BLOBS_TABLE, CHANGES_TABLE, CLASS_VERSIONS_INDEX,
+    FIELD_INDEX_TABLE, MAPS_TABLE, OBJECT_IDS_INDEX, OBJECTS_TABLE, REFERENCE_COUNTS_TABLE,
+    ROOTS_TABLE, SETS_TABLE,
 };
 use anyhow::anyhow;
-use redb::{Database, ReadTransaction, ReadableTable, ReadableTableMetadata, WriteTransaction};
-use std::collections::BTreeSet;
-use tracing::{debug, instrument};
-use tracing::{event, info};
-
-#[instrument(skip(db))]
-pub fn mark_and_sweep(db: &Database) -> anyhow::Result<&Database> {
-    let tx = db.begin_read()?;
-    let reachable = mark(tx)?;
-
-    let tx = match db.begin_write() {
-        Ok(out) => out,
-        Err(e) => {
-            event!(
-                tracing::Level::ERROR,
-                "Error creating write transaction: {}",
-                e
-            );
-
-            panic!("Error creating write transaction: {}", e)
+use redb::{
+    AccessGuard, Database, Durability, Key, ReadTransaction, ReadableTable, ReadableTableMetadata,
+    Table, TableDefinition, TableError, TableHandle, Value, WriteTransaction,
+};
+use serde::{Deserialize, Serialize};
+use std::ops::Bound;
+use std::path::PathBuf;
+use tracing::{debug, info, instrument, warn};
+
+/// Pointers that have been visited
+const MARKED_TABLE: TableDefinition<&[u8], ()> = TableDefinition::new("marked");
+
+/// Pointers waiting to be visited, popped from the end
+const STACK_TABLE: TableDefinition<u64, &[u8]> = TableDefinition::new("stack");
+
+#[derive(Debug, Clone)]
+pub struct GcOptions {
+    /// How many pointers to visit or rows to sweep per transaction
+    pub batch_size: usize,
+    /// How many bytes of the mark set to keep cached in memory
+    pub memory_limit: usize,
+    /// Where to create the scratch database holding the mark set
+    pub scratch_dir: PathBuf,
+    /// Count what would be deleted without deleting it
+    pub dry_run: bool,
+}
+
+impl Default for GcOptions {
+    fn default() -> Self {
+        Self {
+            batch_size: 1000,
+            memory_limit: 64 * 1024 * 1024,
+            scratch_dir: std::env::temp_dir(),
+            dry_run: false,
         }
-    };
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum GcPhase {
+    #[default]
+    Mark,
+    Sweep,
+}
 
-    let _ = sweep(tx, &reachable);
+#[derive(Debug, Clone, Copy, Default)]
+pub struct GcProgress {
+    pub phase: GcPhase,
+    /// Objects, maps, arrays, sets and blobs found to be reachable
+    pub marked: u64,
+    /// Rows the sweep has looked at
+    pub scanned: u64,
+    /// Rows the sweep has deleted, or would have in a dry run
+    pub deleted: u64,
+    /// Blobs the sweep has deleted, or would have in a dry run
+    pub blobs: u64,
+    /// The size of those blobs' payloads
+    pub blob_bytes: u64,
+}
+
+/// Collects garbage in a database nothing else has open, like in the `gc`
+/// command
+#[instrument(skip(db, blob_storage))]
+pub fn mark_and_sweep<'a>(
+    db: &'a Database,
+    blob_storage: &CloudstateBlobStorage,
+) -> anyhow::Result<&'a Database> {
+    run(
+        Collecting::Database(db),
+        blob_storage,
+        &GcOptions::default(),
+        &mut None,
+    )?;
     Ok(db)
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
-pub enum Pointer {
-    Object(CloudstateObjectKey),
-    Map(CloudstateObjectKey),
-    Array(CloudstateObjectKey),
-    // These are only deletable but not sweepable
-    MapField(CloudstateMapFieldKey),
-    ArrayItem(CloudstateArrayItemKey),
+/// Like `mark_and_sweep`, with options and progress reports
+#[instrument(skip(db, blob_storage, progress_callback))]
+pub fn collect_database<'a>(
+    db: &Database,
+    blob_storage: &CloudstateBlobStorage,
+    options: &GcOptions,
+    progress_callback: &mut Option<Box<dyn FnMut(GcProgress) + 'a>>,
+) -> anyhow::Result<GcProgress> {
+    run(
+        Collecting::Database(db),
+        blob_storage,
+        options,
+        progress_callback,
+    )
 }
 
-/// Comsumes the transaction and returns a set of reachable objects
-#[instrument(skip(tx))]
-pub fn mark(tx: ReadTransaction) -> anyhow::Result<BTreeSet<Pointer>> {
-    let reachable = {
-        let roots_table = match tx.open_table(ROOTS_TABLE) {
-            Ok(table) => table,
-            Err(e) => return Err(anyhow!(e)),
-        };
+/// Collects garbage in a database that is being served. Requests only wait
+/// for the batch being swept, never for the whole collection.
+#[instrument(skip(cloudstate, blob_storage, progress_callback))]
+pub fn collect<'a>(
+    cloudstate: &ReDBCloudstate,
+    blob_storage: &CloudstateBlobStorage,
+    options: &GcOptions,
+    progress_callback: &mut Option<Box<dyn FnMut(GcProgress) + 'a>>,
+) -> anyhow::Result<GcProgress> {
+    run(
+        Collecting::Cloudstate(cloudstate),
+        blob_storage,
+        options,
+        progress_callback,
+    )
+}
 
-        let mut roots: Vec<CloudstateObjectKey> = Vec::new();
+/// What the collector runs against
+enum Collecting<'a> {
+    Database(&'a Database),
+    /// Takes turns with the database's other writers
+    Cloudstate(&'a ReDBCloudstate),
+}
 
-        info!("{} items in roots table", roots_table.len().unwrap_or(0));
-        for item in roots_table.iter()? {
-            if let Ok((key, root)) = item {
-                let _key = key.value();
-                let root = root.value();
-                roots.push(CloudstateObjectKey { id: root.id });
-            }
+impl Collecting<'_> {
+    fn begin_read(&self) -> anyhow::Result<ReadTransaction> {
+        match self {
+            Collecting::Database(db) => Ok(db.begin_read()?),
+            Collecting::Cloudstate(cloudstate) => Ok(cloudstate.get_database_mut().begin_read()?),
         }
+    }
 
-        let objects_table = match tx.open_table(OBJECTS_TABLE) {
-            Ok(table) => {
-                info!("{} items in objects table", table.len().unwrap_or(0));
-                Some(table)
+    /// Opens a write transaction, along with the permit keeping other writers
+    /// out until it's committed
+    fn begin_write(&self) -> anyhow::Result<(WriteTransaction, Option<WritePermit>)> {
+        match self {
+            Collecting::Database(db) => Ok((db.begin_write()?, None)),
+            Collecting::Cloudstate(cloudstate) => {
+                let permit = cloudstate.acquire_writer();
+                let write = cloudstate.get_database_mut().begin_write()?;
+                Ok((write, Some(permit)))
             }
-            Err(_e) => None,
-        };
+        }
+    }
+}
 
-        let map_table = match tx.open_table(MAPS_TABLE) {
-            Ok(table) => {
-                info!("{} items in map table", table.len().unwrap_or(0));
-                Some(table)
-            }
-            Err(_e) => None,
-        };
+fn run(
+    source: Collecting,
+    blob_storage: &CloudstateBlobStorage,
+    options: &GcOptions,
+    progress_callback: &mut Option<Box<dyn FnMut(GcProgress) + '_>>,
+) -> anyhow::Result<GcProgress> {
+    let batch_size = options.batch_size.max(1);
+    let scratch = Scratch::create(options)?;
+    let mut progress = GcProgress::default();
+    let mut report = |progress: GcProgress| {
+        if let Some(callback) = progress_callback.as_mut() {
+            callback(progress);
+        }
+    };
 
-        let arr_table = match tx.open_table(ARRAYS_TABLE) {
-            Ok(table) => {
-                info!("{} items in array table", table.len().unwrap_or(0));
-                Some(table)
-            }
-            Err(_e) => None,
-        };
+    let read = source.begin_read()?;
+    // changes after this are marked again before each sweep batch
+    let mut seen = logged_until(&read)?;
+    let read = Transaction::Read(read);
 
-        let mut reachable: BTreeSet<Pointer> = BTreeSet::new();
+    {
+        let scratch_write = scratch.begin_write()?;
+        push_roots(&read, &scratch_write)?;
+        scratch_write.commit()?;
+    }
 
-        let mut stack: Vec<Pointer> = Vec::with_capacity(roots.len());
+    loop {
+        let scratch_write = scratch.begin_write()?;
+        let (marked, done) = catch_corrupt(|| mark(&read, &scratch_write, batch_size))?;
+        scratch_write.commit()?;
 
-        stack.extend(roots.iter().map(|root| Pointer::Object(root.clone())));
+        progress.marked += marked;
+        report(progress);
+        if done {
+            break;
+        }
+    }
+    read.commit()?;
+    info!("Found {} reachable objects", progress.marked);
+
+    progress.phase = GcPhase::Sweep;
+    let mut cursor = SweepCursor::default();
+    while !cursor.done() {
+        let (write, permit) = source.begin_write()?;
+        let changes = PendingChanges::new(&write)?;
+        let write = Transaction::Write(write, changes);
+        let scratch_write = scratch.begin_write()?;
+
+        let swept = catch_corrupt(|| {
+            seen = push_changed(&write, &scratch_write, seen)?;
+            let (marked, _) = mark(&write, &scratch_write, usize::MAX)?;
+            progress.marked += marked;
+
+            let marked = scratch_write.open_table(MARKED_TABLE)?;
+            cursor.sweep(&write, &marked, batch_size, options.dry_run)
+        })?;
+
+        if options.dry_run {
+            write.abort()?;
+        } else {
+            // the deletions are logged so incremental backups remove the rows
+            // too, but they mustn't mark what they deleted in the next batch.
+            // Nothing else can have written since, while the permit is held.
+            write.commit()?;
+            seen = logged_until(&source.begin_read()?)?;
+        }
+        drop(permit);
+        scratch_write.commit()?;
 
-        while let Some(pointer) = stack.pop() {
-            debug!("Stack size: {}", stack.len());
-            debug!("Pointer: {:?}", pointer);
-            if reachable.contains(&pointer) {
-                continue;
+        for blob_id in &swept.blobs {
+            match blob_storage.get_blob_size(blob_id) {
+                Ok(size) => progress.blob_bytes += size as u64,
+                Err(e) => debug!("Failed to get the size of blob {}: {}", blob_id, e),
             }
-
-            reachable.insert(pointer.clone());
-
-            match pointer {
-                Pointer::Object(object_key) => {
-                    if let Some(ref objects_table) = objects_table {
-                        let object = match objects_table.get(&object_key)? {
-                            Some(object) => object,
-                            None => continue,
-                        };
-
-                        for (_key, value) in object.value().data.fields {
-                            match value {
-                                CloudstatePrimitiveData::ObjectReference(obj_ref) => {
-                                    stack.push(Pointer::Object(CloudstateObjectKey {
-                                        id: obj_ref.id,
-                                    }));
-                                }
-                                CloudstatePrimitiveData::MapReference(map_ref) => {
-                                    stack.push(Pointer::Map(CloudstateObjectKey { id: map_ref }));
-                                }
-                                CloudstatePrimitiveData::ArrayReference(arr_ref) => {
-                                    stack.push(Pointer::Array(CloudstateObjectKey { id: arr_ref }));
-                                }
-                                _ => {
-                                    /* These don't have references so they don't need anything */
-                                }
-                            }
-                        }
-                    }
+            if !options.dry_run {
+                // the row is gone, so nothing will come back for the payload
+                if let Err(e) = blob_storage.delete_blob_data(blob_id) {
+                    warn!("Failed to delete blob {}: {}", blob_id, e);
                 }
-                Pointer::Map(map_reference) => {
-                    if let Some(ref map_table) = map_table {
-                        for item in map_table.range(
-                            CloudstateMapFieldKey {
-                                id: map_reference.id.clone(),
-                                field: String::new(),
-                            }..CloudstateMapFieldKey {
-                                id: map_reference.id.clone() + "\u{0}", // null character so it goes through all the fields
-                                field: String::new(),
-                            },
-                        )? {
-                            if let Ok((key, value)) = item {
-                                let key = key.value();
-                                let value = value.value();
-                                if key.id == map_reference.id {
-                                    match value.data {
-                                        CloudstatePrimitiveData::ObjectReference(obj_ref) => {
-                                            stack.push(Pointer::Object(CloudstateObjectKey {
-                                                id: obj_ref.id,
-                                            }));
-                                        }
-                                        CloudstatePrimitiveData::MapReference(map_ref_internal) => {
-                                            stack.push(Pointer::Map(CloudstateObjectKey {
-                                                id: map_ref_internal,
-                                            }));
-                                        }
-                                        CloudstatePrimitiveData::ArrayReference(arr_ref) => {
-                                            stack.push(Pointer::Array(CloudstateObjectKey {
-                                                id: arr_ref,
-                                            }));
-                                        }
-                                        _ => {
-                                            /* These don't have references so they don't need anything */
-                                        }
-                                    }
-                                }
-                            }
-                        }
-                    }
-                }
-                Pointer::Array(arr_ref) => {
-                    if let Some(ref arr_table) = arr_table {
-                        for item in arr_table.range(
-                            CloudstateArrayItemKey {
-                                id: arr_ref.id.clone(),
-                                index: 0,
-                            }..CloudstateArrayItemKey {
-                                id: arr_ref.id.clone(),
-                                index: i32::MAX,
-                            },
-                        )? {
-                            if let Ok((_key, value)) = item {
-                                let value = value.value();
-
-                                match value.data {
-                                    CloudstatePrimitiveData::ObjectReference(obj_ref) => {
-                                        stack.push(Pointer::Object(CloudstateObjectKey {
-                                            id: obj_ref.id,
-                                        }));
-                                    }
-                                    CloudstatePrimitiveData::MapReference(map_ref) => {
-                                        stack.push(Pointer::Map(CloudstateObjectKey {
-                                            id: map_ref,
-                                        }));
-                                    }
-                                    CloudstatePrimitiveData::ArrayReference(arr_ref_internal) => {
-                                        stack.push(Pointer::Array(CloudstateObjectKey {
-                                            id: arr_ref_internal,
-                                        }));
-                                    }
-                                    _ => {
-                                        /* These don't have references so they don't need anything */
-                                    }
-                                }
-                            }
-                        }
-                    }
-                }
-                _ => { /* These don't have references so they don't need anything */ }
             }
         }
-        info!("Found {} reachable objects", reachable.len());
 
-        reachable
-    };
+        progress.scanned += swept.scanned;
+        progress.deleted += swept.deleted.len() as u64;
+        progress.blobs += swept.blobs.len() as u64;
+        report(progress);
+    }
+    info!(
+        "{} {} of {} rows swept, including {} blobs of {} bytes",
+        if options.dry_run {
+            "Would delete"
+        } else {
+            "Deleted"
+        },
+        progress.deleted,
+        progress.scanned,
+        progress.blobs,
+        progress.blob_bytes
+    );
+
+    Ok(progress)
+}
 
-    // tx.close()?;
+/// The sequence number of the last change logged, or 0 if nothing has been
+fn logged_until(read: &ReadTransaction) -> anyhow::Result<u64> {
+    match read.open_table(CHANGES_TABLE) {
+        Ok(log) => last_sequence(&log),
+        Err(TableError::TableDoesNotExist(_)) => Ok(0),
+        Err(e) => Err(e.into()),
+    }
+}
 
-    Ok(reachable)
+/// Turns a corrupt row met while collecting into an error, so a collection
+/// on a server doesn't take it down
+fn catch_corrupt<T>(f: impl FnOnce() -> anyhow::Result<T>) -> anyhow::Result<T> {
+    f().map_err(|e| match e.downcast_ref::<CorruptRow>() {
+        Some(corrupt) => anyhow!("{}, run the check command to quarantine it", corrupt),
+        None => e,
+    })
 }
 
-/// Consumes the transaction and deletes all objects not in the set
-#[instrument(skip(tx, reachable))]
-pub fn sweep(tx: WriteTransaction, reachable: &BTreeSet<Pointer>) -> anyhow::Result<()> {
-    {
-        let mut objects_table = match tx.open_table(OBJECTS_TABLE) {
-            Ok(table) => table,
-            Err(e) => return Err(anyhow!(e)),
-        };
-        let mut maps_table = match tx.open_table(MAPS_TABLE) {
-            Ok(table) => table,
-            Err(e) => return Err(anyhow!(e)),
-        };
+/// The scratch database holding the mark set, deleted when dropped
+struct Scratch {
+    db: Option<Database>,
+    path: PathBuf,
+}
 
-        let mut arrays_table = match tx.open_table(ARRAYS_TABLE) {
-            Ok(table) => table,
-            Err(e) => return Err(anyhow!(e)),
-        };
+impl Scratch {
+    fn create(options: &GcOptions) -> anyhow::Result<Self> {
+        let path = options
+            .scratch_dir
+            .join(format!("cloudstate-gc-{}", CloudstateId::random()));
+        let db = Database::builder()
+            .set_cache_size(options.memory_limit)
+            .create(&path)?;
+
+        Ok(Self { db: Some(db), path })
+    }
 
-        let mut to_delete: Vec<Pointer> = Vec::new();
+    /// Writes to the scratch database aren't worth syncing, since it's
+    /// thrown away if the collection doesn't finish
+    fn begin_write(&self) -> anyhow::Result<WriteTransaction> {
+        let mut write = self.db.as_ref().unwrap().begin_write()?;
+        write.set_durability(Durability::None);
+        Ok(write)
+    }
+}
 
-        for item in objects_table.iter()? {
-            if let Ok((key, _value)) = item {
-                let key = key.value();
-                if !reachable.contains(&Pointer::Object(key.clone())) {
-                    to_delete.push(Pointer::Object(key));
-                }
-            }
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        drop(self.db.take());
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            debug!("Failed to remove {:?}: {}", self.path, e);
         }
+    }
+}
 
-        for item in maps_table.iter()? {
-            if let Ok((key, _value)) = item {
-                let key = key.value();
+/// A stored value with rows of its own, which are reachable when it is
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum Pointer {
+    Object(CloudstateObjectKey),
+    Map(CloudstateObjectKey),
+    Array(CloudstateObjectKey),
+    Set(CloudstateObjectKey),
+    Blob(CloudstateBlobKey),
+}
 
-                if !reachable.contains(&Pointer::Map(CloudstateObjectKey { id: key.id.clone() })) {
-                    to_delete.push(Pointer::MapField(key));
-                }
+impl Pointer {
+    /// What a stored value points to, if it references anything
+    pub fn from_data(data: &CloudstatePrimitiveData) -> Option<Self> {
+        match data {
+            CloudstatePrimitiveData::ObjectReference(obj_ref) => {
+                Some(Pointer::Object(CloudstateObjectKey { id: obj_ref.id }))
+            }
+            CloudstatePrimitiveData::MapReference(map_ref) => {
+                Some(Pointer::Map(CloudstateObjectKey { id: *map_ref }))
+            }
+            CloudstatePrimitiveData::ArrayReference(arr_ref) => {
+                Some(Pointer::Array(CloudstateObjectKey { id: *arr_ref }))
+            }
+            CloudstatePrimitiveData::SetReference(set_ref) => {
+                Some(Pointer::Set(CloudstateObjectKey { id: *set_ref }))
+            }
+            CloudstatePrimitiveData::Blob(blob) => {
+                Some(Pointer::Blob(CloudstateBlobKey { id: blob.id }))
             }
+            _ => None, /* These don't have references so they don't need anything */
         }
+    }
+
+    fn to_bytes(&self) -> [u8; 17] {
+        let (kind, id) = match self {
+            Pointer::Object(key) => (0, key.id),
+            Pointer::Map(key) => (1, key.id),
+            Pointer::Array(key) => (2, key.id),
+            Pointer::Set(key) => (3, key.id),
+            Pointer::Blob(key) => (4, key.id),
+        };
+
+        let mut bytes = [kind; 17];
+        bytes[1..].copy_from_slice(&id.0);
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Self {
+        let id = CloudstateId(bytes[1..].try_into().unwrap());
+        let key = CloudstateObjectKey { id };
+
+        match bytes[0] {
+            0 => Pointer::Object(key),
+            1 => Pointer::Map(key),
+            2 => Pointer::Array(key),
+            3 => Pointer::Set(key),
+            _ => Pointer::Blob(CloudstateBlobKey { id }),
+        }
+    }
+}
+
+fn push(stack: &mut Table<u64, &[u8]>, pointer: Pointer) -> anyhow::Result<()> {
+    let next = stack.last()?.map_or(0, |(index, _)| index.value() + 1);
+    stack.insert(next, pointer.to_bytes().as_slice())?;
+    Ok(())
+}
+
+/// Pushes the pointer for a stored value onto the mark stack, if it references anything
+fn push_reference(
+    stack: &mut Table<u64, &[u8]>,
+    data: CloudstatePrimitiveData,
+) -> anyhow::Result<()> {
+    match Pointer::from_data(&data) {
+        Some(pointer) => push(stack, pointer),
+        None => Ok(()),
+    }
+}
+
+fn push_roots(read: &Transaction, scratch: &WriteTransaction) -> anyhow::Result<()> {
+    let mut stack = scratch.open_table(STACK_TABLE)?;
+    let Some(roots) = read.try_open_table(ROOTS_TABLE)? else {
+        return Ok(());
+    };
+
+    for item in roots.iter()? {
+        let (_key, root) = item?;
+        push(
+            &mut stack,
+            Pointer::Object(CloudstateObjectKey { id: root.id }),
+        )?;
+    }
 
-        for item in arrays_table.iter()? {
-            if let Ok((key, _value)) = item {
-                let key = key.value();
+    Ok(())
+}
 
-                if !reachable.contains(&Pointer::Array(CloudstateObjectKey { id: key.id.clone() }))
-                {
-                    to_delete.push(Pointer::ArrayItem(key));
+/// Pushes whatever the rows written after sequence `seen` point to, along
+/// with what they belong to, and returns the sequence of the last change
+fn push_changed(write: &Transaction, scratch: &WriteTransaction, seen: u64) -> anyhow::Result<u64> {
+    let Transaction::Write(transaction, _) = write else {
+        return Ok(seen);
+    };
+    let log = transaction.open_table(raw(&CHANGES_TABLE))?;
+    let roots = write.open_table(ROOTS_TABLE)?;
+    let objects = write.open_table(OBJECTS_TABLE)?;
+    let maps = write.open_table(MAPS_TABLE)?;
+    let arrays = write.open_table(ARRAYS_TABLE)?;
+    let sets = write.open_table(SETS_TABLE)?;
+    let mut stack = scratch.open_table(STACK_TABLE)?;
+    let mut last = seen;
+
+    for item in log.range::<&[u8]>(&(seen + 1).to_le_bytes()[..]..)? {
+        let (sequence, change) = item?;
+        last = u64::decode(sequence.value())?;
+        let CloudstateChange { table, key } = Bincode::<CloudstateChange>::decode(change.value())?;
+
+        if table == ROOTS_TABLE.name() {
+            let key = Ordered::<CloudstateRootKey>::decode(&key)?;
+            if let Some(root) = roots.get(&key)? {
+                let id = root.id;
+                push(&mut stack, Pointer::Object(CloudstateObjectKey { id }))?;
+            }
+        } else if table == OBJECTS_TABLE.name() {
+            let key = Ordered::<CloudstateObjectKey>::decode(&key)?;
+            if let Some(object) = objects.get(&key)? {
+                for (_field, value) in object.data.fields {
+                    push_reference(&mut stack, value)?;
                 }
             }
+            push(&mut stack, Pointer::Object(key))?;
+        } else if table == MAPS_TABLE.name() {
+            let key = Ordered::<CloudstateMapFieldKey>::decode(&key)?;
+            if let Some(value) = maps.get(&key)? {
+                push_reference(&mut stack, value.data)?;
+            }
+            push(&mut stack, Pointer::Map(CloudstateObjectKey { id: key.id }))?;
+        } else if table == ARRAYS_TABLE.name() {
+            let key = Ordered::<CloudstateArrayItemKey>::decode(&key)?;
+            if let Some(value) = arrays.get(&key)? {
+                push_reference(&mut stack, value.data)?;
+            }
+            push(
+                &mut stack,
+                Pointer::Array(CloudstateObjectKey { id: key.id }),
+            )?;
+        } else if table == SETS_TABLE.name() {
+            let key = Bincode::<CloudstateSetItemKey>::decode(&key)?;
+            if let Some(value) = sets.get(&key)? {
+                push_reference(&mut stack, value.data)?;
+            }
+            push(&mut stack, Pointer::Set(CloudstateObjectKey { id: key.id }))?;
+        } else if table == BLOBS_TABLE.name() {
+            // a blob is stored before whatever references it
+            let key = Bincode::<CloudstateBlobKey>::decode(&key)?;
+            push(&mut stack, Pointer::Blob(key))?;
         }
+    }
 
-        info!("deleting {:?} items", to_delete.len());
-        for pointer in to_delete {
-            // delete key
-            let _: anyhow::Result<()> = match pointer {
-                Pointer::Object(key) => {
-                    let _ = objects_table.remove(&key)?;
-                    Ok(())
-                }
+    Ok(last)
+}
 
-                Pointer::MapField(field) => {
-                    let _ = maps_table.remove(&field)?;
-                    Ok(())
+/// Visits pointers from the stack until it's empty or `limit` have been
+/// visited, marking them and pushing what they point to. Returns how many
+/// were newly marked, and whether the stack is empty.
+fn mark(
+    transaction: &Transaction,
+    scratch: &WriteTransaction,
+    limit: usize,
+) -> anyhow::Result<(u64, bool)> {
+    let mut stack = scratch.open_table(STACK_TABLE)?;
+    let mut marked = scratch.open_table(MARKED_TABLE)?;
+
+    // a snapshot from before a table was created doesn't have it
+    let objects_table = transaction.try_open_table(OBJECTS_TABLE)?;
+    let map_table = transaction.try_open_table(MAPS_TABLE)?;
+    let arr_table = transaction.try_open_table(ARRAYS_TABLE)?;
+    let set_table = transaction.try_open_table(SETS_TABLE)?;
+
+    let mut count = 0;
+    for _ in 0..limit {
+        let bytes = match stack.pop_last()? {
+            Some((_index, bytes)) => bytes.value().to_vec(),
+            None => return Ok((count, true)),
+        };
+        if marked.insert(bytes.as_slice(), ())?.is_some() {
+            continue;
+        }
+        count += 1;
+
+        let pointer = Pointer::from_bytes(&bytes);
+        debug!("Pointer: {:?}", pointer);
+        match pointer {
+            Pointer::Object(object_key) => {
+                if let Some(ref objects_table) = objects_table {
+                    let Some(object) = objects_table.get(&object_key)? else {
+                        continue;
+                    };
+
+                    for (_key, value) in object.data.fields {
+                        push_reference(&mut stack, value)?;
+                    }
+                }
+            }
+            Pointer::Map(map_reference) => {
+                if let Some(ref map_table) = map_table {
+                    for item in map_table.range(CloudstateMapFieldKey::range(&map_reference.id))? {
+                        let (_key, value) = item?;
+                        push_reference(&mut stack, value.data)?;
+                    }
                 }
-                Pointer::ArrayItem(field) => {
-                    let _ = arrays_table.remove(&field)?;
-                    Ok(())
+            }
+            Pointer::Array(arr_ref) => {
+                if let Some(ref arr_table) = arr_table {
+                    for item in arr_table.range(CloudstateArrayItemKey::range(&arr_ref.id))? {
+                        let (_key, value) = item?;
+                        push_reference(&mut stack, value.data)?;
+                    }
                 }
-                _ => {
-                    // Array and Map don't exist without their reference or their items, so they don't need to be garbage collected
-                    Ok(())
+            }
+            Pointer::Set(set_ref) => {
+                if let Some(ref set_table) = set_table {
+                    for item in set_table.range(CloudstateSetItemKey::range(&set_ref.id))? {
+                        let (_key, value) = item?;
+                        push_reference(&mut stack, value.data)?;
+                    }
                 }
-            };
+            }
+            Pointer::Blob(_) => {}
         }
     }
-    tx.commit()?;
-    Ok(())
+
+    Ok((count, stack.is_empty()?))
+}
+
+#[derive(Debug, Default)]
+struct SweptRows {
+    scanned: u64,
+    /// The encoded keys of the rows deleted
+    deleted: Vec<Vec<u8>>,
+    /// Blobs whose rows were deleted, whose payloads still need to be
+    blobs: Vec<CloudstateId>,
+}
+
+/// How far the sweep has got: the index of the table it's on, and the
+/// encoded key of the last row it looked at there
+#[derive(Debug, Default)]
+struct SweepCursor {
+    table: usize,
+    after: Option<Vec<u8>>,
+}
+
+impl SweepCursor {
+    const TABLES: usize = 10;
+
+    fn done(&self) -> bool {
+        self.table >= Self::TABLES
+    }
+
+    /// Deletes the unmarked rows among the next `limit`, moving on to the
+    /// next table if this one runs out. A dry run only counts them.
+    fn sweep(
+        &mut self,
+        write: &Transaction,
+        marked: &impl ReadableTable<&'static [u8], ()>,
+        limit: usize,
+        dry_run: bool,
+    ) -> anyhow::Result<SweptRows> {
+        let after = self.after.as_deref();
+        let (swept, last) = match self.table {
+            0 => sweep_rows(
+                write,
+                OBJECTS_TABLE,
+                after,
+                limit,
+                marked,
+                dry_run,
+                |key, _| Pointer::Object(key),
+            )?,
+            1 => sweep_rows(
+                write,
+                OBJECT_IDS_INDEX,
+                after,
+                limit,
+                marked,
+                dry_run,
+                |_, owner| Pointer::Object(owner),
+            )?,
+            2 => sweep_rows(
+                write,
+                FIELD_INDEX_TABLE,
+                after,
+                limit,
+                marked,
+                dry_run,
+                |key, _| Pointer::Object(CloudstateObjectKey { id: key.object_id }),
+            )?,
+            3 => sweep_rows(
+                write,
+                CLASS_VERSIONS_INDEX,
+                after,
+                limit,
+                marked,
+                dry_run,
+                |key, _| Pointer::Object(CloudstateObjectKey { id: key.object_id }),
+            )?,
+            4 => sweep_rows(
+                write,
+                MAPS_TABLE,
+                after,
+                limit,
+                marked,
+                dry_run,
+                |key, _| Pointer::Map(CloudstateObjectKey { id: key.id }),
+            )?,
+            5 => sweep_rows(
+                write,
+                ARRAYS_TABLE,
+                after,
+                limit,
+                marked,
+                dry_run,
+                |key, _| Pointer::Array(CloudstateObjectKey { id: key.id }),
+            )?,
+            6 => sweep_rows(
+                write,
+                ARRAY_METADATA_TABLE,
+                after,
+                limit,
+                marked,
+                dry_run,
+                |key, _| Pointer::Array(CloudstateObjectKey { id: key.id }),
+            )?,
+            7 => sweep_rows(
+                write,
+                SETS_TABLE,
+                after,
+                limit,
+                marked,
+                dry_run,
+                |key, _| Pointer::Set(CloudstateObjectKey { id: key.id }),
+            )?,
+            // counts left behind by cycles, which never drop to zero
+            8 => sweep_rows(
+                write,
+                REFERENCE_COUNTS_TABLE,
+                after,
+                limit,
+                marked,
+                dry_run,
+                |key, _| key,
+            )?,
+            9 => {
+                let (mut swept, last) = sweep_rows(
+                    write,
+                    BLOBS_TABLE,
+                    after,
+                    limit,
+                    marked,
+                    dry_run,
+                    |key, _| Pointer::Blob(key),
+                )?;
+                swept.blobs = swept
+                    .deleted
+                    .iter()
+                    .map(|key| Ok(Bincode::<CloudstateBlobKey>::decode(key)?.id))
+                    .collect::<Result<_, CorruptRow>>()?;
+                (swept, last)
+            }
+            _ => (SweptRows::default(), None),
+        };
+
+        // rows written behind the cursor since were marked when they were
+        // written, so there's no need to come back for them
+        if swept.scanned < limit as u64 {
+            self.table += 1;
+            self.after = None;
+        } else {
+            self.after = last;
+        }
+
+        Ok(swept)
+    }
+}
+
+/// Deletes the rows among the next `limit` after the encoded key `after`
+/// whose owner isn't marked, or only finds them in a dry run. Returns what it
+/// did and the encoded key of the last row it looked at.
+fn sweep_rows<K: Key + Decode + 'static, V: Decode + 'static>(
+    write: &Transaction,
+    definition: TableDefinition<K, V>,
+    after: Option<&[u8]>,
+    limit: usize,
+    marked: &impl ReadableTable<&'static [u8], ()>,
+    dry_run: bool,
+    owner: impl Fn(K::Decoded, V::Decoded) -> Pointer,
+) -> anyhow::Result<(SweptRows, Option<Vec<u8>>)> {
+    let mut table = write.open_table(definition)?;
+    let mut swept = SweptRows::default();
+    let mut last = None;
+
+    let start = after.map_or(Bound::Unbounded, Bound::Excluded);
+    let mut rows = table.encoded_range((start, Bound::Unbounded))?;
+    while swept.scanned < limit as u64 {
+        let Some(item) = rows.next_encoded() else {
+            break;
+        };
+        let (bytes, key, value) = item?;
+        swept.scanned += 1;
+
+        if marked
+            .get(owner(key, value).to_bytes().as_slice())?
+            .is_none()
+        {
+            swept.deleted.push(bytes.clone());
+        }
+        last = Some(bytes);
+    }
+    drop(rows);
+
+    if dry_run {
+        return Ok((swept, last));
+    }
+
+    debug!(
+        "Deleting {} rows from {}",
+        swept.deleted.len(),
+        definition.name()
+    );
+    for key in &swept.deleted {
+        table.remove_encoded(key)?;
+    }
+
+    Ok((swept, last))
 }
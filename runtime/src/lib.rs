@@ -1,18 +1,29 @@
 pub mod backup;
 pub mod bincode;
 pub mod blob_storage;
+pub mod changes;
+pub mod check;
 pub mod cloudstate_extensions;
 pub mod execution;
+pub mod export;
 pub mod extensions;
 pub mod gc;
+pub mod migrations;
+pub mod ordered;
 pub mod permissions;
 pub mod print;
+pub mod refcount;
+pub mod snapshot;
 pub mod tables;
 pub mod transpile;
 
 #[macro_use]
 pub mod v8_macros;
 
+use extensions::cloudstate::{RequestBody, ResponseSink};
+use serde::Serialize;
+use std::collections::HashMap;
+
 #[cfg(test)]
 mod tests;
 
@@ -21,3 +32,27 @@ pub struct ServerInfo {
     pub deployment_id: Option<String>,
     pub domain: Option<String>,
 }
+
+/// What a request passes to the script serving it, which reads it with
+/// `__requestArgs()` instead of having it spliced into its source
+#[derive(Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RequestArgs {
+    /// The id of the root or object the request is for
+    pub id: String,
+    /// The method being called, for method requests
+    pub method: String,
+    pub params: Vec<serde_json::Value>,
+    /// The HTTP method, for fetch requests
+    pub http_method: String,
+    pub uri: String,
+    pub headers: Vec<(String, String)>,
+    pub env: HashMap<String, String>,
+    pub invalidate_endpoint: String,
+    /// The request's body, for fetch requests
+    #[serde(skip)]
+    pub body: Option<RequestBody>,
+    /// Where the response goes, for fetch requests
+    #[serde(skip)]
+    pub response: Option<ResponseSink>,
+}
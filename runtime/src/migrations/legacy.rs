@@ -0,0 +1,125 @@
+//! The stored types as they were before ids were 16 byte values, when they
+//! were the strings `uuidv4()` made in JavaScript. Migrations from those
+//! formats read and write these, since the current types have moved on.
+
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use crate::extensions::cloudstate::CloudstateMapKey;
+use crate::ordered::OrderedKey;
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub enum PrimitiveData {
+    Number(f64),
+    String(String),
+    Boolean(bool),
+    BigInt(Box<[u64]>),
+    Undefined,
+    Null,
+    Date(DateTime<Utc>),
+    Blob(IdKey),
+    Url(Url),
+    ObjectReference(IdKey),
+    MapReference(String),
+    ArrayReference(String),
+    SetReference(String),
+}
+
+/// Any key, value or reference that was only an id: object keys, root
+/// values, `object_ids` values, array headers, blob keys and references to
+/// objects and blobs
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct IdKey {
+    pub id: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ObjectValue {
+    pub data: ObjectData,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ObjectData {
+    pub fields: HashMap<String, PrimitiveData>,
+    pub constructor_name: Option<String>,
+    pub version: u32,
+}
+
+/// Values of map entries, array items and set members
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ItemValue {
+    pub data: PrimitiveData,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MapFieldKey {
+    pub id: String,
+    pub field: CloudstateMapKey,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ArrayItemKey {
+    pub id: String,
+    pub index: i32,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SetItemKey {
+    pub id: String,
+    /// The member serialized as a `PrimitiveData`
+    pub item: Vec<u8>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FieldIndexKey {
+    pub class_name: String,
+    pub field: String,
+    pub value: Vec<u8>,
+    pub object_id: String,
+}
+
+// the order-preserving encodings of format version 4, which start with the id
+// as an escaped string
+
+impl OrderedKey for IdKey {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.id.encode(out);
+    }
+
+    fn decode(input: &mut &[u8]) -> Result<Self, &'static str> {
+        Ok(IdKey {
+            id: String::decode(input)?,
+        })
+    }
+}
+
+impl OrderedKey for MapFieldKey {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.id.encode(out);
+        self.field.encode(out);
+    }
+
+    fn decode(input: &mut &[u8]) -> Result<Self, &'static str> {
+        Ok(MapFieldKey {
+            id: String::decode(input)?,
+            field: CloudstateMapKey::decode(input)?,
+        })
+    }
+}
+
+impl OrderedKey for ArrayItemKey {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.id.encode(out);
+        self.index.encode(out);
+    }
+
+    fn decode(input: &mut &[u8]) -> Result<Self, &'static str> {
+        Ok(ArrayItemKey {
+            id: String::decode(input)?,
+            index: i32::decode(input)?,
+        })
+    }
+}
@@ -0,0 +1,769 @@
+use std::{collections::HashMap, fmt::Debug, marker::PhantomData};
+
+use anyhow::bail;
+use bincode::Options;
+use redb::{
+    Database, Key, ReadableTable, TableDefinition, TableHandle, TypeName, Value, WriteTransaction,
+};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use tracing::{info, warn};
+
+use crate::bincode::Bincode;
+use crate::blob_storage::CloudstateBlobStorage;
+use crate::changes::CloudstateChange;
+use crate::extensions::cloudstate::{
+    Blob, CloudstateArrayItemKey, CloudstateArrayItemValue, CloudstateArrayMetadataKey,
+    CloudstateArrayMetadataValue, CloudstateBlobKey, CloudstateClassVersionKey,
+    CloudstateFieldIndexKey, CloudstateId, CloudstateMapFieldKey, CloudstateMapFieldValue,
+    CloudstateMapKey, CloudstateObjectData, CloudstateObjectIdIndexKey, CloudstateObjectKey,
+    CloudstateObjectValue, CloudstatePrimitiveData, CloudstateRootKey, CloudstateRootValue,
+    CloudstateSetItemKey, CloudstateSetItemValue, ObjectReference,
+};
+use crate::ordered::{Ordered, OrderedKey};
+use crate::tables::{
+    ARRAY_METADATA_TABLE, ARRAYS_TABLE, BLOBS_TABLE, CHANGE_SEQUENCES_TABLE, CHANGES_TABLE,
+    CLASS_VERSIONS_INDEX, FIELD_INDEX_TABLE, MAPS_TABLE, METADATA_TABLE, OBJECT_IDS_INDEX,
+    OBJECTS_TABLE, ROOTS_TABLE, SETS_TABLE,
+};
+
+pub(crate) mod legacy;
+
+/// The version of the on-disk format this build reads and writes. Bump it
+/// and add a migration to `MIGRATIONS` whenever a stored type changes.
+pub const FORMAT_VERSION: u64 = 8;
+
+/// Databases written before the format was versioned
+const UNVERSIONED: u64 = 1;
+
+const FORMAT_VERSION_KEY: &str = "format_version";
+
+struct Migration {
+    /// The version the database is at after this runs
+    version: u64,
+    description: &'static str,
+    run: fn(&WriteTransaction, &mut BlobMoves) -> anyhow::Result<()>,
+}
+
+/// Blob payloads live outside the database, under the text of their id, so
+/// migrations that change a blob's id copy its payload over. The old copies
+/// are only deleted once the migration has committed.
+struct BlobMoves<'a> {
+    storage: &'a CloudstateBlobStorage,
+    moved: Vec<String>,
+}
+
+impl BlobMoves<'_> {
+    fn copy(&mut self, legacy_id: &str, id: &CloudstateId) -> anyhow::Result<()> {
+        if self.storage.copy_legacy_blob_data(legacy_id, id)? {
+            self.moved.push(legacy_id.to_string());
+        } else {
+            warn!("Blob {:?} has no payload to move to {}", legacy_id, id);
+        }
+        Ok(())
+    }
+}
+
+/// Run in order on databases older than their version
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 2,
+        description: "store map keys as typed values",
+        run: migrate_typed_map_keys,
+    },
+    Migration {
+        version: 3,
+        description: "record the class version of objects",
+        run: migrate_object_class_versions,
+    },
+    Migration {
+        version: 4,
+        description: "store keys in an order-preserving encoding",
+        run: migrate_ordered_keys,
+    },
+    Migration {
+        version: 5,
+        description: "store ids as 16 byte values",
+        run: migrate_binary_ids,
+    },
+    Migration {
+        version: 6,
+        description: "allow reference counts",
+        run: migrate_reference_counts,
+    },
+    Migration {
+        version: 7,
+        description: "index every object by its id field",
+        run: migrate_object_id_index,
+    },
+    Migration {
+        version: 8,
+        description: "index objects of custom classes by their class version",
+        run: migrate_class_version_index,
+    },
+];
+
+/// The format version recorded in the database, if there is one
+pub fn format_version(write: &WriteTransaction) -> anyhow::Result<Option<u64>> {
+    let metadata = write.open_table(METADATA_TABLE)?;
+    let version = metadata.get(FORMAT_VERSION_KEY)?.map(|value| value.value());
+    Ok(version)
+}
+
+/// The format version of the database, refusing databases written by a
+/// newer version of cloudstate
+fn supported_version(write: &WriteTransaction) -> anyhow::Result<u64> {
+    let version = match format_version(write)? {
+        Some(version) => version,
+        // a database without any tables is new
+        None if write
+            .list_tables()?
+            .all(|table| table.name() == METADATA_TABLE.name()) =>
+        {
+            FORMAT_VERSION
+        }
+        None => UNVERSIONED,
+    };
+
+    if version > FORMAT_VERSION {
+        bail!(
+            "The database is in format version {}, but this version of cloudstate only supports up to {}. Upgrade cloudstate to open it.",
+            version,
+            FORMAT_VERSION
+        );
+    }
+
+    Ok(version)
+}
+
+/// Checks the database is in `FORMAT_VERSION` before it's used, recording the
+/// version in new databases. Older databases are refused too, since their
+/// rows wouldn't decode, and have to go through `migrate` first.
+pub fn check_format_version(db: &Database) -> anyhow::Result<()> {
+    let write = db.begin_write()?;
+    let version = supported_version(&write)?;
+    if version < FORMAT_VERSION {
+        bail!(
+            "The database is in format version {}, but this version of cloudstate needs {}. Migrate it before opening it.",
+            version,
+            FORMAT_VERSION
+        );
+    }
+
+    write
+        .open_table(METADATA_TABLE)?
+        .insert(FORMAT_VERSION_KEY, FORMAT_VERSION)?;
+    write.commit()?;
+
+    Ok(())
+}
+
+/// Brings the database up to `FORMAT_VERSION`, refusing to touch databases
+/// written by a newer version of cloudstate. `blob_storage` is where the
+/// database's blob payloads are, which move if their ids change.
+pub fn migrate(db: &Database, blob_storage: &CloudstateBlobStorage) -> anyhow::Result<()> {
+    let write = db.begin_write()?;
+    let version = supported_version(&write)?;
+
+    let mut blobs = BlobMoves {
+        storage: blob_storage,
+        moved: Vec::new(),
+    };
+    for migration in MIGRATIONS.iter().filter(|m| m.version > version) {
+        info!(
+            "Migrating database to format version {}: {}",
+            migration.version, migration.description
+        );
+        (migration.run)(&write, &mut blobs)?;
+    }
+
+    write
+        .open_table(METADATA_TABLE)?
+        .insert(FORMAT_VERSION_KEY, FORMAT_VERSION)?;
+    write.commit()?;
+
+    for legacy_id in blobs.moved {
+        if let Err(e) = blob_storage.delete_legacy_blob_data(&legacy_id) {
+            warn!(
+                "Failed to delete the old payload of blob {:?}: {}",
+                legacy_id, e
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Reads and writes rows as bytes in a table of `T`, for when the type `T`
+/// encodes has changed since. Keys compare as bytes, which may not be how the
+/// table was sorted, so these tables are only iterated and rebuilt.
+#[derive(Debug)]
+struct Raw<T>(PhantomData<T>);
+
+impl<T: Value + 'static> Value for Raw<T> {
+    type SelfType<'a>
+        = &'a [u8]
+    where
+        Self: 'a;
+
+    type AsBytes<'a>
+        = &'a [u8]
+    where
+        Self: 'a;
+
+    fn fixed_width() -> Option<usize> {
+        T::fixed_width()
+    }
+
+    fn from_bytes<'a>(data: &'a [u8]) -> Self::SelfType<'a>
+    where
+        Self: 'a,
+    {
+        data
+    }
+
+    fn as_bytes<'a, 'b: 'a>(value: &'a Self::SelfType<'b>) -> Self::AsBytes<'a>
+    where
+        Self: 'a,
+        Self: 'b,
+    {
+        value
+    }
+
+    fn type_name() -> TypeName {
+        // matches `T` so redb opens the table
+        T::type_name()
+    }
+}
+
+impl<T: Value + 'static> Key for Raw<T> {
+    fn compare(data1: &[u8], data2: &[u8]) -> std::cmp::Ordering {
+        data1.cmp(data2)
+    }
+}
+
+/// How map field keys were stored before they kept their type
+#[derive(Serialize, Deserialize)]
+struct StringMapFieldKey {
+    id: String,
+    field: String,
+}
+
+/// Map keys used to be strings, so rewrites each key as a string key. Rows
+/// already in the typed format are kept as they are.
+fn migrate_typed_map_keys(write: &WriteTransaction, _blobs: &mut BlobMoves) -> anyhow::Result<()> {
+    let maps: TableDefinition<
+        Raw<Bincode<CloudstateMapFieldKey>>,
+        Raw<Bincode<CloudstateMapFieldValue>>,
+    > = TableDefinition::new(MAPS_TABLE.name());
+
+    // typed keys would otherwise sometimes read as a string key followed by
+    // leftover bytes
+    let legacy = legacy_options();
+
+    let mut rows = Vec::new();
+    {
+        let table = write.open_table(maps)?;
+        for item in table.iter()? {
+            let (key, value) = item?;
+            let key = match legacy.deserialize::<StringMapFieldKey>(key.value()) {
+                Ok(StringMapFieldKey { id, field }) => legacy::MapFieldKey {
+                    id,
+                    field: CloudstateMapKey::String(field),
+                },
+                Err(_) => bincode::deserialize(key.value())?,
+            };
+            // values haven't changed, so they're copied as they are
+            rows.push((bincode::serialize(&key)?, value.value().to_vec()));
+        }
+    }
+
+    // the keys sort differently now, so the table is rebuilt
+    rebuild_raw(write, maps, rows)
+}
+
+/// Options matching `bincode::deserialize`, except that rows in the new format
+/// don't also read as the old one with bytes left over
+fn legacy_options() -> impl Options + Copy {
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .reject_trailing_bytes()
+}
+
+/// How objects were stored before they recorded their class version
+#[derive(Serialize, Deserialize)]
+struct UnversionedObjectValue {
+    data: UnversionedObjectData,
+}
+
+#[derive(Serialize, Deserialize)]
+struct UnversionedObjectData {
+    fields: HashMap<String, legacy::PrimitiveData>,
+    constructor_name: Option<String>,
+}
+
+/// Objects written before classes had versions are at version 0
+fn migrate_object_class_versions(
+    write: &WriteTransaction,
+    _blobs: &mut BlobMoves,
+) -> anyhow::Result<()> {
+    let objects: TableDefinition<
+        Raw<Bincode<CloudstateObjectKey>>,
+        Raw<Bincode<CloudstateObjectValue>>,
+    > = TableDefinition::new(OBJECTS_TABLE.name());
+
+    let mut rows = Vec::new();
+    {
+        let table = write.open_table(objects)?;
+        for item in table.iter()? {
+            let (key, value) = item?;
+            let value = match legacy_options().deserialize(value.value()) {
+                Ok(UnversionedObjectValue { data }) => bincode::serialize(&legacy::ObjectValue {
+                    data: legacy::ObjectData {
+                        fields: data.fields,
+                        constructor_name: data.constructor_name,
+                        version: 0,
+                    },
+                })?,
+                Err(_) => value.value().to_vec(),
+            };
+            rows.push((key.value().to_vec(), value));
+        }
+    }
+
+    rebuild_raw(write, objects, rows)
+}
+
+/// Keys of the largest tables used to be bincode, which redb had to decode on
+/// every comparison
+fn migrate_ordered_keys(write: &WriteTransaction, _blobs: &mut BlobMoves) -> anyhow::Result<()> {
+    let (roots, objects, maps, arrays) = (ROOTS_TABLE, OBJECTS_TABLE, MAPS_TABLE, ARRAYS_TABLE);
+    reencode_keys::<CloudstateRootKey, _, _>(write, roots)?;
+    reencode_keys::<legacy::IdKey, _, _>(write, objects)?;
+    reencode_keys::<legacy::MapFieldKey, _, _>(write, maps)?;
+    reencode_keys::<legacy::ArrayItemKey, _, _>(write, arrays)?;
+
+    // the change log refers to rows by their key bytes, so it is rebuilt with
+    // the new keys for incremental backups to find them
+    let mut changes = Vec::new();
+    for item in write.open_table(CHANGES_TABLE)?.iter()? {
+        let (sequence, change) = item?;
+        let CloudstateChange { table, key } = change.value();
+        let key = if table == roots.name() {
+            reencode_key::<CloudstateRootKey>(&key)?
+        } else if table == objects.name() {
+            reencode_key::<legacy::IdKey>(&key)?
+        } else if table == maps.name() {
+            reencode_key::<legacy::MapFieldKey>(&key)?
+        } else if table == arrays.name() {
+            reencode_key::<legacy::ArrayItemKey>(&key)?
+        } else {
+            key
+        };
+        changes.push((sequence.value(), CloudstateChange { table, key }));
+    }
+
+    rebuild_change_log(write, changes)
+}
+
+/// Replaces the change log with the given changes, keyed by their sequence
+fn rebuild_change_log(
+    write: &WriteTransaction,
+    changes: Vec<(u64, CloudstateChange)>,
+) -> anyhow::Result<()> {
+    write.delete_table(CHANGES_TABLE)?;
+    write.delete_table(CHANGE_SEQUENCES_TABLE)?;
+    let mut log = write.open_table(CHANGES_TABLE)?;
+    let mut sequences = write.open_table(CHANGE_SEQUENCES_TABLE)?;
+    for (sequence, change) in changes {
+        sequences.insert(&change, sequence)?;
+        log.insert(sequence, change)?;
+    }
+
+    Ok(())
+}
+
+fn reencode_key<K: OrderedKey + DeserializeOwned>(key: &[u8]) -> anyhow::Result<Vec<u8>> {
+    Ok(Ordered::encode(&bincode::deserialize::<K>(key)?))
+}
+
+/// Rebuilds a table of `Ordered<K>` keys, whose keys were stored as bincode,
+/// reading them as `L`, how `K` was stored at the time. Values are copied as
+/// they are.
+fn reencode_keys<L, K, V>(
+    write: &WriteTransaction,
+    definition: TableDefinition<Ordered<K>, V>,
+) -> anyhow::Result<()>
+where
+    L: OrderedKey + DeserializeOwned,
+    K: OrderedKey + Debug + Serialize + DeserializeOwned + 'static,
+    V: Value + 'static,
+{
+    let bincode_keys: TableDefinition<Raw<Bincode<K>>, Raw<V>> =
+        TableDefinition::new(definition.name());
+    let ordered_keys: TableDefinition<Raw<Ordered<K>>, Raw<V>> =
+        TableDefinition::new(definition.name());
+
+    let mut rows = Vec::new();
+    {
+        let table = write.open_table(bincode_keys)?;
+        for item in table.iter()? {
+            let (key, value) = item?;
+            rows.push((reencode_key::<L>(key.value())?, value.value().to_vec()));
+        }
+    }
+
+    write.delete_table(bincode_keys)?;
+    // ordered keys compare as bytes, so these are sorted the way they'll be read
+    rebuild_raw(write, ordered_keys, rows)
+}
+
+/// Replaces the rows of a table with rows already encoded for it
+fn rebuild_raw<K: Value + 'static, V: Value + 'static>(
+    write: &WriteTransaction,
+    definition: TableDefinition<Raw<K>, Raw<V>>,
+    rows: Vec<(Vec<u8>, Vec<u8>)>,
+) -> anyhow::Result<()> {
+    write.delete_table(definition)?;
+    let mut table = write.open_table(definition)?;
+    for (key, value) in rows {
+        table.insert(key.as_slice(), value.as_slice())?;
+    }
+
+    Ok(())
+}
+
+/// Gives each string id its 16 byte id. Ids made by `uuidv4()` are UUIDs and
+/// keep their value, anything else gets a new random id, the same one
+/// everywhere it appears.
+#[derive(Default)]
+struct IdMap {
+    replaced: HashMap<String, CloudstateId>,
+    /// Blobs whose id no longer reads the same as text, so whose payloads
+    /// have to move
+    renamed_blobs: HashMap<String, CloudstateId>,
+}
+
+impl IdMap {
+    fn get(&mut self, id: &str) -> CloudstateId {
+        match id.parse() {
+            Ok(id) => id,
+            Err(_) => *self
+                .replaced
+                .entry(id.to_string())
+                .or_insert_with(CloudstateId::random),
+        }
+    }
+
+    fn primitive(&mut self, data: legacy::PrimitiveData) -> CloudstatePrimitiveData {
+        match data {
+            legacy::PrimitiveData::Number(value) => CloudstatePrimitiveData::Number(value),
+            legacy::PrimitiveData::String(value) => CloudstatePrimitiveData::String(value),
+            legacy::PrimitiveData::Boolean(value) => CloudstatePrimitiveData::Boolean(value),
+            legacy::PrimitiveData::BigInt(value) => CloudstatePrimitiveData::BigInt(value),
+            legacy::PrimitiveData::Undefined => CloudstatePrimitiveData::Undefined,
+            legacy::PrimitiveData::Null => CloudstatePrimitiveData::Null,
+            legacy::PrimitiveData::Date(value) => CloudstatePrimitiveData::Date(value),
+            legacy::PrimitiveData::Blob(blob) => CloudstatePrimitiveData::Blob(Blob {
+                id: self.blob(&blob.id),
+            }),
+            legacy::PrimitiveData::Url(value) => CloudstatePrimitiveData::Url(value),
+            legacy::PrimitiveData::ObjectReference(reference) => {
+                CloudstatePrimitiveData::ObjectReference(ObjectReference {
+                    id: self.get(&reference.id),
+                })
+            }
+            legacy::PrimitiveData::MapReference(id) => {
+                CloudstatePrimitiveData::MapReference(self.get(&id))
+            }
+            legacy::PrimitiveData::ArrayReference(id) => {
+                CloudstatePrimitiveData::ArrayReference(self.get(&id))
+            }
+            legacy::PrimitiveData::SetReference(id) => {
+                CloudstatePrimitiveData::SetReference(self.get(&id))
+            }
+        }
+    }
+
+    /// Blob payloads are stored under the text of their id, so blobs whose
+    /// id reads differently now are recorded to have their payloads moved
+    fn blob(&mut self, id: &str) -> CloudstateId {
+        let new_id = self.get(id);
+        if new_id.to_string() != id {
+            self.renamed_blobs.insert(id.to_string(), new_id);
+        }
+        new_id
+    }
+
+    fn object_key(&mut self, key: &[u8]) -> anyhow::Result<CloudstateObjectKey> {
+        let legacy::IdKey { id } = Ordered::<legacy::IdKey>::decode(key)?;
+        Ok(CloudstateObjectKey { id: self.get(&id) })
+    }
+
+    fn map_key(&mut self, key: &[u8]) -> anyhow::Result<CloudstateMapFieldKey> {
+        let legacy::MapFieldKey { id, field } = Ordered::<legacy::MapFieldKey>::decode(key)?;
+        Ok(CloudstateMapFieldKey {
+            id: self.get(&id),
+            field,
+        })
+    }
+
+    fn array_key(&mut self, key: &[u8]) -> anyhow::Result<CloudstateArrayItemKey> {
+        let legacy::ArrayItemKey { id, index } = Ordered::<legacy::ArrayItemKey>::decode(key)?;
+        Ok(CloudstateArrayItemKey {
+            id: self.get(&id),
+            index,
+        })
+    }
+
+    fn array_metadata_key(&mut self, key: &[u8]) -> anyhow::Result<CloudstateArrayMetadataKey> {
+        let legacy::IdKey { id } = bincode::deserialize(key)?;
+        Ok(CloudstateArrayMetadataKey { id: self.get(&id) })
+    }
+
+    fn set_key(&mut self, key: &[u8]) -> anyhow::Result<CloudstateSetItemKey> {
+        let legacy::SetItemKey { id, item } = bincode::deserialize(key)?;
+        // members that are references hold ids too
+        let item = self.primitive(bincode::deserialize(&item)?);
+        Ok(CloudstateSetItemKey::new(self.get(&id), &item))
+    }
+
+    fn blob_key(&mut self, key: &[u8]) -> anyhow::Result<CloudstateBlobKey> {
+        let legacy::IdKey { id } = bincode::deserialize(key)?;
+        Ok(CloudstateBlobKey { id: self.blob(&id) })
+    }
+
+    fn field_index_key(&mut self, key: &[u8]) -> anyhow::Result<CloudstateFieldIndexKey> {
+        let legacy::FieldIndexKey {
+            class_name,
+            field,
+            value,
+            object_id,
+        } = bincode::deserialize(key)?;
+        // references aren't indexed, so the value's bytes haven't changed
+        Ok(CloudstateFieldIndexKey {
+            class_name,
+            field,
+            value,
+            object_id: self.get(&object_id),
+        })
+    }
+}
+
+/// Ids used to be the 36 character strings `uuidv4()` made from
+/// `Math.random()`, repeated in every key and reference
+fn migrate_binary_ids(write: &WriteTransaction, blobs: &mut BlobMoves) -> anyhow::Result<()> {
+    let mut ids = IdMap::default();
+
+    rebuild_table(write, ROOTS_TABLE, |key, value| {
+        let legacy::IdKey { id } = bincode::deserialize(value)?;
+        Ok((
+            Ordered::<CloudstateRootKey>::decode(key)?,
+            CloudstateRootValue { id: ids.get(&id) },
+        ))
+    })?;
+
+    rebuild_table(write, OBJECTS_TABLE, |key, value| {
+        let legacy::ObjectValue { data } = bincode::deserialize(value)?;
+        let fields = data
+            .fields
+            .into_iter()
+            .map(|(name, value)| (name, ids.primitive(value)))
+            .collect();
+        Ok((
+            ids.object_key(key)?,
+            CloudstateObjectValue {
+                data: CloudstateObjectData {
+                    fields,
+                    constructor_name: data.constructor_name,
+                    version: data.version,
+                },
+            },
+        ))
+    })?;
+
+    rebuild_table(write, OBJECT_IDS_INDEX, |key, value| {
+        let legacy::IdKey { id } = bincode::deserialize(value)?;
+        Ok((
+            bincode::deserialize::<CloudstateObjectIdIndexKey>(key)?,
+            CloudstateObjectKey { id: ids.get(&id) },
+        ))
+    })?;
+
+    rebuild_table(write, FIELD_INDEX_TABLE, |key, _| {
+        Ok((ids.field_index_key(key)?, ()))
+    })?;
+
+    rebuild_table(write, MAPS_TABLE, |key, value| {
+        let legacy::ItemValue { data } = bincode::deserialize(value)?;
+        Ok((
+            ids.map_key(key)?,
+            CloudstateMapFieldValue {
+                data: ids.primitive(data),
+            },
+        ))
+    })?;
+
+    rebuild_table(write, ARRAYS_TABLE, |key, value| {
+        let legacy::ItemValue { data } = bincode::deserialize(value)?;
+        Ok((
+            ids.array_key(key)?,
+            CloudstateArrayItemValue {
+                data: ids.primitive(data),
+            },
+        ))
+    })?;
+
+    rebuild_table(write, ARRAY_METADATA_TABLE, |key, value| {
+        Ok((
+            ids.array_metadata_key(key)?,
+            bincode::deserialize::<CloudstateArrayMetadataValue>(value)?,
+        ))
+    })?;
+
+    rebuild_table(write, SETS_TABLE, |key, value| {
+        let legacy::ItemValue { data } = bincode::deserialize(value)?;
+        Ok((
+            ids.set_key(key)?,
+            CloudstateSetItemValue {
+                data: ids.primitive(data),
+            },
+        ))
+    })?;
+
+    rebuild_table(write, BLOBS_TABLE, |key, value| {
+        Ok((ids.blob_key(key)?, bincode::deserialize(value)?))
+    })?;
+
+    // the change log refers to rows by their key bytes, which have changed
+    // for every table holding ids in its keys
+    let mut changes = Vec::new();
+    for item in write.open_table(CHANGES_TABLE)?.iter()? {
+        let (sequence, change) = item?;
+        let CloudstateChange { table, key } = change.value();
+        let key = if table == OBJECTS_TABLE.name() {
+            Ordered::encode(&ids.object_key(&key)?)
+        } else if table == FIELD_INDEX_TABLE.name() {
+            bincode::serialize(&ids.field_index_key(&key)?)?
+        } else if table == MAPS_TABLE.name() {
+            Ordered::encode(&ids.map_key(&key)?)
+        } else if table == ARRAYS_TABLE.name() {
+            Ordered::encode(&ids.array_key(&key)?)
+        } else if table == ARRAY_METADATA_TABLE.name() {
+            bincode::serialize(&ids.array_metadata_key(&key)?)?
+        } else if table == SETS_TABLE.name() {
+            bincode::serialize(&ids.set_key(&key)?)?
+        } else if table == BLOBS_TABLE.name() {
+            bincode::serialize(&ids.blob_key(&key)?)?
+        } else {
+            key
+        };
+        changes.push((sequence.value(), CloudstateChange { table, key }));
+    }
+
+    if !ids.replaced.is_empty() {
+        info!(
+            "Gave new ids to {} objects and collections whose ids weren't UUIDs",
+            ids.replaced.len()
+        );
+    }
+
+    for (legacy_id, id) in &ids.renamed_blobs {
+        blobs.copy(legacy_id, id)?;
+    }
+
+    rebuild_change_log(write, changes)
+}
+
+/// Rebuilds a table from its rows in an older format, which `convert` turns
+/// into rows of the current types
+fn rebuild_table<K, V>(
+    write: &WriteTransaction,
+    definition: TableDefinition<K, V>,
+    mut convert: impl FnMut(
+        &[u8],
+        &[u8],
+    ) -> anyhow::Result<(K::SelfType<'static>, V::SelfType<'static>)>,
+) -> anyhow::Result<()>
+where
+    K: Key + 'static,
+    V: Value + 'static,
+{
+    let raw: TableDefinition<Raw<K>, Raw<V>> = TableDefinition::new(definition.name());
+
+    let mut rows = Vec::new();
+    {
+        let table = write.open_table(raw)?;
+        for item in table.iter()? {
+            let (key, value) = item?;
+            rows.push(convert(key.value(), value.value())?);
+        }
+    }
+
+    write.delete_table(raw)?;
+    let mut table = write.open_table(definition)?;
+    for (key, value) in rows {
+        table.insert(&key, &value)?;
+    }
+
+    Ok(())
+}
+
+/// Nothing is stored differently, but older versions of cloudstate would
+/// leave reference counts out of date, so they mustn't open databases that
+/// might keep them
+fn migrate_reference_counts(
+    _write: &WriteTransaction,
+    _blobs: &mut BlobMoves,
+) -> anyhow::Result<()> {
+    Ok(())
+}
+
+/// Objects written before the `id` field was indexed could only be found by
+/// scanning, so indexes every object with a string `id`. Entries already in
+/// the index were written since and are kept, otherwise the first object with
+/// the id wins, like the scan.
+fn migrate_object_id_index(write: &WriteTransaction, _blobs: &mut BlobMoves) -> anyhow::Result<()> {
+    let objects = write.open_table(OBJECTS_TABLE)?;
+    let mut index = write.open_table(OBJECT_IDS_INDEX)?;
+
+    let mut indexed = 0;
+    for item in objects.iter()? {
+        let (key, object) = item?;
+        let Some(CloudstatePrimitiveData::String(id)) = object.value().data.fields.remove("id")
+        else {
+            continue;
+        };
+
+        let index_key = CloudstateObjectIdIndexKey { id };
+        if index.get(&index_key)?.is_none() {
+            index.insert(&index_key, &key.value())?;
+            indexed += 1;
+        }
+    }
+    info!("Indexed {} objects by id", indexed);
+
+    Ok(())
+}
+
+/// Finding the objects a class version left behind used to scan every
+/// object, so indexes every object of a custom class by its class version
+fn migrate_class_version_index(
+    write: &WriteTransaction,
+    _blobs: &mut BlobMoves,
+) -> anyhow::Result<()> {
+    let objects = write.open_table(OBJECTS_TABLE)?;
+    let mut index = write.open_table(CLASS_VERSIONS_INDEX)?;
+
+    let mut indexed = 0;
+    for item in objects.iter()? {
+        let (key, object) = item?;
+        let key = key.value();
+        if let Some(index_key) = CloudstateClassVersionKey::new(&object.value().data, &key.id) {
+            index.insert(index_key, ())?;
+            indexed += 1;
+        }
+    }
+    info!("Indexed {} objects by class version", indexed);
+
+    Ok(())
+}
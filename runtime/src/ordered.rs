@@ -0,0 +1,353 @@
+use std::{any::type_name, cmp::Ordering, fmt::Debug};
+
+use chrono::{DateTime, Utc};
+use redb::{Key, TypeName, Value};
+
+use crate::bincode::{CorruptRow, Decode};
+use crate::extensions::cloudstate::{
+    CloudstateArrayItemKey, CloudstateId, CloudstateMapFieldKey, CloudstateMapKey,
+    CloudstateObjectKey, CloudstateRootKey,
+};
+
+/// Wrapper type for keys encoded so their bytes sort in the same order as the
+/// keys, which lets redb compare them without decoding
+#[derive(Debug)]
+pub struct Ordered<T>(pub T);
+
+/// A key with an order-preserving encoding: for any two keys, comparing their
+/// encodings as bytes gives the same result as comparing the keys.
+pub trait OrderedKey: Sized {
+    fn encode(&self, out: &mut Vec<u8>);
+
+    fn decode(input: &mut &[u8]) -> Result<Self, &'static str>;
+}
+
+impl<T: OrderedKey> Ordered<T> {
+    pub fn encode(value: &T) -> Vec<u8> {
+        let mut out = Vec::new();
+        value.encode(&mut out);
+        out
+    }
+
+    /// Decodes a stored key, returning an error instead of panicking when the
+    /// bytes are corrupt
+    pub fn decode(mut data: &[u8]) -> Result<T, CorruptRow> {
+        let corrupt = |error: &str| CorruptRow {
+            type_name: type_name::<T>(),
+            error: error.to_string(),
+        };
+
+        let value = T::decode(&mut data).map_err(corrupt)?;
+        if !data.is_empty() {
+            return Err(corrupt("trailing bytes"));
+        }
+        Ok(value)
+    }
+}
+
+impl<T> Value for Ordered<T>
+where
+    T: Debug + OrderedKey,
+{
+    type SelfType<'a>
+        = T
+    where
+        Self: 'a;
+
+    type AsBytes<'a>
+        = Vec<u8>
+    where
+        Self: 'a;
+
+    fn fixed_width() -> Option<usize> {
+        None
+    }
+
+    fn from_bytes<'a>(data: &'a [u8]) -> Self::SelfType<'a>
+    where
+        Self: 'a,
+    {
+        // rows that may be corrupt are read through `Raw` and `Decode`
+        Self::decode(data).unwrap_or_else(|corrupt| panic!("{corrupt}"))
+    }
+
+    fn as_bytes<'a, 'b: 'a>(value: &'a Self::SelfType<'b>) -> Self::AsBytes<'a>
+    where
+        Self: 'a,
+        Self: 'b,
+    {
+        Self::encode(value)
+    }
+
+    fn type_name() -> TypeName {
+        TypeName::new(&format!("Ordered<{}>", type_name::<T>()))
+    }
+}
+
+impl<T> Decode for Ordered<T>
+where
+    T: Debug + OrderedKey,
+{
+    type Decoded = T;
+
+    fn decode(data: &[u8]) -> Result<T, CorruptRow> {
+        Ordered::<T>::decode(data)
+    }
+}
+
+impl<T> Key for Ordered<T>
+where
+    T: Debug + OrderedKey,
+{
+    fn compare(data1: &[u8], data2: &[u8]) -> Ordering {
+        data1.cmp(data2)
+    }
+}
+
+fn take<'a>(input: &mut &'a [u8], len: usize) -> Result<&'a [u8], &'static str> {
+    if input.len() < len {
+        return Err("unexpected end of key");
+    }
+    let (taken, rest) = input.split_at(len);
+    *input = rest;
+    Ok(taken)
+}
+
+fn take_array<const N: usize>(input: &mut &[u8]) -> Result<[u8; N], &'static str> {
+    Ok(take(input, N)?.try_into().unwrap())
+}
+
+// Strings end in 0x00 0x01, and their own 0x00 bytes are written as 0x00 0xff,
+// so a string sorts before every longer string it is a prefix of.
+impl OrderedKey for String {
+    fn encode(&self, out: &mut Vec<u8>) {
+        for &byte in self.as_bytes() {
+            out.push(byte);
+            if byte == 0 {
+                out.push(0xff);
+            }
+        }
+        out.extend_from_slice(&[0, 1]);
+    }
+
+    fn decode(input: &mut &[u8]) -> Result<Self, &'static str> {
+        let mut bytes = Vec::new();
+        loop {
+            let end = input
+                .iter()
+                .position(|&byte| byte == 0)
+                .ok_or("unexpected end of key")?;
+            bytes.extend_from_slice(take(input, end)?);
+            match take_array(input)? {
+                [0, 0xff] => bytes.push(0),
+                [0, 1] => break,
+                _ => return Err("invalid string escape"),
+            }
+        }
+        String::from_utf8(bytes).map_err(|_| "invalid UTF-8")
+    }
+}
+
+// Signed integers have their sign bit flipped so negative numbers sort first
+impl OrderedKey for i32 {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&((*self as u32) ^ (1 << 31)).to_be_bytes());
+    }
+
+    fn decode(input: &mut &[u8]) -> Result<Self, &'static str> {
+        Ok((u32::from_be_bytes(take_array(input)?) ^ (1 << 31)) as i32)
+    }
+}
+
+impl OrderedKey for i64 {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&((*self as u64) ^ (1 << 63)).to_be_bytes());
+    }
+
+    fn decode(input: &mut &[u8]) -> Result<Self, &'static str> {
+        Ok((u64::from_be_bytes(take_array(input)?) ^ (1 << 63)) as i64)
+    }
+}
+
+// Sorts like `f64::total_cmp`: negative numbers have every bit flipped so
+// larger magnitudes sort first, positive numbers only the sign bit
+impl OrderedKey for f64 {
+    fn encode(&self, out: &mut Vec<u8>) {
+        let bits = self.to_bits();
+        let bits = if bits >> 63 == 1 {
+            !bits
+        } else {
+            bits | (1 << 63)
+        };
+        out.extend_from_slice(&bits.to_be_bytes());
+    }
+
+    fn decode(input: &mut &[u8]) -> Result<Self, &'static str> {
+        let bits = u64::from_be_bytes(take_array(input)?);
+        let bits = if bits >> 63 == 1 {
+            bits ^ (1 << 63)
+        } else {
+            !bits
+        };
+        Ok(f64::from_bits(bits))
+    }
+}
+
+// Ids are fixed width, so their bytes as they are
+impl OrderedKey for CloudstateId {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.0);
+    }
+
+    fn decode(input: &mut &[u8]) -> Result<Self, &'static str> {
+        Ok(CloudstateId(take_array(input)?))
+    }
+}
+
+// Magnitudes are written as their word count followed by the words from most
+// significant, since they have no trailing zero words. Negative numbers are
+// written with every bit flipped, so larger magnitudes sort first.
+fn encode_bigint(negative: bool, words: &[u64], out: &mut Vec<u8>) {
+    let start = out.len();
+    out.extend_from_slice(&(words.len() as u32).to_be_bytes());
+    for word in words.iter().rev() {
+        out.extend_from_slice(&word.to_be_bytes());
+    }
+    if negative {
+        for byte in &mut out[start..] {
+            *byte = !*byte;
+        }
+    }
+}
+
+fn decode_bigint(negative: bool, input: &mut &[u8]) -> Result<Box<[u64]>, &'static str> {
+    let flip = |bytes: &[u8]| -> Vec<u8> {
+        bytes
+            .iter()
+            .map(|byte| if negative { !byte } else { *byte })
+            .collect()
+    };
+
+    let len = u32::from_be_bytes(flip(take(input, 4)?).try_into().unwrap()) as usize;
+    let bytes = flip(take(
+        input,
+        len.checked_mul(8).ok_or("invalid bigint length")?,
+    )?);
+    let mut words: Vec<u64> = bytes
+        .chunks_exact(8)
+        .map(|word| u64::from_be_bytes(word.try_into().unwrap()))
+        .collect();
+    words.reverse();
+
+    if words.last() == Some(&0) {
+        return Err("bigint has trailing zero words");
+    }
+    Ok(words.into_boxed_slice())
+}
+
+// Keys of different types start with their `type_order`, so they sort by type
+// first like `Ord for CloudstateMapKey`
+impl OrderedKey for CloudstateMapKey {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.push(self.type_order());
+        match self {
+            CloudstateMapKey::Boolean(value) => out.push(*value as u8),
+            CloudstateMapKey::Number(value) => value.encode(out),
+            CloudstateMapKey::BigInt { negative, words } => {
+                out.push(!negative as u8);
+                encode_bigint(*negative, words, out);
+            }
+            CloudstateMapKey::String(value) => value.encode(out),
+            CloudstateMapKey::Date(value) => {
+                value.timestamp().encode(out);
+                out.extend_from_slice(&value.timestamp_subsec_nanos().to_be_bytes());
+            }
+        }
+    }
+
+    fn decode(input: &mut &[u8]) -> Result<Self, &'static str> {
+        let [tag] = take_array(input)?;
+        let key = match tag {
+            0 => match take_array(input)? {
+                [0] => CloudstateMapKey::Boolean(false),
+                [1] => CloudstateMapKey::Boolean(true),
+                _ => return Err("invalid boolean"),
+            },
+            1 => CloudstateMapKey::Number(f64::decode(input)?),
+            2 => {
+                let negative = match take_array(input)? {
+                    [0] => true,
+                    [1] => false,
+                    _ => return Err("invalid bigint sign"),
+                };
+                let words = decode_bigint(negative, input)?;
+                if negative && words.is_empty() {
+                    return Err("bigint is negative zero");
+                }
+                CloudstateMapKey::BigInt { negative, words }
+            }
+            3 => CloudstateMapKey::String(String::decode(input)?),
+            4 => {
+                let seconds = i64::decode(input)?;
+                let nanos = u32::from_be_bytes(take_array(input)?);
+                let date: DateTime<Utc> =
+                    DateTime::from_timestamp(seconds, nanos).ok_or("invalid date")?;
+                CloudstateMapKey::Date(date)
+            }
+            _ => return Err("unknown map key type"),
+        };
+        Ok(key)
+    }
+}
+
+impl OrderedKey for CloudstateRootKey {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.alias.encode(out);
+    }
+
+    fn decode(input: &mut &[u8]) -> Result<Self, &'static str> {
+        Ok(CloudstateRootKey {
+            alias: String::decode(input)?,
+        })
+    }
+}
+
+impl OrderedKey for CloudstateObjectKey {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.id.encode(out);
+    }
+
+    fn decode(input: &mut &[u8]) -> Result<Self, &'static str> {
+        Ok(CloudstateObjectKey {
+            id: CloudstateId::decode(input)?,
+        })
+    }
+}
+
+impl OrderedKey for CloudstateMapFieldKey {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.id.encode(out);
+        self.field.encode(out);
+    }
+
+    fn decode(input: &mut &[u8]) -> Result<Self, &'static str> {
+        Ok(CloudstateMapFieldKey {
+            id: CloudstateId::decode(input)?,
+            field: CloudstateMapKey::decode(input)?,
+        })
+    }
+}
+
+impl OrderedKey for CloudstateArrayItemKey {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.id.encode(out);
+        self.index.encode(out);
+    }
+
+    fn decode(input: &mut &[u8]) -> Result<Self, &'static str> {
+        Ok(CloudstateArrayItemKey {
+            id: CloudstateId::decode(input)?,
+            index: i32::decode(input)?,
+        })
+    }
+}
@@ -1,7 +1,10 @@
 use redb::ReadableTable;
 use tracing::debug;
 
-use crate::tables::{ARRAYS_TABLE, BLOBS_TABLE, MAPS_TABLE, OBJECTS_TABLE, ROOTS_TABLE};
+use crate::tables::{
+    ARRAY_METADATA_TABLE, ARRAYS_TABLE, BLOBS_TABLE, FIELD_INDEX_TABLE, MAPS_TABLE,
+    OBJECT_IDS_INDEX, OBJECTS_TABLE, ROOTS_TABLE, SETS_TABLE,
+};
 
 pub fn print_database(db: &redb::Database) {
     let txn = db.begin_read().unwrap();
@@ -14,6 +17,23 @@ pub fn print_database(db: &redb::Database) {
         }
     }
 
+    debug!("Object IDs Index");
+    if let Ok(table) = txn.open_table(OBJECT_IDS_INDEX) {
+        for entry in table.iter().unwrap() {
+            let entry = entry.unwrap();
+            debug!("{:#?}: {:#?}", entry.0.value().id, entry.1.value().id);
+        }
+    }
+
+    debug!("Field Index Table");
+    if let Ok(table) = txn.open_table(FIELD_INDEX_TABLE) {
+        for entry in table.iter().unwrap() {
+            let entry = entry.unwrap();
+            let key = entry.0.value();
+            debug!("{}.{}: {:#?}", key.class_name, key.field, key.object_id);
+        }
+    }
+
     debug!("Arrays Table");
     if let Ok(table) = txn.open_table(ARRAYS_TABLE) {
         for entry in table.iter().unwrap() {
@@ -22,6 +42,14 @@ pub fn print_database(db: &redb::Database) {
         }
     }
 
+    debug!("Array Metadata Table");
+    if let Ok(table) = txn.open_table(ARRAY_METADATA_TABLE) {
+        for entry in table.iter().unwrap() {
+            let entry = entry.unwrap();
+            debug!("{:#?}: {:#?}", entry.0.value().id, entry.1.value());
+        }
+    }
+
     debug!("Roots Table");
     if let Ok(table) = txn.open_table(ROOTS_TABLE) {
         for entry in table.iter().unwrap() {
@@ -45,4 +73,12 @@ pub fn print_database(db: &redb::Database) {
             debug!("{:#?}: {:#?}", entry.0.value().id, entry.1.value().data);
         }
     }
+
+    debug!("Sets Table");
+    if let Ok(table) = txn.open_table(SETS_TABLE) {
+        for entry in table.iter().unwrap() {
+            let entry = entry.unwrap();
+            debug!("{:#?}: {:#?}", entry.0.value().id, entry.1.value().data);
+        }
+    }
 }
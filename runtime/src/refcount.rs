@@ -0,0 +1,403 @@
+//! Reference counts for objects, maps, arrays and sets, for databases that
+//! keep them. When a commit drops the last reference to something, it's
+//! deleted along with anything only it pointed to once the request that
+//! dropped it is over, rather than waiting for the garbage collector. Counts
+//! never drop to zero inside a cycle, so `gc::mark_and_sweep` still collects
+//! those.
+//!
+//! Counts are updated from the rows a transaction changed, so every write
+//! has to go through `PendingChanges` while they're kept. Deleting waits for
+//! the end of the request because a script can still hold a value after the
+//! commit that dropped its last reference, and store it again in a later
+//! one, which only writes a reference to it.
+
+use std::collections::BTreeMap;
+
+use redb::{
+    Database, Key, ReadableTable, Table, TableDefinition, TableHandle, Value, WriteTransaction,
+};
+use tracing::{debug, info};
+
+use crate::bincode::{Bincode, CorruptRow, raw};
+use crate::changes::CloudstateChange;
+use crate::extensions::cloudstate::{
+    CloudstateArrayItemKey, CloudstateArrayItemValue, CloudstateArrayMetadataKey,
+    CloudstateClassVersionKey, CloudstateFieldIndexKey, CloudstateMapFieldKey,
+    CloudstateMapFieldValue, CloudstateObjectIdIndexKey, CloudstateObjectKey,
+    CloudstateObjectValue, CloudstatePrimitiveData, CloudstateRootValue, CloudstateSetItemKey,
+    CloudstateSetItemValue,
+};
+use crate::gc::Pointer;
+use crate::tables::{
+    ARRAY_METADATA_TABLE, ARRAYS_TABLE, CLASS_VERSIONS_INDEX, FIELD_INDEX_TABLE, MAPS_TABLE,
+    METADATA_TABLE, OBJECT_IDS_INDEX, OBJECTS_TABLE, REFERENCE_COUNTS_TABLE, ROOTS_TABLE,
+    SETS_TABLE,
+};
+
+const REFERENCE_COUNTS_KEY: &str = "reference_counts";
+
+/// Whether the database keeps reference counts
+pub fn enabled(write: &WriteTransaction) -> anyhow::Result<bool> {
+    let metadata = write.open_table(METADATA_TABLE)?;
+    let enabled = metadata.get(REFERENCE_COUNTS_KEY)?.is_some();
+    Ok(enabled)
+}
+
+/// Counts every reference in the database and keeps the counts up to date
+/// from then on. Does nothing if they already are.
+pub fn enable(db: &Database) -> anyhow::Result<()> {
+    let write = db.begin_write()?;
+    if enabled(&write)? {
+        return Ok(());
+    }
+
+    let mut counts: BTreeMap<Pointer, u64> = BTreeMap::new();
+    let mut count = |pointer: Option<Pointer>| {
+        if let Some(pointer) = pointer {
+            *counts.entry(pointer).or_default() += 1;
+        }
+    };
+
+    for item in write.open_table(ROOTS_TABLE)?.iter()? {
+        let (_key, root) = item?;
+        count(Some(Pointer::Object(CloudstateObjectKey {
+            id: root.value().id,
+        })));
+    }
+    for item in write.open_table(OBJECTS_TABLE)?.iter()? {
+        let (_key, object) = item?;
+        for value in object.value().data.fields.values() {
+            count(Pointer::from_data(value));
+        }
+    }
+    for item in write.open_table(MAPS_TABLE)?.iter()? {
+        let (_key, value) = item?;
+        count(Pointer::from_data(&value.value().data));
+    }
+    for item in write.open_table(ARRAYS_TABLE)?.iter()? {
+        let (_key, value) = item?;
+        count(Pointer::from_data(&value.value().data));
+    }
+    for item in write.open_table(SETS_TABLE)?.iter()? {
+        let (_key, value) = item?;
+        count(Pointer::from_data(&value.value().data));
+    }
+
+    info!("Counted references to {} values", counts.len());
+    {
+        let mut table = write.open_table(REFERENCE_COUNTS_TABLE)?;
+        for (pointer, count) in counts {
+            table.insert(pointer, count)?;
+        }
+        write
+            .open_table(METADATA_TABLE)?
+            .insert(REFERENCE_COUNTS_KEY, 1)?;
+    }
+    write.commit()?;
+
+    Ok(())
+}
+
+/// Stops keeping reference counts, leaving unreachable values to the garbage
+/// collector
+pub fn disable(db: &Database) -> anyhow::Result<()> {
+    let write = db.begin_write()?;
+    write.delete_table(REFERENCE_COUNTS_TABLE)?;
+    write
+        .open_table(METADATA_TABLE)?
+        .remove(REFERENCE_COUNTS_KEY)?;
+    write.commit()?;
+
+    Ok(())
+}
+
+/// The rows counting changed, for the change log, and the values whose last
+/// reference was dropped
+#[derive(Debug, Default)]
+pub(crate) struct Counted {
+    pub changed: Vec<CloudstateChange>,
+    pub unreferenced: Vec<Pointer>,
+}
+
+/// Updates the counts for the rows a transaction changed, given what each
+/// held before. Whatever no longer has any references is returned rather than
+/// deleted, for `reclaim` at the end of the request.
+pub(crate) fn update(
+    write: &WriteTransaction,
+    rows: &BTreeMap<CloudstateChange, Option<Vec<u8>>>,
+) -> anyhow::Result<Counted> {
+    let mut deltas: BTreeMap<Pointer, i64> = BTreeMap::new();
+    for (change, previous) in rows {
+        if let Some(previous) = previous {
+            for pointer in references(&change.table, previous)? {
+                *deltas.entry(pointer).or_default() -= 1;
+            }
+        }
+        if let Some(current) = current_value(write, change)? {
+            for pointer in references(&change.table, &current)? {
+                *deltas.entry(pointer).or_default() += 1;
+            }
+        }
+    }
+
+    let mut counts = Counts {
+        table: write.open_table(REFERENCE_COUNTS_TABLE)?,
+        changed: Vec::new(),
+        unreferenced: Vec::new(),
+    };
+    for (pointer, delta) in deltas {
+        if delta != 0 {
+            counts.adjust(pointer, delta)?;
+        }
+    }
+
+    Ok(Counted {
+        changed: counts.changed,
+        unreferenced: counts.unreferenced,
+    })
+}
+
+/// Deletes the values whose last reference was dropped earlier in a request,
+/// unless something has referenced them again since, along with anything
+/// only they pointed to. Returns the rows this changed, for the change log.
+pub(crate) fn reclaim(
+    write: &WriteTransaction,
+    unreferenced: Vec<Pointer>,
+) -> anyhow::Result<Vec<CloudstateChange>> {
+    let mut counts = Counts {
+        table: write.open_table(REFERENCE_COUNTS_TABLE)?,
+        changed: Vec::new(),
+        unreferenced: Vec::new(),
+    };
+    for pointer in unreferenced {
+        if counts.table.get(&pointer)?.is_none() {
+            counts.unreferenced.push(pointer);
+        }
+    }
+    // a value can lose its last reference more than once in a request
+    counts.unreferenced.sort();
+    counts.unreferenced.dedup();
+
+    let mut deleted = 0;
+    while let Some(pointer) = counts.unreferenced.pop() {
+        deleted += 1;
+        for reference in delete(write, &pointer, &mut counts.changed)? {
+            counts.adjust(reference, -1)?;
+        }
+    }
+    if deleted > 0 {
+        debug!("Deleted {} values that lost their last reference", deleted);
+    }
+
+    Ok(counts.changed)
+}
+
+struct Counts<'txn> {
+    table: Table<'txn, Bincode<Pointer>, u64>,
+    /// Rows written so far
+    changed: Vec<CloudstateChange>,
+    /// Values whose count has dropped to zero, which are yet to be deleted
+    unreferenced: Vec<Pointer>,
+}
+
+impl Counts<'_> {
+    fn adjust(&mut self, pointer: Pointer, delta: i64) -> anyhow::Result<()> {
+        let count = self.table.get(&pointer)?.map_or(0, |count| count.value());
+        let updated = count as i64 + delta;
+        if updated > 0 {
+            self.table.insert(&pointer, updated as u64)?;
+        } else {
+            self.table.remove(&pointer)?;
+            if count > 0 && updated == 0 {
+                self.unreferenced.push(pointer.clone());
+            } else {
+                // the garbage collector already deleted it, or its count was
+                // lost, either way it isn't ours to delete
+                debug!("No references left to count for {:?}", pointer);
+            }
+        }
+
+        self.changed.push(change(REFERENCE_COUNTS_TABLE, &pointer));
+        Ok(())
+    }
+}
+
+fn change<K: Key + 'static, V: Value + 'static>(
+    definition: TableDefinition<K, V>,
+    key: &K::SelfType<'_>,
+) -> CloudstateChange {
+    CloudstateChange {
+        table: definition.name().to_string(),
+        key: K::as_bytes(key).as_ref().to_vec(),
+    }
+}
+
+/// The encoded value of a changed row as it is now, if it still exists
+fn current_value(
+    write: &WriteTransaction,
+    change: &CloudstateChange,
+) -> anyhow::Result<Option<Vec<u8>>> {
+    fn get<K: Key + 'static, V: Value + 'static>(
+        write: &WriteTransaction,
+        definition: TableDefinition<K, V>,
+        key: &[u8],
+    ) -> anyhow::Result<Option<Vec<u8>>> {
+        let table = write.open_table(raw(&definition))?;
+        let value = table.get(key)?.map(|value| value.value().to_vec());
+        Ok(value)
+    }
+
+    match change.table.as_str() {
+        table if table == ROOTS_TABLE.name() => get(write, ROOTS_TABLE, &change.key),
+        table if table == OBJECTS_TABLE.name() => get(write, OBJECTS_TABLE, &change.key),
+        table if table == MAPS_TABLE.name() => get(write, MAPS_TABLE, &change.key),
+        table if table == ARRAYS_TABLE.name() => get(write, ARRAYS_TABLE, &change.key),
+        table if table == SETS_TABLE.name() => get(write, SETS_TABLE, &change.key),
+        _ => Ok(None),
+    }
+}
+
+/// What an encoded row of the table points to
+fn references(table: &str, value: &[u8]) -> Result<Vec<Pointer>, CorruptRow> {
+    let pointers = match table {
+        table if table == ROOTS_TABLE.name() => {
+            let root = Bincode::<CloudstateRootValue>::decode(value)?;
+            vec![Pointer::Object(CloudstateObjectKey { id: root.id })]
+        }
+        table if table == OBJECTS_TABLE.name() => Bincode::<CloudstateObjectValue>::decode(value)?
+            .data
+            .fields
+            .values()
+            .filter_map(Pointer::from_data)
+            .collect(),
+        table if table == MAPS_TABLE.name() => {
+            let value = Bincode::<CloudstateMapFieldValue>::decode(value)?;
+            Pointer::from_data(&value.data).into_iter().collect()
+        }
+        table if table == ARRAYS_TABLE.name() => {
+            let value = Bincode::<CloudstateArrayItemValue>::decode(value)?;
+            Pointer::from_data(&value.data).into_iter().collect()
+        }
+        table if table == SETS_TABLE.name() => {
+            let value = Bincode::<CloudstateSetItemValue>::decode(value)?;
+            Pointer::from_data(&value.data).into_iter().collect()
+        }
+        _ => Vec::new(),
+    };
+    Ok(pointers)
+}
+
+/// Deletes every row of a value, along with its index entries, and returns
+/// what those rows pointed to
+fn delete(
+    write: &WriteTransaction,
+    pointer: &Pointer,
+    changed: &mut Vec<CloudstateChange>,
+) -> anyhow::Result<Vec<Pointer>> {
+    let mut references = Vec::new();
+    let mut reference = |data: &CloudstatePrimitiveData| {
+        references.extend(Pointer::from_data(data));
+    };
+
+    match pointer {
+        Pointer::Object(key) => {
+            let Some(object) = write
+                .open_table(OBJECTS_TABLE)?
+                .remove(key)?
+                .map(|object| object.value().data)
+            else {
+                return Ok(Vec::new());
+            };
+            changed.push(change(OBJECTS_TABLE, key));
+
+            if let Some(CloudstatePrimitiveData::String(id)) = object.fields.get("id") {
+                let mut index = write.open_table(OBJECT_IDS_INDEX)?;
+                let index_key = CloudstateObjectIdIndexKey { id: id.clone() };
+                let owner = index.get(&index_key)?.map(|owner| owner.value());
+                if owner.as_ref() == Some(key) {
+                    index.remove(&index_key)?;
+                    changed.push(change(OBJECT_IDS_INDEX, &index_key));
+                }
+            }
+
+            // which fields are indexed isn't stored, so try all of them
+            if let Some(class_name) = &object.constructor_name {
+                let mut index = write.open_table(FIELD_INDEX_TABLE)?;
+                for (field, value) in &object.fields {
+                    let Some(index_key) =
+                        CloudstateFieldIndexKey::new(class_name, field, value, &key.id)
+                    else {
+                        continue;
+                    };
+                    if index.remove(&index_key)?.is_some() {
+                        changed.push(change(FIELD_INDEX_TABLE, &index_key));
+                    }
+                }
+            }
+
+            if let Some(index_key) = CloudstateClassVersionKey::new(&object, &key.id) {
+                let mut index = write.open_table(CLASS_VERSIONS_INDEX)?;
+                if index.remove(&index_key)?.is_some() {
+                    changed.push(change(CLASS_VERSIONS_INDEX, &index_key));
+                }
+            }
+
+            for value in object.fields.values() {
+                reference(value);
+            }
+        }
+        Pointer::Map(map) => {
+            let mut table = write.open_table(MAPS_TABLE)?;
+            let keys = table
+                .range(CloudstateMapFieldKey::range(&map.id))?
+                .map(|item| item.map(|(key, _)| key.value()))
+                .collect::<Result<Vec<_>, _>>()?;
+            for key in keys {
+                if let Some(value) = table.remove(&key)? {
+                    reference(&value.value().data);
+                }
+                changed.push(change(MAPS_TABLE, &key));
+            }
+        }
+        Pointer::Array(array) => {
+            let mut table = write.open_table(ARRAYS_TABLE)?;
+            let keys = table
+                .range(CloudstateArrayItemKey::range(&array.id))?
+                .map(|item| item.map(|(key, _)| key.value()))
+                .collect::<Result<Vec<_>, _>>()?;
+            for key in keys {
+                if let Some(value) = table.remove(&key)? {
+                    reference(&value.value().data);
+                }
+                changed.push(change(ARRAYS_TABLE, &key));
+            }
+
+            let metadata_key = CloudstateArrayMetadataKey { id: array.id };
+            if write
+                .open_table(ARRAY_METADATA_TABLE)?
+                .remove(&metadata_key)?
+                .is_some()
+            {
+                changed.push(change(ARRAY_METADATA_TABLE, &metadata_key));
+            }
+        }
+        Pointer::Set(set) => {
+            let mut table = write.open_table(SETS_TABLE)?;
+            let keys = table
+                .range(CloudstateSetItemKey::range(&set.id))?
+                .map(|item| item.map(|(key, _)| key.value()))
+                .collect::<Result<Vec<_>, _>>()?;
+            for key in keys {
+                if let Some(value) = table.remove(&key)? {
+                    reference(&value.value().data);
+                }
+                changed.push(change(SETS_TABLE, &key));
+            }
+        }
+        // deleting the row here would leave the payload behind, so blobs
+        // are left for the garbage collector, which has the blob storage
+        Pointer::Blob(_) => {}
+    }
+
+    Ok(references)
+}
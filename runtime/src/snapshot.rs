@@ -0,0 +1,36 @@
+//! A V8 startup snapshot of the cloudstate extension set, so runtimes don't
+//! evaluate every extension's JavaScript from source when they start.
+//!
+//! The server's build script writes it with `create_snapshot`, and runtimes
+//! started from it take `cloudstate_extensions_from_snapshot`, which has to
+//! list the same extensions in the same order as `cloudstate_extensions`.
+
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use anyhow::anyhow;
+use deno_core::snapshot::{CreateSnapshotOptions, create_snapshot as create_v8_snapshot};
+
+use crate::cloudstate_extensions::cloudstate_extensions;
+use crate::transpile;
+
+/// Writes the snapshot to `path` and returns the files it was made from
+pub fn create_snapshot(path: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let snapshot = create_v8_snapshot(
+        CreateSnapshotOptions {
+            cargo_manifest_dir: env!("CARGO_MANIFEST_DIR"),
+            startup_snapshot: None,
+            skip_op_registration: false,
+            extensions: cloudstate_extensions(),
+            extension_transpiler: Some(Rc::new(|specifier, source| {
+                transpile::maybe_transpile_source(specifier, source)
+            })),
+            with_runtime_cb: None,
+        },
+        None,
+    )
+    .map_err(|e| anyhow!("Failed to create the snapshot: {}", e))?;
+
+    std::fs::write(path, snapshot.output)?;
+    Ok(snapshot.files_loaded_during_snapshot)
+}
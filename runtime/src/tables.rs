@@ -1,33 +1,89 @@
 use crate::{
     bincode::Bincode,
     blob_storage::CloudstateBlobMetadata,
+    changes::CloudstateChange,
+    check::{QuarantinedRowKey, QuarantinedRowValue},
     extensions::cloudstate::{
-        CloudstateArrayItemKey, CloudstateArrayItemValue, CloudstateBlobKey, CloudstateMapFieldKey,
-        CloudstateMapFieldValue, CloudstateObjectKey, CloudstateObjectValue, CloudstateRootKey,
-        CloudstateRootValue,
+        CloudstateArrayItemKey, CloudstateArrayItemValue, CloudstateArrayMetadataKey,
+        CloudstateArrayMetadataValue, CloudstateBlobKey, CloudstateClassVersionKey,
+        CloudstateFieldIndexKey, CloudstateMapFieldKey, CloudstateMapFieldValue,
+        CloudstateObjectIdIndexKey, CloudstateObjectKey, CloudstateObjectValue, CloudstateRootKey,
+        CloudstateRootValue, CloudstateSetItemKey, CloudstateSetItemValue,
     },
+    gc::Pointer,
+    ordered::Ordered,
 };
 use redb::TableDefinition;
 
-pub const ROOTS_TABLE: TableDefinition<Bincode<CloudstateRootKey>, Bincode<CloudstateRootValue>> =
+pub const ROOTS_TABLE: TableDefinition<Ordered<CloudstateRootKey>, Bincode<CloudstateRootValue>> =
     TableDefinition::new("roots");
 
 pub const OBJECTS_TABLE: TableDefinition<
-    Bincode<CloudstateObjectKey>,
+    Ordered<CloudstateObjectKey>,
     Bincode<CloudstateObjectValue>,
 > = TableDefinition::new("objects");
 
+pub const OBJECT_IDS_INDEX: TableDefinition<
+    Bincode<CloudstateObjectIdIndexKey>,
+    Bincode<CloudstateObjectKey>,
+> = TableDefinition::new("object_ids");
+
+pub const FIELD_INDEX_TABLE: TableDefinition<Bincode<CloudstateFieldIndexKey>, ()> =
+    TableDefinition::new("field_index");
+
+/// The fields each custom class's entries in `FIELD_INDEX_TABLE` were built
+/// for, so the index is rebuilt when a class starts declaring others
+pub const INDEXED_FIELDS_TABLE: TableDefinition<&str, Bincode<Vec<String>>> =
+    TableDefinition::new("indexed_fields");
+
+/// Objects of custom classes by the class version they were written at, so
+/// the ones a newer version left behind can be found without a full scan
+pub const CLASS_VERSIONS_INDEX: TableDefinition<Bincode<CloudstateClassVersionKey>, ()> =
+    TableDefinition::new("class_versions");
+
 pub const MAPS_TABLE: TableDefinition<
-    Bincode<CloudstateMapFieldKey>,
+    Ordered<CloudstateMapFieldKey>,
     Bincode<CloudstateMapFieldValue>,
 > = TableDefinition::new("maps");
 
 pub const ARRAYS_TABLE: TableDefinition<
-    Bincode<CloudstateArrayItemKey>,
+    Ordered<CloudstateArrayItemKey>,
     Bincode<CloudstateArrayItemValue>,
 > = TableDefinition::new("arrays");
 
+pub const ARRAY_METADATA_TABLE: TableDefinition<
+    Bincode<CloudstateArrayMetadataKey>,
+    Bincode<CloudstateArrayMetadataValue>,
+> = TableDefinition::new("array_metadata");
+
 pub const BLOBS_TABLE: TableDefinition<
     Bincode<CloudstateBlobKey>,
     Bincode<CloudstateBlobMetadata>,
 > = TableDefinition::new("blobs");
+
+pub const SETS_TABLE: TableDefinition<
+    Bincode<CloudstateSetItemKey>,
+    Bincode<CloudstateSetItemValue>,
+> = TableDefinition::new("sets");
+
+/// Which row changed at each sequence number, for incremental backups
+pub const CHANGES_TABLE: TableDefinition<u64, Bincode<CloudstateChange>> =
+    TableDefinition::new("changes");
+
+/// The latest sequence number of each row in `CHANGES_TABLE`
+pub const CHANGE_SEQUENCES_TABLE: TableDefinition<Bincode<CloudstateChange>, u64> =
+    TableDefinition::new("change_sequences");
+
+/// Facts about the database itself, like the version of its format
+pub const METADATA_TABLE: TableDefinition<&str, u64> = TableDefinition::new("metadata");
+
+/// Rows `check_database` moved out of their tables because they didn't decode
+pub const QUARANTINE_TABLE: TableDefinition<
+    Bincode<QuarantinedRowKey>,
+    Bincode<QuarantinedRowValue>,
+> = TableDefinition::new("quarantine");
+
+/// How many rows and roots point to each object, map, array and set, in
+/// databases that keep reference counts
+pub const REFERENCE_COUNTS_TABLE: TableDefinition<Bincode<Pointer>, u64> =
+    TableDefinition::new("reference_counts");
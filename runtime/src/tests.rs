@@ -1,8 +1,15 @@
 use crate::js_test;
-// mod gc_tests;
+mod backup_tests;
+mod check_tests;
+mod export_tests;
+mod gc_tests;
+mod index_tests;
 mod js_test;
+mod migration_tests;
+mod ordered_tests;
+mod refcount_tests;
+mod set_tests;
 
-// mod gc_tests; // TODO: ERR
 js_test!(array_at);
 js_test!(array_every);
 js_test!(array_from); // TODO: ERR
@@ -35,6 +42,7 @@ js_test!(array_reduce_right);
 js_test!(array_reduce);
 js_test!(array_reverse);
 js_test!(array_shift);
+js_test!(array_shift_unshift);
 js_test!(array_slice);
 js_test!(array_some);
 // js_test!(array_sort_objects); // TODO: ERR
@@ -54,11 +62,13 @@ js_test!(blob_size);
 js_test!(blob_text);
 js_test!(blob_type);
 js_test!(class_getters);
+js_test!(class_migrations);
 js_test!(counter_class);
 js_test!(counter_manager_class);
 js_test!(custom_classes);
 js_test!(fetch);
 js_test!(get_cloudstate);
+js_test!(get_cloudstate_changed_id);
 js_test!(map_clear);
 js_test!(map_constructor);
 js_test!(map_delete);
@@ -72,8 +82,11 @@ js_test!(map_has);
 // js_test!(map_iterator); // TODO: ERR (0 iterations)
 js_test!(map_keys);
 js_test!(map_of_objects);
+js_test!(map_prefix);
+js_test!(map_range);
 js_test!(map_size_in_array);
 js_test!(map_size);
+js_test!(map_typed_keys);
 js_test!(map_values);
 js_test!(map_values_objects);
 js_test!(multiple_references);
@@ -81,12 +94,21 @@ js_test!(nested_arrays);
 js_test!(nested_objects);
 js_test!(objects_and_arrays);
 js_test!(push_to_arrays);
+js_test!(query_index);
+js_test!(query_index_backfill);
 js_test!(root_custom_classes);
 js_test!(roots_same_obj_multi_txns);
 js_test!(roots_same_obj_single_txn);
+js_test!(set_add);
+js_test!(set_constructor);
+js_test!(set_delete);
+js_test!(set_has);
+js_test!(set_of_objects);
+js_test!(set_values);
 js_test!(simple_objects);
 js_test!(todolist_map_internal_classes);
 js_test!(todolist_map_internal_objects);
+js_test!(transaction);
 js_test!(v8_bigint);
 js_test!(v8_boolean);
 js_test!(v8_date);
@@ -0,0 +1,359 @@
+use chrono::DateTime;
+use redb::{Database, Key, ReadableTable, TableDefinition, Value};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use crate::{
+    backup::{BlobBackup, list_snapshots, prune_snapshots, restore_backup, snapshot_path},
+    bincode::raw,
+    blob_storage::{
+        CloudstateBlobStorage, CloudstateBlobStorageEngine, in_memory_store::InMemoryBlobStore,
+    },
+    execution::run_script,
+    extensions::cloudstate::{
+        CloudstateId, CloudstateRootKey, CloudstateRootValue, ReDBCloudstate,
+    },
+    gc::mark_and_sweep,
+    refcount, tables,
+};
+
+/// A directory for a test's database files, removed when dropped
+struct TestDir(PathBuf);
+
+impl TestDir {
+    fn new() -> Self {
+        let path =
+            std::env::temp_dir().join(format!("cloudstate-backup-test-{}", CloudstateId::random()));
+        fs::create_dir_all(&path).unwrap();
+        Self(path)
+    }
+
+    fn join(&self, name: &str) -> PathBuf {
+        self.0.join(name)
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+fn open(path: &Path) -> ReDBCloudstate {
+    let db = Database::create(path).unwrap();
+    ReDBCloudstate::new(Arc::new(Mutex::new(db))).unwrap()
+}
+
+fn run(path: &str, cloudstate: &ReDBCloudstate, blob_storage: &CloudstateBlobStorage) {
+    let (_, result) = run_script(path, cloudstate.clone(), blob_storage.clone()).unwrap();
+    result.unwrap();
+}
+
+/// Points the root `alias` at a new id, without going through a script
+fn set_root(cloudstate: &ReDBCloudstate, alias: &str) -> CloudstateId {
+    let id = CloudstateId::random();
+    let db = cloudstate.get_database_mut();
+    let write = db.begin_write().unwrap();
+    write
+        .open_table(tables::ROOTS_TABLE)
+        .unwrap()
+        .insert(
+            CloudstateRootKey {
+                alias: alias.to_string(),
+            },
+            CloudstateRootValue { id },
+        )
+        .unwrap();
+    write.commit().unwrap();
+    id
+}
+
+fn get_root(cloudstate: &ReDBCloudstate, alias: &str) -> Option<CloudstateId> {
+    let db = cloudstate.get_database_mut();
+    let read = db.begin_read().unwrap();
+    let roots = read.open_table(tables::ROOTS_TABLE).unwrap();
+    roots
+        .get(CloudstateRootKey {
+            alias: alias.to_string(),
+        })
+        .unwrap()
+        .map(|root| root.value().id)
+}
+
+/// The ids of the blobs a database has rows for
+fn blob_ids(cloudstate: &ReDBCloudstate) -> Vec<CloudstateId> {
+    let db = cloudstate.get_database_mut();
+    let read = db.begin_read().unwrap();
+    let blobs = read.open_table(tables::BLOBS_TABLE).unwrap();
+    blobs
+        .iter()
+        .unwrap()
+        .map(|item| item.unwrap().0.value().id)
+        .collect()
+}
+
+/// A table's rows as they're stored
+fn rows<K: Key + 'static, V: Value + 'static>(
+    cloudstate: &ReDBCloudstate,
+    definition: TableDefinition<K, V>,
+) -> Vec<(Vec<u8>, Vec<u8>)> {
+    let db = cloudstate.get_database_mut();
+    let read = db.begin_read().unwrap();
+    let Ok(table) = read.open_table(raw(&definition)) else {
+        return Vec::new();
+    };
+    table
+        .iter()
+        .unwrap()
+        .map(|item| {
+            let (key, value) = item.unwrap();
+            (key.value().to_vec(), value.value().to_vec())
+        })
+        .collect()
+}
+
+/// Takes a full backup, leaves part of the root unreachable, deletes it with
+/// `collect` and takes an incremental backup, then checks restoring both
+/// gives back the database without what was deleted
+fn check_restore_after_delete(counted: bool, collect: impl Fn(&ReDBCloudstate)) {
+    let dir = TestDir::new();
+    let blob_storage = CloudstateBlobStorage::new(Arc::new(InMemoryBlobStore::new()));
+
+    let cloudstate = open(&dir.join("live.redb"));
+    if counted {
+        refcount::enable(&cloudstate.get_database_mut()).unwrap();
+    }
+    run("tests/backup/store.js", &cloudstate, &blob_storage);
+    let since = cloudstate
+        .backup(dir.join("full.redb"), None, &mut None)
+        .unwrap();
+
+    run("tests/backup/mutate.js", &cloudstate, &blob_storage);
+    collect(&cloudstate);
+    cloudstate
+        .backup_incremental(dir.join("delta.redb"), since, None, &mut None)
+        .unwrap();
+
+    restore_backup(
+        dir.join("full.redb"),
+        &[dir.join("delta.redb")],
+        dir.join("restored.redb"),
+        &blob_storage,
+    )
+    .unwrap();
+    let restored = open(&dir.join("restored.redb"));
+
+    // the root and the object it kept
+    assert_eq!(rows(&restored, tables::OBJECTS_TABLE).len(), 2);
+    assert!(rows(&restored, tables::ARRAYS_TABLE).is_empty());
+    assert!(rows(&restored, tables::ARRAY_METADATA_TABLE).is_empty());
+    assert_eq!(
+        rows(&restored, tables::OBJECTS_TABLE),
+        rows(&cloudstate, tables::OBJECTS_TABLE)
+    );
+    assert_eq!(
+        rows(&restored, tables::REFERENCE_COUNTS_TABLE),
+        rows(&cloudstate, tables::REFERENCE_COUNTS_TABLE)
+    );
+
+    run("tests/backup/verify.js", &restored, &blob_storage);
+}
+
+#[test]
+fn test_restore_removes_collected_rows() {
+    check_restore_after_delete(false, |cloudstate| {
+        let db = cloudstate.get_database_mut();
+        mark_and_sweep(&db, &CloudstateBlobStorage::default()).unwrap();
+    });
+}
+
+#[test]
+fn test_restore_removes_reclaimed_rows() {
+    // the request deletes what it left unreferenced, without a collection
+    check_restore_after_delete(true, |_| {});
+}
+
+#[test]
+fn test_compaction_drops_removed_rows() {
+    let dir = TestDir::new();
+    let blob_storage = CloudstateBlobStorage::default();
+
+    let cloudstate = open(&dir.join("live.redb"));
+    run("tests/backup/store.js", &cloudstate, &blob_storage);
+    let first = cloudstate
+        .backup(dir.join("first.redb"), None, &mut None)
+        .unwrap();
+
+    run("tests/backup/mutate.js", &cloudstate, &blob_storage);
+    mark_and_sweep(&cloudstate.get_database_mut(), &blob_storage).unwrap();
+    let logged = rows(&cloudstate, tables::CHANGES_TABLE).len();
+
+    // a full backup or snapshot alone keeps the log, so incremental backups
+    // can still start from the first one
+    let second = cloudstate
+        .backup(dir.join("second.redb"), None, &mut None)
+        .unwrap();
+    cloudstate.snapshot(dir.join("snapshots")).unwrap();
+    assert_eq!(rows(&cloudstate, tables::CHANGES_TABLE).len(), logged);
+    cloudstate
+        .backup_incremental(dir.join("since_first.redb"), first, None, &mut None)
+        .unwrap();
+
+    let compacted = cloudstate.compact_change_log(second).unwrap();
+    assert!(compacted > 0);
+
+    // the dropped object and its array are gone from the log, everything
+    // that's left still has its entry
+    let log = rows(&cloudstate, tables::CHANGES_TABLE);
+    assert!(log.len() < logged, "{} of {} entries", log.len(), logged);
+    assert_eq!(
+        log.len(),
+        rows(&cloudstate, tables::CHANGE_SEQUENCES_TABLE).len()
+    );
+    // the backup still has the entries it was taken with
+    let backup = open(&dir.join("second.redb"));
+    assert_eq!(rows(&backup, tables::CHANGES_TABLE).len(), logged);
+    drop(backup);
+
+    // the removals since the first backup can't be replayed anymore
+    let error = cloudstate
+        .backup_incremental(dir.join("stale.redb"), first, None, &mut None)
+        .unwrap_err();
+    assert!(error.to_string().contains("full backup"), "{error}");
+
+    run("tests/backup/verify.js", &cloudstate, &blob_storage);
+    let delta = cloudstate
+        .backup_incremental(dir.join("delta.redb"), second, None, &mut None)
+        .unwrap();
+    assert_eq!(delta, second);
+}
+
+#[test]
+fn test_restore_brings_back_blob_payloads() {
+    let dir = TestDir::new();
+    let blob_storage = CloudstateBlobStorage::new(Arc::new(InMemoryBlobStore::new()));
+    let backed_up = Arc::new(InMemoryBlobStore::new());
+    let blobs = BlobBackup {
+        source: &blob_storage,
+        destination: backed_up.as_ref(),
+    };
+
+    let cloudstate = open(&dir.join("live.redb"));
+    run("tests/backup/store_blobs.js", &cloudstate, &blob_storage);
+    let since = cloudstate
+        .backup(dir.join("full.redb"), Some(&blobs), &mut None)
+        .unwrap();
+    let stored = blob_ids(&cloudstate);
+    assert_eq!(stored.len(), 2);
+    for id in &stored {
+        assert!(backed_up.has_blob(&id.to_string()).unwrap());
+    }
+
+    run("tests/backup/add_blob.js", &cloudstate, &blob_storage);
+    cloudstate
+        .backup_incremental(dir.join("delta.redb"), since, Some(&blobs), &mut None)
+        .unwrap();
+
+    let restored_storage = CloudstateBlobStorage::new(backed_up.clone());
+    restore_backup(
+        dir.join("full.redb"),
+        &[dir.join("delta.redb")],
+        dir.join("restored.redb"),
+        &restored_storage,
+    )
+    .unwrap();
+    let restored = open(&dir.join("restored.redb"));
+
+    let ids = blob_ids(&restored);
+    assert_eq!(ids, blob_ids(&cloudstate));
+    assert_eq!(ids.len(), 3);
+    for id in &ids {
+        assert_eq!(
+            restored_storage.get_blob_data(id).unwrap().data,
+            blob_storage.get_blob_data(id).unwrap().data,
+            "payload of blob {id:?}"
+        );
+    }
+}
+
+#[test]
+fn test_restore_replaces_target() {
+    let dir = TestDir::new();
+    let cloudstate = open(&dir.join("live.redb"));
+    let id = set_root(&cloudstate, "root");
+    cloudstate
+        .backup(dir.join("full.redb"), None, &mut None)
+        .unwrap();
+    set_root(&cloudstate, "root");
+    set_root(&cloudstate, "after");
+    drop(cloudstate);
+
+    restore_backup(
+        dir.join("full.redb"),
+        &[] as &[PathBuf],
+        dir.join("live.redb"),
+        &CloudstateBlobStorage::default(),
+    )
+    .unwrap();
+
+    let restored = open(&dir.join("live.redb"));
+    assert_eq!(get_root(&restored, "root"), Some(id));
+    assert_eq!(get_root(&restored, "after"), None);
+    assert!(!dir.join(".live.redb.restore").exists());
+}
+
+#[test]
+fn test_restore_refuses_open_target() {
+    let dir = TestDir::new();
+    let cloudstate = open(&dir.join("live.redb"));
+    set_root(&cloudstate, "root");
+    cloudstate
+        .backup(dir.join("full.redb"), None, &mut None)
+        .unwrap();
+    let id = set_root(&cloudstate, "root");
+
+    // the server still has it open
+    let error = restore_backup(
+        dir.join("full.redb"),
+        &[] as &[PathBuf],
+        dir.join("live.redb"),
+        &CloudstateBlobStorage::default(),
+    )
+    .unwrap_err();
+    assert!(error.to_string().contains("in use"), "{error}");
+
+    assert_eq!(get_root(&cloudstate, "root"), Some(id));
+    assert!(!dir.join(".live.redb.restore").exists());
+}
+
+#[test]
+fn test_prune_snapshots_keeps_newest_and_open() {
+    let dir = TestDir::new();
+    let paths: Vec<PathBuf> = (1..=4)
+        .map(|millis| {
+            let path = snapshot_path(&dir.0, DateTime::from_timestamp_millis(millis).unwrap());
+            Database::create(&path).unwrap();
+            path
+        })
+        .collect();
+
+    // as if it were being restored
+    let in_use = Database::open(&paths[1]).unwrap();
+    let pruned = prune_snapshots(&dir.0, 2).unwrap();
+    assert_eq!(pruned, vec![paths[0].clone()]);
+
+    let left: Vec<PathBuf> = list_snapshots(&dir.0)
+        .unwrap()
+        .into_iter()
+        .map(|snapshot| snapshot.path)
+        .collect();
+    assert_eq!(left, paths[1..]);
+
+    // once it's closed, the next prune catches up
+    drop(in_use);
+    let pruned = prune_snapshots(&dir.0, 2).unwrap();
+    assert_eq!(pruned, vec![paths[1].clone()]);
+}
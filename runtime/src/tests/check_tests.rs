@@ -0,0 +1,185 @@
+use redb::{Database, ReadableTable, ReadableTableMetadata, backends::InMemoryBackend};
+use std::sync::{Arc, Mutex};
+
+use crate::{
+    bincode::{Bincode, encode, raw},
+    blob_storage::{CloudstateBlobStorage, in_memory_store::InMemoryBlobStore},
+    changes::CloudstateChange,
+    check::{QuarantinedRowKey, check_database},
+    execution::run_script,
+    extensions::cloudstate::{
+        CloudstateObjectKey, CloudstateRootKey, CloudstateRootValue, ReDBCloudstate,
+    },
+    gc::Pointer,
+    ordered::Ordered,
+    refcount, tables,
+};
+
+fn run(path: &str, cloudstate: &ReDBCloudstate) -> anyhow::Result<()> {
+    let (_, result) = run_script(
+        path,
+        cloudstate.clone(),
+        CloudstateBlobStorage::new(Arc::new(InMemoryBlobStore::new())),
+    )
+    .unwrap();
+    result
+}
+
+/// Stores two roots and overwrites the object behind the one called
+/// "corrupt" with bytes that don't decode, returning the object's key
+fn store_corrupt_object() -> (ReDBCloudstate, Vec<u8>) {
+    let db = Database::builder()
+        .create_with_backend(InMemoryBackend::default())
+        .unwrap();
+    let cloudstate = ReDBCloudstate::new(Arc::new(Mutex::new(db))).unwrap();
+    run("tests/check/store.js", &cloudstate).unwrap();
+
+    let db = cloudstate.get_database_mut();
+    let id = {
+        let read = db.begin_read().unwrap();
+        let roots = read.open_table(tables::ROOTS_TABLE).unwrap();
+        let root = roots
+            .get(CloudstateRootKey {
+                alias: "corrupt".to_string(),
+            })
+            .unwrap()
+            .unwrap();
+        root.value().id
+    };
+
+    let key = encode::<Ordered<CloudstateObjectKey>>(&CloudstateObjectKey { id });
+    let write = db.begin_write().unwrap();
+    {
+        let mut objects = write.open_table(raw(&tables::OBJECTS_TABLE)).unwrap();
+        objects.insert(key.as_slice(), [0xffu8].as_slice()).unwrap();
+    }
+    write.commit().unwrap();
+    drop(db);
+
+    (cloudstate, key)
+}
+
+fn has_root(cloudstate: &ReDBCloudstate, alias: &str) -> bool {
+    let db = cloudstate.get_database_mut();
+    let read = db.begin_read().unwrap();
+    let roots = read.open_table(tables::ROOTS_TABLE).unwrap();
+    roots
+        .get(CloudstateRootKey {
+            alias: alias.to_string(),
+        })
+        .unwrap()
+        .is_some()
+}
+
+#[test]
+fn test_check_finds_corrupt_rows() {
+    let (cloudstate, key) = store_corrupt_object();
+
+    let db = cloudstate.get_database_mut();
+    let report = check_database(&db, false).unwrap();
+    assert_eq!(report.corrupt.len(), 1);
+    assert_eq!(report.corrupt[0].table, "objects");
+    assert_eq!(report.corrupt[0].key, key);
+
+    // without quarantine the row stays where it was
+    let read = db.begin_read().unwrap();
+    let objects = read.open_table(tables::OBJECTS_TABLE).unwrap();
+    assert_eq!(objects.len().unwrap(), 2);
+    assert!(read.open_table(tables::QUARANTINE_TABLE).is_err());
+}
+
+#[test]
+fn test_corrupt_row_fails_the_transaction() {
+    let (cloudstate, _key) = store_corrupt_object();
+
+    // the script catches the error, but its write must not be committed
+    let result = run("tests/check/read_corrupt.js", &cloudstate);
+    assert!(result.is_err());
+    assert!(!has_root(&cloudstate, "written"));
+}
+
+#[test]
+fn test_quarantine_moves_corrupt_rows() {
+    let (cloudstate, key) = store_corrupt_object();
+
+    {
+        let db = cloudstate.get_database_mut();
+        let report = check_database(&db, true).unwrap();
+        assert_eq!(report.corrupt.len(), 1);
+
+        let read = db.begin_read().unwrap();
+        let objects = read.open_table(tables::OBJECTS_TABLE).unwrap();
+        assert_eq!(objects.len().unwrap(), 1);
+
+        let quarantine = read.open_table(tables::QUARANTINE_TABLE).unwrap();
+        let row = quarantine
+            .get(QuarantinedRowKey {
+                table: "objects".to_string(),
+                key,
+            })
+            .unwrap()
+            .unwrap();
+        assert_eq!(row.value().value, vec![0xff]);
+
+        // quarantined rows aren't checked again
+        let report = check_database(&db, false).unwrap();
+        assert!(report.corrupt.is_empty());
+    }
+
+    // the rest of the database stays usable
+    run("tests/check/read_intact.js", &cloudstate).unwrap();
+    assert!(has_root(&cloudstate, "written"));
+}
+
+#[test]
+fn test_quarantine_logs_removals_and_counts() {
+    let db = Database::builder()
+        .create_with_backend(InMemoryBackend::default())
+        .unwrap();
+    let cloudstate = ReDBCloudstate::new(Arc::new(Mutex::new(db))).unwrap();
+    refcount::enable(&cloudstate.get_database_mut()).unwrap();
+    run("tests/check/store.js", &cloudstate).unwrap();
+
+    // move the root called "corrupt" to a key that doesn't decode
+    let db = cloudstate.get_database_mut();
+    let key = encode::<Ordered<CloudstateRootKey>>(&CloudstateRootKey {
+        alias: "corrupt".to_string(),
+    });
+    let value = {
+        let write = db.begin_write().unwrap();
+        let value = {
+            let mut roots = write.open_table(raw(&tables::ROOTS_TABLE)).unwrap();
+            let value = roots
+                .remove(key.as_slice())
+                .unwrap()
+                .unwrap()
+                .value()
+                .to_vec();
+            roots
+                .insert(b"corrupt".as_slice(), value.as_slice())
+                .unwrap();
+            value
+        };
+        write.commit().unwrap();
+        value
+    };
+    let id = Bincode::<CloudstateRootValue>::decode(&value).unwrap().id;
+
+    let report = check_database(&db, true).unwrap();
+    assert_eq!(report.corrupt.len(), 1);
+    assert_eq!(report.corrupt[0].table, "roots");
+
+    // incremental backups see the row go
+    let read = db.begin_read().unwrap();
+    let sequences = read.open_table(tables::CHANGE_SEQUENCES_TABLE).unwrap();
+    let removed = CloudstateChange {
+        table: "roots".to_string(),
+        key: b"corrupt".to_vec(),
+    };
+    assert!(sequences.get(&removed).unwrap().is_some());
+
+    // and the object it pointed to is no longer counted as referenced
+    let counts = read.open_table(tables::REFERENCE_COUNTS_TABLE).unwrap();
+    let pointer = Pointer::Object(CloudstateObjectKey { id });
+    assert!(counts.get(&pointer).unwrap().is_none());
+}
@@ -1,11 +1,20 @@
-use redb::{backends::InMemoryBackend, Database, ReadableTable};
-use std::sync::{Arc, Mutex};
+use redb::{
+    backends::InMemoryBackend, Database, ReadableTable, ReadableTableMetadata, TableDefinition,
+};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
 
 use crate::{
     blob_storage::{in_memory_store::InMemoryBlobStore, CloudstateBlobStorage},
+    changes::PendingChanges,
     execution::run_script,
-    extensions::cloudstate::ReDBCloudstate,
-    gc::mark_and_sweep,
+    extensions::cloudstate::{
+        CloudstateId, CloudstateObjectData, CloudstateObjectKey, CloudstateObjectValue,
+        CloudstateRootKey, CloudstateRootValue, ReDBCloudstate, Transaction,
+    },
+    gc::{collect, mark_and_sweep, GcOptions, GcPhase, GcProgress},
     tables,
 };
 
@@ -14,7 +23,7 @@ fn test_gc_objects() {
     let db = Database::builder()
         .create_with_backend(InMemoryBackend::default())
         .unwrap();
-    let cloudstate = ReDBCloudstate::new(Arc::new(Mutex::new(db)));
+    let cloudstate = ReDBCloudstate::new(Arc::new(Mutex::new(db))).unwrap();
 
     let (cloudstate, _) = run_script(
         "tests/gc/base.js",
@@ -46,7 +55,7 @@ fn test_gc_objects() {
     read.close().unwrap();
 
     // Run the garbage collector
-    mark_and_sweep(&db).unwrap();
+    mark_and_sweep(&db, &CloudstateBlobStorage::default()).unwrap();
 
     let read = db.begin_read().unwrap();
 
@@ -73,7 +82,7 @@ fn test_gc_maps() {
     let db = Database::builder()
         .create_with_backend(InMemoryBackend::default())
         .unwrap();
-    let cloudstate = ReDBCloudstate::new(Arc::new(Mutex::new(db)));
+    let cloudstate = ReDBCloudstate::new(Arc::new(Mutex::new(db))).unwrap();
 
     let (cloudstate, _) = run_script(
         "tests/gc/map.js",
@@ -104,7 +113,7 @@ fn test_gc_maps() {
     read.close().unwrap();
 
     // Run the garbage collector
-    mark_and_sweep(&db).unwrap();
+    mark_and_sweep(&db, &CloudstateBlobStorage::default()).unwrap();
 
     let read = db.begin_read();
     let read = match read {
@@ -131,7 +140,7 @@ pub fn test_gc_array() {
     let db = Database::builder()
         .create_with_backend(InMemoryBackend::default())
         .unwrap();
-    let cloudstate = ReDBCloudstate::new(Arc::new(Mutex::new(db)));
+    let cloudstate = ReDBCloudstate::new(Arc::new(Mutex::new(db))).unwrap();
 
     let (cloudstate, _) = run_script(
         "tests/gc/array.js",
@@ -163,7 +172,7 @@ pub fn test_gc_array() {
     read.close().unwrap();
 
     // Run the garbage collector
-    mark_and_sweep(&db).unwrap();
+    mark_and_sweep(&db, &CloudstateBlobStorage::default()).unwrap();
 
     let read = db.begin_read();
     let read = match read {
@@ -184,3 +193,255 @@ pub fn test_gc_array() {
         assert_eq!(count, 5);
     }
 }
+
+#[test]
+pub fn test_gc_set() {
+    let db = Database::builder()
+        .create_with_backend(InMemoryBackend::default())
+        .unwrap();
+    let cloudstate = ReDBCloudstate::new(Arc::new(Mutex::new(db))).unwrap();
+
+    let (cloudstate, _) = run_script(
+        "tests/gc/set.js",
+        cloudstate,
+        CloudstateBlobStorage::new(Arc::new(InMemoryBlobStore::new())),
+    )
+    .unwrap();
+
+    let db = &cloudstate.get_database_mut();
+
+    // Run the garbage collector
+    mark_and_sweep(&db, &CloudstateBlobStorage::default()).unwrap();
+
+    let read = db.begin_read().unwrap();
+    {
+        let set_table = match read.open_table(tables::SETS_TABLE) {
+            Ok(table) => table,
+            Err(e) => panic!("Error opening sets table: {}", e),
+        };
+        let mut count = 0;
+        for item in set_table.iter().unwrap() {
+            if let Ok((_key, _value)) = item {
+                count += 1;
+            }
+        }
+        assert_eq!(count, 2);
+    }
+}
+
+#[test]
+fn test_gc_in_batches() {
+    let db = Database::builder()
+        .create_with_backend(InMemoryBackend::default())
+        .unwrap();
+    let cloudstate = ReDBCloudstate::new(Arc::new(Mutex::new(db))).unwrap();
+
+    let (cloudstate, _) = run_script(
+        "tests/gc/base.js",
+        cloudstate,
+        CloudstateBlobStorage::new(Arc::new(InMemoryBlobStore::new())),
+    )
+    .unwrap();
+
+    let mut sweep_batches = 0;
+    let options = GcOptions {
+        batch_size: 1,
+        ..GcOptions::default()
+    };
+    let progress = collect(
+        &cloudstate,
+        &CloudstateBlobStorage::default(),
+        &options,
+        &mut Some(Box::new(|progress: GcProgress| {
+            if progress.phase == GcPhase::Sweep {
+                sweep_batches += 1;
+            }
+        })),
+    )
+    .unwrap();
+
+    // one batch per row, plus one to find each table has run out
+    assert!(sweep_batches > progress.scanned);
+    assert_eq!(progress.marked, 3);
+
+    let read = cloudstate.get_database_mut().begin_read().unwrap();
+    let objects_table = read.open_table(tables::OBJECTS_TABLE).unwrap();
+    assert_eq!(objects_table.iter().unwrap().count(), 3);
+}
+
+#[test]
+fn test_gc_fails_without_sweeping_when_a_table_cannot_be_opened() {
+    let db = Database::builder()
+        .create_with_backend(InMemoryBackend::default())
+        .unwrap();
+    let cloudstate = ReDBCloudstate::new(Arc::new(Mutex::new(db))).unwrap();
+
+    let (cloudstate, _) = run_script(
+        "tests/gc/base.js",
+        cloudstate,
+        CloudstateBlobStorage::new(Arc::new(InMemoryBlobStore::new())),
+    )
+    .unwrap();
+
+    // a sets table this version of cloudstate can't open, which is nothing
+    // like one that was never created
+    {
+        let db = cloudstate.get_database_mut();
+        let write = db.begin_write().unwrap();
+        let mismatched: TableDefinition<&str, u64> = TableDefinition::new("sets");
+        write
+            .open_table(mismatched)
+            .unwrap()
+            .insert("a", 1)
+            .unwrap();
+        write.commit().unwrap();
+    }
+
+    let collected = collect(
+        &cloudstate,
+        &CloudstateBlobStorage::default(),
+        &GcOptions::default(),
+        &mut None,
+    );
+    assert!(collected.is_err());
+
+    let read = cloudstate.get_database_mut().begin_read().unwrap();
+    let objects_table = read.open_table(tables::OBJECTS_TABLE).unwrap();
+    assert_eq!(objects_table.len().unwrap(), 5);
+}
+
+/// Stores an empty object under the root `alias` in a transaction of its own,
+/// logging the writes like a request does
+fn store_root(cloudstate: &ReDBCloudstate, alias: &str, id: CloudstateId) {
+    let _permit = cloudstate.acquire_writer();
+    let write = cloudstate.get_database_mut().begin_write().unwrap();
+    let changes = PendingChanges::new(&write).unwrap();
+    let write = Transaction::Write(write, changes);
+    {
+        let mut objects = write.open_table(tables::OBJECTS_TABLE).unwrap();
+        objects
+            .insert(
+                CloudstateObjectKey { id },
+                CloudstateObjectValue {
+                    data: CloudstateObjectData {
+                        fields: HashMap::new(),
+                        constructor_name: None,
+                        version: 0,
+                    },
+                },
+            )
+            .unwrap();
+
+        let mut roots = write.open_table(tables::ROOTS_TABLE).unwrap();
+        roots
+            .insert(
+                CloudstateRootKey {
+                    alias: alias.to_string(),
+                },
+                CloudstateRootValue { id },
+            )
+            .unwrap();
+    }
+    write.commit().unwrap();
+}
+
+#[test]
+fn test_gc_keeps_objects_written_during_sweep() {
+    let db = Database::builder()
+        .create_with_backend(InMemoryBackend::default())
+        .unwrap();
+    let cloudstate = ReDBCloudstate::new(Arc::new(Mutex::new(db))).unwrap();
+
+    let (cloudstate, _) = run_script(
+        "tests/gc/base.js",
+        cloudstate,
+        CloudstateBlobStorage::new(Arc::new(InMemoryBlobStore::new())),
+    )
+    .unwrap();
+
+    // sorts after every other object, so the sweep hasn't passed it yet
+    let written = CloudstateId([0xff; 16]);
+    let mut stored = false;
+    let options = GcOptions {
+        batch_size: 1,
+        ..GcOptions::default()
+    };
+    collect(
+        &cloudstate,
+        &CloudstateBlobStorage::default(),
+        &options,
+        &mut Some(Box::new(|progress: GcProgress| {
+            // the first batch has only swept one of the objects
+            if progress.phase == GcPhase::Sweep && !stored {
+                store_root(&cloudstate, "written", written);
+                stored = true;
+            }
+        })),
+    )
+    .unwrap();
+    assert!(stored);
+
+    // the snapshot the collector marked didn't have the object, so only the
+    // change log kept it from being swept
+    let read = cloudstate.get_database_mut().begin_read().unwrap();
+    let objects = read.open_table(tables::OBJECTS_TABLE).unwrap();
+    assert!(
+        objects
+            .get(CloudstateObjectKey { id: written })
+            .unwrap()
+            .is_some()
+    );
+    assert_eq!(objects.len().unwrap(), 4);
+}
+
+/// The ids of the blobs a database has rows for
+fn blob_ids(cloudstate: &ReDBCloudstate) -> Vec<CloudstateId> {
+    let read = cloudstate.get_database_mut().begin_read().unwrap();
+    let blobs = read.open_table(tables::BLOBS_TABLE).unwrap();
+    blobs
+        .iter()
+        .unwrap()
+        .map(|item| item.unwrap().0.value().id)
+        .collect()
+}
+
+#[test]
+fn test_gc_blobs() {
+    let db = Database::builder()
+        .create_with_backend(InMemoryBackend::default())
+        .unwrap();
+    let cloudstate = ReDBCloudstate::new(Arc::new(Mutex::new(db))).unwrap();
+    let blob_storage = CloudstateBlobStorage::new(Arc::new(InMemoryBlobStore::new()));
+
+    let (cloudstate, _) = run_script("tests/gc/blob.js", cloudstate, blob_storage.clone()).unwrap();
+    let stored = blob_ids(&cloudstate);
+    assert_eq!(stored.len(), 3);
+
+    let dry_run = GcOptions {
+        dry_run: true,
+        ..GcOptions::default()
+    };
+    let progress = collect(&cloudstate, &blob_storage, &dry_run, &mut None).unwrap();
+    assert_eq!(progress.blobs, 1);
+    assert_eq!(progress.blob_bytes, 7);
+
+    // a dry run leaves both the rows and the payloads
+    assert_eq!(blob_ids(&cloudstate), stored);
+    for id in &stored {
+        assert!(blob_storage.has_blob(id).unwrap());
+    }
+
+    let progress = collect(&cloudstate, &blob_storage, &GcOptions::default(), &mut None).unwrap();
+    assert_eq!(progress.blobs, 1);
+    assert_eq!(progress.blob_bytes, 7);
+
+    let remaining = blob_ids(&cloudstate);
+    assert_eq!(remaining.len(), 2);
+    for id in &stored {
+        assert_eq!(
+            blob_storage.has_blob(id).unwrap(),
+            remaining.contains(id),
+            "payload of blob {id:?}"
+        );
+    }
+}
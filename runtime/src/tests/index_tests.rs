@@ -0,0 +1,66 @@
+use redb::{Database, TableDefinition, backends::InMemoryBackend};
+use std::sync::{Arc, Mutex};
+
+use crate::{
+    blob_storage::CloudstateBlobStorage, execution::run_script,
+    extensions::cloudstate::ReDBCloudstate,
+};
+
+#[test]
+fn test_query_index_reports_unreadable_table() {
+    let db = Database::builder()
+        .create_with_backend(InMemoryBackend::default())
+        .unwrap();
+    let cloudstate = ReDBCloudstate::new(Arc::new(Mutex::new(db))).unwrap();
+
+    // a field index this version of cloudstate can't open
+    {
+        let db = cloudstate.get_database_mut();
+        let write = db.begin_write().unwrap();
+        let mismatched: TableDefinition<&str, u64> = TableDefinition::new("field_index");
+        write
+            .open_table(mismatched)
+            .unwrap()
+            .insert("email", 1)
+            .unwrap();
+        write.commit().unwrap();
+    }
+
+    let (_, result) = run_script(
+        "tests/index/query_unreadable.js",
+        cloudstate,
+        CloudstateBlobStorage::default(),
+    )
+    .unwrap();
+    result.unwrap();
+}
+
+#[test]
+fn test_get_cloudstate_reports_unreadable_table() {
+    let db = Database::builder()
+        .create_with_backend(InMemoryBackend::default())
+        .unwrap();
+    let cloudstate = ReDBCloudstate::new(Arc::new(Mutex::new(db))).unwrap();
+
+    // an id index this version of cloudstate can't open, which mustn't look
+    // like the object doesn't exist
+    {
+        let db = cloudstate.get_database_mut();
+        let write = db.begin_write().unwrap();
+        let mismatched: TableDefinition<&str, u64> = TableDefinition::new("object_ids");
+        write
+            .open_table(mismatched)
+            .unwrap()
+            .insert("ada", 1)
+            .unwrap();
+        write.commit().unwrap();
+    }
+
+    let (_, result) = run_script(
+        "tests/index/cloudstate_unreadable.js",
+        cloudstate,
+        CloudstateBlobStorage::default(),
+    )
+    .unwrap();
+    result.unwrap();
+}
@@ -13,7 +13,8 @@ macro_rules! js_test {
                             .create_with_backend(redb::backends::InMemoryBackend::default())
                             .unwrap(),
                     ),
-                )),
+                ))
+                .unwrap(),
                 $crate::blob_storage::CloudstateBlobStorage::new(std::sync::Arc::new(
                     $crate::blob_storage::in_memory_store::InMemoryBlobStore::default(),
                 )),
@@ -0,0 +1,412 @@
+use redb::{
+    Database, ReadableTable, ReadableTableMetadata, TableDefinition, TableHandle,
+    backends::InMemoryBackend,
+};
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use crate::{
+    bincode::{Bincode, Raw, raw},
+    blob_storage::{
+        CloudstateBlobMetadata, CloudstateBlobStorage, CloudstateBlobStorageEngine,
+        in_memory_store::InMemoryBlobStore,
+    },
+    execution::run_script,
+    extensions::cloudstate::{
+        CloudstateClassVersionKey, CloudstateId, CloudstateMapFieldKey, CloudstateMapFieldValue,
+        CloudstateObjectData, CloudstateObjectIdIndexKey, CloudstateObjectKey,
+        CloudstateObjectValue, CloudstatePrimitiveData, CloudstateRootKey, CloudstateRootValue,
+        ReDBCloudstate,
+    },
+    migrations::{FORMAT_VERSION, legacy, migrate},
+    ordered::Ordered,
+    tables,
+};
+
+const LEGACY_BLOB_ID: &str = "legacy-blob";
+const LEGACY_OBJECT_ID: &str = "legacy-object";
+
+/// A database in format version 4, from before ids were 16 byte values, with a
+/// root object holding a blob whose id isn't a UUID
+fn legacy_blob_database() -> Database {
+    let db = Database::builder()
+        .create_with_backend(InMemoryBackend::default())
+        .unwrap();
+
+    let object_id = legacy::IdKey {
+        id: LEGACY_OBJECT_ID.to_string(),
+    };
+    let blob_id = legacy::IdKey {
+        id: LEGACY_BLOB_ID.to_string(),
+    };
+
+    let write = db.begin_write().unwrap();
+    {
+        write
+            .open_table(tables::METADATA_TABLE)
+            .unwrap()
+            .insert("format_version", 4)
+            .unwrap();
+
+        let mut roots = write.open_table(raw(&tables::ROOTS_TABLE)).unwrap();
+        let key = Ordered::encode(&CloudstateRootKey {
+            alias: "root".to_string(),
+        });
+        let value = bincode::serialize(&object_id).unwrap();
+        roots.insert(key.as_slice(), value.as_slice()).unwrap();
+
+        let mut objects = write.open_table(raw(&tables::OBJECTS_TABLE)).unwrap();
+        let key = Ordered::encode(&object_id);
+        let value = bincode::serialize(&legacy::ObjectValue {
+            data: legacy::ObjectData {
+                fields: HashMap::from([(
+                    "file".to_string(),
+                    legacy::PrimitiveData::Blob(blob_id.clone()),
+                )]),
+                constructor_name: None,
+                version: 0,
+            },
+        })
+        .unwrap();
+        objects.insert(key.as_slice(), value.as_slice()).unwrap();
+
+        let mut blobs = write.open_table(raw(&tables::BLOBS_TABLE)).unwrap();
+        let key = bincode::serialize(&blob_id).unwrap();
+        let value = bincode::serialize(&CloudstateBlobMetadata {
+            type_: "text/plain".to_string(),
+        })
+        .unwrap();
+        blobs.insert(key.as_slice(), value.as_slice()).unwrap();
+    }
+    write.commit().unwrap();
+
+    db
+}
+
+#[test]
+fn test_migration_moves_legacy_blob_payloads() {
+    let db = legacy_blob_database();
+    let engine = Arc::new(InMemoryBlobStore::new());
+    engine
+        .put_blob(LEGACY_BLOB_ID, b"hello".to_vec().into())
+        .unwrap();
+    let blob_storage = CloudstateBlobStorage::new(engine.clone());
+
+    migrate(&db, &blob_storage).unwrap();
+
+    let read = db.begin_read().unwrap();
+    let version = read
+        .open_table(tables::METADATA_TABLE)
+        .unwrap()
+        .get("format_version")
+        .unwrap()
+        .unwrap()
+        .value();
+    assert_eq!(version, FORMAT_VERSION);
+
+    let blobs = read.open_table(tables::BLOBS_TABLE).unwrap();
+    assert_eq!(blobs.len().unwrap(), 1);
+    let (key, metadata) = blobs.first().unwrap().unwrap();
+    let blob_id = key.value().id;
+    assert_eq!(metadata.value().type_, "text/plain");
+
+    // the object still refers to the same blob
+    let root = read
+        .open_table(tables::ROOTS_TABLE)
+        .unwrap()
+        .get(CloudstateRootKey {
+            alias: "root".to_string(),
+        })
+        .unwrap()
+        .unwrap()
+        .value();
+    let object = read
+        .open_table(tables::OBJECTS_TABLE)
+        .unwrap()
+        .get(CloudstateObjectKey { id: root.id })
+        .unwrap()
+        .unwrap()
+        .value();
+    match object.data.fields.get("file") {
+        Some(CloudstatePrimitiveData::Blob(blob)) => assert_eq!(blob.id, blob_id),
+        other => panic!("Expected a blob, got {:?}", other),
+    }
+
+    // and its payload moved with it
+    assert_eq!(blob_storage.get_blob_data(&blob_id).unwrap().data, b"hello");
+    assert!(!engine.has_blob(LEGACY_BLOB_ID).unwrap());
+}
+
+#[test]
+fn test_migration_indexes_objects_by_id() {
+    let db = Database::builder()
+        .create_with_backend(InMemoryBackend::default())
+        .unwrap();
+
+    let object = |id: &str| CloudstateObjectValue {
+        data: CloudstateObjectData {
+            fields: HashMap::from([(
+                "id".to_string(),
+                CloudstatePrimitiveData::String(id.to_string()),
+            )]),
+            constructor_name: None,
+            version: 0,
+        },
+    };
+    let unindexed = CloudstateObjectKey {
+        id: CloudstateId::random(),
+    };
+    let duplicate = CloudstateObjectKey {
+        id: CloudstateId::random(),
+    };
+    let indexed = CloudstateObjectKey {
+        id: CloudstateId::random(),
+    };
+
+    let write = db.begin_write().unwrap();
+    {
+        write
+            .open_table(tables::METADATA_TABLE)
+            .unwrap()
+            .insert("format_version", 6)
+            .unwrap();
+
+        let mut objects = write.open_table(tables::OBJECTS_TABLE).unwrap();
+        objects.insert(&unindexed, object("unindexed")).unwrap();
+        objects.insert(&duplicate, object("indexed")).unwrap();
+        objects.insert(&indexed, object("indexed")).unwrap();
+
+        // written after the index was added, so it's the latest object with
+        // its id
+        write
+            .open_table(tables::OBJECT_IDS_INDEX)
+            .unwrap()
+            .insert(
+                CloudstateObjectIdIndexKey {
+                    id: "indexed".to_string(),
+                },
+                &indexed,
+            )
+            .unwrap();
+    }
+    write.commit().unwrap();
+
+    migrate(&db, &CloudstateBlobStorage::default()).unwrap();
+
+    let read = db.begin_read().unwrap();
+    let index = read.open_table(tables::OBJECT_IDS_INDEX).unwrap();
+    let owner = |id: &str| {
+        index
+            .get(CloudstateObjectIdIndexKey { id: id.to_string() })
+            .unwrap()
+            .map(|owner| owner.value())
+    };
+    assert_eq!(owner("unindexed"), Some(unindexed));
+    assert_eq!(owner("indexed"), Some(indexed));
+    assert_eq!(index.len().unwrap(), 2);
+}
+
+#[test]
+fn test_migration_indexes_objects_by_class_version() {
+    let db = Database::builder()
+        .create_with_backend(InMemoryBackend::default())
+        .unwrap();
+
+    let object = |constructor_name: Option<&str>, version: u32| CloudstateObjectValue {
+        data: CloudstateObjectData {
+            fields: HashMap::new(),
+            constructor_name: constructor_name.map(str::to_string),
+            version,
+        },
+    };
+    let outdated = CloudstateObjectKey {
+        id: CloudstateId::random(),
+    };
+    let current = CloudstateObjectKey {
+        id: CloudstateId::random(),
+    };
+    let plain = CloudstateObjectKey {
+        id: CloudstateId::random(),
+    };
+
+    let write = db.begin_write().unwrap();
+    {
+        write
+            .open_table(tables::METADATA_TABLE)
+            .unwrap()
+            .insert("format_version", 7)
+            .unwrap();
+
+        let mut objects = write.open_table(tables::OBJECTS_TABLE).unwrap();
+        objects
+            .insert(&outdated, object(Some("_Counter"), 1))
+            .unwrap();
+        objects
+            .insert(&current, object(Some("_Counter"), 2))
+            .unwrap();
+        objects.insert(&plain, object(None, 0)).unwrap();
+    }
+    write.commit().unwrap();
+
+    migrate(&db, &CloudstateBlobStorage::default()).unwrap();
+
+    let read = db.begin_read().unwrap();
+    let index = read.open_table(tables::CLASS_VERSIONS_INDEX).unwrap();
+    let outdated_ids = index
+        .range(CloudstateClassVersionKey::outdated("Counter", 2))
+        .unwrap()
+        .map(|entry| entry.unwrap().0.value().object_id)
+        .collect::<Vec<_>>();
+    assert_eq!(outdated_ids, vec![outdated.id]);
+    assert_eq!(index.len().unwrap(), 2);
+}
+
+/// How objects were stored before they recorded their class version
+#[derive(Serialize)]
+struct UnversionedObjectValue {
+    data: UnversionedObjectData,
+}
+
+#[derive(Serialize)]
+struct UnversionedObjectData {
+    fields: HashMap<String, legacy::PrimitiveData>,
+    constructor_name: Option<String>,
+}
+
+/// How map field keys were stored before they kept their type
+#[derive(Serialize)]
+struct StringMapFieldKey {
+    id: String,
+    field: String,
+}
+
+/// A database from before the format was versioned, when map keys were
+/// always strings, with a root object holding a map
+fn string_map_key_database() -> Database {
+    let db = Database::builder()
+        .create_with_backend(InMemoryBackend::default())
+        .unwrap();
+
+    let object_id = CloudstateId::random().to_string();
+    let map_id = CloudstateId::random().to_string();
+
+    let roots: TableDefinition<Raw<Bincode<CloudstateRootKey>>, Raw<Bincode<CloudstateRootValue>>> =
+        TableDefinition::new(tables::ROOTS_TABLE.name());
+    let objects: TableDefinition<
+        Raw<Bincode<CloudstateObjectKey>>,
+        Raw<Bincode<CloudstateObjectValue>>,
+    > = TableDefinition::new(tables::OBJECTS_TABLE.name());
+    let maps: TableDefinition<
+        Raw<Bincode<CloudstateMapFieldKey>>,
+        Raw<Bincode<CloudstateMapFieldValue>>,
+    > = TableDefinition::new(tables::MAPS_TABLE.name());
+
+    let write = db.begin_write().unwrap();
+    {
+        let key = bincode::serialize(&CloudstateRootKey {
+            alias: "root".to_string(),
+        })
+        .unwrap();
+        let value = bincode::serialize(&legacy::IdKey {
+            id: object_id.clone(),
+        })
+        .unwrap();
+        let mut roots = write.open_table(roots).unwrap();
+        roots.insert(key.as_slice(), value.as_slice()).unwrap();
+
+        let key = bincode::serialize(&legacy::IdKey { id: object_id }).unwrap();
+        let value = bincode::serialize(&UnversionedObjectValue {
+            data: UnversionedObjectData {
+                fields: HashMap::from([(
+                    "map".to_string(),
+                    legacy::PrimitiveData::MapReference(map_id.clone()),
+                )]),
+                constructor_name: None,
+            },
+        })
+        .unwrap();
+        let mut objects = write.open_table(objects).unwrap();
+        objects.insert(key.as_slice(), value.as_slice()).unwrap();
+
+        let key = bincode::serialize(&StringMapFieldKey {
+            id: map_id,
+            field: "greeting".to_string(),
+        })
+        .unwrap();
+        let value = bincode::serialize(&legacy::ItemValue {
+            data: legacy::PrimitiveData::String("hello".to_string()),
+        })
+        .unwrap();
+        let mut maps = write.open_table(maps).unwrap();
+        maps.insert(key.as_slice(), value.as_slice()).unwrap();
+    }
+    write.commit().unwrap();
+
+    db
+}
+
+#[test]
+fn test_migration_reads_string_map_keys() {
+    let db = string_map_key_database();
+    let blob_storage = CloudstateBlobStorage::default();
+    migrate(&db, &blob_storage).unwrap();
+
+    let cloudstate = ReDBCloudstate::new(Arc::new(Mutex::new(db))).unwrap();
+    let (_, result) = run_script(
+        "tests/migration/string_map_keys.js",
+        cloudstate,
+        blob_storage,
+    )
+    .unwrap();
+    result.unwrap();
+}
+
+#[test]
+fn test_open_refuses_newer_format() {
+    let db = Database::builder()
+        .create_with_backend(InMemoryBackend::default())
+        .unwrap();
+    let write = db.begin_write().unwrap();
+    write
+        .open_table(tables::METADATA_TABLE)
+        .unwrap()
+        .insert("format_version", FORMAT_VERSION + 1)
+        .unwrap();
+    write.commit().unwrap();
+
+    let error = migrate(&db, &CloudstateBlobStorage::default()).unwrap_err();
+    assert!(error.to_string().contains("Upgrade cloudstate"), "{error}");
+
+    let error = ReDBCloudstate::new(Arc::new(Mutex::new(db))).err().unwrap();
+    assert!(error.to_string().contains("Upgrade cloudstate"), "{error}");
+}
+
+#[test]
+fn test_open_refuses_older_format() {
+    let error = ReDBCloudstate::new(Arc::new(Mutex::new(legacy_blob_database())))
+        .err()
+        .unwrap();
+    assert!(error.to_string().contains("Migrate it"), "{error}");
+}
+
+#[test]
+fn test_open_records_format_of_new_database() {
+    let db = Database::builder()
+        .create_with_backend(InMemoryBackend::default())
+        .unwrap();
+    let cloudstate = ReDBCloudstate::new(Arc::new(Mutex::new(db))).unwrap();
+
+    let db = cloudstate.get_database_mut();
+    let read = db.begin_read().unwrap();
+    let version = read
+        .open_table(tables::METADATA_TABLE)
+        .unwrap()
+        .get("format_version")
+        .unwrap()
+        .unwrap()
+        .value();
+    assert_eq!(version, FORMAT_VERSION);
+}
@@ -0,0 +1,203 @@
+use chrono::DateTime;
+use std::{cmp::Ordering, fmt::Debug};
+
+use crate::{
+    extensions::cloudstate::CloudstateMapKey,
+    ordered::{Ordered, OrderedKey},
+};
+
+/// A xorshift generator, so the values checked are the same on every run
+struct Values(u64);
+
+impl Values {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+
+    fn string(&mut self) -> String {
+        // few enough characters that strings often share prefixes
+        const CHARS: &[char] = &['\0', '\u{1}', 'a', 'b', '\u{7f}', 'é', '\u{10ffff}'];
+        (0..self.below(5))
+            .map(|_| CHARS[self.below(CHARS.len() as u64) as usize])
+            .collect()
+    }
+
+    fn bigint(&mut self) -> CloudstateMapKey {
+        let words = (0..self.below(4))
+            .map(|_| match self.below(3) {
+                0 => 0,
+                1 => u64::MAX,
+                _ => self.next(),
+            })
+            .collect();
+        CloudstateMapKey::bigint(self.below(2) == 0, words)
+    }
+
+    fn map_key(&mut self) -> CloudstateMapKey {
+        match self.below(5) {
+            0 => CloudstateMapKey::Boolean(self.below(2) == 0),
+            1 => CloudstateMapKey::number(f64::from_bits(self.next())),
+            2 => self.bigint(),
+            3 => CloudstateMapKey::String(self.string()),
+            _ => CloudstateMapKey::Date(
+                DateTime::from_timestamp(
+                    self.next() as i64 % 10_000_000_000,
+                    self.below(1_000_000_000) as u32,
+                )
+                .unwrap(),
+            ),
+        }
+    }
+}
+
+/// Checks that every pair of keys compares the same encoded as it does
+/// decoded, and that every key decodes to itself
+fn check<T: OrderedKey + Debug>(
+    keys: &[T],
+    cmp: impl Fn(&T, &T) -> Ordering,
+    same: impl Fn(&T, &T) -> bool,
+) {
+    let encoded: Vec<Vec<u8>> = keys.iter().map(Ordered::encode).collect();
+
+    for (key, bytes) in keys.iter().zip(&encoded) {
+        let decoded = Ordered::<T>::decode(bytes).unwrap();
+        assert!(same(key, &decoded), "{key:?} decoded as {decoded:?}");
+    }
+
+    for (a, a_bytes) in keys.iter().zip(&encoded) {
+        for (b, b_bytes) in keys.iter().zip(&encoded) {
+            assert_eq!(
+                a_bytes.cmp(b_bytes),
+                cmp(a, b),
+                "{a:?} and {b:?} compare differently encoded"
+            );
+        }
+    }
+}
+
+fn check_ord<T: OrderedKey + Ord + Debug>(keys: &[T]) {
+    check(keys, T::cmp, T::eq);
+}
+
+#[test]
+fn test_ordered_strings() {
+    let mut keys: Vec<String> = [
+        "",
+        "\0",
+        "\0\0",
+        "\0\u{1}",
+        "\u{1}",
+        "a",
+        "a\0",
+        "a\0\0",
+        "a\0b",
+        "a\u{1}",
+        "ab",
+        "b",
+        "\u{7f}",
+        "é",
+        "\u{10ffff}",
+    ]
+    .iter()
+    .map(|key| key.to_string())
+    .collect();
+
+    let mut values = Values(0x2545f4914f6cdd1d);
+    keys.extend((0..200).map(|_| values.string()));
+
+    check_ord(&keys);
+}
+
+#[test]
+fn test_ordered_integers() {
+    let mut small = vec![i32::MIN, i32::MIN + 1, -256, -1, 0, 1, 255, 256, i32::MAX];
+    let mut large = vec![
+        i64::MIN,
+        i64::MIN + 1,
+        i32::MIN as i64 - 1,
+        -1,
+        0,
+        1,
+        i32::MAX as i64 + 1,
+        i64::MAX,
+    ];
+
+    let mut values = Values(0x9e3779b97f4a7c15);
+    small.extend((0..200).map(|_| values.next() as i32));
+    large.extend((0..200).map(|_| values.next() as i64));
+
+    check_ord(&small);
+    check_ord(&large);
+}
+
+#[test]
+fn test_ordered_floats() {
+    let mut keys = vec![
+        f64::NEG_INFINITY,
+        f64::MIN,
+        -1.5,
+        -f64::MIN_POSITIVE,
+        -0.0,
+        0.0,
+        f64::MIN_POSITIVE,
+        f64::EPSILON,
+        1.5,
+        f64::MAX,
+        f64::INFINITY,
+        f64::NAN,
+        -f64::NAN,
+    ];
+
+    // random bits cover subnormals and NaNs with payloads
+    let mut values = Values(0xd1b54a32d192ed03);
+    keys.extend((0..200).map(|_| f64::from_bits(values.next())));
+
+    check(&keys, f64::total_cmp, |a, b| a.to_bits() == b.to_bits());
+}
+
+#[test]
+fn test_ordered_map_keys() {
+    let date =
+        |seconds, nanos| CloudstateMapKey::Date(DateTime::from_timestamp(seconds, nanos).unwrap());
+    let mut keys = vec![
+        CloudstateMapKey::Boolean(false),
+        CloudstateMapKey::Boolean(true),
+        CloudstateMapKey::number(f64::NEG_INFINITY),
+        CloudstateMapKey::number(-1.0),
+        CloudstateMapKey::number(-0.0),
+        CloudstateMapKey::number(0.0),
+        CloudstateMapKey::number(1.0),
+        CloudstateMapKey::number(f64::INFINITY),
+        CloudstateMapKey::number(f64::NAN),
+        CloudstateMapKey::bigint(true, vec![u64::MAX, u64::MAX, 1]),
+        CloudstateMapKey::bigint(true, vec![0, 1]),
+        CloudstateMapKey::bigint(true, vec![u64::MAX]),
+        CloudstateMapKey::bigint(true, vec![1]),
+        CloudstateMapKey::bigint(true, vec![0]),
+        CloudstateMapKey::bigint(false, vec![]),
+        CloudstateMapKey::bigint(false, vec![1]),
+        CloudstateMapKey::bigint(false, vec![u64::MAX]),
+        CloudstateMapKey::bigint(false, vec![0, 1]),
+        CloudstateMapKey::bigint(false, vec![u64::MAX, u64::MAX, 1]),
+        CloudstateMapKey::String(String::new()),
+        CloudstateMapKey::String("\0".to_string()),
+        CloudstateMapKey::String("a\0b".to_string()),
+        date(-62_135_596_800, 0),
+        date(-1, 999_999_999),
+        date(0, 0),
+        date(0, 1),
+        date(1_700_000_000, 500_000_000),
+    ];
+
+    let mut values = Values(0xbf58476d1ce4e5b9);
+    keys.extend((0..300).map(|_| values.map_key()));
+
+    check_ord(&keys);
+}
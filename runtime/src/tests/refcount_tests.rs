@@ -0,0 +1,79 @@
+use redb::{Database, ReadableTableMetadata, backends::InMemoryBackend};
+use std::sync::{Arc, Mutex};
+
+use crate::{
+    blob_storage::{CloudstateBlobStorage, in_memory_store::InMemoryBlobStore},
+    execution::run_script,
+    extensions::cloudstate::ReDBCloudstate,
+    gc::mark_and_sweep,
+    refcount, tables,
+};
+
+fn run_counted(path: &str) -> ReDBCloudstate {
+    let db = Database::builder()
+        .create_with_backend(InMemoryBackend::default())
+        .unwrap();
+    refcount::enable(&db).unwrap();
+
+    let (cloudstate, result) = run_script(
+        path,
+        ReDBCloudstate::new(Arc::new(Mutex::new(db))).unwrap(),
+        CloudstateBlobStorage::new(Arc::new(InMemoryBlobStore::new())),
+    )
+    .unwrap();
+    result.unwrap();
+
+    cloudstate
+}
+
+#[test]
+fn test_refcount_overwrite() {
+    let cloudstate = run_counted("tests/refcount/overwrite.js");
+
+    let db = cloudstate.get_database_mut();
+    let read = db.begin_read().unwrap();
+
+    // the root, the object it still references twice and the new child
+    let objects = read.open_table(tables::OBJECTS_TABLE).unwrap();
+    assert_eq!(objects.len().unwrap(), 3);
+
+    let arrays = read.open_table(tables::ARRAYS_TABLE).unwrap();
+    assert_eq!(arrays.len().unwrap(), 0);
+}
+
+#[test]
+fn test_refcount_cycle() {
+    let cloudstate = run_counted("tests/refcount/cycle.js");
+
+    let db = cloudstate.get_database_mut();
+    {
+        let read = db.begin_read().unwrap();
+        let objects = read.open_table(tables::OBJECTS_TABLE).unwrap();
+        assert_eq!(objects.len().unwrap(), 2);
+    }
+
+    // cycles are left to the garbage collector
+    mark_and_sweep(&db, &CloudstateBlobStorage::default()).unwrap();
+
+    let read = db.begin_read().unwrap();
+    let objects = read.open_table(tables::OBJECTS_TABLE).unwrap();
+    assert_eq!(objects.len().unwrap(), 1);
+
+    let counts = read.open_table(tables::REFERENCE_COUNTS_TABLE).unwrap();
+    assert_eq!(counts.len().unwrap(), 1);
+}
+
+#[test]
+fn test_refcount_rereference_after_commit() {
+    let cloudstate = run_counted("tests/refcount/rereference.js");
+
+    let db = cloudstate.get_database_mut();
+    let read = db.begin_read().unwrap();
+
+    // the root and the object it references again
+    let objects = read.open_table(tables::OBJECTS_TABLE).unwrap();
+    assert_eq!(objects.len().unwrap(), 2);
+
+    let arrays = read.open_table(tables::ARRAYS_TABLE).unwrap();
+    assert_eq!(arrays.len().unwrap(), 2);
+}
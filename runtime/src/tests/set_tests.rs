@@ -0,0 +1,36 @@
+use redb::{Database, TableDefinition, backends::InMemoryBackend};
+use std::sync::{Arc, Mutex};
+
+use crate::{
+    blob_storage::CloudstateBlobStorage, execution::run_script,
+    extensions::cloudstate::ReDBCloudstate,
+};
+
+#[test]
+fn test_set_ops_report_unreadable_table() {
+    let db = Database::builder()
+        .create_with_backend(InMemoryBackend::default())
+        .unwrap();
+    let cloudstate = ReDBCloudstate::new(Arc::new(Mutex::new(db))).unwrap();
+
+    // a sets table this version of cloudstate can't open
+    {
+        let db = cloudstate.get_database_mut();
+        let write = db.begin_write().unwrap();
+        let mismatched: TableDefinition<&str, u64> = TableDefinition::new("sets");
+        write
+            .open_table(mismatched)
+            .unwrap()
+            .insert("a", 1)
+            .unwrap();
+        write.commit().unwrap();
+    }
+
+    let (_, result) = run_script(
+        "tests/set/unreadable.js",
+        cloudstate,
+        CloudstateBlobStorage::default(),
+    )
+    .unwrap();
+    result.unwrap();
+}
@@ -0,0 +1,75 @@
+{
+  const object = {
+    value: ["c", "d"],
+  };
+  setRoot("test-root", object);
+  commit();
+}
+
+// END_FILE
+
+{
+  const object = getRoot("test-root");
+
+  // unshift below the first stored item
+  const length = object.value.unshift("a", "b");
+  if (length !== 4) {
+    throw new Error(`Expected length to be 4, got ${length}`);
+  }
+  commit();
+}
+
+// END_FILE
+
+{
+  const expectedArr = ["a", "b", "c", "d"];
+
+  const object = getRoot("test-root");
+  if (object.value.length !== expectedArr.length) {
+    throw new Error(
+      `Expected length to be ${expectedArr.length}, got ${object.value.length}`,
+    );
+  }
+  for (const [i, expected] of expectedArr.entries()) {
+    if (object.value[i] !== expected) {
+      throw new Error(
+        `Expected ${expected} at index ${i}, got ${object.value[i]}`,
+      );
+    }
+  }
+
+  const shifted = object.value.shift();
+  if (shifted !== "a") {
+    throw new Error(`Expected "a", got ${shifted}`);
+  }
+  object.value.push("e");
+  commit();
+}
+
+// END_FILE
+
+{
+  const expectedArr = ["b", "c", "d", "e"];
+
+  const object = getRoot("test-root");
+  if (object.value.length !== expectedArr.length) {
+    throw new Error(
+      `Expected length to be ${expectedArr.length}, got ${object.value.length}`,
+    );
+  }
+  for (const [i, expected] of expectedArr.entries()) {
+    if (object.value[i] !== expected) {
+      throw new Error(
+        `Expected ${expected} at index ${i}, got ${object.value[i]}`,
+      );
+    }
+  }
+
+  const popped = object.value.pop();
+  if (popped !== "e") {
+    throw new Error(`Expected "e", got ${popped}`);
+  }
+  if (object.value.length !== 3) {
+    throw new Error(`Expected length to be 3, got ${object.value.length}`);
+  }
+}
@@ -0,0 +1,8 @@
+{
+  const root = getRoot("test-root");
+  root.added = new Blob(["added after the full backup"], {
+    type: "text/plain",
+  });
+
+  commit();
+}
@@ -0,0 +1,8 @@
+{
+  // leaves the dropped object and its array unreachable
+  const root = getRoot("test-root");
+  delete root.dropped;
+  root.kept.value = 3;
+
+  commit();
+}
@@ -0,0 +1,9 @@
+{
+  const root = {
+    kept: { value: 1 },
+    dropped: { value: 2, items: [1, 2, 3] },
+  };
+
+  setRoot("test-root", root);
+  commit();
+}
@@ -0,0 +1,7 @@
+{
+  setRoot("test-root", {
+    text: new Blob(["hello"], { type: "text/plain" }),
+    bytes: new Blob([new Uint8Array([0, 1, 2, 255])]),
+  });
+  commit();
+}
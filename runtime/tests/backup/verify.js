@@ -0,0 +1,14 @@
+{
+  const root = getRoot("test-root");
+  if (!root) {
+    throw new Error("root should exist");
+  }
+  if (root.kept.value !== 3) {
+    throw new Error("root.kept.value should be 3");
+  }
+  if (root.dropped !== undefined) {
+    throw new Error("root.dropped should have been deleted");
+  }
+
+  commit();
+}
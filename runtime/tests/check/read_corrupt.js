@@ -0,0 +1,15 @@
+{
+  let error;
+  try {
+    getRoot("corrupt");
+  } catch (e) {
+    error = e;
+  }
+  if (!(error instanceof CorruptRowError)) {
+    throw new Error(`reading the corrupt object should throw, got ${error}`);
+  }
+
+  // the transaction read a corrupt row, so this is rolled back
+  setRoot("written", { value: 3 });
+  commit();
+}
@@ -0,0 +1,9 @@
+{
+  const root = getRoot("intact");
+  if (root.value !== 1) {
+    throw new Error("the intact root should still be readable");
+  }
+
+  setRoot("written", { value: 3 });
+  commit();
+}
@@ -0,0 +1,5 @@
+{
+  setRoot("intact", { value: 1 });
+  setRoot("corrupt", { value: 2 });
+  commit();
+}
@@ -0,0 +1,113 @@
+{
+  class Todo {
+    title = "";
+    done = false;
+  }
+
+  registerCustomClass(Todo);
+
+  const first = new Todo();
+  first.title = "write tests";
+  const second = new Todo();
+  second.title = "ship it";
+  second.done = true;
+
+  setRoot("test-root", { first, second });
+  commit();
+}
+
+// END_FILE
+
+{
+  class Todo {
+    static version = 1;
+    static migrate(old, fromVersion) {
+      if (fromVersion !== 0) {
+        throw new Error(`expected to migrate from version 0, got ${fromVersion}`);
+      }
+      return {
+        text: old.title,
+        completed: old.done,
+        tags: ["migrated"],
+      };
+    }
+  }
+
+  registerCustomClass(Todo);
+
+  const root = getRoot("test-root");
+  const first = root.first;
+
+  if (!(first instanceof Todo)) {
+    throw new Error("first should still be an instance of Todo");
+  }
+  if (first.text !== "write tests") {
+    throw new Error(`first.text should be "write tests", got ${first.text}`);
+  }
+  if (first.completed !== false) {
+    throw new Error("first.completed should be false");
+  }
+  if (first.title !== undefined) {
+    throw new Error("first.title should have been migrated away");
+  }
+  if (first.tags[0] !== "migrated") {
+    throw new Error("first.tags should be stored with the object");
+  }
+
+  commit();
+}
+
+// END_FILE
+
+{
+  let migrations = 0;
+
+  class Todo {
+    static version = 1;
+    static migrate(old) {
+      migrations++;
+      return {
+        text: old.title,
+        completed: old.done,
+        tags: ["migrated"],
+      };
+    }
+  }
+
+  registerCustomClass(Todo);
+
+  // only the object that wasn't read yet is still at version 0
+  const migrated = migrateObjects();
+  if (migrated !== 1 || migrations !== 1) {
+    throw new Error(`one object should be migrated, got ${migrated}`);
+  }
+
+  const root = getRoot("test-root");
+  if (root.second.text !== "ship it" || root.second.completed !== true) {
+    throw new Error("second should be migrated");
+  }
+
+  commit();
+}
+
+// END_FILE
+
+{
+  class Todo {
+    static version = 1;
+    static migrate() {
+      throw new Error("objects should only be migrated once");
+    }
+  }
+
+  registerCustomClass(Todo);
+
+  if (migrateObjects() !== 0) {
+    throw new Error("every object should already be migrated");
+  }
+
+  const root = getRoot("test-root");
+  if (root.first.text !== "write tests" || root.second.text !== "ship it") {
+    throw new Error("migrated objects should keep their new shape");
+  }
+}
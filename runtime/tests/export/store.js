@@ -0,0 +1,36 @@
+{
+  const node = { name: "node" };
+  const other = { name: "other", back: node };
+  node.self = node;
+  node.next = other;
+
+  setRoot("export-root", {
+    big: 2n ** 100n,
+    negativeBig: -(2n ** 70n) - 1n,
+    nan: NaN,
+    infinity: Infinity,
+    negativeInfinity: -Infinity,
+    negativeZero: -0,
+    date: new Date("2001-02-03T04:05:06.789Z"),
+    blob: new Blob(["exported payload"], { type: "text/plain" }),
+    map: new Map([
+      [1, "number"],
+      ["1", "string"],
+      [2n ** 70n, "bigint"],
+      [true, "boolean"],
+      [new Date(0), "date"],
+    ]),
+    set: new Set([1, "one", node]),
+    queue: ["dropped", "first", node, ["nested"]],
+    node,
+  });
+  commit();
+}
+
+// END_FILE
+
+{
+  // arrays are exported relative to where they start now
+  getRoot("export-root").queue.shift();
+  commit();
+}
@@ -0,0 +1,59 @@
+{
+  const root = getRoot("export-root");
+
+  if (root.big !== 2n ** 100n || root.negativeBig !== -(2n ** 70n) - 1n) {
+    throw new Error(
+      `Expected bigints to survive, got ${root.big}, ${root.negativeBig}`,
+    );
+  }
+  if (!Number.isNaN(root.nan)) {
+    throw new Error(`Expected NaN, got ${root.nan}`);
+  }
+  if (root.infinity !== Infinity || root.negativeInfinity !== -Infinity) {
+    throw new Error("Expected infinities to survive");
+  }
+  if (!Object.is(root.negativeZero, -0)) {
+    throw new Error(`Expected -0, got ${root.negativeZero}`);
+  }
+  if (root.date.toISOString() !== "2001-02-03T04:05:06.789Z") {
+    throw new Error(`Expected the date to survive, got ${root.date}`);
+  }
+
+  const map = root.map;
+  if (
+    map.size !== 5 ||
+    map.get(1) !== "number" ||
+    map.get("1") !== "string" ||
+    map.get(2n ** 70n) !== "bigint" ||
+    map.get(true) !== "boolean" ||
+    map.get(new Date(0)) !== "date"
+  ) {
+    throw new Error(
+      `Expected map keys to keep their types, got ${[...map.keys()]}`,
+    );
+  }
+
+  const node = root.node;
+  if (node.self !== node || node.next.back !== node) {
+    throw new Error("Expected cycles to survive");
+  }
+  if (!root.set.has(1) || !root.set.has("one") || !root.set.has(node)) {
+    throw new Error("Expected set members to survive");
+  }
+
+  const queue = root.queue;
+  if (
+    queue.length !== 3 ||
+    queue[0] !== "first" ||
+    queue[1] !== node ||
+    queue[2][0] !== "nested"
+  ) {
+    throw new Error(`Expected the shifted array to survive, got ${queue}`);
+  }
+
+  root.blob.text().then((text) => {
+    if (root.blob.type !== "text/plain" || text !== "exported payload") {
+      throw new Error(`Expected the blob to survive, got ${text}`);
+    }
+  });
+}
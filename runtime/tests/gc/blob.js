@@ -0,0 +1,18 @@
+{
+  setRoot("test-root", {
+    kept: new Blob(["hello"], { type: "text/plain" }),
+    replaced: new Blob(["goodbye"], { type: "text/plain" }),
+  });
+  commit();
+}
+
+// END_FILE
+
+{
+  // the first blob stored under replaced is now garbage
+  const root = getRoot("test-root");
+  root.replaced = new Blob(["hi"], { type: "text/plain" });
+
+  setRoot("test-root", root);
+  commit();
+}
@@ -50,8 +50,7 @@
 
   delete root.nested1.value;
 
-  transaction.setObject(root);
-  transaction.setRoot("test-root", root);
+  setRoot("test-root", root);
 
   commit();
 }
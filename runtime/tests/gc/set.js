@@ -0,0 +1,26 @@
+{
+  // Test that the garbage collector works correctly
+  const root = {
+    nested: {
+      value: new Set([1, 2, 3]),
+      value2: new Set(["a", "b"]),
+    },
+  };
+
+  setRoot("test-root", root);
+  commit();
+}
+
+// END_FILE
+
+{
+  // drop the first set so its members become unreachable
+  const root = getRoot("test-root");
+  if (!root) {
+    throw new Error("root should exist");
+  }
+
+  root.nested.value = null;
+
+  commit();
+}
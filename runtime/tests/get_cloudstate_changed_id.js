@@ -0,0 +1,57 @@
+{
+  class CounterCS {
+    id = "counter";
+    count = 0;
+  }
+
+  registerCustomClass(CounterCS);
+
+  const object = {
+    counter: new CounterCS(),
+  };
+
+  setRoot("test-root", object);
+  commit();
+}
+
+// END_FILE
+
+{
+  class CounterCS {
+    id = "counter";
+    count = 0;
+  }
+
+  registerCustomClass(CounterCS);
+
+  const counter = getCloudstate("counter");
+  if (!counter) {
+    throw new Error("counter should exist");
+  }
+  counter.id = "renamed-counter";
+  counter.count = 1;
+  commit();
+}
+
+// END_FILE
+
+{
+  class CounterCS {
+    id = "counter";
+    count = 0;
+  }
+
+  registerCustomClass(CounterCS);
+
+  if (getCloudstate("counter") !== undefined) {
+    throw new Error("counter should no longer be found by its old id");
+  }
+
+  const counter = getCloudstate("renamed-counter");
+  if (!counter) {
+    throw new Error("counter should be found by its new id");
+  }
+  if (counter.count !== 1) {
+    throw new Error("counter.count should be 1");
+  }
+}
@@ -0,0 +1,12 @@
+{
+  let error;
+  try {
+    getCloudstate("ada");
+  } catch (e) {
+    error = e;
+  }
+
+  if (!error) {
+    throw new Error("getCloudstate should fail when the id index can't be read");
+  }
+}
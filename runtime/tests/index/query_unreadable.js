@@ -0,0 +1,12 @@
+{
+  let error;
+  try {
+    queryIndex("User", "email", "ada@example.com");
+  } catch (e) {
+    error = e;
+  }
+
+  if (!error) {
+    throw new Error("queryIndex should fail when the index can't be read");
+  }
+}
@@ -0,0 +1,50 @@
+{
+  const base = new Map([
+    ["order:1", "a"],
+    ["order:2", "b"],
+    ["user:ada", "c"],
+    ["user:alan", "d"],
+    ["user:grace", "e"],
+    ["users", "f"],
+  ]);
+  const object = {
+    value: base,
+  };
+
+  setRoot("test-root", object);
+  commit();
+}
+
+// END_FILE
+
+{
+  const assertKeys = (entries, expected) => {
+    const keys = entries.map(([key]) => key).join(",");
+    if (keys !== expected.join(",")) {
+      throw new Error(`Expected keys ${expected}, got ${keys}`);
+    }
+  };
+
+  const object = getRoot("test-root");
+
+  assertKeys(object.value.prefix("user:"), [
+    "user:ada",
+    "user:alan",
+    "user:grace",
+  ]);
+  assertKeys(object.value.prefix("user:a"), ["user:ada", "user:alan"]);
+  assertKeys(object.value.prefix("user:", { reverse: true, limit: 2 }), [
+    "user:grace",
+    "user:alan",
+  ]);
+  assertKeys(object.value.prefix("nothing"), []);
+
+  // paging through by starting after the last key seen
+  const firstPage = object.value.prefix("user:", { limit: 2 });
+  const lastKey = firstPage[firstPage.length - 1][0];
+  const secondPage = object.value.prefix("user:", {
+    start: lastKey + "\u0000",
+    limit: 2,
+  });
+  assertKeys(secondPage, ["user:grace"]);
+}
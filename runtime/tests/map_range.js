@@ -0,0 +1,46 @@
+{
+  const base = new Map([
+    ["a", 1],
+    ["b", 2],
+    ["c", 3],
+    ["d", { value: 4 }],
+    ["e", 5],
+  ]);
+  const object = {
+    value: base,
+  };
+
+  setRoot("test-root", object);
+  commit();
+}
+
+// END_FILE
+
+{
+  const assertKeys = (entries, expected) => {
+    const keys = entries.map(([key]) => key).join(",");
+    if (keys !== expected.join(",")) {
+      throw new Error(`Expected keys ${expected}, got ${keys}`);
+    }
+  };
+
+  const object = getRoot("test-root");
+
+  const entries = object.value.range("b", "e");
+  assertKeys(entries, ["b", "c", "d"]);
+  if (entries[0][1] !== 2) {
+    throw new Error(`Expected 2, got ${entries[0][1]}`);
+  }
+  if (entries[2][1].value !== 4) {
+    throw new Error(`Expected nested object value 4, got ${entries[2][1].value}`);
+  }
+
+  assertKeys(object.value.range("c"), ["c", "d", "e"]);
+  assertKeys(object.value.range(undefined, "c"), ["a", "b"]);
+  assertKeys(object.value.range("a", "f", { limit: 2 }), ["a", "b"]);
+  assertKeys(object.value.range("a", "f", { limit: 2, reverse: true }), [
+    "e",
+    "d",
+  ]);
+  assertKeys(object.value.range("d", "b"), []);
+}
@@ -0,0 +1,79 @@
+{
+  const base = new Map([
+    ["1", "string one"],
+    [1, "number one"],
+    [-0, "zero"],
+    [2n, "bigint two"],
+    [-(2n ** 70n), "big negative bigint"],
+    [true, "yes"],
+    [new Date(0), "epoch"],
+  ]);
+  const object = {
+    value: base,
+  };
+
+  setRoot("test-root", object);
+  commit();
+}
+
+// END_FILE
+
+{
+  const object = getRoot("test-root");
+  const map = object.value;
+
+  if (map.size !== 7) {
+    throw new Error(`Expected 7 keys, got ${map.size}`);
+  }
+
+  const expected = [
+    [1, "number one"],
+    ["1", "string one"],
+    [0, "zero"],
+    [2n, "bigint two"],
+    [-(2n ** 70n), "big negative bigint"],
+    [true, "yes"],
+  ];
+  for (const [key, value] of expected) {
+    if (map.get(key) !== value) {
+      throw new Error(
+        `Expected ${String(key)} (${typeof key}) to map to ${value}, got ${map.get(key)}`,
+      );
+    }
+  }
+  if (map.get(new Date(0)) !== "epoch") {
+    throw new Error("Expected Date keys to be found by their time");
+  }
+  if (map.has(2)) {
+    throw new Error("Number 2 should not match the bigint key 2n");
+  }
+
+  // keys come back with the types they were stored with, ordered by type then value
+  const keys = [...map.keys()];
+  const types = keys.map((key) => key instanceof Date ? "date" : typeof key);
+  const expectedTypes = [
+    "boolean",
+    "number",
+    "number",
+    "bigint",
+    "bigint",
+    "string",
+    "date",
+  ];
+  if (types.join(",") !== expectedTypes.join(",")) {
+    throw new Error(`Expected key types ${expectedTypes}, got ${types}`);
+  }
+  if (keys[3] !== -(2n ** 70n) || keys[4] !== 2n) {
+    throw new Error(`Expected bigint keys in order, got ${keys[3]}, ${keys[4]}`);
+  }
+
+  const numbers = map.range(0, 10).map(([key]) => key);
+  if (numbers.join(",") !== "0,1") {
+    throw new Error(`Expected number keys 0,1 in range, got ${numbers}`);
+  }
+
+  map.delete(1);
+  if (map.has(1) || !map.has("1")) {
+    throw new Error("Deleting 1 should only remove the number key");
+  }
+}
@@ -0,0 +1,14 @@
+{
+  const root = getRoot("root");
+  if (!root) {
+    throw new Error("root should exist");
+  }
+
+  const greeting = root.map.get("greeting");
+  if (greeting !== "hello") {
+    throw new Error(`Expected the map to read "hello", got ${greeting}`);
+  }
+  if (root.map.size !== 1) {
+    throw new Error(`Expected 1 entry, got ${root.map.size}`);
+  }
+}
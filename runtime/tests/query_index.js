@@ -0,0 +1,90 @@
+{
+  class User {
+    static indexes = ["email", "status"];
+
+    constructor(email, status) {
+      this.email = email;
+      this.status = status;
+    }
+  }
+
+  registerCustomClass(User);
+
+  const object = {
+    users: [
+      new User("ada@example.com", "active"),
+      new User("grace@example.com", "active"),
+      new User("alan@example.com", "invited"),
+    ],
+  };
+
+  setRoot("test-root", object);
+  commit();
+}
+
+// END_FILE
+
+{
+  class User {
+    static indexes = ["email", "status"];
+
+    constructor(email, status) {
+      this.email = email;
+      this.status = status;
+    }
+  }
+
+  registerCustomClass(User);
+
+  const [ada] = queryIndex(User, "email", "ada@example.com");
+  if (!ada) {
+    throw new Error("ada should be found by email");
+  }
+  if (!(ada instanceof User)) {
+    throw new Error("ada should be an instance of User");
+  }
+  if (ada.status !== "active") {
+    throw new Error(`Expected ada to be active, got ${ada.status}`);
+  }
+
+  const active = queryIndex(User, "status", "active");
+  if (active.length !== 2) {
+    throw new Error(`Expected 2 active users, got ${active.length}`);
+  }
+
+  // moving a user to a new value should update the index
+  ada.status = "suspended";
+  commit();
+}
+
+// END_FILE
+
+{
+  class User {
+    static indexes = ["email", "status"];
+
+    constructor(email, status) {
+      this.email = email;
+      this.status = status;
+    }
+  }
+
+  registerCustomClass(User);
+
+  const active = queryIndex(User, "status", "active");
+  if (active.length !== 1) {
+    throw new Error(`Expected 1 active user, got ${active.length}`);
+  }
+  if (active[0].email !== "grace@example.com") {
+    throw new Error(`Expected grace to be active, got ${active[0].email}`);
+  }
+
+  const suspended = queryIndex("User", "status", "suspended");
+  if (suspended.length !== 1 || suspended[0].email !== "ada@example.com") {
+    throw new Error("Expected ada to be suspended");
+  }
+
+  if (queryIndex(User, "email", "nobody@example.com").length !== 0) {
+    throw new Error("Expected no users for an unknown email");
+  }
+}
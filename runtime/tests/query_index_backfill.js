@@ -0,0 +1,69 @@
+{
+  // written before the class declared any indexes
+  class User {
+    constructor(email, status) {
+      this.email = email;
+      this.status = status;
+    }
+  }
+
+  registerCustomClass(User);
+
+  const object = {
+    users: [
+      new User("ada@example.com", "active"),
+      new User("grace@example.com", "active"),
+      new User("alan@example.com", "invited"),
+    ],
+  };
+
+  setRoot("test-root", object);
+  commit();
+}
+
+// END_FILE
+
+{
+  class User {
+    static indexes = ["email"];
+
+    constructor(email, status) {
+      this.email = email;
+      this.status = status;
+    }
+  }
+
+  registerCustomClass(User);
+
+  const [ada] = queryIndex(User, "email", "ada@example.com");
+  if (!ada || ada.status !== "active") {
+    throw new Error("ada should be found by email once it's indexed");
+  }
+  commit();
+}
+
+// END_FILE
+
+{
+  class User {
+    static indexes = ["status"];
+
+    constructor(email, status) {
+      this.email = email;
+      this.status = status;
+    }
+  }
+
+  registerCustomClass(User);
+
+  const active = queryIndex(User, "status", "active");
+  if (active.length !== 2) {
+    throw new Error(`Expected 2 active users, got ${active.length}`);
+  }
+
+  // the field it stopped indexing is dropped from the index
+  if (queryIndex(User, "email", "ada@example.com").length !== 0) {
+    throw new Error("email should no longer be indexed");
+  }
+  commit();
+}
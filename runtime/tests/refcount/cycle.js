@@ -0,0 +1,22 @@
+{
+  const node = { value: 1 };
+  node.self = node;
+
+  setRoot("test-root", { node });
+  commit();
+}
+
+// END_FILE
+
+{
+  // the node still references itself, so its count never drops to zero
+  const root = getRoot("test-root");
+  if (!root) {
+    throw new Error("root should exist");
+  }
+
+  delete root.node;
+
+  setRoot("test-root", root);
+  commit();
+}
@@ -0,0 +1,49 @@
+{
+  const shared = { value: 1 };
+  const root = {
+    a: shared,
+    b: shared,
+    child: {
+      value: 2,
+      nested: { value: 3 },
+    },
+    list: [{ value: 4 }, { value: 5 }],
+  };
+
+  setRoot("test-root", root);
+  commit();
+}
+
+// END_FILE
+
+{
+  // drop one of two references to shared, and the only ones to the rest
+  const root = getRoot("test-root");
+  if (!root) {
+    throw new Error("root should exist");
+  }
+
+  delete root.a;
+  root.child = { value: 6 };
+  root.list = [];
+
+  setRoot("test-root", root);
+  commit();
+}
+
+// END_FILE
+
+{
+  const root = getRoot("test-root");
+  if (root.b.value !== 1) {
+    throw new Error("root.b should still exist");
+  }
+  if (root.child.value !== 6) {
+    throw new Error("root.child should be the new object");
+  }
+  if (root.list.length !== 0) {
+    throw new Error("root.list should be empty");
+  }
+
+  commit();
+}
@@ -0,0 +1,35 @@
+{
+  setRoot("test-root", { a: { value: 1, list: [2, 3] }, b: null });
+  commit();
+}
+
+// END_FILE
+
+{
+  const root = getRoot("test-root");
+  const x = root.a;
+  root.a = null;
+
+  // drops the last reference to x, as waiting on the event loop does
+  commit();
+
+  root.b = x;
+  commit();
+}
+
+// END_FILE
+
+{
+  const root = getRoot("test-root");
+  if (root.a !== null) {
+    throw new Error("root.a should be null");
+  }
+  if (root.b.value !== 1) {
+    throw new Error("root.b should still exist");
+  }
+  if (root.b.list.length !== 2 || root.b.list[1] !== 3) {
+    throw new Error("root.b.list should still exist");
+  }
+
+  commit();
+}
@@ -0,0 +1,12 @@
+{
+  let error;
+  try {
+    setRoot("test-root", { tags: new Set(["a", "b"]) });
+  } catch (e) {
+    error = e;
+  }
+
+  if (!error) {
+    throw new Error("storing a set should fail when its table can't be read");
+  }
+}
@@ -0,0 +1,36 @@
+{
+  const object = {
+    value: new Set(),
+  };
+
+  setRoot("test-root", object);
+  commit();
+}
+
+// END_FILE
+
+{
+  const object = getRoot("test-root");
+
+  const returned = object.value.add("a").add("b").add("a");
+  if (returned !== object.value) {
+    throw new Error("add should return the set");
+  }
+
+  commit();
+}
+
+// END_FILE
+
+{
+  const object = getRoot("test-root");
+
+  if (object.value.size !== 2) {
+    throw new Error(
+      `object.value should have size 2, got ${object.value.size}`,
+    );
+  }
+  if (!object.value.has("a") || !object.value.has("b")) {
+    throw new Error("object.value should have 'a' and 'b'");
+  }
+}
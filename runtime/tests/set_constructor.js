@@ -0,0 +1,25 @@
+{
+  const object = {
+    value: new Set([1, "a", 2n, true]),
+  };
+
+  setRoot("test-root", object);
+  commit();
+}
+
+// END_FILE
+
+{
+  const object = getRoot("test-root");
+  if (!object) {
+    throw new Error("object should exist");
+  }
+  if (!(object.value instanceof Set)) {
+    throw new Error("object.value should be a Set");
+  }
+  for (const expected of [1, "a", 2n, true]) {
+    if (!object.value.has(expected)) {
+      throw new Error(`object.value should have ${String(expected)}`);
+    }
+  }
+}
@@ -0,0 +1,53 @@
+{
+  const object = {
+    value: new Set(["a", "b", "c"]),
+  };
+
+  setRoot("test-root", object);
+  commit();
+}
+
+// END_FILE
+
+{
+  const object = getRoot("test-root");
+
+  if (!object.value.delete("a")) {
+    throw new Error("Expected 'a' to be deleted (return value should be truthy)");
+  }
+  if (object.value.delete("z")) {
+    throw new Error("Expected falsy because 'z' does not exist");
+  }
+
+  commit();
+}
+
+// END_FILE
+
+{
+  const object = getRoot("test-root");
+
+  if (object.value.has("a")) {
+    throw new Error("object.value should not have 'a'");
+  }
+  if (object.value.size !== 2) {
+    throw new Error(
+      `object.value should have size 2, got ${object.value.size}`,
+    );
+  }
+
+  object.value.clear();
+  commit();
+}
+
+// END_FILE
+
+{
+  const object = getRoot("test-root");
+
+  if (object.value.size !== 0) {
+    throw new Error(
+      `object.value should be empty after clear, got ${object.value.size}`,
+    );
+  }
+}
@@ -0,0 +1,34 @@
+{
+  const object = {
+    value: new Set(["foo", 1, -0]),
+  };
+
+  setRoot("test-root", object);
+  commit();
+}
+
+// END_FILE
+
+{
+  const object = getRoot("test-root");
+
+  if (!object.value.has("foo")) {
+    throw new Error(`Expected ${JSON.stringify("foo")} to exist`);
+  }
+  if (!object.value.has(1)) {
+    throw new Error(`Expected ${JSON.stringify(1)} to exist`);
+  }
+  if (!object.value.has(0)) {
+    throw new Error(`Expected ${JSON.stringify(0)} to exist`);
+  }
+
+  if (object.value.has("1")) {
+    throw new Error(`Expected ${JSON.stringify("1")} to not exist`);
+  }
+  if (object.value.has(undefined)) {
+    throw new Error(`Expected ${JSON.stringify(undefined)} to not exist`);
+  }
+  if (object.value.has({})) {
+    throw new Error(`Expected ${JSON.stringify({})} to not exist`);
+  }
+}
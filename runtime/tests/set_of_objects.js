@@ -0,0 +1,30 @@
+{
+  const item = { name: "first" };
+  const object = {
+    item,
+    value: new Set([item, { name: "second" }]),
+  };
+
+  setRoot("test-root", object);
+  commit();
+}
+
+// END_FILE
+
+{
+  const object = getRoot("test-root");
+
+  if (object.value.size !== 2) {
+    throw new Error(
+      `object.value should have size 2, got ${object.value.size}`,
+    );
+  }
+  if (!object.value.has(object.item)) {
+    throw new Error("object.value should contain object.item");
+  }
+
+  const names = [...object.value].map((item) => item.name).sort();
+  if (JSON.stringify(names) !== JSON.stringify(["first", "second"])) {
+    throw new Error(`Unexpected names ${JSON.stringify(names)}`);
+  }
+}
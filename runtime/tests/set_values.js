@@ -0,0 +1,37 @@
+{
+  const object = {
+    value: new Set(["c", "a", "b"]),
+  };
+
+  setRoot("test-root", object);
+  commit();
+}
+
+// END_FILE
+
+{
+  const object = getRoot("test-root");
+
+  const values = [...object.value.values()].sort();
+  if (JSON.stringify(values) !== JSON.stringify(["a", "b", "c"])) {
+    throw new Error(`Unexpected values ${JSON.stringify(values)}`);
+  }
+
+  const iterated = [];
+  for (const value of object.value) {
+    iterated.push(value);
+  }
+  if (iterated.length !== 3) {
+    throw new Error(`Expected 3 iterations, got ${iterated.length}`);
+  }
+
+  let count = 0;
+  object.value.forEach((value, key, set) => {
+    if (value !== key) throw new Error("forEach value and key should match");
+    if (set !== object.value) throw new Error("forEach should pass the set");
+    count++;
+  });
+  if (count !== 3) {
+    throw new Error(`Expected forEach to run 3 times, got ${count}`);
+  }
+}
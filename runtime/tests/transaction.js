@@ -0,0 +1,100 @@
+{
+  setRoot("test-root", { count: 0 });
+  commit();
+}
+
+// END_FILE
+
+{
+  const counter = getRoot("test-root");
+
+  try {
+    await transaction(async () => {
+      counter.count = 1;
+      counter.items = [1, 2, 3];
+      await new Promise((resolve) => setTimeout(resolve, 10));
+      throw new Error("rolled back");
+    });
+    throw new Error("transaction should rethrow the error");
+  } catch (e) {
+    if (e.message !== "rolled back") throw e;
+  }
+
+  if (counter.count !== 0) {
+    throw new Error(`count should be rolled back to 0, got ${counter.count}`);
+  }
+  if (counter.items !== undefined) {
+    throw new Error("items should be rolled back");
+  }
+
+  // concurrent calls share one transaction, so one throwing discards both
+  const [slow, failing] = await Promise.allSettled([
+    transaction(async () => {
+      counter.slow = true;
+      await new Promise((resolve) => setTimeout(resolve, 20));
+    }),
+    transaction(async () => {
+      counter.failing = true;
+      await new Promise((resolve) => setTimeout(resolve, 5));
+      throw new Error("failed");
+    }),
+  ]);
+  if (failing.status !== "rejected" || failing.reason.message !== "failed") {
+    throw new Error("the failing transaction should rethrow its error");
+  }
+  if (slow.status !== "rejected") {
+    throw new Error("the slow transaction should be rolled back with it");
+  }
+  if (counter.slow !== undefined || counter.failing !== undefined) {
+    throw new Error("concurrent transactions should be rolled back together");
+  }
+
+  // a nested call that throws rolls back its caller, even if it's caught
+  try {
+    await transaction(async () => {
+      counter.outer = true;
+      try {
+        await transaction(() => {
+          throw new Error("inner");
+        });
+      } catch {
+        // the outer call carries on, but can't commit
+      }
+    });
+    throw new Error("the outer transaction should throw");
+  } catch (e) {
+    if (e.message === "the outer transaction should throw") throw e;
+  }
+  if (counter.outer !== undefined) {
+    throw new Error("outer should be rolled back with the nested call");
+  }
+
+  const result = await transaction(async () => {
+    counter.count = 2;
+    await transaction(() => {
+      counter.count++;
+    });
+    await new Promise((resolve) => setTimeout(resolve, 10));
+    return counter.count;
+  });
+
+  if (result !== 3) {
+    throw new Error(`transaction should return 3, got ${result}`);
+  }
+}
+
+// END_FILE
+
+{
+  const counter = getRoot("test-root");
+
+  if (counter.count !== 3) {
+    throw new Error(`count should be 3, got ${counter.count}`);
+  }
+  if (counter.items !== undefined) {
+    throw new Error("items should not have been stored");
+  }
+  if (counter.slow !== undefined || counter.outer !== undefined) {
+    throw new Error("rolled back writes should not have been stored");
+  }
+}
@@ -0,0 +1,56 @@
+//! Compares how long a runtime with the cloudstate extensions takes to start
+//! when their JavaScript is evaluated from source with how long it takes from
+//! the startup snapshot.
+//!
+//! Run with `cargo bench -p server --bench startup`.
+
+use std::hint::black_box;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use cloudstate_runtime::cloudstate_extensions::cloudstate_extensions;
+use cloudstate_runtime::transpile;
+use deno_core::{JsRuntime, RuntimeOptions};
+use server::cloudstate_runner::execute::create_cloudstate_runtime;
+use server::cloudstate_runner::limits::ScriptLimits;
+use server::cloudstate_runner::module_loader::CloudstateModuleLoader;
+
+const RUNS: u32 = 50;
+
+fn main() {
+    let source = measure(|| {
+        JsRuntime::new(RuntimeOptions {
+            extensions: cloudstate_extensions(),
+            extension_transpiler: Some(Rc::new(|specifier, source| {
+                transpile::maybe_transpile_source(specifier, source)
+            })),
+            ..Default::default()
+        })
+    });
+    let snapshot =
+        measure(|| create_cloudstate_runtime(CloudstateModuleLoader::new(String::new())));
+
+    println!("{} runs", RUNS);
+    println!("source   {:>8.2} ms/runtime", source.as_secs_f64() * 1000.0);
+    println!(
+        "snapshot {:>8.2} ms/runtime",
+        snapshot.as_secs_f64() * 1000.0
+    );
+    println!("{:.1}x", source.as_secs_f64() / snapshot.as_secs_f64());
+}
+
+/// The mean time to start a runtime, not counting dropping it
+fn measure(start_runtime: impl Fn() -> JsRuntime) -> Duration {
+    // the first runtime on a thread also sets up V8
+    drop(start_runtime());
+
+    let mut total = Duration::ZERO;
+    for _ in 0..RUNS {
+        let start = Instant::now();
+        let js_runtime = black_box(start_runtime());
+        total += start.elapsed();
+        drop(js_runtime);
+    }
+
+    total / RUNS
+}
@@ -0,0 +1,12 @@
+use std::path::PathBuf;
+
+fn main() {
+    let out_dir = PathBuf::from(std::env::var("OUT_DIR").unwrap());
+    let files =
+        cloudstate_runtime::snapshot::create_snapshot(&out_dir.join("CLOUDSTATE_SNAPSHOT.bin"))
+            .expect("failed to create the cloudstate snapshot");
+
+    for file in files {
+        println!("cargo:rerun-if-changed={}", file.display());
+    }
+}
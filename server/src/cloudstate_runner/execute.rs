@@ -2,17 +2,23 @@ use std::{cell::RefCell, future::poll_fn, rc::Rc, task::Poll};
 
 use cloudstate_runtime::{
     blob_storage::CloudstateBlobStorage,
-    cloudstate_extensions::cloudstate_extensions,
+    cloudstate_extensions::cloudstate_extensions_from_snapshot,
     extensions::cloudstate::{JavaScriptSpans, ReDBCloudstate, TransactionContext},
     permissions::CloudstatePermissions,
-    v8_string_key,
+    v8_string_key, RequestArgs,
 };
-use deno_core::{v8, JsRuntime, ModuleSpecifier};
+use deno_core::{error::CoreError, v8, JsRuntime, ModuleId, ModuleSpecifier};
 use futures_util::FutureExt;
 use serde_json::json;
 use tracing::{debug, event, instrument};
 
-use crate::{cloudstate_runner::module_loader::CloudstateModuleLoader, CloudstateFetchPermissions};
+use crate::{
+    cloudstate_runner::{
+        limits::{LimitExceeded, Limiter, ScriptLimits},
+        module_loader::CloudstateModuleLoader,
+    },
+    CloudstateFetchPermissions,
+};
 
 pub async fn execute_script(
     script: &str,
@@ -20,6 +26,8 @@ pub async fn execute_script(
     cs: ReDBCloudstate,
     blob_storage: CloudstateBlobStorage,
     server_info: crate::ServerInfo,
+    args: RequestArgs,
+    limits: ScriptLimits,
     // js_runner: impl CloudstateRunner + 'static,
 ) -> String {
     let script_string = script.to_string();
@@ -36,6 +44,8 @@ pub async fn execute_script(
             cs,
             blob_storage,
             server_info,
+            args,
+            limits,
         )
     })
     .await
@@ -44,7 +54,7 @@ pub async fn execute_script(
 
 // type CloudstateNodePermissions = AllowAllNodePermissions;
 
-#[instrument(skip(script, classes_script, cs, blob_storage, server_info))]
+#[instrument(skip(script, classes_script, cs, blob_storage, server_info, args, limits))]
 #[tokio::main(flavor = "current_thread")]
 pub async fn execute_script_internal(
     script: &str,
@@ -52,11 +62,14 @@ pub async fn execute_script_internal(
     cs: ReDBCloudstate,
     blob_storage: CloudstateBlobStorage,
     server_info: crate::ServerInfo,
+    args: RequestArgs,
+    limits: ScriptLimits,
 ) -> String {
     let (sender, reciever) = tokio::sync::oneshot::channel();
-    let mut js_runtime = initialize_cloudstate_runtime(reciever);
+    let mut js_runtime = initialize_cloudstate_runtime(reciever, &limits);
 
     RefCell::borrow_mut(&js_runtime.op_state()).put(server_info);
+    RefCell::borrow_mut(&js_runtime.op_state()).put(args);
 
     run_script(
         script,
@@ -71,10 +84,24 @@ pub async fn execute_script_internal(
 
 pub fn initialize_cloudstate_runtime(
     reciever: tokio::sync::oneshot::Receiver<String>,
+    limits: &ScriptLimits,
+) -> JsRuntime {
+    create_cloudstate_runtime(CloudstateModuleLoader::new_async(reciever), limits)
+}
+
+/// The extensions' JavaScript evaluated ahead of time by the build script
+pub static CLOUDSTATE_SNAPSHOT: &[u8] =
+    include_bytes!(concat!(env!("OUT_DIR"), "/CLOUDSTATE_SNAPSHOT.bin"));
+
+pub fn create_cloudstate_runtime(
+    module_loader: CloudstateModuleLoader,
+    limits: &ScriptLimits,
 ) -> JsRuntime {
     let mut js_runtime = JsRuntime::new(deno_core::RuntimeOptions {
-        module_loader: Some(Rc::new(CloudstateModuleLoader::new_async(reciever))),
-        extensions: cloudstate_extensions(),
+        module_loader: Some(Rc::new(module_loader)),
+        startup_snapshot: Some(CLOUDSTATE_SNAPSHOT),
+        create_params: limits.create_params(),
+        extensions: cloudstate_extensions_from_snapshot(),
         extension_transpiler: Some(Rc::new(|specifier, source| {
             cloudstate_runtime::transpile::maybe_transpile_source(specifier, source)
         })),
@@ -86,6 +113,7 @@ pub fn initialize_cloudstate_runtime(
     RefCell::borrow_mut(&js_runtime.op_state()).put(CloudstatePermissions {});
     RefCell::borrow_mut(&js_runtime.op_state()).put(CloudstateFetchPermissions {});
     RefCell::borrow_mut(&js_runtime.op_state()).put(JavaScriptSpans::new());
+    Limiter::install(&mut js_runtime, limits);
 
     // RefCell::borrow_mut(&js_runtime.op_state()).put(CloudstateNodePermissions {});
 
@@ -108,38 +136,80 @@ pub async fn run_script(
 
     RefCell::borrow_mut(&js_runtime.op_state()).put(transaction_context);
 
-    let main_module = ModuleSpecifier::from_file_path(
-        std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("src/main.js"),
-    )
-    .unwrap();
-    let script = script.to_string();
-    let future = async move {
-        let mod_id = js_runtime
-            .load_main_es_module_from_code(&main_module, script)
-            .await
-            .unwrap();
-
-        debug!("evaluating module");
-        let mut evaluation = js_runtime.mod_evaluate(mod_id);
-
-        debug!("starting js event loop polling");
-        let result = poll_fn(|cx| match evaluation.poll_unpin(cx) {
-            Poll::Pending => {
-                let poll_result = js_runtime.poll_event_loop(cx, Default::default());
-                let _ = js_runtime.execute_script("<handle>", "globalThis.commit();");
-                poll_result
-            }
-            Poll::Ready(result) => return Poll::Ready(result),
-        })
-        .await;
-        debug!("ending js event loop polling");
-
-        (js_runtime, result)
-    };
-
-    let (js_runtime, result) = future.await;
+    let main_module = script_specifier("main.js");
+    let mod_id = js_runtime
+        .load_main_es_module_from_code(&main_module, script.to_string())
+        .await
+        .unwrap();
+
+    let result = evaluate_module(js_runtime, mod_id).await;
     event!(tracing::Level::DEBUG, "result: {:#?}", result);
 
+    if let Some(exceeded) = take_exceeded(js_runtime) {
+        return exceeded.to_result();
+    }
+
+    take_result(js_runtime)
+}
+
+/// Where a script is loaded from, which is what `./lib.js` in it resolves
+/// against
+pub fn script_specifier(name: &str) -> ModuleSpecifier {
+    ModuleSpecifier::from_file_path(
+        std::path::Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("src")
+            .join(name),
+    )
+    .unwrap()
+}
+
+/// Runs a loaded module to the end, committing whenever it waits on the
+/// event loop, and terminating it if it runs past the runtime's timeout
+pub async fn evaluate_module(
+    js_runtime: &mut JsRuntime,
+    mod_id: ModuleId,
+) -> Result<(), CoreError> {
+    let limiter = RefCell::borrow(&js_runtime.op_state())
+        .try_borrow::<Limiter>()
+        .cloned();
+    let _deadline = limiter.as_ref().and_then(Limiter::start);
+
+    debug!("evaluating module");
+    let mut evaluation = js_runtime.mod_evaluate(mod_id);
+
+    debug!("starting js event loop polling");
+    let result = poll_fn(|cx| match evaluation.poll_unpin(cx) {
+        Poll::Pending => {
+            let poll_result = js_runtime.poll_event_loop(cx, Default::default());
+            let _ = js_runtime.execute_script("<handle>", "globalThis.commit();");
+            poll_result
+        }
+        Poll::Ready(result) => Poll::Ready(result),
+    })
+    .await;
+    debug!("ending js event loop polling");
+
+    result
+}
+
+/// Which limit the script just evaluated went over, if any, rolling back
+/// what it left uncommitted
+pub fn take_exceeded(js_runtime: &mut JsRuntime) -> Option<LimitExceeded> {
+    let op_state = js_runtime.op_state();
+    let mut op_state = RefCell::borrow_mut(&op_state);
+    let exceeded = op_state.try_borrow::<Limiter>()?.take_exceeded()?;
+
+    debug!("{}", exceeded);
+    if let Some(transaction_context) = op_state.try_borrow_mut::<TransactionContext>() {
+        transaction_context.abort_transaction();
+    }
+
+    Some(exceeded)
+}
+
+/// Reads `globalThis.result`, which scripts set to what they respond with, as
+/// JSON
+pub fn take_result(js_runtime: &mut JsRuntime) -> String {
     let mut js_runtime = js_runtime.handle_scope();
     let scope = &mut js_runtime;
     let context = scope.get_current_context();
@@ -0,0 +1,220 @@
+//! Limits on how long a script can run and how much heap it can use, so a
+//! method with an infinite loop or runaway allocation doesn't hang its thread
+//! or take the process down. A script over either is terminated, and its
+//! caller gets an error saying which. Time spent waiting for a slow client to
+//! read a response doesn't count towards the timeout.
+
+use std::{
+    fmt,
+    rc::Rc,
+    sync::{
+        Arc, Mutex,
+        mpsc::{Receiver, RecvTimeoutError, Sender, channel},
+    },
+    time::{Duration, Instant},
+};
+
+use cloudstate_runtime::extensions::cloudstate::ScriptClock;
+use deno_core::{JsRuntime, v8};
+use serde_json::json;
+
+#[derive(Debug, Clone)]
+pub struct ScriptLimits {
+    /// How long a script can run before it's terminated
+    pub timeout: Option<Duration>,
+    /// How big the heap can grow before the script is terminated
+    pub max_heap_bytes: Option<usize>,
+}
+
+impl Default for ScriptLimits {
+    fn default() -> Self {
+        Self {
+            timeout: Some(Duration::from_secs(30)),
+            max_heap_bytes: Some(512 * 1024 * 1024),
+        }
+    }
+}
+
+impl ScriptLimits {
+    /// No limits, for scripts an operator runs, like migrations
+    pub fn unlimited() -> Self {
+        Self {
+            timeout: None,
+            max_heap_bytes: None,
+        }
+    }
+
+    /// The heap limit, which has to be set when the runtime is created
+    pub fn create_params(&self) -> Option<v8::CreateParams> {
+        self.max_heap_bytes
+            .map(|max_heap_bytes| v8::CreateParams::default().heap_limits(0, max_heap_bytes))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitExceeded {
+    Timeout(Duration),
+    Heap(usize),
+}
+
+impl fmt::Display for LimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LimitExceeded::Timeout(timeout) => {
+                write!(f, "Script was terminated after running for {:?}", timeout)
+            }
+            LimitExceeded::Heap(max_heap_bytes) => write!(
+                f,
+                "Script was terminated for using more than {} bytes of heap",
+                max_heap_bytes
+            ),
+        }
+    }
+}
+
+impl LimitExceeded {
+    /// The result a script would have set, for the caller
+    pub fn to_result(&self) -> String {
+        let code = match self {
+            LimitExceeded::Timeout(_) => "timeout",
+            LimitExceeded::Heap(_) => "heap_limit",
+        };
+
+        json!({
+            "error": {
+                "message": self.to_string(),
+                "stack": self.to_string(),
+                "code": code,
+            }
+        })
+        .to_string()
+    }
+}
+
+/// Terminates a runtime's scripts when they go over its limits. It's kept in
+/// the runtime's op state.
+#[derive(Clone)]
+pub struct Limiter {
+    limits: ScriptLimits,
+    handle: v8::IsolateHandle,
+    exceeded: Arc<Mutex<Option<LimitExceeded>>>,
+    /// Pauses and resumes the running deadline, if there is one
+    clock: Arc<Mutex<Option<Sender<Clock>>>>,
+}
+
+enum Clock {
+    Pause,
+    Resume,
+}
+
+impl Limiter {
+    /// Watches a runtime created with `ScriptLimits::create_params`
+    pub fn install(js_runtime: &mut JsRuntime, limits: &ScriptLimits) {
+        let limiter = Limiter {
+            limits: limits.clone(),
+            handle: js_runtime.v8_isolate().thread_safe_handle(),
+            exceeded: Arc::new(Mutex::new(None)),
+            clock: Arc::new(Mutex::new(None)),
+        };
+
+        if let Some(max_heap_bytes) = limits.max_heap_bytes {
+            let limiter = limiter.clone();
+            js_runtime.add_near_heap_limit_callback(move |current_limit, _initial_limit| {
+                limiter.terminate(LimitExceeded::Heap(max_heap_bytes));
+                // leave the script room to unwind instead of crashing. The
+                // raised limit stays for the rest of the isolate's life, so
+                // a runtime that went over is never given another script.
+                current_limit * 2
+            });
+        }
+
+        let clock: Rc<dyn ScriptClock> = Rc::new(limiter.clone());
+        js_runtime.op_state().borrow_mut().put(clock);
+        js_runtime.op_state().borrow_mut().put(limiter);
+    }
+
+    fn terminate(&self, exceeded: LimitExceeded) {
+        self.exceeded.lock().unwrap().get_or_insert(exceeded);
+        self.handle.terminate_execution();
+    }
+
+    /// Terminates whatever is running once the timeout has passed, not
+    /// counting the time the clock is paused, unless the returned deadline
+    /// has been dropped by then
+    pub fn start(&self) -> Option<Deadline> {
+        let timeout = self.limits.timeout?;
+        let (clock, events) = channel();
+        *self.clock.lock().unwrap() = Some(clock);
+        let limiter = self.clone();
+        std::thread::spawn(move || {
+            if let Err(RecvTimeoutError::Timeout) = wait_out(timeout, events) {
+                limiter.terminate(LimitExceeded::Timeout(timeout));
+            }
+        });
+
+        Some(Deadline {
+            clock: self.clock.clone(),
+        })
+    }
+
+    fn tick(&self, event: Clock) {
+        if let Some(clock) = self.clock.lock().unwrap().as_ref() {
+            // the deadline has passed if its thread is gone
+            let _ = clock.send(event);
+        }
+    }
+
+    /// Which limit was gone over since the last call, if any, letting the
+    /// runtime run scripts again
+    pub fn take_exceeded(&self) -> Option<LimitExceeded> {
+        let exceeded = self.exceeded.lock().unwrap().take();
+        if exceeded.is_some() {
+            self.handle.cancel_terminate_execution();
+        }
+        exceeded
+    }
+}
+
+impl ScriptClock for Limiter {
+    fn pause(&self) {
+        self.tick(Clock::Pause);
+    }
+
+    fn resume(&self) {
+        self.tick(Clock::Resume);
+    }
+}
+
+/// Waits until `timeout` has run out while unpaused, returning `Timeout`, or
+/// until the clock is dropped, returning `Disconnected`
+fn wait_out(timeout: Duration, events: Receiver<Clock>) -> Result<(), RecvTimeoutError> {
+    let mut remaining = timeout;
+    let mut paused: usize = 0;
+    loop {
+        let event = if paused > 0 {
+            events.recv().map_err(|_| RecvTimeoutError::Disconnected)?
+        } else {
+            let started = Instant::now();
+            let event = events.recv_timeout(remaining)?;
+            remaining = remaining.saturating_sub(started.elapsed());
+            event
+        };
+
+        match event {
+            Clock::Pause => paused += 1,
+            Clock::Resume => paused = paused.saturating_sub(1),
+        }
+    }
+}
+
+/// Cancels a timeout when dropped
+pub struct Deadline {
+    clock: Arc<Mutex<Option<Sender<Clock>>>>,
+}
+
+impl Drop for Deadline {
+    fn drop(&mut self) {
+        // the deadline's thread stops once nothing can send to it
+        self.clock.lock().unwrap().take();
+    }
+}
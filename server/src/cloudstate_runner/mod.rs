@@ -1,11 +1,13 @@
 use cloudstate_runtime::{
-    blob_storage::CloudstateBlobStorage, extensions::cloudstate::ReDBCloudstate,
+    blob_storage::CloudstateBlobStorage, extensions::cloudstate::ReDBCloudstate, RequestArgs,
 };
 
 use crate::ServerInfo;
 
 pub mod execute;
+pub mod limits;
 pub mod module_loader;
+pub mod pooled;
 pub mod simple;
 pub trait CloudstateRunner: Send + Sync + Clone {
     fn run_cloudstate(
@@ -15,5 +17,6 @@ pub trait CloudstateRunner: Send + Sync + Clone {
         cs: ReDBCloudstate,
         blob_storage: CloudstateBlobStorage,
         request_info: ServerInfo,
+        args: RequestArgs,
     ) -> impl std::future::Future<Output = String> + Send;
 }
@@ -46,6 +46,9 @@ impl ModuleLoader for CloudstateModuleLoader {
         );
         match replaced_lib {
             CloudstateModuleLoaderLibrary::Sync(lib) => {
+                // an isolate serving more than one request loads the classes
+                // again for each of them
+                *lib_lock = CloudstateModuleLoaderLibrary::Sync(lib.clone());
                 ModuleLoadResponse::Sync(Ok(ModuleSource::new(
                     ModuleType::JavaScript,
                     ModuleSourceCode::String(lib.into()),
@@ -0,0 +1,357 @@
+//! A runner that keeps an isolate per worker thread warm between requests,
+//! rather than building one, with every extension, for each of them.
+//!
+//! Each isolate is made for one classes script. Every request is loaded as a
+//! new side module from a directory of its own, so its `./lib.js` import
+//! resolves to a new instance of the classes, and runs with fresh op state
+//! and the globals the isolate had when it was warmed. Nothing a request
+//! leaves in the classes' module scope is seen by the next one, like with a
+//! runtime per request. Since modules can't be unloaded, isolates are
+//! replaced after `PoolOptions::max_requests` requests, once their heap grows
+//! past `PoolOptions::max_heap_bytes`, or when a request fails to evaluate.
+
+use std::{
+    cell::RefCell,
+    panic::AssertUnwindSafe,
+    sync::{
+        Arc, Mutex,
+        mpsc::{Receiver, Sender, channel},
+    },
+};
+
+use anyhow::anyhow;
+use cloudstate_runtime::{
+    RequestArgs,
+    blob_storage::CloudstateBlobStorage,
+    extensions::cloudstate::{JavaScriptSpans, ReDBCloudstate, TransactionContext},
+};
+use deno_core::{JsRuntime, v8};
+use serde_json::json;
+use tracing::{debug, error};
+
+use crate::{CloudstateRunner, ServerInfo};
+
+use super::{
+    execute::{
+        create_cloudstate_runtime, evaluate_module, script_specifier, take_exceeded, take_result,
+    },
+    limits::ScriptLimits,
+    module_loader::CloudstateModuleLoader,
+};
+
+/// Remembers the globals a warm isolate starts each request with
+const WARM_SCRIPT: &str = "const warmGlobals = new Set(Reflect.ownKeys(globalThis));";
+
+/// Puts an isolate back how it was when it was warmed
+const RESET_SCRIPT: &str = "
+for (const key of Reflect.ownKeys(globalThis)) {
+  if (!warmGlobals.has(key)) delete globalThis[key];
+}
+globalThis.__resetState();
+";
+
+#[derive(Debug, Clone)]
+pub struct PoolOptions {
+    /// How many worker threads to run, each with its own isolate
+    pub workers: usize,
+    /// How many requests an isolate serves before it's replaced
+    pub max_requests: usize,
+    /// How big an isolate's heap can grow before it's replaced
+    pub max_heap_bytes: usize,
+    /// The limits each script runs under
+    pub limits: ScriptLimits,
+}
+
+impl Default for PoolOptions {
+    fn default() -> Self {
+        Self {
+            workers: std::thread::available_parallelism().map_or(4, |workers| workers.get()),
+            max_requests: 1000,
+            max_heap_bytes: 256 * 1024 * 1024,
+            limits: ScriptLimits::default(),
+        }
+    }
+}
+
+struct Job {
+    script: String,
+    classes_script: String,
+    cs: ReDBCloudstate,
+    blob_storage: CloudstateBlobStorage,
+    server_info: ServerInfo,
+    args: RequestArgs,
+    respond: tokio::sync::oneshot::Sender<String>,
+}
+
+#[derive(Clone)]
+pub struct PooledCloudstateRunner {
+    jobs: Sender<Job>,
+}
+
+impl PooledCloudstateRunner {
+    /// Starts the worker threads, which stop once every clone of the runner
+    /// has been dropped
+    pub fn new(options: PoolOptions) -> Self {
+        let (jobs, receiver) = channel();
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        for index in 0..options.workers.max(1) {
+            let receiver = Arc::clone(&receiver);
+            let options = options.clone();
+            std::thread::Builder::new()
+                .name(format!("cloudstate-isolate-{index}"))
+                .spawn(move || work(&receiver, &options))
+                .expect("failed to spawn isolate pool worker");
+        }
+
+        Self { jobs }
+    }
+}
+
+impl CloudstateRunner for PooledCloudstateRunner {
+    async fn run_cloudstate(
+        &self,
+        script: &str,
+        classes_script: &str,
+        cs: ReDBCloudstate,
+        blob_storage: CloudstateBlobStorage,
+        server_info: ServerInfo,
+        args: RequestArgs,
+    ) -> String {
+        let (respond, response) = tokio::sync::oneshot::channel();
+        let job = Job {
+            script: script.to_string(),
+            classes_script: classes_script.to_string(),
+            cs,
+            blob_storage,
+            server_info,
+            args,
+            respond,
+        };
+
+        if self.jobs.send(job).is_err() {
+            return error_result("The isolate pool has shut down");
+        }
+        response
+            .await
+            .unwrap_or_else(|_| error_result("The isolate running the script panicked"))
+    }
+}
+
+fn error_result(message: &str) -> String {
+    json!({
+        "error": {
+            "message": message,
+            "stack": message,
+        }
+    })
+    .to_string()
+}
+
+/// Takes jobs until the runner is dropped, keeping the isolate it used for
+/// the last one warm for the next
+fn work(jobs: &Mutex<Receiver<Job>>, options: &PoolOptions) {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("failed to build isolate pool runtime");
+    let mut warm: Option<WarmIsolate> = None;
+
+    loop {
+        let Ok(job) = jobs.lock().unwrap().recv() else {
+            return;
+        };
+        let Job {
+            script,
+            classes_script,
+            cs,
+            blob_storage,
+            server_info,
+            args,
+            respond,
+        } = job;
+
+        let isolate = warm.take();
+        let served = std::panic::catch_unwind(AssertUnwindSafe(|| {
+            runtime.block_on(serve(
+                isolate,
+                &script,
+                &classes_script,
+                &cs,
+                &blob_storage,
+                server_info,
+                args,
+                options,
+            ))
+        }));
+        match served {
+            Ok((result, isolate)) => {
+                warm = isolate;
+                let _ = respond.send(result);
+            }
+            Err(_) => error!("An isolate panicked running a script, replacing it"),
+        }
+
+        // warm the replacement now, rather than on the next request
+        if warm.is_none() {
+            let warmed = std::panic::catch_unwind(AssertUnwindSafe(|| {
+                let _runtime = runtime.enter();
+                WarmIsolate::new(&classes_script, &options.limits)
+            }));
+            warm = match warmed {
+                Ok(Ok(isolate)) => Some(isolate),
+                Ok(Err(e)) => {
+                    error!("Failed to warm an isolate: {:?}", e);
+                    None
+                }
+                Err(_) => None,
+            };
+        }
+    }
+}
+
+/// Runs a script on the warm isolate if it was made for the same classes, or
+/// on a new one. Returns the result, along with the isolate if it's fit to
+/// keep.
+async fn serve(
+    isolate: Option<WarmIsolate>,
+    script: &str,
+    classes_script: &str,
+    cs: &ReDBCloudstate,
+    blob_storage: &CloudstateBlobStorage,
+    server_info: ServerInfo,
+    args: RequestArgs,
+    options: &PoolOptions,
+) -> (String, Option<WarmIsolate>) {
+    let mut isolate = match isolate {
+        Some(isolate) if isolate.classes_script == classes_script => isolate,
+        stale => {
+            // isolates on a thread have to be dropped in the reverse of the
+            // order they were made in
+            drop(stale);
+            match WarmIsolate::new(classes_script, &options.limits) {
+                Ok(isolate) => isolate,
+                Err(e) => return (error_result(&e.to_string()), None),
+            }
+        }
+    };
+
+    match isolate
+        .run(script, cs, blob_storage, server_info, args)
+        .await
+    {
+        Ok(result) => {
+            let heap_bytes = isolate.heap_bytes();
+            let keep =
+                isolate.requests < options.max_requests && heap_bytes < options.max_heap_bytes;
+            if !keep {
+                debug!(
+                    "Replacing an isolate after {} requests with {} heap bytes",
+                    isolate.requests, heap_bytes
+                );
+            }
+            (result, keep.then_some(isolate))
+        }
+        Err(result) => {
+            debug!("Replacing an isolate after a failed or terminated script");
+            (result, None)
+        }
+    }
+}
+
+struct WarmIsolate {
+    js_runtime: JsRuntime,
+    classes_script: String,
+    requests: usize,
+}
+
+impl WarmIsolate {
+    /// Builds an isolate for the classes, which each request it serves
+    /// imports afresh
+    fn new(classes_script: &str, limits: &ScriptLimits) -> anyhow::Result<Self> {
+        let mut js_runtime = create_cloudstate_runtime(
+            CloudstateModuleLoader::new(classes_script.to_string()),
+            limits,
+        );
+        js_runtime
+            .execute_script("<warm>", WARM_SCRIPT)
+            .map_err(|e| anyhow!("Failed to warm the isolate: {}", e))?;
+
+        Ok(Self {
+            js_runtime,
+            classes_script: classes_script.to_string(),
+            requests: 0,
+        })
+    }
+
+    /// Runs a script, returning its result, or an error to respond with
+    /// when the isolate isn't fit to keep after it
+    async fn run(
+        &mut self,
+        script: &str,
+        cs: &ReDBCloudstate,
+        blob_storage: &CloudstateBlobStorage,
+        server_info: ServerInfo,
+        args: RequestArgs,
+    ) -> Result<String, String> {
+        self.requests += 1;
+        {
+            let op_state = self.js_runtime.op_state();
+            let mut op_state = RefCell::borrow_mut(&op_state);
+            op_state.put(server_info);
+            op_state.put(args);
+            op_state.put(cs.clone());
+            op_state.put(TransactionContext::new(cs.clone(), blob_storage.clone()));
+            op_state.put(JavaScriptSpans::new());
+        }
+
+        let evaluated = self.evaluate(script).await;
+        let exceeded = take_exceeded(&mut self.js_runtime);
+
+        // a transaction left open would keep other writers out until the
+        // next request, and a response left open would never end, so they're
+        // dropped now like a spent runtime's would be
+        {
+            let op_state = self.js_runtime.op_state();
+            let mut op_state = RefCell::borrow_mut(&op_state);
+            drop(op_state.take::<TransactionContext>());
+            drop(op_state.try_take::<RequestArgs>());
+        }
+
+        // going over the heap limit raised it to let the script unwind, and
+        // a terminated script can leave anything behind, so the isolate is
+        // replaced rather than kept with either
+        if let Some(exceeded) = exceeded {
+            return Err(exceeded.to_result());
+        }
+        match evaluated {
+            Ok(()) => Ok(take_result(&mut self.js_runtime)),
+            Err(e) => Err(error_result(&e.to_string())),
+        }
+    }
+
+    async fn evaluate(&mut self, script: &str) -> anyhow::Result<()> {
+        self.js_runtime
+            .execute_script("<reset>", RESET_SCRIPT)
+            .map_err(|e| anyhow!("Failed to reset the isolate: {}", e))?;
+
+        // module specifiers can only be loaded once per isolate, and the
+        // classes are loaded again next to each request so it doesn't see
+        // what the last one left in their module scope
+        let specifier = script_specifier(&format!("request-{}/main.js", self.requests));
+        let mod_id = self
+            .js_runtime
+            .load_side_es_module_from_code(&specifier, script.to_string())
+            .await
+            .map_err(|e| anyhow!("Failed to load the script: {}", e))?;
+        evaluate_module(&mut self.js_runtime, mod_id)
+            .await
+            .map_err(|e| anyhow!("Failed to evaluate the script: {}", e))
+    }
+
+    fn heap_bytes(&mut self) -> usize {
+        let mut stats = v8::HeapStatistics::default();
+        self.js_runtime.v8_isolate().get_heap_statistics(&mut stats);
+        stats.used_heap_size()
+    }
+}
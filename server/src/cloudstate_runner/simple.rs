@@ -1,13 +1,21 @@
+use cloudstate_runtime::RequestArgs;
+
 use crate::{CloudstateRunner, ServerInfo};
 
-use super::execute::execute_script;
+use super::{execute::execute_script, limits::ScriptLimits};
 
 #[derive(Clone)]
-pub struct SimpleCloudstateRunner {}
+pub struct SimpleCloudstateRunner {
+    limits: ScriptLimits,
+}
 
 impl SimpleCloudstateRunner {
     pub fn new() -> Self {
-        Self {}
+        Self::with_limits(ScriptLimits::default())
+    }
+
+    pub fn with_limits(limits: ScriptLimits) -> Self {
+        Self { limits }
     }
 }
 
@@ -19,7 +27,17 @@ impl CloudstateRunner for SimpleCloudstateRunner {
         cs: cloudstate_runtime::extensions::cloudstate::ReDBCloudstate,
         blob_storage: cloudstate_runtime::blob_storage::CloudstateBlobStorage,
         server_info: ServerInfo,
+        args: RequestArgs,
     ) -> String {
-        execute_script(script, classes_script, cs, blob_storage, server_info).await
+        execute_script(
+            script,
+            classes_script,
+            cs,
+            blob_storage,
+            server_info,
+            args,
+            self.limits.clone(),
+        )
+        .await
     }
 }
@@ -1,5 +1,7 @@
+const args = __requestArgs();
+
 globalThis.process = {
-    env: $ENV_STRING,
+    env: args.env,
 };
 
 const classes = await import("./lib.js").catch((e) => {
@@ -16,14 +18,14 @@ for (const className of Object.keys(classes)) {
 globalThis.requestContext = {
     getStore: () => {
         return {
-            request: new Request($URI, {
-                headers: new Headers($HEADERS),
+            request: new Request(args.uri, {
+                headers: new Headers(args.headers),
             }),
             env: {
                 invalidateMethod: (rawMethod) => {
                     const method = rawMethod.toJSON();
                     fetch(
-                        `$INVALIDATE_ENDPOINT/${method.instance}/${method.method}`,
+                        `${args.invalidateEndpoint}/${method.instance}/${method.method}`,
                         {
                             method: "POST",
                             headers: {
@@ -42,34 +44,29 @@ globalThis.requestContext = {
 let object;
 
 try {
-    object = getRoot($ID) || getCloudstate($ID);
+    object = getRoot(args.id) || getCloudstate(args.id);
 } catch (e) {
     console.error("Error getting root or cloudstate", e);
     globalThis.result = { error: { message: e.message, stack: e.stack } };
 }
 
 try {
-    const req = new Request($URI, {
-        headers: new Headers($HEADERS),
-        method: "$HTTP_METHOD",
-        // TODO
-        // body: ["GET", "HEAD"].includes(method) ? undefined : bytes.buffer,
+    const req = new Request(args.uri, {
+        headers: new Headers(args.headers),
+        method: args.httpMethod,
+        body: ["GET", "HEAD"].includes(args.httpMethod)
+            ? undefined
+            : __requestBody(),
     });
 
-    let out = object.fetch(req);
-
-    if (out instanceof Promise) {
-        out = await out;
-    }
+    // a handler that throws leaves nothing behind, and what it wrote is
+    // committed before anyone sees the response
+    const out = await transaction(() => object.fetch(req));
 
     if (out instanceof Response) {
-        const body = await out.bytes();
-        const headers = [...out.headers.entries()];
-
-        // uint8array to array
-        let bytes = Array.from(body);
-
-        globalThis.result = { response: { bytes, headers } };
+        await __sendResponse(out);
+        // tells the server the body was sent in full
+        globalThis.result = { status: out.status };
     }
 } catch (e) {
     globalThis.result = { error: { message: e.message, stack: e.stack } };
@@ -1,5 +1,5 @@
 globalThis.process = {
-  env: env_string,
+  env: __requestArgs().env,
 };
 
 const classes = await import("./lib.js").catch((e) => {
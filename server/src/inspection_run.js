@@ -1,5 +1,7 @@
+const args = __requestArgs();
+
 globalThis.process = {
-    env: env_string,
+    env: args.env,
 };
 
 const classes = await import("./lib.js").catch((e) => {
@@ -15,7 +17,7 @@ globalThis.requestContext = {
                 invalidateMethod: (rawMethod) => {
                     const method = rawMethod.toJSON();
                     fetch(
-                        `invalidate_endpoint/${method.instance}/${method.method}`,
+                        `${args.invalidateEndpoint}/${method.instance}/${method.method}`,
                         {
                             method: "POST",
                             headers: {
@@ -36,11 +38,12 @@ for (const className of Object.keys(classes)) {
     registerCustomClass(klass);
 }
 
+// the script is the body of an async function, run like one declared here
+const AsyncFunction = (async function () {}).constructor;
+
 try {
     globalThis.result = {
-        result: await (async function () {
-            run_script;
-        })(),
+        result: await new AsyncFunction(args.params[0])(),
     };
 } catch (e) {
     globalThis.result = {
@@ -7,7 +7,12 @@ use axum::{
     Json, RequestExt, Router,
 };
 use cloudstate_runner::CloudstateRunner;
-use cloudstate_runtime::{blob_storage::CloudstateBlobStorage, gc::mark_and_sweep, ServerInfo};
+use cloudstate_runtime::{
+    blob_storage::CloudstateBlobStorage,
+    extensions::cloudstate::{RequestBody, ResponseSink, ResponseStream},
+    gc::{collect, GcOptions, GcProgress},
+    RequestArgs, ServerInfo,
+};
 use deno_runtime::deno_permissions::PermissionCheckError;
 
 use cloudstate_runtime::extensions::cloudstate::ReDBCloudstate;
@@ -41,14 +46,17 @@ impl<R: CloudstateRunner> CloudstateServer<R> {
         cloudstate_runner: R,
         server_info: ServerInfo,
     ) -> Self {
-        let env_string = serde_json::to_string(&env).unwrap();
         cloudstate_runner
             .run_cloudstate(
-                &include_str!("./initialize.js").replace("env_string", &env_string),
+                include_str!("./initialize.js"),
                 classes,
                 cloudstate.clone(),
                 blob_storage.clone(),
                 server_info.clone(),
+                RequestArgs {
+                    env: env.clone(),
+                    ..Default::default()
+                },
             )
             .await;
 
@@ -65,6 +73,7 @@ impl<R: CloudstateRunner> CloudstateServer<R> {
                 cloudstate.clone(),
                 blob_storage.clone(),
                 server_info.clone(),
+                RequestArgs::default(),
             )
             .await;
 
@@ -98,28 +107,32 @@ impl<R: CloudstateRunner> CloudstateServer<R> {
         }
     }
 
-    pub async fn gc(&self) -> anyhow::Result<()> {
-        let db = self.cloudstate.get_database_mut();
-        match mark_and_sweep(&db) {
-            Ok(_) => Ok(()),
-            Err(e) => {
-                return Err(anyhow!("Error running garbage collection: {:?}", e));
-            }
-        }
-    }
-}
+    /// Collects garbage in batches, so requests keep being served while it
+    /// runs
+    pub async fn gc(&self, options: GcOptions) -> anyhow::Result<GcProgress> {
+        let cloudstate = self.cloudstate.clone();
+        let blob_storage = self.blob_storage.clone();
+        let result = tokio::task::spawn_blocking(move || {
+            collect(
+                &cloudstate,
+                &blob_storage,
+                &options,
+                &mut Some(Box::new(|progress| {
+                    debug!("Garbage collection progress: {:?}", progress)
+                })),
+            )
+        })
+        .await?;
 
-#[derive(Deserialize, Debug)]
-#[serde(untagged)]
-enum ScriptResponseResult {
-    Response { response: ResponseData },
-    Error { error: ErrorData },
+        result.map_err(|e| anyhow!("Error running garbage collection: {:?}", e))
+    }
 }
 
+/// What a fetch request's script leaves as its result when it fails before
+/// responding
 #[derive(Deserialize, Debug)]
-struct ResponseData {
-    pub bytes: Vec<u8>,
-    pub headers: Vec<(String, String)>,
+struct ScriptError {
+    error: ErrorData,
 }
 
 #[derive(Deserialize, Serialize, Debug)]
@@ -129,12 +142,11 @@ struct ErrorData {
 }
 
 #[instrument(skip(id, state, request))]
-async fn fetch_request<R: CloudstateRunner>(
+async fn fetch_request<R: CloudstateRunner + 'static>(
     axum::extract::Path(id): axum::extract::Path<String>,
     State(state): State<AppState<R>>,
     request: Request,
 ) -> axum::response::Response {
-    let id = serde_json::to_string(&id).unwrap();
     let (parts, body) = request.into_parts();
     let http_method = parts.method.to_string();
 
@@ -152,96 +164,112 @@ async fn fetch_request<R: CloudstateRunner>(
     };
     let host = host.to_string();
     // TODO: find a way to not need the http:// prefix
-    let uri = format!("http://{}{}", host, parts.uri.path());
-    let uri = serde_json::to_string(&uri).unwrap();
-
-    let headers = headers
-        .iter()
-        .map(|(key, value)| {
-            format!(
-                "{}: {}",
-                serde_json::to_string(&key.to_string()).unwrap(),
-                serde_json::to_string(&value.to_str().unwrap_or_default().to_string()).unwrap()
-            )
-        })
-        .collect::<Vec<String>>()
-        .join(", ");
-
-    let headers = format!("{{{}}}", headers);
-    // let headers = serde_json::to_string(&headers).unwrap();
-
-    let mut bytes = Vec::new();
-    let mut stream = body.into_data_stream();
-    while let Ok(Some(chunk)) = stream.try_next().await {
-        bytes.extend_from_slice(&chunk);
-    }
-
-    let _bytes = format!(
-        "[{}]",
-        bytes
-            .iter()
-            .map(|b| b.to_string())
-            .collect::<Vec<String>>()
-            .join(", ")
+    let uri = format!(
+        "http://{}{}",
+        host,
+        parts
+            .uri
+            .path_and_query()
+            .map_or(parts.uri.path(), |path_and_query| path_and_query.as_str())
     );
+    let headers = header_pairs(&headers);
+
+    // the body is passed on as the script reads it, rather than up front
+    let (body_chunks, request_body) = RequestBody::channel();
+    tokio::spawn(async move {
+        let mut stream = body.into_data_stream();
+        loop {
+            let chunk = match stream.try_next().await {
+                Ok(Some(chunk)) => Ok(chunk.to_vec()),
+                Ok(None) => return,
+                Err(e) => Err(e.to_string()),
+            };
+            let failed = chunk.is_err();
+            if body_chunks.send(chunk).await.is_err() || failed {
+                return;
+            }
+        }
+    });
+    let (response, response_stream) = ResponseSink::channel();
 
-    let env_string = serde_json::to_string(&state.env).unwrap();
-    let invalidate_endpoint = state.invalidate_endpoint.clone();
-
-    let script = include_str!("./fetch_request.js")
-        .replace("$ENV_STRING", &env_string)
-        .replace("$INVALIDATE_ENDPOINT", &invalidate_endpoint)
-        .replace("$URI", &uri)
-        .replace("$ID", &id)
-        .replace("$HTTP_METHOD", &http_method)
-        .replace("$HEADERS", &headers);
+    let classes = if id == "inspection" {
+        include_str!("./inspection.js").to_string()
+    } else {
+        state.classes.clone()
+    };
+    let args = RequestArgs {
+        id,
+        http_method,
+        uri,
+        headers,
+        env: state.env.clone(),
+        invalidate_endpoint: state.invalidate_endpoint.clone(),
+        body: Some(request_body),
+        response: Some(response),
+        ..Default::default()
+    };
 
     debug!("executing script");
 
-    let result = state
-        .cloudstate_runner
-        .run_cloudstate(
-            script.as_str(),
-            if id == "\"inspection\"" {
-                include_str!("./inspection.js")
-            } else {
-                &state.classes
-            },
-            state.cloudstate,
-            state.blob_storage.clone(),
-            state.server_info.clone(),
-        )
-        .await;
-
-    debug!("script finished");
-
-    let json = serde_json::from_str::<ScriptResponseResult>(&result).unwrap_or(
-        ScriptResponseResult::Error {
-            error: ErrorData {
-                message: "Unknown error executing script".to_string(),
-                stack: "Unknown error executing script".to_string(),
-            },
-        },
-    );
-
-    let mut builder = Response::builder();
+    // the script keeps running after it starts responding, to send the body
+    let script = tokio::spawn(async move {
+        state
+            .cloudstate_runner
+            .run_cloudstate(
+                include_str!("./fetch_request.js"),
+                &classes,
+                state.cloudstate,
+                state.blob_storage,
+                state.server_info,
+                args,
+            )
+            .await
+    });
+
+    let ResponseStream { head, chunks } = response_stream;
+    let Ok(head) = head.await else {
+        // the script finished without responding
+        let result = script.await.unwrap_or_default();
+        debug!("script finished");
+
+        let message = serde_json::from_str::<ScriptError>(&result)
+            .map(|result| result.error.message)
+            .unwrap_or("Unknown error executing script".to_string());
+        return Response::builder()
+            .status(axum::http::StatusCode::INTERNAL_SERVER_ERROR)
+            .body(Body::from(message))
+            .unwrap();
+    };
 
-    match json {
-        ScriptResponseResult::Response { response } => {
-            for (key, value) in response.headers {
-                builder = builder.header(key, value);
+    let mut builder = Response::builder().status(head.status);
+    for (key, value) in head.headers {
+        builder = builder.header(key, value);
+    }
+    // once the head is out, the only way left to report a failure is to end
+    // the body with an error, which makes hyper abort the connection rather
+    // than finish the response as if it were whole
+    let body = futures::stream::unfold(Some((chunks, script)), |state| async move {
+        let (mut chunks, script) = state?;
+        match chunks.recv().await {
+            Some(Ok(chunk)) => Some((Ok(chunk), Some((chunks, script)))),
+            Some(Err(message)) => Some((Err(message), None)),
+            // the script may still have failed without saying so, like when
+            // it's terminated for running too long
+            None => {
+                let message = match script.await {
+                    Ok(result) => {
+                        serde_json::from_str::<ScriptError>(&result)
+                            .ok()?
+                            .error
+                            .message
+                    }
+                    Err(e) => e.to_string(),
+                };
+                Some((Err(message), None))
             }
-            let body = Body::from(response.bytes);
-            builder.body(body).unwrap()
         }
-        ScriptResponseResult::Error { error } => {
-            let body = Body::from(error.message);
-            builder
-                .status(axum::http::StatusCode::INTERNAL_SERVER_ERROR)
-                .body(body)
-                .unwrap()
-        }
-    }
+    });
+    builder.body(Body::from_stream(body)).unwrap()
 }
 
 #[derive(Clone)]
@@ -255,6 +283,19 @@ struct AppState<R: CloudstateRunner> {
     server_info: ServerInfo,
 }
 
+/// A request's headers as name and value pairs, which `new Headers()` takes
+fn header_pairs(headers: &axum::http::HeaderMap) -> Vec<(String, String)> {
+    headers
+        .iter()
+        .map(|(key, value)| {
+            (
+                key.to_string(),
+                value.to_str().unwrap_or_default().to_string(),
+            )
+        })
+        .collect()
+}
+
 #[derive(Debug, Deserialize)]
 struct MethodParams {
     params: Vec<serde_json::Value>,
@@ -266,9 +307,6 @@ async fn method_request<R: CloudstateRunner>(
     request: Request<Body>,
 ) -> axum::response::Json<serde_json::Value> {
     debug!("method_request");
-    // turn into valid, sanitized, json string
-    let id = serde_json::to_string(&id).unwrap();
-    let method = serde_json::to_string(&method).unwrap();
 
     // get host from request
     let Some(Ok(host)) = request.headers().get("Host").map(|h| h.to_str()) else {
@@ -281,76 +319,44 @@ async fn method_request<R: CloudstateRunner>(
 
     // TODO: find a way to not need the http:// prefix
     let uri = format!("https://{}{}", host, request.uri().path());
-    let uri = serde_json::to_string(&uri).unwrap();
-
-    let headers = request.headers();
-    let headers = headers
-        .iter()
-        .map(|(key, value)| {
-            format!(
-                "{}: {}",
-                serde_json::to_string(&key.to_string()).unwrap(),
-                serde_json::to_string(&value.to_str().unwrap_or_default().to_string()).unwrap()
-            )
-        })
-        .collect::<Vec<String>>()
-        .join(", ");
-    let headers = format!("{{{}}}", headers);
-
-    let Json::<MethodParams>(params) = request.extract().await.unwrap();
-
-    // only used for inspection api
-    let run_script = &params.params.first().map(|p| p.as_str());
-
-    let params = serde_json::to_string(&params.params).unwrap();
-    let params = serde_json::to_string(&params).unwrap();
-    let env_string = serde_json::to_string(&state.env).unwrap();
-    let invalidate_endpoint = state.invalidate_endpoint.clone();
-
-    // TODO: fix injection vulnerability
-    let script = include_str!("./method_request.js")
-        .replace("$ENV_STRING", &env_string)
-        .replace("$URI", &uri)
-        .replace("$HEADERS", &headers)
-        .replace("$INVALIDATE_ENDPOINT", &invalidate_endpoint)
-        .replace("$ID", &id)
-        .replace("$METHOD", &method)
-        .replace("$PARAMS", &params);
+    let headers = header_pairs(request.headers());
+
+    let Json::<MethodParams>(MethodParams { params }) = request.extract().await.unwrap();
+
+    // the inspection api's run method runs the script it's passed against
+    // the user's classes rather than calling a method
+    let (script, classes) = match (id.as_str(), method.as_str()) {
+        ("inspection", "run") => (include_str!("./inspection_run.js"), state.classes.as_str()),
+        ("inspection", _) => (
+            include_str!("./method_request.js"),
+            include_str!("./inspection.js"),
+        ),
+        _ => (include_str!("./method_request.js"), state.classes.as_str()),
+    };
+    let args = RequestArgs {
+        id,
+        method,
+        params,
+        uri,
+        headers,
+        env: state.env.clone(),
+        invalidate_endpoint: state.invalidate_endpoint.clone(),
+        ..Default::default()
+    };
 
     debug!("executing script");
 
-    let result = if id == "\"inspection\"" && method == "\"run\"" {
-        let run_script = run_script.unwrap().unwrap();
-
-        state
-            .cloudstate_runner
-            .run_cloudstate(
-                &include_str!("./inspection_run.js")
-                    .replace("env_string", &env_string)
-                    .replace("run_script", run_script)
-                    .replace("invalidate_endpoint", &invalidate_endpoint),
-                &state.classes,
-                state.cloudstate,
-                state.blob_storage.clone(),
-                state.server_info.clone(),
-            )
-            .await
-    } else {
-        state
-            .cloudstate_runner
-            .run_cloudstate(
-                script.as_str(),
-                if id == "\"inspection\"" {
-                    include_str!("./inspection.js")
-                } else {
-                    &state.classes
-                },
-                state.cloudstate,
-                state.blob_storage.clone(),
-                state.server_info.clone(),
-            )
-            .await
-    };
+    let result = state
+        .cloudstate_runner
+        .run_cloudstate(
+            script,
+            classes,
+            state.cloudstate.clone(),
+            state.blob_storage.clone(),
+            state.server_info.clone(),
+            args,
+        )
+        .await;
 
     Json(serde_json::from_str(&result).unwrap_or(json!({
         "error": {
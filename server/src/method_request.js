@@ -1,5 +1,7 @@
+const args = __requestArgs();
+
 globalThis.process = {
-    env: $ENV_STRING,
+    env: args.env,
 };
 
 const classes = await import("./lib.js").catch((e) => {
@@ -16,14 +18,14 @@ for (const className of Object.keys(classes)) {
 globalThis.requestContext = {
     getStore: () => {
         return {
-            request: new Request($URI, {
-                headers: new Headers($HEADERS),
+            request: new Request(args.uri, {
+                headers: new Headers(args.headers),
             }),
             env: {
                 invalidateMethod: (rawMethod) => {
                     const method = rawMethod.toJSON();
                     fetch(
-                        `$INVALIDATE_ENDPOINT/${method.instance}/${method.method}`,
+                        `${args.invalidateEndpoint}/${method.instance}/${method.method}`,
                         {
                             method: "POST",
                             headers: {
@@ -41,17 +43,62 @@ globalThis.requestContext = {
 
 let object;
 
+// look the object up on a read snapshot, so requests only wait for other
+// writers once we know the method needs to write
+commit();
+__setReadOnly();
+
 try {
-    object = getRoot($ID) || getCloudstate($ID);
+    object = getRoot(args.id) || getCloudstate(args.id);
 } catch (e) {
     console.error("Error getting root or cloudstate", e);
     throw e;
 }
 
+// a method that throws leaves nothing behind
+function callMethod() {
+    return transaction(() =>
+        object[args.method](...deserializeWithBlobs(args.params))
+    );
+}
+
+// methods listed in a class's static readOnlyMethods always run on a snapshot.
+// classes with static optimistic = true run every method on a snapshot first
+// and retry it as a writer if it turns out to write. the retry starts the
+// method over, so whatever it did before its first write, like a fetch, is
+// done again: methods of optimistic classes have to be safe to run twice.
+async function runMethod() {
+    const klass = object.constructor;
+    const readOnlyMethod = klass?.readOnlyMethods?.includes(args.method) ?? false;
+
+    if (readOnlyMethod || klass?.optimistic) {
+        let result, error;
+        try {
+            result = await callMethod();
+        } catch (e) {
+            error = e;
+        }
+
+        if (!__takeWriteAttempted()) {
+            if (error) throw error;
+            return result;
+        }
+
+        if (readOnlyMethod) {
+            throw new Error(
+                `${args.method} is listed in readOnlyMethods but modified state`,
+            );
+        }
+    }
+
+    __setReadWrite();
+    return await callMethod();
+}
+
 try {
     if (!object) {
         globalThis.result = { error: { message: "Object not found" } };
-    } else if (!object[$METHOD]) {
+    } else if (!object[args.method]) {
         globalThis.result = {
             error: {
                 message: `Method not found on class ${
@@ -60,17 +107,13 @@ try {
             },
         };
     } else {
-        globalThis.result = {
-            result: await object[$METHOD](...deserializeJsonWithBlobs($PARAMS)),
-        };
+        globalThis.result = { result: await runMethod() };
     }
 } catch (e) {
     globalThis.result = { error: { message: e.message, stack: e.stack } };
 }
 
-export function deserializeJsonWithBlobs(jsonString) {
-    const obj = JSON.parse(jsonString);
-
+export function deserializeWithBlobs(obj) {
     const deserializeBlob = ({ mimeType, data }) => {
         const byteCharacters = atob(data);
         const byteNumbers = new Array(byteCharacters.length);
@@ -18,8 +18,10 @@ use tower::{util::ServiceExt, Service};
 
 use crate::{cloudstate_runner::simple::SimpleCloudstateRunner, CloudstateServer};
 
-// mod concurrency;
+mod concurrency;
 mod fetch_method;
+mod limits;
+mod pooled;
 
 #[tokio::test]
 async fn test_method_request() {
@@ -29,7 +31,8 @@ async fn test_method_request() {
         redb::Database::builder()
             .create_with_backend(redb::backends::InMemoryBackend::default())
             .unwrap(),
-    )));
+    )))
+    .unwrap();
 
     let mut router = CloudstateServer::new(
         cloudstate.clone(),
@@ -127,7 +130,8 @@ async fn test_async_write() {
             redb::Database::builder()
                 .create_with_backend(redb::backends::InMemoryBackend::default())
                 .unwrap(),
-        ))),
+        )))
+        .unwrap(),
         CloudstateBlobStorage::new(Arc::new(InMemoryBlobStore::default())),
         r#"export class DelayedCounter {
             static id = 'delayed-counter';
@@ -224,3 +228,279 @@ async fn test_async_write() {
         })
     );
 }
+
+#[tokio::test]
+async fn test_method_error_rolls_back() {
+    let _ = tracing_subscriber::fmt::try_init();
+
+    let mut router = CloudstateServer::new(
+        ReDBCloudstate::new(Arc::new(Mutex::new(
+            redb::Database::builder()
+                .create_with_backend(redb::backends::InMemoryBackend::default())
+                .unwrap(),
+        )))
+        .unwrap(),
+        CloudstateBlobStorage::new(Arc::new(InMemoryBlobStore::default())),
+        r#"export class FlakyCounter {
+            static id = 'flaky-counter';
+            count = 0;
+            async incrementThenFail() {
+                this.count++;
+                await new Promise(resolve => setTimeout(resolve, 100));
+                throw new Error('failed after incrementing');
+            }
+            getCount() {
+                return this.count;
+            }
+        }"#,
+        HashMap::new(),
+        "http://localhost:8910/__invalidate__".to_string(),
+        SimpleCloudstateRunner::new(),
+        ServerInfo {
+            deployment_id: None,
+            domain: None,
+        },
+    )
+    .await;
+
+    let mut results = Vec::new();
+    for method in ["incrementThenFail", "getCount"] {
+        let response = ServiceExt::<Request<Body>>::ready(&mut router.router)
+            .await
+            .unwrap()
+            .call(
+                Request::builder()
+                    .uri(format!("/cloudstate/instances/flaky-counter/{method}"))
+                    .method("POST")
+                    .header(http::header::HOST, "localhost")
+                    .header(http::header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+                    .body(Body::from(
+                        serde_json::to_vec(&json!({
+                            "params": []
+                        }))
+                        .unwrap(),
+                    ))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        results.push(serde_json::from_slice::<serde_json::Value>(&body).unwrap());
+    }
+
+    assert_eq!(
+        results[0]["error"]["message"],
+        json!("failed after incrementing")
+    );
+    // the increment was rolled back along with the failed call
+    assert_eq!(results[1], json!({ "result": 0 }));
+}
+
+#[tokio::test]
+async fn test_method_arguments_are_not_evaluated() {
+    let _ = tracing_subscriber::fmt::try_init();
+
+    let mut router = CloudstateServer::new(
+        ReDBCloudstate::new(Arc::new(Mutex::new(
+            redb::Database::builder()
+                .create_with_backend(redb::backends::InMemoryBackend::default())
+                .unwrap(),
+        )))
+        .unwrap(),
+        CloudstateBlobStorage::new(Arc::new(InMemoryBlobStore::default())),
+        r#"export class EchoCS {
+            static id = 'echo';
+            echo(value) {
+                const request = requestContext.getStore().request;
+                return [value, request.headers.get('x-echo')];
+            }
+        }"#,
+        HashMap::new(),
+        "http://localhost:8910/__invalidate__".to_string(),
+        SimpleCloudstateRunner::new(),
+        ServerInfo {
+            deployment_id: None,
+            domain: None,
+        },
+    )
+    .await;
+
+    // these used to be spliced into the script's source
+    let param = r#"$METHOD"); globalThis.result = { result: "injected" }; ("#;
+    let header = "$ID $PARAMS";
+
+    let response = ServiceExt::<Request<Body>>::ready(&mut router.router)
+        .await
+        .unwrap()
+        .call(
+            Request::builder()
+                .uri("/cloudstate/instances/echo/echo")
+                .method("POST")
+                .header(http::header::HOST, "localhost")
+                .header(http::header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+                .header("x-echo", header)
+                .body(Body::from(
+                    serde_json::to_vec(&json!({
+                        "params": [param]
+                    }))
+                    .unwrap(),
+                ))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let body_json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+
+    assert_eq!(body_json, json!({ "result": [param, header] }));
+}
+
+/// Calls a method on an instance, returning the response's JSON
+async fn call_method(
+    router: &mut axum::Router,
+    instance: &str,
+    method: &str,
+    params: serde_json::Value,
+) -> serde_json::Value {
+    let response = ServiceExt::<Request<Body>>::ready(router)
+        .await
+        .unwrap()
+        .call(
+            Request::builder()
+                .uri(format!("/cloudstate/instances/{instance}/{method}"))
+                .method("POST")
+                .header(http::header::HOST, "localhost")
+                .header(http::header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+                .body(Body::from(
+                    serde_json::to_vec(&json!({
+                        "params": params
+                    }))
+                    .unwrap(),
+                ))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    serde_json::from_slice(&body).unwrap()
+}
+
+#[tokio::test]
+async fn test_methods_migrate_outdated_objects() {
+    let _ = tracing_subscriber::fmt::try_init();
+
+    let cloudstate = ReDBCloudstate::new(Arc::new(Mutex::new(
+        redb::Database::builder()
+            .create_with_backend(redb::backends::InMemoryBackend::default())
+            .unwrap(),
+    )))
+    .unwrap();
+    let blob_storage = CloudstateBlobStorage::new(Arc::new(InMemoryBlobStore::default()));
+    let server = |classes: &'static str| {
+        CloudstateServer::new(
+            cloudstate.clone(),
+            blob_storage.clone(),
+            classes,
+            HashMap::new(),
+            "http://localhost:8910/__invalidate__".to_string(),
+            SimpleCloudstateRunner::new(),
+            ServerInfo {
+                deployment_id: None,
+                domain: None,
+            },
+        )
+    };
+
+    let mut router = server(
+        r#"export class Note {
+            constructor(id, title) {
+                this.id = id;
+                this.title = title;
+            }
+        }
+        export class Notebook {
+            static id = 'notebook';
+            notes = [];
+            addNote(id, title) {
+                this.notes.push(new Note(id, title));
+                return id;
+            }
+        }"#,
+    )
+    .await
+    .router;
+    for (id, title) in [("first", "first"), ("second", "second")] {
+        assert_eq!(
+            call_method(&mut router, "notebook", "addNote", json!([id, title])).await,
+            json!({ "result": id })
+        );
+    }
+
+    // both notes are still at version 0 when the class moves on, and every
+    // method starts by looking its object up on a read snapshot
+    let mut router = server(
+        r#"export class Note {
+            static version = 1;
+            static migrate(old) {
+                return { id: old.id, heading: old.title.toUpperCase() };
+            }
+            getHeading() {
+                return this.heading;
+            }
+        }
+        export class Notebook {
+            static id = 'notebook';
+            static readOnlyMethods = ['headings'];
+            notes = [];
+            headings() {
+                const headings = [];
+                for (let i = 0; i < this.notes.length; i++) {
+                    headings.push(this.notes[i].heading);
+                }
+                return headings;
+            }
+        }"#,
+    )
+    .await
+    .router;
+    assert_eq!(
+        call_method(&mut router, "first", "getHeading", json!([])).await,
+        json!({ "result": "FIRST" })
+    );
+    // the second note is first read by a read-only method
+    assert_eq!(
+        call_method(&mut router, "notebook", "headings", json!([])).await,
+        json!({ "result": ["FIRST", "SECOND"] })
+    );
+
+    // the migrations were saved, so they aren't run again
+    let mut router = server(
+        r#"export class Note {
+            static version = 1;
+            static migrate() {
+                throw new Error('notes should only be migrated once');
+            }
+        }
+        export class Notebook {
+            static id = 'notebook';
+            static readOnlyMethods = ['headings'];
+            notes = [];
+            headings() {
+                const headings = [];
+                for (let i = 0; i < this.notes.length; i++) {
+                    headings.push(this.notes[i].heading);
+                }
+                return headings;
+            }
+        }"#,
+    )
+    .await
+    .router;
+    assert_eq!(
+        call_method(&mut router, "notebook", "headings", json!([])).await,
+        json!({ "result": ["FIRST", "SECOND"] })
+    );
+}
@@ -1,52 +1,151 @@
 use axum::{
+    Router,
     body::Body,
-    http::{self, Request},
+    extract::State,
+    http::{self, Request, StatusCode},
+    routing::get,
 };
-use cloudstate_runtime::extensions::cloudstate::ReDBCloudstate;
+use cloudstate_runtime::{
+    ServerInfo,
+    blob_storage::{CloudstateBlobStorage, in_memory_store::InMemoryBlobStore},
+    extensions::cloudstate::ReDBCloudstate,
+};
+use futures_util::future::join_all;
+use http_body_util::BodyExt;
 use serde_json::json;
 use std::{
     collections::HashMap,
     sync::{Arc, Mutex},
+    time::Duration,
 };
-use tokio::net::TcpListener;
+use tokio::sync::{mpsc, watch};
+use tower::util::ServiceExt;
 
-#[tokio::test]
-async fn the_real_deal() {
-    let mut router = crate::CloudstateServer::new(
+use crate::{CloudstateServer, cloudstate_runner::simple::SimpleCloudstateRunner};
+
+// methods wait on the gate inside their transaction, which stays open across
+// awaits until the method returns
+const CLASSES: &str = r#"export class SlowCounter {
+    static id = 'slow-counter';
+    static readOnlyMethods = ['slowGetCount', 'getCount', 'sneakyIncrement'];
+    count = 0;
+    async slowGetCount() {
+        const count = this.count;
+        await (await fetch(process.env.GATE_URL)).text();
+        return count;
+    }
+    async slowIncrement() {
+        const count = this.count;
+        await (await fetch(process.env.GATE_URL)).text();
+        this.count = count + 1;
+        return this.count;
+    }
+    getCount() {
+        return this.count;
+    }
+    sneakyIncrement() {
+        return ++this.count;
+    }
+}
+
+let attempts = 0;
+
+export class OptimisticCounter {
+    static id = 'optimistic-counter';
+    static optimistic = true;
+    count = 0;
+    getCount() {
+        return this.count;
+    }
+    increment() {
+        return ++this.count;
+    }
+    countAttempts() {
+        attempts++;
+        this.count++;
+        return attempts;
+    }
+}"#;
+
+/// How long to wait on anything that should happen straight away, so a
+/// regression fails the test instead of hanging it
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// An endpoint methods fetch to wait on the test. Each request reports that
+/// it arrived, then waits until the test opens the gate.
+struct Gate {
+    url: String,
+    arrived: mpsc::UnboundedReceiver<()>,
+    open: watch::Sender<bool>,
+}
+
+impl Gate {
+    async fn new() -> Self {
+        let (arrived_tx, arrived) = mpsc::unbounded_channel();
+        let (open, open_rx) = watch::channel(false);
+
+        let router = Router::new()
+            .route("/", get(wait_at_gate))
+            .with_state((arrived_tx, open_rx));
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+
+        Self { url, arrived, open }
+    }
+
+    /// Waits for `count` methods to be held at the gate at once
+    async fn wait_for(&mut self, count: usize) {
+        for _ in 0..count {
+            tokio::time::timeout(TIMEOUT, self.arrived.recv())
+                .await
+                .expect("methods should reach the gate together")
+                .unwrap();
+        }
+    }
+
+    fn open(&self) {
+        self.open.send_replace(true);
+    }
+}
+
+async fn wait_at_gate(
+    State((arrived, mut open)): State<(mpsc::UnboundedSender<()>, watch::Receiver<bool>)>,
+) -> &'static str {
+    let _ = arrived.send(());
+    let _ = open.wait_for(|open| *open).await;
+    "open"
+}
+
+async fn server(gate: &Gate) -> CloudstateServer<SimpleCloudstateRunner> {
+    CloudstateServer::new(
         ReDBCloudstate::new(Arc::new(Mutex::new(
             redb::Database::builder()
                 .create_with_backend(redb::backends::InMemoryBackend::default())
                 .unwrap(),
-        ))),
-        r"export class CounterCS {
-                static id = 'counter';
-                count = 0;
-                async increment() {
-                    console.log('incrementing');
-                    await new Promise(resolve => setTimeout(resolve, 5000));
-                    console.log('incremented');
-                    return ++this.count;
-                }
-            }",
-        HashMap::new(),
+        )))
+        .unwrap(),
+        CloudstateBlobStorage::new(Arc::new(InMemoryBlobStore::default())),
+        CLASSES,
+        HashMap::from([("GATE_URL".to_string(), gate.url.clone())]),
+        "http://localhost:8910/__invalidate__".to_string(),
+        SimpleCloudstateRunner::new(),
+        ServerInfo {
+            deployment_id: None,
+            domain: None,
+        },
     )
-    .await;
-
-    let listener = TcpListener::bind("0.0.0.0:0").await.unwrap();
-    let addr = listener.local_addr().unwrap();
-
-    tokio::spawn(async move {
-        axum::serve(listener, router.router).await.unwrap();
-    });
-
-    let client = hyper_util::client::legacy::Client::builder(hyper_util::rt::TokioExecutor::new())
-        .build_http();
+    .await
+}
 
-    let response = client
-        .request(
+async fn call(router: Router, instance: &str, method: &str) -> serde_json::Value {
+    let response = router
+        .oneshot(
             Request::builder()
-                .uri(format!("http://{addr}"))
-                .header("Host", "localhost")
+                .uri(format!("/cloudstate/instances/{instance}/{method}"))
+                .method("POST")
+                .header(http::header::HOST, "localhost")
                 .header(http::header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
                 .body(Body::from(
                     serde_json::to_vec(&json!({
@@ -59,6 +158,123 @@ async fn the_real_deal() {
         .await
         .unwrap();
 
+    assert_eq!(response.status(), StatusCode::OK);
+
     let body = response.into_body().collect().await.unwrap().to_bytes();
-    assert_eq!(&body[..], b"Hello, World!");
+    serde_json::from_slice(&body).unwrap()
+}
+
+#[tokio::test]
+async fn test_read_only_methods_run_in_parallel() {
+    let _ = tracing_subscriber::fmt::try_init();
+
+    let mut gate = Gate::new().await;
+    let server = server(&gate).await;
+
+    let readers = (0..4)
+        .map(|_| tokio::spawn(call(server.router.clone(), "slow-counter", "slowGetCount")))
+        .collect::<Vec<_>>();
+
+    // each reader holds its snapshot at the gate, so they can only all get
+    // there if they run at the same time
+    gate.wait_for(4).await;
+    gate.open();
+
+    for response in join_all(readers).await {
+        assert_eq!(response.unwrap(), json!({ "result": 0 }));
+    }
+}
+
+#[tokio::test]
+async fn test_readers_do_not_wait_for_writers() {
+    let _ = tracing_subscriber::fmt::try_init();
+
+    let mut gate = Gate::new().await;
+    let server = server(&gate).await;
+
+    // the writer holds its transaction open at the gate
+    let writer = tokio::spawn(call(server.router.clone(), "slow-counter", "slowIncrement"));
+    gate.wait_for(1).await;
+
+    // the reader sees the snapshot from before the write
+    let response = tokio::time::timeout(
+        TIMEOUT,
+        call(server.router.clone(), "slow-counter", "getCount"),
+    )
+    .await
+    .expect("the reader shouldn't wait for the writer");
+    assert_eq!(response, json!({ "result": 0 }));
+    assert!(!writer.is_finished());
+
+    gate.open();
+    assert_eq!(writer.await.unwrap(), json!({ "result": 1 }));
+    assert_eq!(
+        call(server.router.clone(), "slow-counter", "getCount").await,
+        json!({ "result": 1 })
+    );
+}
+
+#[tokio::test]
+async fn test_read_only_method_cannot_write() {
+    let _ = tracing_subscriber::fmt::try_init();
+
+    let gate = Gate::new().await;
+    let server = server(&gate).await;
+
+    let response = call(server.router.clone(), "slow-counter", "sneakyIncrement").await;
+    assert!(response.get("error").is_some(), "got {response}");
+
+    assert_eq!(
+        call(server.router.clone(), "slow-counter", "getCount").await,
+        json!({ "result": 0 })
+    );
+}
+
+#[tokio::test]
+async fn test_optimistic_methods_retry_as_writers() {
+    let _ = tracing_subscriber::fmt::try_init();
+
+    let gate = Gate::new().await;
+    let server = server(&gate).await;
+
+    assert_eq!(
+        call(server.router.clone(), "optimistic-counter", "getCount").await,
+        json!({ "result": 0 })
+    );
+
+    let responses =
+        join_all((0..4).map(|_| call(server.router.clone(), "optimistic-counter", "increment")))
+            .await;
+
+    let mut results = responses
+        .into_iter()
+        .map(|response| response["result"].as_i64().unwrap())
+        .collect::<Vec<_>>();
+    results.sort();
+
+    // every increment is retried as a writer and none of them are lost
+    assert_eq!(results, vec![1, 2, 3, 4]);
+    assert_eq!(
+        call(server.router.clone(), "optimistic-counter", "getCount").await,
+        json!({ "result": 4 })
+    );
+}
+
+#[tokio::test]
+async fn test_optimistic_methods_run_again_from_the_start() {
+    let _ = tracing_subscriber::fmt::try_init();
+
+    let gate = Gate::new().await;
+    let server = server(&gate).await;
+
+    // the snapshot attempt got as far as its first write, so everything
+    // before it ran twice, but only the second attempt's writes are kept
+    assert_eq!(
+        call(server.router.clone(), "optimistic-counter", "countAttempts").await,
+        json!({ "result": 2 })
+    );
+    assert_eq!(
+        call(server.router.clone(), "optimistic-counter", "getCount").await,
+        json!({ "result": 1 })
+    );
 }
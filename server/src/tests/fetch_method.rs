@@ -10,10 +10,11 @@ use http_body_util::BodyExt;
 use std::{
     collections::HashMap,
     sync::{Arc, Mutex},
+    time::Duration,
 };
 use tower::{util::ServiceExt, Service};
 
-use crate::cloudstate_runner::simple::SimpleCloudstateRunner;
+use crate::cloudstate_runner::{limits::ScriptLimits, simple::SimpleCloudstateRunner};
 
 #[tokio::test]
 async fn test_fetch_request() {
@@ -23,7 +24,8 @@ async fn test_fetch_request() {
         redb::Database::builder()
             .create_with_backend(redb::backends::InMemoryBackend::default())
             .unwrap(),
-    )));
+    )))
+    .unwrap();
 
     let mut router = crate::CloudstateServer::new(
         cloudstate,
@@ -63,3 +65,245 @@ async fn test_fetch_request() {
 
     assert_eq!(body_str, "Hello, World!");
 }
+
+#[tokio::test]
+async fn test_fetch_request_body_and_streamed_response() {
+    let _ = tracing_subscriber::fmt::try_init();
+
+    let cloudstate = ReDBCloudstate::new(Arc::new(Mutex::new(
+        redb::Database::builder()
+            .create_with_backend(redb::backends::InMemoryBackend::default())
+            .unwrap(),
+    )))
+    .unwrap();
+
+    let mut router = crate::CloudstateServer::new(
+        cloudstate,
+        CloudstateBlobStorage::new(Arc::new(InMemoryBlobStore::new())),
+        r#"export class EchoCS {
+            static id = 'echo';
+            async fetch(req) {
+                const url = new URL(req.url);
+                const body = await req.text();
+                const parts = [req.method, url.searchParams.get('name'), body];
+                const encoder = new TextEncoder();
+                return new Response(
+                    new ReadableStream({
+                        pull(controller) {
+                            const part = parts.shift();
+                            if (part === undefined) {
+                                controller.close();
+                            } else {
+                                controller.enqueue(encoder.encode(part + '\n'));
+                            }
+                        },
+                    }),
+                    { status: 201, headers: { 'x-echo': 'yes' } },
+                );
+            }
+        }"#,
+        HashMap::new(),
+        "http://localhost:8910/__invalidate__".to_string(),
+        SimpleCloudstateRunner::new(),
+        crate::ServerInfo {
+            deployment_id: None,
+            domain: None,
+        },
+    )
+    .await;
+
+    let body = "x".repeat(1024 * 1024);
+    let response = ServiceExt::<Request<Body>>::ready(&mut router.router)
+        .await
+        .unwrap()
+        .call(
+            Request::builder()
+                .uri("/cloudstate/instances/echo?name=cloudstate")
+                .method("PUT")
+                .header(http::header::HOST, "localhost")
+                .body(Body::from(body.clone()))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), http::StatusCode::CREATED);
+    assert_eq!(response.headers()["x-echo"], "yes");
+
+    let response_body = response.into_body().collect().await.unwrap().to_bytes();
+    let response_body = String::from_utf8(response_body.to_vec()).unwrap();
+
+    assert_eq!(response_body, format!("PUT\ncloudstate\n{body}\n"));
+}
+
+/// Serves `classes` with scripts limited to running for a second
+async fn timed_server(classes: &str) -> axum::Router {
+    let cloudstate = ReDBCloudstate::new(Arc::new(Mutex::new(
+        redb::Database::builder()
+            .create_with_backend(redb::backends::InMemoryBackend::default())
+            .unwrap(),
+    )))
+    .unwrap();
+
+    crate::CloudstateServer::new(
+        cloudstate,
+        CloudstateBlobStorage::new(Arc::new(InMemoryBlobStore::new())),
+        classes,
+        HashMap::new(),
+        "http://localhost:8910/__invalidate__".to_string(),
+        SimpleCloudstateRunner::with_limits(ScriptLimits {
+            timeout: Some(Duration::from_secs(1)),
+            max_heap_bytes: None,
+        }),
+        crate::ServerInfo {
+            deployment_id: None,
+            domain: None,
+        },
+    )
+    .await
+    .router
+}
+
+async fn get(router: axum::Router, uri: &str) -> http::Response<Body> {
+    router
+        .oneshot(
+            Request::builder()
+                .uri(uri)
+                .method("GET")
+                .header(http::header::HOST, "localhost")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn test_fetch_error_rolls_back() {
+    let _ = tracing_subscriber::fmt::try_init();
+
+    let router = timed_server(
+        r#"export class FlakyCS {
+            static id = 'flaky';
+            count = 0;
+            async fetch(req) {
+                this.count++;
+                await new Promise(resolve => setTimeout(resolve, 100));
+                if (new URL(req.url).searchParams.has('fail')) {
+                    throw new Error('failed after incrementing');
+                }
+                return new Response(String(this.count));
+            }
+        }"#,
+    )
+    .await;
+
+    get(router.clone(), "/cloudstate/instances/flaky?fail").await;
+
+    // the failed request's increment was rolled back
+    let response = get(router, "/cloudstate/instances/flaky").await;
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    assert_eq!(body, "1");
+}
+
+#[tokio::test]
+async fn test_fetch_response_fails_mid_body() {
+    let _ = tracing_subscriber::fmt::try_init();
+
+    let router = timed_server(
+        r#"export class BrokenCS {
+            static id = 'broken';
+            fetch() {
+                let sent = false;
+                return new Response(
+                    new ReadableStream({
+                        pull(controller) {
+                            if (sent) {
+                                controller.error(new Error('the stream broke'));
+                            } else {
+                                sent = true;
+                                controller.enqueue(new TextEncoder().encode('partial'));
+                            }
+                        },
+                    }),
+                );
+            }
+        }"#,
+    )
+    .await;
+
+    let response = get(router, "/cloudstate/instances/broken").await;
+    assert_eq!(response.status(), http::StatusCode::OK);
+
+    // the body errors instead of ending after what was sent
+    let error = response.into_body().collect().await.unwrap_err();
+    assert!(error.to_string().contains("the stream broke"), "{error}");
+}
+
+#[tokio::test]
+async fn test_fetch_response_times_out_mid_body() {
+    let _ = tracing_subscriber::fmt::try_init();
+
+    let router = timed_server(
+        r#"export class SlowCS {
+            static id = 'slow';
+            fetch() {
+                let sent = false;
+                return new Response(
+                    new ReadableStream({
+                        pull(controller) {
+                            if (sent) {
+                                while (true) {}
+                            }
+                            sent = true;
+                            controller.enqueue(new TextEncoder().encode('partial'));
+                        },
+                    }),
+                );
+            }
+        }"#,
+    )
+    .await;
+
+    let response = get(router, "/cloudstate/instances/slow").await;
+    assert_eq!(response.status(), http::StatusCode::OK);
+
+    let error = response.into_body().collect().await.unwrap_err();
+    assert!(error.to_string().contains("terminated"), "{error}");
+}
+
+#[tokio::test]
+async fn test_fetch_response_waiting_on_client_is_not_timed() {
+    let _ = tracing_subscriber::fmt::try_init();
+
+    // far more than fits in the channel, so the script has to wait for the
+    // body to be read
+    let router = timed_server(
+        r#"export class LargeCS {
+            static id = 'large';
+            fetch() {
+                let sent = 0;
+                return new Response(
+                    new ReadableStream({
+                        pull(controller) {
+                            if (sent++ < 64) {
+                                controller.enqueue(new Uint8Array(64 * 1024));
+                            } else {
+                                controller.close();
+                            }
+                        },
+                    }),
+                );
+            }
+        }"#,
+    )
+    .await;
+
+    let response = get(router, "/cloudstate/instances/large").await;
+
+    // a client slower than the timeout
+    tokio::time::sleep(Duration::from_millis(1500)).await;
+
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    assert_eq!(body.len(), 64 * 64 * 1024);
+}
@@ -0,0 +1,125 @@
+use axum::{
+    Router,
+    body::Body,
+    http::{self, Request},
+};
+use cloudstate_runtime::{
+    ServerInfo,
+    blob_storage::{CloudstateBlobStorage, in_memory_store::InMemoryBlobStore},
+    extensions::cloudstate::ReDBCloudstate,
+};
+use http_body_util::BodyExt;
+use serde_json::json;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tower::util::ServiceExt;
+
+use crate::{
+    CloudstateServer,
+    cloudstate_runner::{limits::ScriptLimits, simple::SimpleCloudstateRunner},
+};
+
+const CLASSES: &str = r#"export class Counter {
+    static id = 'counter';
+    count = 0;
+    incrementForever() {
+        this.count++;
+        while (true) {}
+    }
+    allocateForever() {
+        this.count++;
+        const chunks = [];
+        while (true) {
+            chunks.push(new Array(1024 * 1024).fill(chunks.length));
+        }
+    }
+    getCount() {
+        return this.count;
+    }
+}"#;
+
+async fn server(limits: ScriptLimits) -> CloudstateServer<SimpleCloudstateRunner> {
+    CloudstateServer::new(
+        ReDBCloudstate::new(Arc::new(Mutex::new(
+            redb::Database::builder()
+                .create_with_backend(redb::backends::InMemoryBackend::default())
+                .unwrap(),
+        )))
+        .unwrap(),
+        CloudstateBlobStorage::new(Arc::new(InMemoryBlobStore::default())),
+        CLASSES,
+        HashMap::new(),
+        "http://localhost:8910/__invalidate__".to_string(),
+        SimpleCloudstateRunner::with_limits(limits),
+        ServerInfo {
+            deployment_id: None,
+            domain: None,
+        },
+    )
+    .await
+}
+
+async fn call(router: Router, method: &str) -> serde_json::Value {
+    let response = router
+        .oneshot(
+            Request::builder()
+                .uri(format!("/cloudstate/instances/counter/{method}"))
+                .method("POST")
+                .header(http::header::HOST, "localhost")
+                .header(http::header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+                .body(Body::from(
+                    serde_json::to_vec(&json!({
+                        "params": []
+                    }))
+                    .unwrap(),
+                ))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    serde_json::from_slice(&body).unwrap()
+}
+
+#[tokio::test]
+async fn test_timeout_terminates_script() {
+    let _ = tracing_subscriber::fmt::try_init();
+
+    let server = server(ScriptLimits {
+        timeout: Some(Duration::from_secs(1)),
+        max_heap_bytes: None,
+    })
+    .await;
+
+    let result = call(server.router.clone(), "incrementForever").await;
+    assert_eq!(result["error"]["code"], json!("timeout"));
+
+    // the increment was rolled back along with the terminated call
+    assert_eq!(
+        call(server.router.clone(), "getCount").await,
+        json!({ "result": 0 })
+    );
+}
+
+#[tokio::test]
+async fn test_heap_limit_terminates_script() {
+    let _ = tracing_subscriber::fmt::try_init();
+
+    let server = server(ScriptLimits {
+        timeout: None,
+        max_heap_bytes: Some(64 * 1024 * 1024),
+    })
+    .await;
+
+    let result = call(server.router.clone(), "allocateForever").await;
+    assert_eq!(result["error"]["code"], json!("heap_limit"));
+
+    assert_eq!(
+        call(server.router.clone(), "getCount").await,
+        json!({ "result": 0 })
+    );
+}
@@ -0,0 +1,191 @@
+use axum::{
+    Router,
+    body::Body,
+    http::{self, Request, StatusCode},
+};
+use cloudstate_runtime::{
+    ServerInfo,
+    blob_storage::{CloudstateBlobStorage, in_memory_store::InMemoryBlobStore},
+    extensions::cloudstate::ReDBCloudstate,
+};
+use http_body_util::BodyExt;
+use serde_json::json;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+use tower::util::ServiceExt;
+
+use crate::{
+    CloudstateServer,
+    cloudstate_runner::{
+        limits::ScriptLimits,
+        pooled::{PoolOptions, PooledCloudstateRunner},
+    },
+};
+
+const CLASSES: &str = r#"let calls = 0;
+
+export class Counter {
+    static id = 'counter';
+    count = 0;
+    increment() {
+        return ++this.count;
+    }
+    getCount() {
+        return this.count;
+    }
+    leak() {
+        globalThis.leaked = true;
+        return true;
+    }
+    isLeaked() {
+        return globalThis.leaked ?? false;
+    }
+    countCalls() {
+        return ++calls;
+    }
+    allocateForever() {
+        this.count++;
+        const chunks = [];
+        while (true) {
+            chunks.push(new Array(1024 * 1024).fill(chunks.length));
+        }
+    }
+}"#;
+
+async fn server(options: PoolOptions) -> CloudstateServer<PooledCloudstateRunner> {
+    CloudstateServer::new(
+        ReDBCloudstate::new(Arc::new(Mutex::new(
+            redb::Database::builder()
+                .create_with_backend(redb::backends::InMemoryBackend::default())
+                .unwrap(),
+        )))
+        .unwrap(),
+        CloudstateBlobStorage::new(Arc::new(InMemoryBlobStore::default())),
+        CLASSES,
+        HashMap::new(),
+        "http://localhost:8910/__invalidate__".to_string(),
+        PooledCloudstateRunner::new(options),
+        ServerInfo {
+            deployment_id: None,
+            domain: None,
+        },
+    )
+    .await
+}
+
+async fn call(router: Router, method: &str) -> serde_json::Value {
+    let response = router
+        .oneshot(
+            Request::builder()
+                .uri(format!("/cloudstate/instances/counter/{method}"))
+                .method("POST")
+                .header(http::header::HOST, "localhost")
+                .header(http::header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+                .body(Body::from(
+                    serde_json::to_vec(&json!({
+                        "params": []
+                    }))
+                    .unwrap(),
+                ))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    serde_json::from_slice(&body).unwrap()
+}
+
+#[tokio::test]
+async fn test_pooled_isolates_see_each_others_writes() {
+    let _ = tracing_subscriber::fmt::try_init();
+
+    // a few requests per isolate, so some are served warm and some recycled
+    let server = server(PoolOptions {
+        workers: 2,
+        max_requests: 3,
+        ..PoolOptions::default()
+    })
+    .await;
+
+    for expected in 1..=10 {
+        assert_eq!(
+            call(server.router.clone(), "increment").await,
+            json!({ "result": expected })
+        );
+    }
+    assert_eq!(
+        call(server.router.clone(), "getCount").await,
+        json!({ "result": 10 })
+    );
+}
+
+#[tokio::test]
+async fn test_pooled_isolates_reset_globals() {
+    let _ = tracing_subscriber::fmt::try_init();
+
+    let server = server(PoolOptions {
+        workers: 1,
+        ..PoolOptions::default()
+    })
+    .await;
+
+    assert_eq!(
+        call(server.router.clone(), "leak").await,
+        json!({ "result": true })
+    );
+    assert_eq!(
+        call(server.router.clone(), "isLeaked").await,
+        json!({ "result": false })
+    );
+}
+
+#[tokio::test]
+async fn test_pooled_isolates_reset_module_state() {
+    let _ = tracing_subscriber::fmt::try_init();
+
+    let server = server(PoolOptions {
+        workers: 1,
+        ..PoolOptions::default()
+    })
+    .await;
+
+    // each request imports the classes afresh, like it would with a runtime
+    // of its own
+    for _ in 0..3 {
+        assert_eq!(
+            call(server.router.clone(), "countCalls").await,
+            json!({ "result": 1 })
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_pooled_isolates_replaced_after_heap_limit() {
+    let _ = tracing_subscriber::fmt::try_init();
+
+    let server = server(PoolOptions {
+        workers: 1,
+        limits: ScriptLimits {
+            timeout: None,
+            max_heap_bytes: Some(64 * 1024 * 1024),
+        },
+        ..PoolOptions::default()
+    })
+    .await;
+
+    // the isolate a script ran out of heap in is replaced, rather than kept
+    // with the limit it raised to unwind, and the pool carries on serving
+    for _ in 0..2 {
+        let result = call(server.router.clone(), "allocateForever").await;
+        assert_eq!(result["error"]["code"], json!("heap_limit"));
+        assert_eq!(
+            call(server.router.clone(), "getCount").await,
+            json!({ "result": 0 })
+        );
+    }
+}